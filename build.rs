@@ -1,11 +1,64 @@
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::configure()
         .build_server(true)
         .build_client(false)
         .file_descriptor_set_path("target/snowgauge_descriptor.bin")
         .compile_protos(
-            &["proto/snowgauge.proto"],
+            &["proto/snowgauge.proto", "proto/health.proto"],
             &["proto"],
         )?;
+
+    embed_build_info()?;
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Pass the build details reported by GetBuildInfo to the compiler as
+/// SNOWGAUGE_* environment variables
+fn embed_build_info() -> Result<(), Box<dyn std::error::Error>> {
+    let git_describe = command_output("git", &["describe", "--always", "--dirty", "--tags"])
+        .unwrap_or_else(|| "unknown".to_string());
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = command_output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".to_string());
+    // SOURCE_DATE_EPOCH keeps reproducible builds reproducible
+    let build_time = match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(epoch) => epoch.parse()?,
+        Err(_) => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+    };
+    let proto = std::fs::read("proto/snowgauge.proto")?;
+
+    println!("cargo:rustc-env=SNOWGAUGE_GIT_DESCRIBE={}", git_describe);
+    println!("cargo:rustc-env=SNOWGAUGE_RUSTC_VERSION={}", rustc_version);
+    println!("cargo:rustc-env=SNOWGAUGE_BUILD_TIME={}", build_time);
+    println!("cargo:rustc-env=SNOWGAUGE_PROTO_REVISION={:016x}", fnv1a(&proto));
+    println!("cargo:rustc-env=SNOWGAUGE_TARGET={}", std::env::var("TARGET")?);
+
+    // Describe the new commit after a commit or checkout
+    for path in [".git/HEAD", ".git/index"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+    if let Some(head) = std::fs::read_to_string(".git/HEAD").ok().and_then(|h| h.strip_prefix("ref: ").map(|r| r.trim().to_string())) {
+        let path = format!(".git/{}", head);
+        if Path::new(&path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    Ok(())
+}
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    let text = String::from_utf8(output.stdout).ok()?;
+    (output.status.success() && !text.trim().is_empty()).then(|| text.trim().to_string())
+}
+
+/// 64-bit FNV-1a, a stable fingerprint of the proto file
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
@@ -0,0 +1,256 @@
+/// Client address allow and deny lists
+///
+/// TCP connections are checked by their remote address as they are
+/// accepted, and each gRPC request by the address of the connection it
+/// arrived on. An address matching a deny entry is always refused;
+/// otherwise it is admitted if the allow list is empty or it matches an
+/// allow entry. This keeps the gauge to known collector subnets without
+/// needing certificates. Unix socket clients have no address and are vetted
+/// by the socket's file permissions instead, so they always pass.
+use std::io;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use log::warn;
+use tokio::net::TcpStream;
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Request, Status};
+
+/// An IPv4 or IPv6 network in CIDR notation; a bare address is a single host
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        // Dual-stack listeners report IPv4 clients as mapped IPv6 addresses
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+            v4 => v4,
+        };
+        match (self.network, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let network: IpAddr = addr.parse().map_err(|_| format!("Invalid network '{}': bad address", s))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .parse::<u8>()
+                .ok()
+                .filter(|&p| p <= max)
+                .ok_or_else(|| format!("Invalid network '{}': prefix must be 0-{}", s, max))?,
+            None => max,
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AccessList {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    pub fn new(allow: Vec<Cidr>, deny: Vec<Cidr>) -> Self {
+        Self { allow, deny }
+    }
+
+    pub fn permits(&self, addr: IpAddr) -> bool {
+        !self.deny.iter().any(|c| c.contains(addr)) && (self.allow.is_empty() || self.allow.iter().any(|c| c.contains(addr)))
+    }
+
+    /// Refuse a request from an address the lists don't admit
+    ///
+    /// Any other request with no remote address is only admitted with an
+    /// empty allow list, since it can't be shown to come from an allowed
+    /// network.
+    #[allow(clippy::result_large_err)]
+    pub fn check<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        let admitted = match request.remote_addr() {
+            Some(addr) => self.permits(addr.ip()),
+            None if is_unix_socket(request) => true,
+            None => self.allow.is_empty(),
+        };
+        if admitted {
+            return Ok(());
+        }
+        let addr = request
+            .remote_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        warn!("Refusing gRPC request from [{}]: address not allowed", addr);
+        Err(Status::permission_denied("client address not allowed"))
+    }
+}
+
+#[cfg(unix)]
+fn is_unix_socket<T>(request: &Request<T>) -> bool {
+    request.extensions().get::<tonic::transport::server::UdsConnectInfo>().is_some()
+}
+
+#[cfg(not(unix))]
+fn is_unix_socket<T>(_request: &Request<T>) -> bool {
+    false
+}
+
+/// Close accepted connections from addresses `access` doesn't admit, before
+/// any service, health and reflection included, sees them
+pub fn filter_incoming<S>(incoming: S, access: Arc<AccessList>) -> impl Stream<Item = io::Result<TcpStream>>
+where
+    S: Stream<Item = io::Result<TcpStream>>,
+{
+    incoming.filter(move |connection| match connection.as_ref().map(TcpStream::peer_addr) {
+        Ok(Ok(addr)) if !access.permits(addr.ip()) => {
+            warn!("Refusing gRPC connection from [{}]: address not allowed", addr);
+            false
+        }
+        _ => true,
+    })
+}
+
+/// Interceptor checking `access` before attaching the request's trace context
+#[allow(clippy::result_large_err)]
+pub fn interceptor(access: Arc<AccessList>) -> impl Fn(Request<()>) -> Result<Request<()>, Status> + Clone {
+    move |request| {
+        access.check(&request)?;
+        crate::trace::interceptor(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn cidrs(list: &[&str]) -> Vec<Cidr> {
+        list.iter().map(|s| s.parse().unwrap()).collect()
+    }
+
+    /// Serve the health service on a local port behind `access`
+    async fn serve_health(access: AccessList) -> std::net::SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = filter_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener), Arc::new(access));
+        let (_, healthy) = tokio::sync::watch::channel(true);
+        let health = crate::health::proto::health_server::HealthServer::new(crate::health::HealthService::new(healthy));
+        tokio::spawn(tonic::transport::Server::builder().add_service(health).serve_with_incoming(incoming));
+        addr
+    }
+
+    async fn check_health(addr: std::net::SocketAddr) -> Result<i32, Status> {
+        let channel = tonic::transport::Endpoint::from_shared(format!("http://{}", addr))
+            .unwrap()
+            .connect()
+            .await
+            .map_err(|e| Status::unavailable(e.to_string()))?;
+        let mut client = tonic::client::Grpc::new(channel);
+        client.ready().await.map_err(|e| Status::unavailable(e.to_string()))?;
+        let request = Request::new(crate::health::proto::HealthCheckRequest { service: String::new() });
+        let path = http::uri::PathAndQuery::from_static("/grpc.health.v1.Health/Check");
+        let response: tonic::Response<crate::health::proto::HealthCheckResponse> =
+            client.unary(request, path, tonic::codec::ProstCodec::default()).await?;
+        Ok(response.into_inner().status)
+    }
+
+    #[tokio::test]
+    async fn test_denied_connection_reaches_no_service() {
+        let open = serve_health(AccessList::default()).await;
+        assert_eq!(check_health(open).await.unwrap(), crate::health::proto::health_check_response::ServingStatus::Serving as i32);
+
+        let denied = serve_health(AccessList::new(vec![], cidrs(&["127.0.0.0/8"]))).await;
+        assert!(check_health(denied).await.is_err());
+        let not_allowed = serve_health(AccessList::new(cidrs(&["10.0.0.0/8"]), vec![])).await;
+        assert!(check_health(not_allowed).await.is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("10.1.0.0/16".parse::<Cidr>().unwrap().to_string(), "10.1.0.0/16");
+        assert_eq!("192.168.1.5".parse::<Cidr>().unwrap().to_string(), "192.168.1.5/32");
+        assert_eq!("fd00::/8".parse::<Cidr>().unwrap().to_string(), "fd00::/8");
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("fd00::/129".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_contains() {
+        let net: Cidr = "10.20.0.0/14".parse().unwrap();
+        assert!(net.contains(ip("10.23.255.1")));
+        assert!(!net.contains(ip("10.24.0.1")));
+        assert!(net.contains(ip("::ffff:10.21.0.9")));
+        assert!(!net.contains(ip("fd00::1")));
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("203.0.113.7")));
+        let v6: Cidr = "fd12:3456::/32".parse().unwrap();
+        assert!(v6.contains(ip("fd12:3456:1::1")));
+        assert!(!v6.contains(ip("fd12:3457::1")));
+    }
+
+    #[test]
+    fn test_permits() {
+        assert!(AccessList::default().permits(ip("203.0.113.7")));
+
+        let access = AccessList::new(cidrs(&["10.0.0.0/8", "127.0.0.1"]), cidrs(&["10.66.0.0/16"]));
+        assert!(access.permits(ip("10.1.2.3")));
+        assert!(access.permits(ip("127.0.0.1")));
+        assert!(!access.permits(ip("10.66.1.1")));
+        assert!(!access.permits(ip("192.168.1.1")));
+
+        let deny_only = AccessList::new(Vec::new(), cidrs(&["192.168.0.0/16"]));
+        assert!(deny_only.permits(ip("10.1.2.3")));
+        assert!(!deny_only.permits(ip("192.168.4.4")));
+    }
+
+    #[test]
+    fn test_check_without_remote_addr() {
+        let request = Request::new(());
+        assert!(AccessList::default().check(&request).is_ok());
+        let access = AccessList::new(cidrs(&["10.0.0.0/8"]), Vec::new());
+        assert_eq!(access.check(&request).unwrap_err().code(), tonic::Code::PermissionDenied);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_socket_admitted() {
+        let mut request = Request::new(());
+        request.extensions_mut().insert(tonic::transport::server::UdsConnectInfo {
+            peer_addr: None,
+            peer_cred: None,
+        });
+        let access = AccessList::new(cidrs(&["10.0.0.0/8"]), Vec::new());
+        assert!(access.check(&request).is_ok());
+    }
+}
@@ -0,0 +1,157 @@
+/// Anomaly detection over the emitted series
+///
+/// Each emitted reading is scored against the readings before it with a
+/// robust z-score: its distance from their median in units of their median
+/// absolute deviation (scaled to match a standard deviation under normal
+/// noise). The batch filters only see one batch at a time, so a batch that
+/// is internally consistent but out of line with the recent series (a
+/// deflected sensor, an object under the gauge, a sudden board clearing)
+/// passes them; this flags it.
+use std::collections::VecDeque;
+use std::time::SystemTime;
+
+/// Readings needed before anything is flagged
+const MIN_SAMPLES: usize = 10;
+
+/// Scale factor from MAD to standard deviation for normally distributed noise
+const MAD_SCALE: f64 = 1.4826;
+
+/// Floor on the scaled MAD (mm), so a perfectly steady series does not flag
+/// sub-millimetre changes
+const MIN_SPREAD: f64 = 0.5;
+
+/// A reading flagged as inconsistent with recent behavior
+#[derive(Debug, Clone, PartialEq)]
+pub struct Anomaly {
+    pub timestamp: SystemTime,
+    pub distance: f64,
+    /// Median of the recent readings it was compared with
+    pub expected: f64,
+    /// Robust z-score; positive means the reading is longer than expected
+    pub score: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct AnomalyDetector {
+    window: usize,
+    threshold: f64,
+    recent: VecDeque<f64>,
+}
+
+impl AnomalyDetector {
+    /// Flag readings scoring above `threshold` against the previous `window` readings
+    pub fn new(window: usize, threshold: f64) -> Self {
+        Self {
+            window: window.max(MIN_SAMPLES),
+            threshold,
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// Score a reading and add it to the recent series
+    ///
+    /// Flagged readings are kept in the series too, so a lasting change of
+    /// level stops being flagged once it makes up half the window.
+    pub fn check(&mut self, timestamp: SystemTime, distance: f64) -> Option<Anomaly> {
+        let anomaly = if self.recent.len() >= MIN_SAMPLES {
+            let mut sorted: Vec<f64> = self.recent.iter().copied().collect();
+            sorted.sort_by(|a, b| a.total_cmp(b));
+            let expected = median(&sorted);
+            let mut deviations: Vec<f64> = sorted.iter().map(|v| (v - expected).abs()).collect();
+            deviations.sort_by(|a, b| a.total_cmp(b));
+            let spread = (median(&deviations) * MAD_SCALE).max(MIN_SPREAD);
+            let score = (distance - expected) / spread;
+            (score.abs() > self.threshold).then_some(Anomaly {
+                timestamp,
+                distance,
+                expected,
+                score,
+            })
+        } else {
+            None
+        };
+
+        if self.recent.len() >= self.window {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(distance);
+        anomaly
+    }
+}
+
+/// Median of a sorted, non-empty slice
+fn median(sorted: &[f64]) -> f64 {
+    let mid = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        (sorted[mid - 1] + sorted[mid]) / 2.0
+    } else {
+        sorted[mid]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noisy(i: usize) -> f64 {
+        1500.0 - i as f64 * 0.1 + ((i * 7919) % 7) as f64 - 3.0
+    }
+
+    #[test]
+    fn test_no_flags_on_normal_series() {
+        let mut detector = AnomalyDetector::new(30, 5.0);
+        for i in 0..200 {
+            assert!(detector.check(SystemTime::UNIX_EPOCH, noisy(i)).is_none(), "reading {}", i);
+        }
+    }
+
+    #[test]
+    fn test_flags_spike() {
+        let mut detector = AnomalyDetector::new(30, 5.0);
+        for i in 0..50 {
+            detector.check(SystemTime::UNIX_EPOCH, noisy(i));
+        }
+        let anomaly = detector.check(SystemTime::UNIX_EPOCH, 1300.0).unwrap();
+        assert!(anomaly.score < -5.0);
+        assert!((anomaly.expected - 1496.0).abs() < 3.0);
+        assert!(detector.check(SystemTime::UNIX_EPOCH, noisy(51)).is_none());
+    }
+
+    #[test]
+    fn test_warm_up() {
+        let mut detector = AnomalyDetector::new(30, 5.0);
+        for i in 0..MIN_SAMPLES {
+            detector.check(SystemTime::UNIX_EPOCH, 1000.0 + i as f64);
+        }
+        assert!(detector.check(SystemTime::UNIX_EPOCH, 5000.0).is_some());
+
+        let mut detector = AnomalyDetector::new(30, 5.0);
+        for _ in 0..MIN_SAMPLES - 1 {
+            detector.check(SystemTime::UNIX_EPOCH, 1000.0);
+        }
+        assert!(detector.check(SystemTime::UNIX_EPOCH, 5000.0).is_none());
+    }
+
+    #[test]
+    fn test_steady_series_uses_spread_floor() {
+        let mut detector = AnomalyDetector::new(30, 5.0);
+        for _ in 0..30 {
+            detector.check(SystemTime::UNIX_EPOCH, 1000.0);
+        }
+        assert!(detector.check(SystemTime::UNIX_EPOCH, 1002.0).is_none());
+        assert!(detector.check(SystemTime::UNIX_EPOCH, 1003.0).is_some());
+    }
+
+    #[test]
+    fn test_level_change_accepted() {
+        let mut detector = AnomalyDetector::new(20, 5.0);
+        for _ in 0..20 {
+            detector.check(SystemTime::UNIX_EPOCH, 1000.0);
+        }
+        // Board cleared: the new level is flagged until it dominates the window
+        let flagged = (0..20)
+            .filter(|_| detector.check(SystemTime::UNIX_EPOCH, 1200.0).is_some())
+            .count();
+        assert_eq!(flagged, 10);
+    }
+}
@@ -0,0 +1,829 @@
+/// Command line arguments and subcommands
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use clap::Parser;
+use serialport::{DataBits, Parity, StopBits};
+
+use crate::acl::Cidr;
+use crate::battery::VoltageProvider;
+use crate::compensation::AmbientSource;
+use crate::filter::FilterPipeline;
+use crate::frame::{Checksum, FrameFormat, FrameSpec};
+use crate::i2c::{self, LidarMode};
+use crate::interpolate::Interpolation;
+use crate::lora;
+use crate::modbus::{DataType, ModbusConfig, RegisterType};
+use crate::mqtt::MqttConfig;
+use crate::queue::OverflowPolicy;
+use crate::schedule::PowerLine;
+use crate::sensor_filter::FilterType;
+use crate::serial::{self, SerialConfig};
+use crate::snowgauge::Unit;
+use crate::source::SensorSource;
+use crate::stations::StationSpec;
+use crate::supervisor::RestartPolicy;
+use crate::swe::DensityModel;
+
+/// Command line arguments
+#[derive(Parser, Debug, Clone)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Serial port name, or tcp://host:port or rfc2217://host:port for a serial server
+    #[arg(long, env = "PORT", default_value = "/dev/ttyS0")]
+    pub port: String,
+
+    /// Serial baud rate
+    #[arg(long, env = "BAUD_RATE", default_value = "9600", value_parser = clap::value_parser!(u32).range(1..))]
+    baud_rate: u32,
+
+    /// Serial data bits: 5, 6, 7, or 8
+    #[arg(long, env = "DATA_BITS", default_value = "8", value_parser = serial::parse_data_bits)]
+    data_bits: DataBits,
+
+    /// Serial parity: none, odd, or even
+    #[arg(long, env = "PARITY", default_value = "none", value_parser = serial::parse_parity)]
+    parity: Parity,
+
+    /// Serial stop bits: 1 or 2
+    #[arg(long, env = "STOP_BITS", default_value = "1", value_parser = serial::parse_stop_bits)]
+    stop_bits: StopBits,
+
+    /// Sensor frame format: r3 (R + 3 digits in cm, the XL-MaxSonar), r4 (R + 4 digits in mm, the MB7544 and most MaxBotix sensors), r5 (R + 5 digits, the MB7360/MB7369), any of those with -crlf for frames ending \r\n, auto to detect it, or nmea (nmea:FIELD) for NMEA-style sentences with the distance in field 1 (FIELD)
+    #[arg(long, env = "FRAME_FORMAT", default_value = "r4", value_parser = clap::value_parser!(FrameFormat))]
+    frame_format: FrameFormat,
+
+    /// Checksum byte the sensor appends before the frame's terminator: none, xor, or sum (modulo 256); NMEA sentences carry their own
+    #[arg(long, env = "FRAME_CHECKSUM", default_value = "none", value_parser = clap::value_parser!(Checksum))]
+    pub frame_checksum: Checksum,
+
+    /// JSON frame spec describing the sensor's frames, used instead of --frame-format
+    #[arg(long, env = "FRAME_SPEC")]
+    frame_spec: Option<PathBuf>,
+
+    /// Where distances are read from: serial (a serial port or serial server, see --port), i2cxl (an I2CXL-MaxSonar such as the MB7040 on --i2c-bus), lidar-lite (a Garmin LIDAR-Lite on --i2c-bus), modbus (a Modbus RTU level sensor on --port), gpio (a sensor's pulse-width output on --gpio-line), or mqtt (distances published to --mqtt-topic)
+    #[arg(long, env = "SOURCE", default_value = "serial", value_parser = clap::value_parser!(SensorSource))]
+    pub source: SensorSource,
+
+    /// I2C bus of an I2C sensor
+    #[arg(long, env = "I2C_BUS", default_value = "/dev/i2c-1")]
+    pub i2c_bus: PathBuf,
+
+    /// Address of an I2C sensor, e.g. 0x70 [default: 0x70 for i2cxl, 0x62 for lidar-lite]
+    #[arg(long, env = "I2C_ADDRESS", value_parser = i2c::parse_address)]
+    i2c_address: Option<u16>,
+
+    /// Milliseconds between readings from sensors that range on request
+    #[arg(long, env = "POLL_INTERVAL", default_value = "200")]
+    pub poll_interval: u64,
+
+    /// LIDAR-Lite acquisition mode: default, short-range, max-range, high-sensitivity, or low-sensitivity (fewer false returns)
+    #[arg(long, env = "LIDAR_MODE", default_value = "default", value_parser = clap::value_parser!(LidarMode))]
+    pub lidar_mode: LidarMode,
+
+    /// LIDAR-Lite readings between receiver bias corrections; 1 corrects every reading
+    #[arg(long, env = "LIDAR_BIAS_INTERVAL", default_value = "100", value_parser = clap::value_parser!(u32).range(1..))]
+    pub lidar_bias_interval: u32,
+
+    /// Modbus unit (slave) address of a Modbus sensor
+    #[arg(long, env = "MODBUS_UNIT", default_value = "1", value_parser = clap::value_parser!(u8).range(1..=247))]
+    modbus_unit: u8,
+
+    /// Register holding a Modbus sensor's distance, counting from 0
+    #[arg(long, env = "MODBUS_REGISTER", default_value = "0")]
+    modbus_register: u16,
+
+    /// Modbus register type: holding or input
+    #[arg(long, env = "MODBUS_REGISTER_TYPE", default_value = "holding", value_parser = clap::value_parser!(RegisterType))]
+    modbus_register_type: RegisterType,
+
+    /// Modbus register data type: u16, i16, or u32, i32, f32 in two registers, high word first
+    #[arg(long, env = "MODBUS_DATA_TYPE", default_value = "u16", value_parser = clap::value_parser!(DataType))]
+    modbus_data_type: DataType,
+
+    /// Multiplies a Modbus sensor's value into mm, e.g. 10 for cm or 1000 for m
+    #[arg(long, env = "MODBUS_SCALE", default_value = "1.0")]
+    modbus_scale: f64,
+
+    /// GPIO chip of a pulse-width sensor's line
+    #[arg(long, env = "GPIO_CHIP", default_value = "/dev/gpiochip0")]
+    pub gpio_chip: PathBuf,
+
+    /// GPIO line (offset on --gpio-chip) wired to a sensor's PW output, required with --source gpio
+    #[arg(long, env = "GPIO_LINE")]
+    pub gpio_line: Option<u32>,
+
+    /// Pulse width in µs per mm: 1 for the HRXL-MaxSonar, 5.8 for XL-MaxSonar models reporting in cm
+    #[arg(long, env = "PULSE_US_PER_MM", default_value = "1.0")]
+    pub pulse_us_per_mm: f64,
+
+    /// MQTT broker distances are published to, as host or host:port, required with --source mqtt
+    #[arg(long, env = "MQTT_BROKER")]
+    pub mqtt_broker: Option<String>,
+
+    /// MQTT topic distances are published on; wildcards are allowed
+    #[arg(long, env = "MQTT_TOPIC")]
+    pub mqtt_topic: Option<String>,
+
+    /// JSON field of each message holding the distance, e.g. sensor.distance; without it messages are plain numbers
+    #[arg(long, env = "MQTT_FIELD")]
+    mqtt_field: Option<String>,
+
+    /// MQTT user name
+    #[arg(long, env = "MQTT_USERNAME")]
+    mqtt_username: Option<String>,
+
+    /// MQTT password
+    #[arg(long, env = "MQTT_PASSWORD", hide_env_values = true)]
+    mqtt_password: Option<String>,
+
+    /// Multiplies each MQTT distance into mm, e.g. 10 for cm
+    #[arg(long, env = "MQTT_SCALE", default_value = "1.0")]
+    mqtt_scale: f64,
+
+    /// Turn on debugging output
+    #[arg(long, env = "DEBUG")]
+    pub debug: bool,
+
+    /// Addresses to listen on for gRPC connections (repeatable or comma-separated)
+    #[arg(long, env = "LISTEN_ADDR", default_value = "0.0.0.0:7669", value_delimiter = ',')]
+    pub listen_addr: Vec<SocketAddr>,
+
+    /// Unix domain socket to also listen on for gRPC connections
+    #[arg(long, env = "LISTEN_UNIX")]
+    pub listen_unix: Option<PathBuf>,
+
+    /// Permissions (octal) of the --listen-unix socket
+    #[arg(long, env = "LISTEN_UNIX_MODE", default_value = "660", value_parser = parse_mode)]
+    pub listen_unix_mode: u32,
+
+    /// Networks (CIDR, comma-separated) allowed to make gRPC requests; all if empty
+    #[arg(long, env = "ALLOW_CIDRS", value_delimiter = ',', value_parser = clap::value_parser!(Cidr))]
+    pub allow_cidrs: Vec<Cidr>,
+
+    /// Networks (CIDR, comma-separated) refused even if allowed
+    #[arg(long, env = "DENY_CIDRS", value_delimiter = ',', value_parser = clap::value_parser!(Cidr))]
+    pub deny_cidrs: Vec<Cidr>,
+
+    /// Unary RPCs allowed per second from each client address (0 for no limit)
+    #[arg(long, env = "RPC_RATE_LIMIT", default_value = "5")]
+    pub rpc_rate_limit: f64,
+
+    /// Unary RPCs a client may make in a burst before --rpc-rate-limit applies
+    #[arg(long, env = "RPC_BURST", default_value = "20")]
+    pub rpc_burst: usize,
+
+    /// Seconds without a heartbeat before a bidirectional stream client is dropped
+    #[arg(long, env = "HEARTBEAT_TIMEOUT", default_value = "30")]
+    pub heartbeat_timeout: u64,
+
+    /// Number of recent batch readings retained for resuming streams
+    #[arg(long, env = "REPLAY_BUFFER", default_value = "1000")]
+    pub replay_buffer: usize,
+
+    /// Maximum number of open gRPC streams (0 for no limit)
+    #[arg(long, env = "MAX_CLIENTS", default_value = "100")]
+    pub max_clients: usize,
+
+    /// Readings queued for a stream client before --slow-client-policy applies
+    #[arg(long, env = "CLIENT_QUEUE_SIZE", default_value = "256")]
+    pub client_queue_size: usize,
+
+    /// What to do when a stream client's queue is full: drop-oldest, drop-newest, or disconnect
+    #[arg(long, env = "SLOW_CLIENT_POLICY", default_value = "drop-oldest", value_parser = clap::value_parser!(OverflowPolicy))]
+    pub slow_client_policy: OverflowPolicy,
+
+    /// Unit of the full-precision reading value for clients that don't ask for one: mm, cm, or in
+    #[arg(long, env = "UNIT", default_value = "mm", value_parser = clap::value_parser!(Unit))]
+    pub unit: Unit,
+
+    /// Log the distance to stdout
+    #[arg(long, env = "LOG_DISTANCE")]
+    pub log: bool,
+
+    /// Enable simulator mode
+    #[arg(long, env = "SIMULATOR")]
+    pub simulator: bool,
+
+    /// Base distance for simulator (starting distance in mm)
+    #[arg(long, env = "SIMULATOR_BASE_DISTANCE", default_value = "1000.0")]
+    pub simulator_base_distance: f64,
+
+    /// Station name for this snow gauge
+    #[arg(long, env = "STATION_NAME", default_value = "snowgauge")]
+    pub station_name: String,
+
+    /// JSON file listing more stations for this daemon to serve, each with its own station name, port, and filter preset
+    #[arg(long, env = "STATIONS")]
+    pub stations: Option<PathBuf>,
+
+    /// Other stations' sensors on the same surface, fused with this one's into a best-estimate distance (comma-separated)
+    #[arg(long, env = "FUSE_WITH", value_delimiter = ',')]
+    pub fuse_with: Vec<String>,
+
+    /// Standard deviation of a single reading in mm, weighting the sensor in fusion; the source's typical noise by default
+    #[arg(long, env = "SENSOR_NOISE")]
+    sensor_noise: Option<f64>,
+
+    /// Oldest batch result (seconds) of another station's sensor used in fusion
+    #[arg(long, env = "FUSION_MAX_AGE", default_value = "60")]
+    pub fusion_max_age: u64,
+
+    /// Station latitude in decimal degrees, north positive
+    #[arg(long, env = "LATITUDE", allow_negative_numbers = true)]
+    pub latitude: Option<f64>,
+
+    /// Station longitude in decimal degrees, east positive
+    #[arg(long, env = "LONGITUDE", allow_negative_numbers = true)]
+    pub longitude: Option<f64>,
+
+    /// Station elevation in meters above sea level
+    #[arg(long, env = "ELEVATION", allow_negative_numbers = true)]
+    pub elevation: Option<f64>,
+
+    /// Free-form station description, e.g. the site or plot name
+    #[arg(long, env = "STATION_DESCRIPTION", default_value = "")]
+    pub station_description: String,
+
+    /// Battery voltage source reported with each reading: file:PATH (volts) or adc:PATH[*DIVIDER] (IIO raw channel)
+    #[arg(long, env = "BATTERY_VOLTAGE", value_parser = clap::value_parser!(VoltageProvider))]
+    pub battery_voltage: Option<VoltageProvider>,
+
+    /// Air temperature source (°C) raw readings are corrected for the speed of sound with: fixed:VALUE, file:PATH or sysfs:PATH (thousandths)
+    #[arg(long, env = "TEMPERATURE_SOURCE", value_parser = clap::value_parser!(AmbientSource))]
+    pub temperature_source: Option<AmbientSource>,
+
+    /// Relative humidity source (%) for the speed-of-sound correction: fixed:VALUE, file:PATH or sysfs:PATH (thousandths)
+    #[arg(long, env = "HUMIDITY_SOURCE", value_parser = clap::value_parser!(AmbientSource))]
+    pub humidity_source: Option<AmbientSource>,
+
+    /// Temperature in °C at which the sensor's uncorrected readings are true
+    #[arg(long, env = "SOUND_REFERENCE_TEMPERATURE", default_value = "20.0")]
+    pub sound_reference_temperature: f64,
+
+    /// CSV table of measured,true distances (mm) that raw readings are corrected by, interpolating between points
+    #[arg(long, env = "CALIBRATION_FILE")]
+    pub calibration_file: Option<PathBuf>,
+
+    /// Sensor tilt from vertical in degrees; readings are scaled by its cosine to the vertical distance
+    #[arg(long, env = "MOUNT_ANGLE_DEG", default_value = "0")]
+    pub mount_angle_deg: f64,
+
+    /// Sensor-to-ground distance in mm that snow depth is measured from; a saved --baseline-file takes precedence
+    #[arg(long, env = "BASELINE")]
+    pub baseline: Option<f64>,
+
+    /// JSON file the baseline is loaded from at startup, if it exists; SetBaseline saves to it
+    #[arg(long, env = "BASELINE_FILE")]
+    pub baseline_file: Option<PathBuf>,
+
+    /// JSON file the manual offset is loaded from at startup, if it exists; ApplyOffset saves to it
+    #[arg(long, env = "OFFSET_FILE")]
+    pub offset_file: Option<PathBuf>,
+
+    /// Snow density for water equivalents: kg/m³, or 'temperature' for new-snow density from --temperature-source
+    #[arg(long, env = "SNOW_DENSITY", value_parser = clap::value_parser!(DensityModel))]
+    pub snow_density: Option<DensityModel>,
+
+    /// Percentage to trim from each end (0.0-0.5)
+    #[arg(long, env = "TRIM_PERCENTAGE", default_value = "0.15")]
+    pub trim_percentage: f64,
+
+    /// Number of readings to collect before averaging
+    #[arg(long, env = "BATCH_SIZE", default_value = "30")]
+    pub batch_size: usize,
+
+    /// Readings between batch results, each over the last --batch-size readings (0 = --batch-size, back-to-back batches)
+    #[arg(long, env = "BATCH_STEP", default_value = "0")]
+    pub batch_step: usize,
+
+    /// Emit the last batch reading again until the result moves more than this many mm from it (0 = disabled)
+    #[arg(long, env = "DEAD_BAND", default_value = "0")]
+    pub dead_band: f64,
+
+    /// Filter type: none, exponential, trimmed-mean, both, kalman, median, mode, or trend
+    #[arg(long, env = "FILTER_TYPE", default_value = "both", value_parser = clap::value_parser!(FilterType))]
+    pub filter_type: FilterType,
+
+    /// Filter stages in place of --filter-type, e.g. "hampel -> ema -> trimmed-mean"; stages: hampel, ema, kalman, median, then mean, trimmed-mean, or mode
+    #[arg(long, env = "FILTER_PIPELINE", value_parser = clap::value_parser!(FilterPipeline))]
+    pub filter_pipeline: Option<FilterPipeline>,
+
+    /// Filter initialization period (number of readings)
+    #[arg(long, env = "FILTER_INIT_PERIOD", default_value = "40")]
+    pub filter_init_period: usize,
+
+    /// Filter rate limit (maximum change per reading in mm)
+    #[arg(long, env = "FILTER_RATE_LIMIT", default_value = "1.0")]
+    pub filter_rate_limit: f64,
+
+    /// Filter smoothing factor (0.0-1.0, higher = more responsive)
+    #[arg(long, env = "FILTER_ALPHA", default_value = "0.2")]
+    pub filter_alpha: f64,
+
+    /// Adapt the filter alpha to the spread of recent readings: the std dev (mm) at which it is midway between --filter-alpha and --filter-alpha-min (0 = fixed alpha)
+    #[arg(long, env = "FILTER_ADAPTIVE_NOISE", default_value = "0")]
+    pub filter_adaptive_noise: f64,
+
+    /// Smallest adaptive alpha, used as recent readings spread out
+    #[arg(long, env = "FILTER_ALPHA_MIN", default_value = "0.05")]
+    pub filter_alpha_min: f64,
+
+    /// Kalman filter process noise: variance of the change in rate between readings (mm²/reading⁴)
+    #[arg(long, env = "KALMAN_PROCESS_NOISE", default_value = "0.001")]
+    pub kalman_process_noise: f64,
+
+    /// Kalman filter measurement noise: variance of a single reading (mm²)
+    #[arg(long, env = "KALMAN_MEASUREMENT_NOISE", default_value = "4.0")]
+    pub kalman_measurement_noise: f64,
+
+    /// Median filter window (number of readings)
+    #[arg(long, env = "MEDIAN_WINDOW", default_value = "5")]
+    pub median_window: usize,
+
+    /// Mode filter bin width in mm
+    #[arg(long, env = "MODE_BIN_WIDTH", default_value = "5.0")]
+    pub mode_bin_width: f64,
+
+    /// Trend filter window: readings the Theil–Sen line is fitted to (300 is five minutes at one reading per second)
+    #[arg(long, env = "TREND_WINDOW", default_value = "300")]
+    pub trend_window: usize,
+
+    /// Discard raw readings more than this many scaled MADs from the rolling median before filtering (0 = disabled)
+    #[arg(long, env = "DESPIKE_THRESHOLD", default_value = "0")]
+    pub despike_threshold: f64,
+
+    /// Raw readings the despiking median and MAD are taken over
+    #[arg(long, env = "DESPIKE_WINDOW", default_value = "15")]
+    pub despike_window: usize,
+
+    /// Discard raw readings shorter than this (mm) before filtering (0 = no lower bound)
+    #[arg(long, env = "MIN_DISTANCE", default_value = "0")]
+    pub min_distance: f64,
+
+    /// Discard raw readings longer than this (mm) before filtering (0 = no upper bound)
+    #[arg(long, env = "MAX_DISTANCE", default_value = "0")]
+    pub max_distance: f64,
+
+    /// JSON filter preset loaded at startup in place of the filter options; ApplyFilterPreset saves to it
+    #[arg(long, env = "FILTER_PRESET")]
+    pub filter_preset: Option<PathBuf>,
+
+    /// Name of the preset built from the filter options (defaults to the station name)
+    #[arg(long, env = "FILTER_PRESET_NAME")]
+    pub filter_preset_name: Option<String>,
+
+    /// Write the effective filter configuration to this preset file and exit
+    #[arg(long)]
+    pub export_filter_preset: Option<PathBuf>,
+
+    /// Number of emitted readings to retain in history (one week of 30-second batches by default)
+    #[arg(long, env = "HISTORY_SIZE", default_value = "20160")]
+    pub history_size: usize,
+
+    /// File the history is kept in across restarts (in memory only by default)
+    #[arg(long, env = "HISTORY_FILE")]
+    pub history_file: Option<PathBuf>,
+
+    /// Time without raw readings (seconds) recorded as a gap in history
+    #[arg(long, env = "GAP_THRESHOLD", default_value = "60")]
+    pub gap_threshold: u64,
+
+    /// Emit interpolated readings, flagged as such, over short gaps in the batch readings
+    #[arg(long, env = "INTERPOLATE_GAPS")]
+    pub interpolate_gaps: bool,
+
+    /// Longest gap in the batch readings (seconds) bridged with interpolated readings
+    #[arg(long, env = "INTERPOLATE_MAX_GAP", default_value = "600")]
+    pub interpolate_max_gap: u64,
+
+    /// How the snowfall rate and totals bridge short gaps in the batch readings: none, linear, or hold
+    #[arg(long, env = "DERIVED_INTERPOLATION", default_value = "none", value_parser = clap::value_parser!(Interpolation))]
+    pub derived_interpolation: Interpolation,
+
+    /// Longest gap in the batch readings (seconds) the snowfall rate and totals bridge
+    #[arg(long, env = "DERIVED_MAX_GAP", default_value = "600")]
+    pub derived_max_gap: u64,
+
+    /// Robust z-score above which an emitted reading is flagged as anomalous (0 disables)
+    #[arg(long, env = "ANOMALY_THRESHOLD", default_value = "5.0")]
+    pub anomaly_threshold: f64,
+
+    /// Number of preceding emitted readings each reading is compared with
+    #[arg(long, env = "ANOMALY_WINDOW", default_value = "60")]
+    pub anomaly_window: usize,
+
+    /// Window (seconds) over which the snowfall rate in each reading is estimated (0 disables)
+    #[arg(long, env = "SNOWFALL_RATE_WINDOW", default_value = "3600")]
+    pub snowfall_rate_window: u64,
+
+    /// Snowfall rate (mm/hr) at which a storm-started event is sent; it ends below half this (0 disables)
+    #[arg(long, env = "STORM_RATE_THRESHOLD", default_value = "10.0")]
+    pub storm_rate_threshold: f64,
+
+    /// Depth change (mm) back from the furthest point needed before the snowfall rate changes sign (0 disables)
+    #[arg(long, env = "DIRECTION_HYSTERESIS", default_value = "0")]
+    pub direction_hysteresis: f64,
+
+    /// Seconds the snowfall rate must stay slowly negative before the decrease counts as settling or melt (0 disables)
+    #[arg(long, env = "SETTLING_DURATION", default_value = "0")]
+    pub settling_duration: u64,
+
+    /// Slowest depth loss (mm/hr) counted as settling or melt; slower is noise
+    #[arg(long, env = "SETTLING_MIN_RATE", default_value = "0.5")]
+    pub settling_min_rate: f64,
+
+    /// Fastest depth loss (mm/hr) counted as settling or melt; faster drops are left to the snowfall rate
+    #[arg(long, env = "SETTLING_MAX_RATE", default_value = "10.0")]
+    pub settling_max_rate: f64,
+
+    /// Hours between scheduled clears of the snow board, from local midnight; must divide 24 (0 = ClearBoard only)
+    #[arg(long, env = "BOARD_CLEAR_INTERVAL", default_value = "0")]
+    pub board_clear_interval: u64,
+
+    /// Month (1-12) each snowfall season starts on the first of
+    #[arg(long, env = "SEASON_START_MONTH", default_value = "7")]
+    pub season_start_month: u32,
+
+    /// JSON file the snowfall totals are loaded from at startup, if it exists, and saved to every few minutes
+    #[arg(long, env = "TOTALS_FILE")]
+    pub totals_file: Option<PathBuf>,
+
+    /// Shortest distance (mm) the sensor measures; batch results below it are flagged out of range
+    #[arg(long, env = "SENSOR_MIN_DISTANCE", default_value = "300")]
+    pub sensor_min_distance: f64,
+
+    /// Longest distance (mm) the sensor measures, also reported when it detects no target
+    #[arg(long, env = "SENSOR_MAX_DISTANCE", default_value = "5000")]
+    pub sensor_max_distance: f64,
+
+    /// Standard deviation (mm) of a batch's raw readings above which it is flagged as high variance (0 disables)
+    #[arg(long, env = "VARIANCE_THRESHOLD", default_value = "25.0")]
+    pub variance_threshold: f64,
+
+    /// Identical consecutive raw readings after which the sensor is flagged as stuck (0 disables)
+    #[arg(long, env = "STUCK_READINGS", default_value = "1800")]
+    pub stuck_readings: usize,
+
+    /// Consecutive max-range raw readings after which the target is reported lost (0 disables)
+    #[arg(long, env = "TARGET_LOST_READINGS", default_value = "10")]
+    pub target_lost_readings: usize,
+
+    /// Wind speed source (m/s): fixed:VALUE, file:PATH or sysfs:PATH (thousandths); batches collected in high wind are flagged
+    #[arg(long, env = "WIND_SPEED_SOURCE", value_parser = clap::value_parser!(AmbientSource))]
+    pub wind_speed_source: Option<AmbientSource>,
+
+    /// Wind speed in m/s at and above which batches count as collected in high wind
+    #[arg(long, env = "WIND_SPEED_THRESHOLD", default_value = "10.0")]
+    pub wind_speed_threshold: f64,
+
+    /// Percentage trimmed from each end of high-wind batches by a trimmed-mean batch stage (0.0-0.5)
+    #[arg(long, env = "WIND_TRIM_PERCENTAGE", default_value = "0.3")]
+    pub wind_trim_percentage: f64,
+
+    /// Continuous measurement windows in local time, e.g. 06:00-22:00 (always continuous if unset)
+    #[arg(long, env = "SCHEDULE")]
+    pub schedule: Option<String>,
+
+    /// Seconds between single-batch measurements outside the schedule windows
+    #[arg(long, env = "SCHEDULE_INTERVAL", default_value = "900")]
+    pub schedule_interval: u64,
+
+    /// Serial control line that enables the sensor: none, rts, or dtr
+    #[arg(long, env = "SENSOR_POWER_LINE", default_value = "none", value_parser = clap::value_parser!(PowerLine))]
+    pub sensor_power_line: PowerLine,
+
+    /// Restart policy for crashed tasks: never, on-failure, or always
+    #[arg(long, env = "RESTART_POLICY", default_value = "on-failure", value_parser = clap::value_parser!(RestartPolicy))]
+    pub restart_policy: RestartPolicy,
+
+    /// Restarts of one task allowed within --restart-window before giving up and failing the health check
+    #[arg(long, env = "MAX_RESTARTS", default_value = "5")]
+    pub max_restarts: usize,
+
+    /// Window in seconds over which --max-restarts is counted
+    #[arg(long, env = "RESTART_WINDOW", default_value = "300")]
+    pub restart_window: u64,
+
+    /// Address for the CoAP endpoint (e.g. 0.0.0.0:5683); disabled if unset
+    #[arg(long, env = "COAP_LISTEN_ADDR")]
+    pub coap_listen_addr: Option<String>,
+
+    /// Address for the SNMP agent serving NTCIP 1204 ESS objects (e.g. 0.0.0.0:161); disabled if unset
+    #[arg(long, env = "SNMP_LISTEN_ADDR")]
+    pub snmp_listen_addr: Option<String>,
+
+    /// Read-only SNMP community
+    #[arg(long, env = "SNMP_COMMUNITY", default_value = "public")]
+    pub snmp_community: String,
+
+    /// Sensor height above bare ground in mm for SNMP snow depth when no baseline is set
+    #[arg(long, env = "SNMP_SENSOR_HEIGHT")]
+    pub snmp_sensor_height: Option<f64>,
+
+    /// Advertise the current reading in BLE manufacturer data (Linux/BlueZ only)
+    #[arg(long, env = "BLE_ADVERTISE")]
+    pub ble_advertise: bool,
+
+    /// Bluetooth controller index for BLE advertising (hciN)
+    #[arg(long, env = "BLE_HCI_INDEX", default_value = "0")]
+    pub ble_hci_index: u16,
+
+    /// Bluetooth SIG company identifier for the manufacturer data (65535 = reserved for testing)
+    #[arg(long, env = "BLE_COMPANY_ID", default_value = "65535")]
+    pub ble_company_id: u16,
+
+    /// Serial port of a LoRa radio module for uplink transmission; disabled if unset
+    #[arg(long, env = "LORA_PORT")]
+    pub lora_port: Option<String>,
+
+    /// LoRa module serial baud rate
+    #[arg(long, env = "LORA_BAUD", default_value = "9600")]
+    pub lora_baud: u32,
+
+    /// LoRa module interface: raw (transparent mode) or at (AT command)
+    #[arg(long, env = "LORA_MODE", default_value = "raw", value_parser = clap::value_parser!(lora::LoraMode))]
+    pub lora_mode: lora::LoraMode,
+
+    /// AT command used to send a frame; {len} and {hex} are replaced with the hex payload length and data
+    #[arg(long, env = "LORA_AT_TEMPLATE", default_value = "AT+SEND=0,{len},{hex}")]
+    pub lora_at_template: String,
+
+    /// Minimum seconds between LoRa transmissions (duty cycle)
+    #[arg(long, env = "LORA_INTERVAL", default_value = "300")]
+    pub lora_interval: u64,
+
+    /// Send version 2 LoRa frames, carrying each reading's trace context
+    #[arg(long, env = "LORA_TRACE_CONTEXT")]
+    pub lora_trace_context: bool,
+
+    /// Candidate filter type for A/B comparison (enables the comparison stream)
+    #[arg(long, env = "COMPARE_FILTER_TYPE", value_parser = clap::value_parser!(FilterType))]
+    pub compare_filter_type: Option<FilterType>,
+
+    /// Candidate filter stages in place of --compare-filter-type (enables the comparison stream)
+    #[arg(long, env = "COMPARE_FILTER_PIPELINE", value_parser = clap::value_parser!(FilterPipeline))]
+    pub compare_filter_pipeline: Option<FilterPipeline>,
+
+    /// Candidate filter initialization period (defaults to --filter-init-period)
+    #[arg(long, env = "COMPARE_FILTER_INIT_PERIOD")]
+    pub compare_filter_init_period: Option<usize>,
+
+    /// Candidate filter rate limit in mm (defaults to --filter-rate-limit)
+    #[arg(long, env = "COMPARE_FILTER_RATE_LIMIT")]
+    pub compare_filter_rate_limit: Option<f64>,
+
+    /// Candidate filter smoothing factor (defaults to --filter-alpha)
+    #[arg(long, env = "COMPARE_FILTER_ALPHA")]
+    pub compare_filter_alpha: Option<f64>,
+
+    /// Candidate adaptive alpha noise in mm (defaults to --filter-adaptive-noise)
+    #[arg(long, env = "COMPARE_FILTER_ADAPTIVE_NOISE")]
+    pub compare_filter_adaptive_noise: Option<f64>,
+
+    /// Candidate smallest adaptive alpha (defaults to --filter-alpha-min)
+    #[arg(long, env = "COMPARE_FILTER_ALPHA_MIN")]
+    pub compare_filter_alpha_min: Option<f64>,
+
+    /// Candidate Kalman process noise (defaults to --kalman-process-noise)
+    #[arg(long, env = "COMPARE_KALMAN_PROCESS_NOISE")]
+    pub compare_kalman_process_noise: Option<f64>,
+
+    /// Candidate Kalman measurement noise (defaults to --kalman-measurement-noise)
+    #[arg(long, env = "COMPARE_KALMAN_MEASUREMENT_NOISE")]
+    pub compare_kalman_measurement_noise: Option<f64>,
+
+    /// Candidate median filter window (defaults to --median-window)
+    #[arg(long, env = "COMPARE_MEDIAN_WINDOW")]
+    pub compare_median_window: Option<usize>,
+
+    /// Candidate mode filter bin width in mm (defaults to --mode-bin-width)
+    #[arg(long, env = "COMPARE_MODE_BIN_WIDTH")]
+    pub compare_mode_bin_width: Option<f64>,
+
+    /// Candidate trend filter window (defaults to --trend-window)
+    #[arg(long, env = "COMPARE_TREND_WINDOW")]
+    pub compare_trend_window: Option<usize>,
+
+    /// Candidate despiking threshold (defaults to --despike-threshold)
+    #[arg(long, env = "COMPARE_DESPIKE_THRESHOLD")]
+    pub compare_despike_threshold: Option<f64>,
+
+    /// Candidate despiking window (defaults to --despike-window)
+    #[arg(long, env = "COMPARE_DESPIKE_WINDOW")]
+    pub compare_despike_window: Option<usize>,
+
+    /// Candidate trim percentage (defaults to --trim-percentage)
+    #[arg(long, env = "COMPARE_TRIM_PERCENTAGE")]
+    pub compare_trim_percentage: Option<f64>,
+
+    /// Candidate batch size (defaults to --batch-size)
+    #[arg(long, env = "COMPARE_BATCH_SIZE")]
+    pub compare_batch_size: Option<usize>,
+
+    /// Candidate batch step (defaults to --batch-step)
+    #[arg(long, env = "COMPARE_BATCH_STEP")]
+    pub compare_batch_step: Option<usize>,
+
+    /// Candidate dead-band (defaults to --dead-band)
+    #[arg(long, env = "COMPARE_DEAD_BAND")]
+    pub compare_dead_band: Option<f64>,
+}
+
+impl Args {
+    pub fn serial(&self) -> SerialConfig {
+        SerialConfig { baud_rate: self.baud_rate, data_bits: self.data_bits, parity: self.parity, stop_bits: self.stop_bits }
+    }
+
+    /// The frame spec's format if there is one, or --frame-format
+    pub fn frame_format(&self) -> Result<FrameFormat, String> {
+        match self.frame_spec {
+            Some(ref path) => FrameSpec::load(path).map(FrameFormat::Custom),
+            None => Ok(self.frame_format.clone()),
+        }
+    }
+
+    pub fn sensor_noise(&self) -> f64 {
+        self.sensor_noise.unwrap_or(self.source.noise())
+    }
+
+    pub fn i2c_address(&self) -> u16 {
+        match self.source {
+            SensorSource::LidarLite => self.i2c_address.unwrap_or(i2c::LIDAR_LITE_ADDRESS),
+            _ => self.i2c_address.unwrap_or(i2c::I2CXL_ADDRESS),
+        }
+    }
+
+    pub fn modbus(&self) -> ModbusConfig {
+        ModbusConfig {
+            unit: self.modbus_unit,
+            register_type: self.modbus_register_type,
+            register: self.modbus_register,
+            data_type: self.modbus_data_type,
+            scale: self.modbus_scale,
+        }
+    }
+
+    pub fn mqtt(&self) -> MqttConfig {
+        MqttConfig {
+            broker: self.mqtt_broker.clone().unwrap_or_default(),
+            topic: self.mqtt_topic.clone().unwrap_or_default(),
+            field: self.mqtt_field.clone(),
+            client_id: format!("snowgauge-{}", self.station_name),
+            username: self.mqtt_username.clone(),
+            password: self.mqtt_password.clone(),
+            scale: self.mqtt_scale,
+        }
+    }
+
+    /// The options of a station from the stations file: its own name,
+    /// sensor, filter preset and state files, and the rest of the command line
+    pub fn for_station(&self, spec: &StationSpec) -> Args {
+        Args {
+            station_name: spec.station_name.clone(),
+            source: spec.source.unwrap_or(self.source),
+            port: spec.port.clone().unwrap_or_else(|| self.port.clone()),
+            i2c_bus: spec.i2c_bus.clone().unwrap_or_else(|| self.i2c_bus.clone()),
+            i2c_address: spec.i2c_address.or(self.i2c_address),
+            modbus_unit: spec.modbus_unit.unwrap_or(self.modbus_unit),
+            gpio_chip: spec.gpio_chip.clone().unwrap_or_else(|| self.gpio_chip.clone()),
+            gpio_line: spec.gpio_line.or(self.gpio_line),
+            mqtt_broker: spec.mqtt_broker.clone().or_else(|| self.mqtt_broker.clone()),
+            mqtt_topic: spec.mqtt_topic.clone().or_else(|| self.mqtt_topic.clone()),
+            mqtt_field: spec.mqtt_field.clone().or_else(|| self.mqtt_field.clone()),
+            fuse_with: spec.fuse_with.clone(),
+            sensor_noise: spec.sensor_noise.or(self.sensor_noise.filter(|_| spec.source.is_none())),
+            filter_preset: spec.filter_preset.clone(),
+            filter_preset_name: None,
+            baseline_file: spec.baseline_file.clone(),
+            offset_file: spec.offset_file.clone(),
+            totals_file: spec.totals_file.clone(),
+            history_file: spec.history_file.clone(),
+            ..self.clone()
+        }
+    }
+
+    /// Where the sensor is read from, as reported in GetStationInfo
+    pub fn sensor_port(&self) -> String {
+        match self.source {
+            SensorSource::Serial | SensorSource::Modbus => self.port.clone(),
+            SensorSource::I2cxl | SensorSource::LidarLite => format!("{}@0x{:02x}", self.i2c_bus.display(), self.i2c_address()),
+            SensorSource::Gpio => format!("{}:{}", self.gpio_chip.display(), self.gpio_line.unwrap_or_default()),
+            SensorSource::Mqtt => {
+                let mqtt = self.mqtt();
+                format!("mqtt://{}/{}", mqtt.broker.trim_start_matches("mqtt://").trim_end_matches('/'), mqtt.topic)
+            }
+        }
+    }
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Sweep filter parameters over recorded readings and recommend a configuration
+    Tune(TuneArgs),
+    /// Sample the sensor over a static target and report its noise characteristics
+    BenchSensor(BenchArgs),
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct BenchArgs {
+    /// Serial port name, or tcp://host:port or rfc2217://host:port for a serial server
+    #[arg(long, env = "PORT", default_value = "/dev/ttyS0")]
+    pub port: String,
+
+    /// Serial baud rate
+    #[arg(long, env = "BAUD_RATE", default_value = "9600", value_parser = clap::value_parser!(u32).range(1..))]
+    baud_rate: u32,
+
+    /// Serial data bits: 5, 6, 7, or 8
+    #[arg(long, env = "DATA_BITS", default_value = "8", value_parser = serial::parse_data_bits)]
+    data_bits: DataBits,
+
+    /// Serial parity: none, odd, or even
+    #[arg(long, env = "PARITY", default_value = "none", value_parser = serial::parse_parity)]
+    parity: Parity,
+
+    /// Serial stop bits: 1 or 2
+    #[arg(long, env = "STOP_BITS", default_value = "1", value_parser = serial::parse_stop_bits)]
+    stop_bits: StopBits,
+
+    /// Sensor frame format: r3 (R + 3 digits in cm, the XL-MaxSonar), r4 (R + 4 digits in mm, the MB7544 and most MaxBotix sensors), r5 (R + 5 digits, the MB7360/MB7369), any of those with -crlf for frames ending \r\n, auto to detect it, or nmea (nmea:FIELD) for NMEA-style sentences with the distance in field 1 (FIELD)
+    #[arg(long, env = "FRAME_FORMAT", default_value = "r4", value_parser = clap::value_parser!(FrameFormat))]
+    frame_format: FrameFormat,
+
+    /// Checksum byte the sensor appends before the frame's terminator: none, xor, or sum (modulo 256); NMEA sentences carry their own
+    #[arg(long, env = "FRAME_CHECKSUM", default_value = "none", value_parser = clap::value_parser!(Checksum))]
+    pub frame_checksum: Checksum,
+
+    /// JSON frame spec describing the sensor's frames, used instead of --frame-format
+    #[arg(long, env = "FRAME_SPEC")]
+    frame_spec: Option<PathBuf>,
+
+    /// Sample the simulator instead of a sensor
+    #[arg(long)]
+    pub simulator: bool,
+
+    /// Seconds to sample for
+    #[arg(long, default_value = "300")]
+    pub duration: u64,
+
+    /// Serial control line that enables the sensor: none, rts, or dtr
+    #[arg(long, env = "SENSOR_POWER_LINE", default_value = "none", value_parser = clap::value_parser!(PowerLine))]
+    pub sensor_power_line: PowerLine,
+}
+
+impl BenchArgs {
+    pub fn serial(&self) -> SerialConfig {
+        SerialConfig { baud_rate: self.baud_rate, data_bits: self.data_bits, parity: self.parity, stop_bits: self.stop_bits }
+    }
+
+    /// The frame spec's format if there is one, or --frame-format
+    pub fn frame_format(&self) -> Result<FrameFormat, String> {
+        match self.frame_spec {
+            Some(ref path) => FrameSpec::load(path).map(FrameFormat::Custom),
+            None => Ok(self.frame_format.clone()),
+        }
+    }
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct TuneArgs {
+    /// CSV of raw readings: unix timestamp (seconds), distance (mm)
+    #[arg(long)]
+    pub input: PathBuf,
+
+    /// CSV of manual observations in the same format; without it candidates are scored on smoothness and lag
+    #[arg(long)]
+    pub reference: Option<PathBuf>,
+
+    /// Filter type to tune (all but trend if unset)
+    #[arg(long, value_parser = clap::value_parser!(FilterType))]
+    pub filter_type: Option<FilterType>,
+
+    /// Filter initialization period, held fixed during the sweep
+    #[arg(long, default_value = "40")]
+    pub init_period: usize,
+
+    /// Number of ranked candidates to print
+    #[arg(long, default_value = "5")]
+    pub top: usize,
+
+    /// Write the recommended configuration to this filter preset file
+    #[arg(long)]
+    pub output: Option<PathBuf>,
+
+    /// Name for the recommended preset
+    #[arg(long, default_value = "tuned")]
+    pub name: String,
+}
+
+/// Parse `--listen-unix-mode` octal permissions
+fn parse_mode(s: &str) -> Result<u32, String> {
+    u32::from_str_radix(s, 8)
+        .ok()
+        .filter(|&mode| mode <= 0o777)
+        .ok_or_else(|| format!("Invalid mode '{}': expected octal permissions such as 660", s))
+}
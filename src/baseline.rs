@@ -0,0 +1,113 @@
+/// Sensor-to-ground baseline distance
+///
+/// Snow depth is the baseline less the measured distance, so the gauge
+/// needs to know how far the sensor sits above bare ground. The baseline
+/// comes from `--baseline` or a `SetBaseline` call, and is saved as JSON to
+/// `--baseline-file` so a calibration survives restarts.
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Baseline {
+    /// Sensor-to-ground distance in mm
+    pub distance: f64,
+    pub set_at: SystemTime,
+}
+
+/// On-disk representation
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BaselineFile {
+    distance_mm: f64,
+    /// Milliseconds since the Unix epoch
+    set_at: u64,
+}
+
+impl Baseline {
+    pub fn new(distance: f64, set_at: SystemTime) -> Result<Self, String> {
+        if !distance.is_finite() || distance <= 0.0 {
+            return Err(format!("Baseline distance must be a positive number of mm, got {}", distance));
+        }
+        Ok(Self { distance, set_at })
+    }
+
+    /// Snow depth in mm for a measured distance, clamped at zero since a
+    /// distance past the baseline is bare ground (or a dip in it)
+    pub fn snow_depth(self, distance: f64) -> f64 {
+        (self.distance - distance).max(0.0)
+    }
+
+    pub fn to_json(self) -> String {
+        let file = BaselineFile {
+            distance_mm: self.distance,
+            set_at: self.set_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+        };
+        // Serializing plain numbers cannot fail
+        serde_json::to_string_pretty(&file).unwrap() + "\n"
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let file: BaselineFile = serde_json::from_str(json).map_err(|e| format!("invalid baseline: {}", e))?;
+        Self::new(file.distance_mm, UNIX_EPOCH + Duration::from_millis(file.set_at))
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let json = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read baseline {}: {}", path.display(), e))?;
+        Self::from_json(&json).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Write the baseline, replacing any existing file atomically
+    pub fn save(self, path: &Path) -> Result<(), String> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, self.to_json())
+            .and_then(|_| std::fs::rename(&tmp, path))
+            .map_err(|e| format!("failed to write baseline {}: {}", path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn baseline() -> Baseline {
+        Baseline::new(1834.5, UNIX_EPOCH + Duration::from_millis(1_700_000_000_123)).unwrap()
+    }
+
+    #[test]
+    fn test_json_roundtrip() {
+        let json = baseline().to_json();
+        assert!(json.contains("\"distanceMm\": 1834.5"));
+        assert!(json.contains("\"setAt\": 1700000000123"));
+        assert_eq!(Baseline::from_json(&json).unwrap(), baseline());
+    }
+
+    #[test]
+    fn test_snow_depth() {
+        assert_eq!(baseline().snow_depth(1500.0), 334.5);
+        assert_eq!(baseline().snow_depth(1834.5), 0.0);
+        assert_eq!(baseline().snow_depth(1850.0), 0.0);
+    }
+
+    #[test]
+    fn test_rejects_invalid() {
+        assert!(Baseline::new(0.0, SystemTime::now()).is_err());
+        assert!(Baseline::new(-5.0, SystemTime::now()).is_err());
+        assert!(Baseline::new(f64::NAN, SystemTime::now()).is_err());
+        let json = baseline().to_json();
+        assert!(Baseline::from_json(&json.replace("1834.5", "-1.0")).is_err());
+        assert!(Baseline::from_json("not json").is_err());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("snowgauge-baseline-{}.json", std::process::id()));
+        baseline().save(&path).unwrap();
+        assert_eq!(Baseline::load(&path).unwrap(), baseline());
+        std::fs::remove_file(&path).unwrap();
+        assert!(Baseline::load(&path).is_err());
+    }
+}
@@ -0,0 +1,195 @@
+/// Battery voltage for solar-powered gauges
+///
+/// The voltage is sampled with each emitted reading from one of two
+/// sources: a file holding the voltage in volts (as written by a charge
+/// controller agent or a 1-Wire/I2C monitor driver), or a Linux IIO ADC
+/// channel, whose raw count is scaled by the driver's `scale` (mV per
+/// count) and by the ratio of the resistor divider in front of the ADC.
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum VoltageProvider {
+    /// A file containing the voltage in volts
+    File(PathBuf),
+    /// An IIO channel's `in_voltageN_raw` file
+    Adc { raw: PathBuf, divider: f64 },
+}
+
+impl std::str::FromStr for VoltageProvider {
+    type Err = String;
+
+    /// Parse `file:PATH` or `adc:PATH[*DIVIDER]`, e.g.
+    /// `adc:/sys/bus/iio/devices/iio:device0/in_voltage1_raw*11`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid voltage source '{}'. Expected file:PATH or adc:PATH[*DIVIDER]", s);
+        let (kind, spec) = s.split_once(':').ok_or_else(invalid)?;
+        match kind.to_lowercase().as_str() {
+            "file" if !spec.is_empty() => Ok(VoltageProvider::File(PathBuf::from(spec))),
+            "adc" => {
+                let (path, divider) = match spec.rsplit_once('*') {
+                    Some((path, divider)) => {
+                        let divider: f64 = divider.parse().map_err(|_| invalid())?;
+                        if !divider.is_finite() || divider <= 0.0 {
+                            return Err(format!("Voltage divider ratio must be positive, got {}", divider));
+                        }
+                        (path, divider)
+                    }
+                    None => (spec, 1.0),
+                };
+                if path.is_empty() {
+                    return Err(invalid());
+                }
+                Ok(VoltageProvider::Adc {
+                    raw: PathBuf::from(path),
+                    divider,
+                })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl std::fmt::Display for VoltageProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VoltageProvider::File(path) => write!(f, "file:{}", path.display()),
+            VoltageProvider::Adc { raw, divider } => write!(f, "adc:{}*{}", raw.display(), divider),
+        }
+    }
+}
+
+fn read_number(path: &Path) -> io::Result<f64> {
+    let text = std::fs::read_to_string(path)?;
+    text.trim().parse().map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("{} does not hold a number: {:?}", path.display(), text.trim()))
+    })
+}
+
+/// The IIO scale for a raw channel file: the channel's own `in_voltageN_scale`
+/// or, failing that, the device-wide `in_voltage_scale`
+fn adc_scale(raw: &Path) -> io::Result<f64> {
+    let name = raw.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+    let channel_scale = raw.with_file_name(name.replace("_raw", "_scale"));
+    match read_number(&channel_scale) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => read_number(&raw.with_file_name("in_voltage_scale")),
+        result => result,
+    }
+}
+
+impl VoltageProvider {
+    /// Read the battery voltage in volts
+    pub fn read(&self) -> io::Result<f64> {
+        match self {
+            VoltageProvider::File(path) => read_number(path),
+            VoltageProvider::Adc { raw, divider } => {
+                let count = read_number(raw)?;
+                let millivolts_per_count = adc_scale(raw)?;
+                Ok(count * millivolts_per_count / 1000.0 * divider)
+            }
+        }
+    }
+}
+
+/// Samples a provider, logging when reads start and stop failing rather
+/// than on every reading
+pub struct BatteryMonitor {
+    provider: VoltageProvider,
+    failing: bool,
+}
+
+impl BatteryMonitor {
+    pub fn new(provider: VoltageProvider) -> Self {
+        Self { provider, failing: false }
+    }
+
+    pub fn sample(&mut self) -> Option<f64> {
+        match self.provider.read() {
+            Ok(volts) => {
+                if std::mem::replace(&mut self.failing, false) {
+                    info!("Battery voltage readable again from {}", self.provider);
+                }
+                Some(volts)
+            }
+            Err(e) => {
+                if !std::mem::replace(&mut self.failing, true) {
+                    warn!("Error reading battery voltage from {}: {}", self.provider, e);
+                }
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("snowgauge-battery-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("file:/run/battery".parse(), Ok(VoltageProvider::File(PathBuf::from("/run/battery"))));
+        let adc: VoltageProvider = "adc:/sys/bus/iio/devices/iio:device0/in_voltage1_raw*11".parse().unwrap();
+        assert_eq!(
+            adc,
+            VoltageProvider::Adc {
+                raw: PathBuf::from("/sys/bus/iio/devices/iio:device0/in_voltage1_raw"),
+                divider: 11.0,
+            }
+        );
+        assert_eq!(adc.to_string().parse(), Ok(adc));
+        assert_eq!(
+            "adc:/dev/in_voltage0_raw".parse(),
+            Ok(VoltageProvider::Adc { raw: PathBuf::from("/dev/in_voltage0_raw"), divider: 1.0 })
+        );
+        assert!("adc:/dev/in_voltage0_raw*0".parse::<VoltageProvider>().is_err());
+        assert!("file:".parse::<VoltageProvider>().is_err());
+        assert!("/run/battery".parse::<VoltageProvider>().is_err());
+    }
+
+    #[test]
+    fn test_file() {
+        let dir = temp_dir("file");
+        let path = dir.join("voltage");
+        std::fs::write(&path, "12.61\n").unwrap();
+        assert_eq!(VoltageProvider::File(path.clone()).read().unwrap(), 12.61);
+        std::fs::write(&path, "charging").unwrap();
+        assert!(VoltageProvider::File(path).read().is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_adc() {
+        let dir = temp_dir("adc");
+        let raw = dir.join("in_voltage1_raw");
+        std::fs::write(&raw, "1536\n").unwrap();
+        std::fs::write(dir.join("in_voltage_scale"), "0.732421875\n").unwrap();
+        let provider = VoltageProvider::Adc { raw: raw.clone(), divider: 11.0 };
+        assert!((provider.read().unwrap() - 12.375).abs() < 1e-9);
+
+        // A channel's own scale takes precedence
+        std::fs::write(dir.join("in_voltage1_scale"), "1.0").unwrap();
+        assert!((provider.read().unwrap() - 16.896).abs() < 1e-9);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_monitor() {
+        let dir = temp_dir("monitor");
+        let path = dir.join("voltage");
+        let mut monitor = BatteryMonitor::new(VoltageProvider::File(path.clone()));
+        assert_eq!(monitor.sample(), None);
+        assert!(monitor.failing);
+        std::fs::write(&path, "13.2").unwrap();
+        assert_eq!(monitor.sample(), Some(13.2));
+        assert!(!monitor.failing);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
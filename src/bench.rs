@@ -0,0 +1,214 @@
+/// Sensor noise characterization over a static target
+///
+/// Readings are detrended with a Theil–Sen fit (a target that is not quite
+/// static, such as settling snow, would otherwise count as noise) and the
+/// residuals characterized: the robust noise level from their median
+/// absolute deviation, and spikes as residuals beyond `SPIKE_THRESHOLD`
+/// times that level. Filter settings are then suggested from the
+/// measurements.
+use crate::trend;
+
+/// Residuals beyond this many robust standard deviations count as spikes
+const SPIKE_THRESHOLD: f64 = 5.0;
+
+/// Scale factor from MAD to standard deviation for normally distributed noise
+const MAD_SCALE: f64 = 1.4826;
+
+/// Noise floor (mm) assumed for the suggestions, the sensor's 1 mm resolution
+const MIN_NOISE: f64 = 0.3;
+
+/// Target standard error of a batch average (mm)
+const TARGET_BATCH_NOISE: f64 = 0.5;
+
+/// Target noise of the per-reading exponential filter output (mm)
+const TARGET_FILTERED_NOISE: f64 = 1.0;
+
+/// Percentiles reported for the readings
+pub const PERCENTILES: [f64; 7] = [1.0, 5.0, 25.0, 50.0, 75.0, 95.0, 99.0];
+
+/// Measurements from a bench run
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub samples: usize,
+    /// Seconds from first to last reading
+    pub duration: f64,
+    /// Readings per second
+    pub frame_rate: f64,
+    /// Drift of the target over the run (mm per hour)
+    pub drift_mm_per_hour: f64,
+    /// Standard deviation of the detrended readings (mm), spikes included
+    pub std_dev: f64,
+    /// Robust standard deviation (scaled MAD) of the detrended readings (mm)
+    pub robust_std_dev: f64,
+    pub spikes: usize,
+    /// Fraction of readings that were spikes
+    pub spike_fraction: f64,
+    /// Distance at each of `PERCENTILES` (mm)
+    pub percentiles: Vec<f64>,
+}
+
+/// Suggested filter settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct Suggestion {
+    pub alpha: f64,
+    pub trim_percentage: f64,
+    pub batch_size: usize,
+}
+
+/// Characterize `(seconds, distance mm)` readings, or None with fewer than 10
+pub fn analyze(samples: &[(f64, f64)]) -> Option<Report> {
+    if samples.len() < 10 {
+        return None;
+    }
+    let duration = samples.last()?.0 - samples.first()?.0;
+    let fit = trend::theil_sen(samples)?;
+
+    let residuals: Vec<f64> = samples.iter().map(|&(t, d)| d - fit.value_at(t)).collect();
+    let n = residuals.len() as f64;
+    let mean = residuals.iter().sum::<f64>() / n;
+    let std_dev = (residuals.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / n).sqrt();
+
+    let mut sorted = residuals.clone();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let center = percentile(&sorted, 50.0);
+    let mut deviations: Vec<f64> = sorted.iter().map(|r| (r - center).abs()).collect();
+    deviations.sort_by(|a, b| a.total_cmp(b));
+    let robust_std_dev = percentile(&deviations, 50.0) * MAD_SCALE;
+
+    let spike_level = SPIKE_THRESHOLD * robust_std_dev.max(MIN_NOISE);
+    let spikes = residuals.iter().filter(|r| (*r - center).abs() > spike_level).count();
+
+    let mut distances: Vec<f64> = samples.iter().map(|s| s.1).collect();
+    distances.sort_by(|a, b| a.total_cmp(b));
+
+    Some(Report {
+        samples: samples.len(),
+        duration,
+        frame_rate: if duration > 0.0 { (samples.len() - 1) as f64 / duration } else { 0.0 },
+        drift_mm_per_hour: fit.slope * 3600.0,
+        std_dev,
+        robust_std_dev,
+        spikes,
+        spike_fraction: spikes as f64 / n,
+        percentiles: PERCENTILES.iter().map(|&p| percentile(&distances, p)).collect(),
+    })
+}
+
+impl Report {
+    /// Suggest filter settings for this sensor
+    ///
+    /// - alpha brings the exponential filter's output noise down to
+    ///   `TARGET_FILTERED_NOISE` (its variance is `alpha / (2 - alpha)` of
+    ///   the input's)
+    /// - the trim percentage covers the spike fraction at each end with
+    ///   margin
+    /// - the batch size brings the standard error of the batch average
+    ///   down to `TARGET_BATCH_NOISE`
+    pub fn suggest(&self) -> Suggestion {
+        let noise = self.robust_std_dev.max(MIN_NOISE);
+
+        let r2 = (TARGET_FILTERED_NOISE / noise).powi(2);
+        let alpha = (2.0 * r2 / (1.0 + r2)).clamp(0.05, 1.0);
+
+        // Round up to a multiple of 5%, ignoring floating point error
+        let trim_percentage = ((self.spike_fraction * 1.5 / 0.05 - 1e-9).ceil() * 0.05).clamp(0.05, 0.4);
+
+        let batch_size = ((noise / TARGET_BATCH_NOISE).powi(2).ceil() as usize).clamp(10, 300);
+
+        Suggestion {
+            alpha: (alpha * 100.0).round() / 100.0,
+            trim_percentage: (trim_percentage * 100.0).round() / 100.0,
+            batch_size,
+        }
+    }
+}
+
+/// Linearly interpolated percentile of a sorted, non-empty slice
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = p / 100.0 * (sorted.len() - 1) as f64;
+    let lo = rank.floor() as usize;
+    let hi = rank.ceil() as usize;
+    sorted[lo] + (sorted[hi] - sorted[lo]) * (rank - lo as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 10 readings per second, deterministic noise of up to ±3 mm
+    fn readings(n: usize, drift_per_sec: f64) -> Vec<(f64, f64)> {
+        (0..n)
+            .map(|i| {
+                let t = i as f64 * 0.1;
+                (t, 1500.0 + drift_per_sec * t + ((i * 7919) % 7) as f64 - 3.0)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_static_target() {
+        let report = analyze(&readings(600, 0.0)).unwrap();
+        assert_eq!(report.samples, 600);
+        assert!((report.frame_rate - 10.0).abs() < 1e-9);
+        assert!(report.drift_mm_per_hour.abs() < 1.0);
+        assert_eq!(report.spikes, 0);
+        assert!(report.std_dev > 1.5 && report.std_dev < 2.5, "{:?}", report);
+        assert_eq!(report.percentiles[0], 1497.0);
+        assert_eq!(report.percentiles[3], 1500.0);
+        assert_eq!(report.percentiles[6], 1503.0);
+    }
+
+    #[test]
+    fn test_drift_removed() {
+        let still = analyze(&readings(600, 0.0)).unwrap();
+        let drifting = analyze(&readings(600, 0.05)).unwrap();
+        assert!((drifting.drift_mm_per_hour - 180.0).abs() < 1.0);
+        assert!((drifting.robust_std_dev - still.robust_std_dev).abs() < 0.5);
+    }
+
+    #[test]
+    fn test_spikes_counted() {
+        let mut samples = readings(1000, 0.0);
+        for i in (0..1000).step_by(50) {
+            samples[i].1 += 300.0;
+        }
+        let report = analyze(&samples).unwrap();
+        assert_eq!(report.spikes, 20);
+        assert!((report.spike_fraction - 0.02).abs() < 1e-9);
+        assert!(report.std_dev > report.robust_std_dev * 5.0);
+        assert_eq!(report.suggest().trim_percentage, 0.05);
+    }
+
+    #[test]
+    fn test_suggestions_scale_with_noise() {
+        let quiet = Report {
+            samples: 1000,
+            duration: 100.0,
+            frame_rate: 10.0,
+            drift_mm_per_hour: 0.0,
+            std_dev: 0.3,
+            robust_std_dev: 0.3,
+            spikes: 0,
+            spike_fraction: 0.0,
+            percentiles: Vec::new(),
+        };
+        let noisy = Report {
+            std_dev: 4.0,
+            robust_std_dev: 4.0,
+            spikes: 100,
+            spike_fraction: 0.1,
+            ..quiet.clone()
+        };
+        let quiet = quiet.suggest();
+        let noisy = noisy.suggest();
+        assert_eq!(quiet, Suggestion { alpha: 1.0, trim_percentage: 0.05, batch_size: 10 });
+        assert!(noisy.alpha < 0.15);
+        assert_eq!(noisy.trim_percentage, 0.15);
+        assert_eq!(noisy.batch_size, 64);
+    }
+
+    #[test]
+    fn test_too_few_readings() {
+        assert!(analyze(&readings(9, 0.0)).is_none());
+    }
+}
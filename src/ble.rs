@@ -0,0 +1,294 @@
+/// BLE advertisement broadcast of the current reading
+///
+/// Publishes the latest distance in the manufacturer-specific data of a BLE
+/// advertisement so a phone app or nearby e-ink display can show it without
+/// any network. Advertising is managed through the BlueZ kernel management
+/// API (the same control channel bluetoothd uses), so it coexists with a
+/// running bluetoothd and needs CAP_NET_ADMIN rather than exclusive access
+/// to the controller.
+///
+/// Manufacturer data payload (little-endian):
+/// - byte 0: payload format version (1)
+/// - byte 1: update counter, incremented on every new reading
+/// - bytes 2-3: distance in mm
+use log::{error, info};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+use crate::stream::ClientReceiver;
+
+const BTPROTO_HCI: libc::c_int = 1;
+const HCI_DEV_NONE: u16 = 0xFFFF;
+const HCI_CHANNEL_CONTROL: u16 = 3;
+
+const MGMT_OP_ADD_ADVERTISING: u16 = 0x003E;
+const MGMT_OP_REMOVE_ADVERTISING: u16 = 0x003F;
+const MGMT_EV_CMD_COMPLETE: u16 = 0x0001;
+const MGMT_EV_CMD_STATUS: u16 = 0x0002;
+
+/// Advertise as discoverable; the kernel manages the AD flags field
+const ADV_FLAG_DISCOVERABLE: u32 = 1 << 1;
+
+/// Advertising instance used for the reading
+const INSTANCE: u8 = 1;
+
+const PAYLOAD_VERSION: u8 = 1;
+
+/// Legacy advertising data is limited to 31 bytes
+const MAX_ADV_DATA: usize = 31;
+
+#[repr(C)]
+struct SockaddrHci {
+    hci_family: libc::sa_family_t,
+    hci_dev: u16,
+    hci_channel: u16,
+}
+
+/// Build the advertising data: a single manufacturer-specific AD structure
+pub fn advertising_data(company_id: u16, counter: u8, distance_mm: i32) -> Vec<u8> {
+    let distance = distance_mm.clamp(0, u16::MAX as i32) as u16;
+    let mut manufacturer = vec![0xFF];
+    manufacturer.extend_from_slice(&company_id.to_le_bytes());
+    manufacturer.push(PAYLOAD_VERSION);
+    manufacturer.push(counter);
+    manufacturer.extend_from_slice(&distance.to_le_bytes());
+
+    let mut data = vec![manufacturer.len() as u8];
+    data.extend(manufacturer);
+    data
+}
+
+/// Encode a management command packet
+fn command(opcode: u16, index: u16, params: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(6 + params.len());
+    packet.extend_from_slice(&opcode.to_le_bytes());
+    packet.extend_from_slice(&index.to_le_bytes());
+    packet.extend_from_slice(&(params.len() as u16).to_le_bytes());
+    packet.extend_from_slice(params);
+    packet
+}
+
+/// Encode an Add Advertising command (also replaces an existing instance)
+pub fn add_advertising_command(index: u16, instance: u8, adv_data: &[u8]) -> Vec<u8> {
+    let mut params = vec![instance];
+    params.extend_from_slice(&ADV_FLAG_DISCOVERABLE.to_le_bytes());
+    params.extend_from_slice(&0u16.to_le_bytes()); // duration: controller default
+    params.extend_from_slice(&0u16.to_le_bytes()); // timeout: none
+    params.push(adv_data.len() as u8);
+    params.push(0); // no scan response
+    params.extend_from_slice(adv_data);
+    command(MGMT_OP_ADD_ADVERTISING, index, &params)
+}
+
+/// Return the status byte if `event` completes the command `opcode`
+fn command_status(event: &[u8], opcode: u16) -> Option<u8> {
+    if event.len() < 9 {
+        return None;
+    }
+    let code = u16::from_le_bytes([event[0], event[1]]);
+    let event_opcode = u16::from_le_bytes([event[6], event[7]]);
+    if (code == MGMT_EV_CMD_COMPLETE || code == MGMT_EV_CMD_STATUS) && event_opcode == opcode {
+        Some(event[8])
+    } else {
+        None
+    }
+}
+
+pub struct Advertiser {
+    socket: OwnedFd,
+    index: u16,
+    company_id: u16,
+    counter: u8,
+}
+
+impl Advertiser {
+    /// Open the BlueZ management channel for controller `hci<index>`
+    pub fn open(index: u16, company_id: u16) -> io::Result<Self> {
+        // SAFETY: plain socket(2)/bind(2)/setsockopt(2) calls with a
+        // correctly sized, initialized sockaddr and timeval; the returned fd
+        // is owned by OwnedFd from here on.
+        unsafe {
+            let fd = libc::socket(libc::AF_BLUETOOTH, libc::SOCK_RAW | libc::SOCK_CLOEXEC, BTPROTO_HCI);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let socket = OwnedFd::from_raw_fd(fd);
+
+            let addr = SockaddrHci {
+                hci_family: libc::AF_BLUETOOTH as libc::sa_family_t,
+                hci_dev: HCI_DEV_NONE,
+                hci_channel: HCI_CHANNEL_CONTROL,
+            };
+            if libc::bind(
+                fd,
+                &addr as *const SockaddrHci as *const libc::sockaddr,
+                std::mem::size_of::<SockaddrHci>() as libc::socklen_t,
+            ) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+
+            let timeout = libc::timeval { tv_sec: 1, tv_usec: 0 };
+            if libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &timeout as *const libc::timeval as *const libc::c_void,
+                std::mem::size_of::<libc::timeval>() as libc::socklen_t,
+            ) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(Self {
+                socket,
+                index,
+                company_id,
+                counter: 0,
+            })
+        }
+    }
+
+    /// Advertise a new distance, replacing the previous advertisement
+    pub fn advertise(&mut self, distance_mm: i32) -> io::Result<()> {
+        self.counter = self.counter.wrapping_add(1);
+        let data = advertising_data(self.company_id, self.counter, distance_mm);
+        debug_assert!(data.len() <= MAX_ADV_DATA);
+        self.execute(MGMT_OP_ADD_ADVERTISING, &add_advertising_command(self.index, INSTANCE, &data))
+    }
+
+    /// Remove the advertisement
+    pub fn stop(&mut self) -> io::Result<()> {
+        self.execute(
+            MGMT_OP_REMOVE_ADVERTISING,
+            &command(MGMT_OP_REMOVE_ADVERTISING, self.index, &[INSTANCE]),
+        )
+    }
+
+    /// Send a command and wait for its completion event
+    fn execute(&self, opcode: u16, packet: &[u8]) -> io::Result<()> {
+        let fd = self.socket.as_raw_fd();
+        // SAFETY: write(2)/read(2) on an fd we own, with buffers valid for
+        // the lengths passed.
+        let written = unsafe { libc::write(fd, packet.as_ptr() as *const libc::c_void, packet.len()) };
+        if written < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // The control channel also carries unrelated events; skip them
+        let mut buf = [0u8; 512];
+        for _ in 0..16 {
+            let n = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            match command_status(&buf[..n as usize], opcode) {
+                Some(0) => return Ok(()),
+                Some(status) => {
+                    return Err(io::Error::other(format!(
+                        "BlueZ management command 0x{:04x} failed with status 0x{:02x}",
+                        opcode, status
+                    )))
+                }
+                None => continue,
+            }
+        }
+
+        Err(io::Error::new(io::ErrorKind::TimedOut, "no reply from BlueZ management channel"))
+    }
+}
+
+/// Update the advertisement on every new reading until shutdown
+pub async fn run(
+    advertiser: Advertiser,
+    mut readings: ClientReceiver,
+    cancel_token: CancellationToken,
+) {
+    let advertiser = Arc::new(Mutex::new(advertiser));
+
+    loop {
+        tokio::select! {
+            _ = cancel_token.cancelled() => {
+                info!("BLE advertiser received shutdown signal");
+                break;
+            }
+            reading = readings.recv() => {
+                let Some(Ok(reading)) = reading else {
+                    break;
+                };
+                let advertiser = Arc::clone(&advertiser);
+                let result = tokio::task::spawn_blocking(move || {
+                    advertiser.lock().unwrap().advertise(reading.distance_mm.round() as i32)
+                })
+                .await;
+                match result {
+                    Ok(Err(e)) => error!("Error updating BLE advertisement: {}", e),
+                    Err(e) => error!("BLE advertisement task panicked: {}", e),
+                    Ok(Ok(())) => {}
+                }
+            }
+        }
+    }
+
+    let result = tokio::task::spawn_blocking(move || advertiser.lock().unwrap().stop()).await;
+    if let Ok(Err(e)) = result {
+        error!("Error removing BLE advertisement: {}", e);
+    }
+
+    // Give the controller a moment before the process exits
+    tokio::time::sleep(Duration::from_millis(50)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_advertising_data() {
+        let data = advertising_data(0xFFFF, 7, 1234);
+        assert_eq!(data, vec![7, 0xFF, 0xFF, 0xFF, PAYLOAD_VERSION, 7, 0xD2, 0x04]);
+        assert!(data.len() <= MAX_ADV_DATA);
+
+        // Out-of-range distances are clamped to the u16 field
+        assert_eq!(&advertising_data(0x1234, 0, -5)[6..], &[0, 0]);
+        assert_eq!(&advertising_data(0x1234, 0, 100_000)[6..], &[0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn test_add_advertising_command() {
+        let data = advertising_data(0xFFFF, 1, 1000);
+        let packet = add_advertising_command(0, INSTANCE, &data);
+
+        assert_eq!(&packet[0..2], &MGMT_OP_ADD_ADVERTISING.to_le_bytes());
+        assert_eq!(&packet[2..4], &0u16.to_le_bytes());
+        let param_len = u16::from_le_bytes([packet[4], packet[5]]) as usize;
+        assert_eq!(param_len, packet.len() - 6);
+        assert_eq!(param_len, 11 + data.len());
+
+        let params = &packet[6..];
+        assert_eq!(params[0], INSTANCE);
+        assert_eq!(u32::from_le_bytes([params[1], params[2], params[3], params[4]]), ADV_FLAG_DISCOVERABLE);
+        assert_eq!(params[9] as usize, data.len());
+        assert_eq!(params[10], 0);
+        assert_eq!(&params[11..], &data[..]);
+    }
+
+    #[test]
+    fn test_command_status() {
+        // Command Complete for Add Advertising on hci0, status success
+        let event = [0x01, 0x00, 0x00, 0x00, 0x04, 0x00, 0x3E, 0x00, 0x00, 0x01];
+        assert_eq!(command_status(&event, MGMT_OP_ADD_ADVERTISING), Some(0));
+        assert_eq!(command_status(&event, MGMT_OP_REMOVE_ADVERTISING), None);
+
+        // Command Status with "not powered" (0x0f)
+        let event = [0x02, 0x00, 0x00, 0x00, 0x03, 0x00, 0x3E, 0x00, 0x0F];
+        assert_eq!(command_status(&event, MGMT_OP_ADD_ADVERTISING), Some(0x0F));
+
+        // Unrelated event (New Settings)
+        let event = [0x06, 0x00, 0x00, 0x00, 0x04, 0x00, 0x3E, 0x00, 0x00, 0x00];
+        assert_eq!(command_status(&event, MGMT_OP_ADD_ADVERTISING), None);
+    }
+}
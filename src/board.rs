@@ -0,0 +1,130 @@
+/// New snow since the board was cleared
+///
+/// Observers report new snow off a snow board they clear at fixed times,
+/// separately from the total depth. The gauge keeps a virtual board: a
+/// clear records the distance of the latest batch reading, and the new snow
+/// is how far the surface has risen above it since, settling included as on
+/// a real board. Clears come from ClearBoard or from a schedule every
+/// `interval` from local midnight, e.g. every 6, 12 or 24 hours.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Period between two clears
+#[derive(Debug, Clone, PartialEq)]
+pub struct BoardPeriod {
+    pub start: SystemTime,
+    pub end: SystemTime,
+    /// New snow on the board when it was cleared, in mm
+    pub new_snow: f64,
+}
+
+#[derive(Debug)]
+pub struct Board {
+    /// Clearing schedule, a whole number of minutes dividing a day
+    interval: Option<Duration>,
+    /// When the board was last cleared, and the distance then
+    cleared: Option<(SystemTime, f64)>,
+    next_clear: Option<SystemTime>,
+    latest: Option<f64>,
+}
+
+impl Board {
+    pub fn new(interval: Option<Duration>) -> Result<Self, String> {
+        if let Some(interval) = interval {
+            let minutes = interval.as_secs() / 60;
+            if interval.as_secs() % 60 != 0 || minutes == 0 || 1440 % minutes != 0 {
+                return Err(format!("Board clearing interval must divide a day evenly, got {}s", interval.as_secs()));
+            }
+        }
+        Ok(Self { interval, cleared: None, next_clear: None, latest: None })
+    }
+
+    /// Note a batch reading at `now`, `minute_of_day` minutes after local
+    /// midnight, returning the period that ended if a scheduled clear came
+    /// due; with a schedule, the first reading clears the board
+    pub fn update(&mut self, now: SystemTime, minute_of_day: u32, distance: f64) -> Option<BoardPeriod> {
+        self.latest = Some(distance);
+        let due = self.interval.is_some() && self.next_clear.is_none_or(|next| now >= next);
+        if due { self.clear(now, minute_of_day) } else { None }
+    }
+
+    /// Clear the board at the latest reading, returning the period that
+    /// ended, if it had been cleared before
+    pub fn clear(&mut self, now: SystemTime, minute_of_day: u32) -> Option<BoardPeriod> {
+        let latest = self.latest?;
+        let ended = self.cleared.map(|(start, distance)| BoardPeriod {
+            start,
+            end: now,
+            new_snow: (distance - latest).max(0.0),
+        });
+        self.cleared = Some((now, latest));
+        self.next_clear = self.interval.map(|interval| {
+            let interval = interval.as_secs() / 60;
+            let minutes = interval - u64::from(minute_of_day) % interval;
+            let seconds = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() % 60;
+            now + Duration::from_secs(minutes * 60 - seconds)
+        });
+        ended
+    }
+
+    /// True once a reading has been taken to clear the board at
+    pub fn has_reading(&self) -> bool {
+        self.latest.is_some()
+    }
+
+    /// New snow in mm above the board at `distance`, if it has been cleared
+    pub fn new_snow(&self, distance: f64) -> Option<f64> {
+        // Distance is measured down from the sensor, so it shrinks as snow piles up
+        self.cleared.map(|(_, cleared)| (cleared - distance).max(0.0))
+    }
+
+    pub fn next_clear(&self) -> Option<SystemTime> {
+        self.next_clear
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(minutes: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(minutes * 60)
+    }
+
+    #[test]
+    fn test_scheduled_clear() {
+        let mut board = Board::new(Some(Duration::from_secs(6 * 3600))).unwrap();
+        // First reading at 04:30 clears it; the next clear is at 06:00
+        assert_eq!(board.update(at(270), 270, 2000.0), None);
+        assert_eq!(board.new_snow(2000.0), Some(0.0));
+        assert_eq!(board.next_clear(), Some(at(360)));
+        assert_eq!(board.update(at(300), 300, 1980.0), None);
+        assert_eq!(board.new_snow(1970.0), Some(30.0));
+
+        let ended = board.update(at(360), 360, 1970.0).unwrap();
+        assert_eq!(ended, BoardPeriod { start: at(270), end: at(360), new_snow: 30.0 });
+        assert_eq!(board.new_snow(1970.0), Some(0.0));
+        assert_eq!(board.next_clear(), Some(at(720)));
+        // Settling below the cleared surface is no new snow
+        assert_eq!(board.new_snow(1975.0), Some(0.0));
+    }
+
+    #[test]
+    fn test_manual_clear() {
+        let mut board = Board::new(None).unwrap();
+        assert_eq!(board.clear(at(0), 0), None);
+        assert!(!board.has_reading());
+        board.update(at(10), 10, 1500.0);
+        assert_eq!(board.new_snow(1500.0), None);
+        assert_eq!(board.clear(at(20), 20), None);
+        board.update(at(30), 30, 1450.0);
+        assert_eq!(board.clear(at(40), 40).unwrap().new_snow, 50.0);
+        assert_eq!(board.next_clear(), None);
+    }
+
+    #[test]
+    fn test_interval_validation() {
+        assert!(Board::new(Some(Duration::from_secs(7 * 3600))).is_err());
+        assert!(Board::new(Some(Duration::from_secs(90))).is_err());
+        assert!(Board::new(Some(Duration::from_secs(24 * 3600))).is_ok());
+    }
+}
@@ -0,0 +1,137 @@
+/// Multi-point calibration curve
+///
+/// A table of distances as the gauge measured them against the true
+/// distances at the same points (from a tape measure or a target at known
+/// heights) corrects systematic error that varies over the sensor's range,
+/// such as the nonlinearity near the top of it. Readings between two points
+/// are corrected by linear interpolation between their errors; readings
+/// beyond the first or last point get that point's error.
+///
+/// A sensor that isn't mounted plumb measures along a tilted beam, further
+/// than the vertical distance to the surface by 1 / cos of the tilt, which
+/// `plumb` takes out.
+use std::path::Path;
+
+/// Largest mounting angle accepted, in degrees from vertical; beyond it
+/// little of the echo from a flat surface makes it back to the sensor
+pub const MAX_MOUNT_ANGLE: f64 = 45.0;
+
+/// The vertical distance for one measured along a beam `angle` degrees from vertical
+pub fn plumb(distance: f64, angle: f64) -> f64 {
+    distance * angle.to_radians().cos()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CalibrationCurve {
+    /// `(measured, true)` distances in mm, in increasing measured order
+    points: Vec<(f64, f64)>,
+}
+
+impl CalibrationCurve {
+    /// Build a curve from `(measured, true)` points in any order
+    pub fn new(mut points: Vec<(f64, f64)>) -> Result<Self, String> {
+        if points.is_empty() {
+            return Err("a calibration table needs at least one point".to_string());
+        }
+        if let Some(&(measured, actual)) = points.iter().find(|(m, t)| !m.is_finite() || !t.is_finite()) {
+            return Err(format!("calibration point {},{} is not a pair of numbers", measured, actual));
+        }
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        if let Some(pair) = points.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            return Err(format!("calibration table has two points measured at {} mm", pair[0].0));
+        }
+        Ok(Self { points })
+    }
+
+    /// Parse `measured,true` rows (mm)
+    ///
+    /// Blank lines, `#` comments, a header row, and extra columns are ignored.
+    pub fn parse(csv: &str) -> Result<Self, String> {
+        let mut points = Vec::new();
+        for (i, line) in csv.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split(',').map(str::trim);
+            let measured = fields.next().unwrap_or_default().parse::<f64>();
+            let actual = fields.next().unwrap_or_default().parse::<f64>();
+            match (measured, actual) {
+                (Ok(m), Ok(t)) => points.push((m, t)),
+                _ if points.is_empty() && i == 0 => continue,
+                _ => return Err(format!("line {}: expected measured,true, got '{}'", i + 1, line)),
+            }
+        }
+        Self::new(points)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let csv = std::fs::read_to_string(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        Self::parse(&csv).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    pub fn point_count(&self) -> usize {
+        self.points.len()
+    }
+
+    /// The true distance for a measured one
+    pub fn apply(&self, measured: f64) -> f64 {
+        let error = |&(m, t): &(f64, f64)| t - m;
+        let next = self.points.partition_point(|&(m, _)| m < measured);
+        let correction = match next {
+            0 => error(&self.points[0]),
+            n if n == self.points.len() => error(&self.points[n - 1]),
+            n => {
+                let (low, high) = (self.points[n - 1], self.points[n]);
+                let fraction = (measured - low.0) / (high.0 - low.0);
+                error(&low) + fraction * (error(&high) - error(&low))
+            }
+        };
+        measured + correction
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn curve() -> CalibrationCurve {
+        CalibrationCurve::new(vec![(3000.0, 2990.0), (1000.0, 1000.0), (4000.0, 3960.0)]).unwrap()
+    }
+
+    #[test]
+    fn test_interpolates_between_points() {
+        let curve = curve();
+        assert_eq!(curve.apply(1000.0), 1000.0);
+        assert_eq!(curve.apply(2000.0), 1995.0);
+        assert_eq!(curve.apply(3000.0), 2990.0);
+        assert_eq!(curve.apply(3500.0), 3475.0);
+    }
+
+    #[test]
+    fn test_holds_end_errors_beyond_table() {
+        let curve = curve();
+        assert_eq!(curve.apply(500.0), 500.0);
+        assert_eq!(curve.apply(4500.0), 4460.0);
+        let offset = CalibrationCurve::new(vec![(2000.0, 2012.0)]).unwrap();
+        assert_eq!(offset.apply(1500.0), 1512.0);
+    }
+
+    #[test]
+    fn test_plumb() {
+        assert_eq!(plumb(2000.0, 0.0), 2000.0);
+        assert!((plumb(2000.0, 60.0) - 1000.0).abs() < 1e-9);
+        // 5° is 0.4% over 2 m
+        assert!((plumb(2000.0, 5.0) - 1992.39).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_parse() {
+        let csv = "measured,true\n# tape, 2024-11-02\n1000,1000\n\n3000, 2990, top of the mast\n4000,3960\n";
+        assert_eq!(CalibrationCurve::parse(csv), Ok(curve()));
+        assert_eq!(curve().point_count(), 3);
+        assert!(CalibrationCurve::parse("1000,1000\nfar,3960\n").is_err());
+        assert!(CalibrationCurve::parse("measured,true\n").is_err());
+        assert!(CalibrationCurve::parse("1000,1000\n1000,1010\n").is_err());
+    }
+}
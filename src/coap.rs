@@ -0,0 +1,594 @@
+/// Minimal CoAP (RFC 7252) endpoint for constrained consumers
+///
+/// Serves the latest reading and a one-hour summary as JSON over UDP so
+/// microcontrollers and LoRa/6LoWPAN displays can pull data without an HTTP
+/// or gRPC stack. Clients may observe (RFC 7641) either resource to receive a
+/// notification after every new reading. The reading resource continues the
+/// trace context of the latest streamed reading in its `traceparent`.
+///
+/// Only the subset needed for GET and observe is implemented: no block-wise
+/// transfer, and notifications are sent non-confirmable.
+use log::{debug, info, warn};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
+
+use crate::history::{AmendedReading, History};
+use crate::stream::ClientReceiver;
+use crate::totals::{Date, Totals};
+use crate::trace::TraceContext;
+
+const VERSION: u8 = 1;
+const PAYLOAD_MARKER: u8 = 0xFF;
+
+/// Maximum number of concurrent observers; the oldest is evicted when full
+const MAX_OBSERVERS: usize = 32;
+
+/// Window covered by the summary resource
+const SUMMARY_WINDOW: Duration = Duration::from_secs(3600);
+
+/// Max-Age advertised on responses (seconds)
+const MAX_AGE: u32 = 60;
+
+pub mod code {
+    pub const EMPTY: u8 = 0x00;
+    pub const GET: u8 = 0x01;
+    pub const CONTENT: u8 = 0x45; // 2.05
+    pub const NOT_FOUND: u8 = 0x84; // 4.04
+    pub const METHOD_NOT_ALLOWED: u8 = 0x85; // 4.05
+    pub const SERVICE_UNAVAILABLE: u8 = 0xA3; // 5.03
+}
+
+pub mod option {
+    pub const OBSERVE: u16 = 6;
+    pub const URI_PATH: u16 = 11;
+    pub const CONTENT_FORMAT: u16 = 12;
+    pub const MAX_AGE: u16 = 14;
+}
+
+const CONTENT_FORMAT_LINK: u32 = 40;
+const CONTENT_FORMAT_JSON: u32 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageType {
+    Confirmable = 0,
+    NonConfirmable = 1,
+    Acknowledgement = 2,
+    Reset = 3,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub message_type: MessageType,
+    pub code: u8,
+    pub message_id: u16,
+    pub token: Vec<u8>,
+    /// Options as (number, value), kept in ascending option-number order
+    pub options: Vec<(u16, Vec<u8>)>,
+    pub payload: Vec<u8>,
+}
+
+impl Message {
+    /// Parse a datagram, rejecting anything malformed
+    pub fn parse(buf: &[u8]) -> Result<Self, String> {
+        if buf.len() < 4 {
+            return Err("message shorter than header".to_string());
+        }
+        if buf[0] >> 6 != VERSION {
+            return Err(format!("unsupported CoAP version {}", buf[0] >> 6));
+        }
+        let message_type = match (buf[0] >> 4) & 0x03 {
+            0 => MessageType::Confirmable,
+            1 => MessageType::NonConfirmable,
+            2 => MessageType::Acknowledgement,
+            _ => MessageType::Reset,
+        };
+        let token_len = (buf[0] & 0x0F) as usize;
+        if token_len > 8 {
+            return Err(format!("invalid token length {}", token_len));
+        }
+        let code = buf[1];
+        let message_id = u16::from_be_bytes([buf[2], buf[3]]);
+
+        let mut pos = 4;
+        let token = buf
+            .get(pos..pos + token_len)
+            .ok_or("truncated token")?
+            .to_vec();
+        pos += token_len;
+
+        let mut options = Vec::new();
+        let mut number = 0u16;
+        let mut payload = Vec::new();
+        while pos < buf.len() {
+            if buf[pos] == PAYLOAD_MARKER {
+                payload = buf[pos + 1..].to_vec();
+                if payload.is_empty() {
+                    return Err("payload marker without payload".to_string());
+                }
+                break;
+            }
+
+            let delta_nibble = buf[pos] >> 4;
+            let length_nibble = buf[pos] & 0x0F;
+            pos += 1;
+            let delta = read_extended(buf, &mut pos, delta_nibble)?;
+            let length = read_extended(buf, &mut pos, length_nibble)? as usize;
+
+            number = number
+                .checked_add(delta)
+                .ok_or("option number overflow")?;
+            let value = buf.get(pos..pos + length).ok_or("truncated option value")?;
+            options.push((number, value.to_vec()));
+            pos += length;
+        }
+
+        Ok(Self {
+            message_type,
+            code,
+            message_id,
+            token,
+            options,
+            payload,
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(16 + self.payload.len());
+        out.push((VERSION << 6) | ((self.message_type as u8) << 4) | self.token.len() as u8);
+        out.push(self.code);
+        out.extend_from_slice(&self.message_id.to_be_bytes());
+        out.extend_from_slice(&self.token);
+
+        let mut options = self.options.clone();
+        options.sort_by_key(|(number, _)| *number);
+        let mut previous = 0u16;
+        for (number, value) in &options {
+            let (delta_nibble, delta_ext) = extended(number - previous);
+            let (length_nibble, length_ext) = extended(value.len() as u16);
+            out.push((delta_nibble << 4) | length_nibble);
+            out.extend_from_slice(&delta_ext);
+            out.extend_from_slice(&length_ext);
+            out.extend_from_slice(value);
+            previous = *number;
+        }
+
+        if !self.payload.is_empty() {
+            out.push(PAYLOAD_MARKER);
+            out.extend_from_slice(&self.payload);
+        }
+        out
+    }
+
+    pub fn option(&self, number: u16) -> Option<&[u8]> {
+        self.options
+            .iter()
+            .find(|(n, _)| *n == number)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    /// Uri-Path segments joined with '/'
+    pub fn uri_path(&self) -> String {
+        self.options
+            .iter()
+            .filter(|(n, _)| *n == option::URI_PATH)
+            .map(|(_, v)| String::from_utf8_lossy(v).into_owned())
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+
+/// Decode an option delta/length nibble and its extended bytes
+fn read_extended(buf: &[u8], pos: &mut usize, nibble: u8) -> Result<u16, String> {
+    match nibble {
+        0..=12 => Ok(nibble as u16),
+        13 => {
+            let b = *buf.get(*pos).ok_or("truncated option header")?;
+            *pos += 1;
+            Ok(b as u16 + 13)
+        }
+        14 => {
+            let b = buf.get(*pos..*pos + 2).ok_or("truncated option header")?;
+            *pos += 2;
+            u16::from_be_bytes([b[0], b[1]])
+                .checked_add(269)
+                .ok_or_else(|| "option header overflow".to_string())
+        }
+        _ => Err("reserved option nibble 15".to_string()),
+    }
+}
+
+/// Encode an option delta/length as a nibble plus extended bytes
+fn extended(value: u16) -> (u8, Vec<u8>) {
+    match value {
+        0..=12 => (value as u8, Vec::new()),
+        13..=268 => (13, vec![(value - 13) as u8]),
+        _ => (14, (value - 269).to_be_bytes().to_vec()),
+    }
+}
+
+/// Encode an unsigned option value using the minimal number of bytes
+pub fn encode_uint(value: u32) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|b| **b == 0).count();
+    bytes[skip..].to_vec()
+}
+
+pub fn decode_uint(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0u32, |acc, b| (acc << 8) | *b as u32)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Resource {
+    Reading,
+    Summary,
+}
+
+struct Observer {
+    addr: SocketAddr,
+    token: Vec<u8>,
+    resource: Resource,
+    /// Message ID of the last notification, so a Reset can cancel the observation
+    last_message_id: u16,
+}
+
+pub struct CoapServer {
+    socket: UdpSocket,
+    history: Arc<RwLock<History>>,
+    totals: Arc<std::sync::Mutex<Totals>>,
+    station_name: String,
+    observers: Vec<Observer>,
+    next_message_id: u16,
+    observe_sequence: u32,
+    /// Trace context of the latest streamed reading
+    trace: Option<TraceContext>,
+}
+
+impl CoapServer {
+    pub fn new(
+        socket: UdpSocket,
+        history: Arc<RwLock<History>>,
+        totals: Arc<std::sync::Mutex<Totals>>,
+        station_name: String,
+    ) -> Self {
+        Self {
+            socket,
+            history,
+            totals,
+            station_name,
+            observers: Vec::new(),
+            next_message_id: rand::random(),
+            observe_sequence: 0,
+            trace: None,
+        }
+    }
+
+    /// Serve requests and send observe notifications for each new reading
+    pub async fn run(
+        mut self,
+        mut readings: ClientReceiver,
+        cancel_token: CancellationToken,
+    ) {
+        let mut buf = [0u8; 1152];
+
+        loop {
+            tokio::select! {
+                _ = cancel_token.cancelled() => {
+                    info!("CoAP server received shutdown signal");
+                    break;
+                }
+                received = self.socket.recv_from(&mut buf) => {
+                    match received {
+                        Ok((n, addr)) => match Message::parse(&buf[..n]) {
+                            Ok(request) => self.handle(request, addr).await,
+                            Err(e) => debug!("Ignoring malformed CoAP message from {}: {}", addr, e),
+                        },
+                        Err(e) => warn!("Error receiving CoAP datagram: {}", e),
+                    }
+                }
+                reading = readings.recv() => {
+                    let Some(reading) = reading else {
+                        break;
+                    };
+                    self.trace = reading.ok().and_then(|reading| TraceContext::parse(&reading.traceparent));
+                    self.notify_observers().await;
+                }
+            }
+        }
+    }
+
+    async fn handle(&mut self, request: Message, addr: SocketAddr) {
+        match request.message_type {
+            MessageType::Reset => {
+                self.observers
+                    .retain(|o| !(o.addr == addr && o.last_message_id == request.message_id));
+                return;
+            }
+            MessageType::Acknowledgement => return,
+            _ => {}
+        }
+
+        if request.code == code::EMPTY {
+            // CoAP ping: answer a confirmable empty message with a Reset
+            if request.message_type == MessageType::Confirmable {
+                self.send(&reset(request.message_id), addr).await;
+            }
+            return;
+        }
+
+        let path = request.uri_path();
+        let resource = match path.as_str() {
+            "reading" => Some(Resource::Reading),
+            "summary" => Some(Resource::Summary),
+            _ => None,
+        };
+
+        let mut options = Vec::new();
+        let (response_code, payload) = if request.code != code::GET {
+            (code::METHOD_NOT_ALLOWED, Vec::new())
+        } else if path == ".well-known/core" {
+            options.push((option::CONTENT_FORMAT, encode_uint(CONTENT_FORMAT_LINK)));
+            (
+                code::CONTENT,
+                b"</reading>;rt=\"snowgauge.reading\";obs,</summary>;rt=\"snowgauge.summary\";obs".to_vec(),
+            )
+        } else if let Some(resource) = resource {
+            let rendered = self.render(resource).await;
+            match request.option(option::OBSERVE).map(decode_uint) {
+                // A non-2.05 response ends the observation, so only register with content
+                Some(0) if rendered.is_some() => {
+                    self.register(addr, request.token.clone(), resource);
+                    options.push((option::OBSERVE, encode_uint(self.observe_sequence)));
+                }
+                Some(1) => self.observers.retain(|o| !(o.addr == addr && o.token == request.token)),
+                _ => {}
+            }
+
+            match rendered {
+                Some(payload) => {
+                    options.push((option::CONTENT_FORMAT, encode_uint(CONTENT_FORMAT_JSON)));
+                    options.push((option::MAX_AGE, encode_uint(MAX_AGE)));
+                    (code::CONTENT, payload)
+                }
+                None => (code::SERVICE_UNAVAILABLE, b"no readings yet".to_vec()),
+            }
+        } else {
+            (code::NOT_FOUND, Vec::new())
+        };
+
+        // Piggyback the response on the ACK for confirmable requests
+        let (message_type, message_id) = if request.message_type == MessageType::Confirmable {
+            (MessageType::Acknowledgement, request.message_id)
+        } else {
+            (MessageType::NonConfirmable, self.message_id())
+        };
+
+        let response = Message {
+            message_type,
+            code: response_code,
+            message_id,
+            token: request.token,
+            options,
+            payload,
+        };
+        self.send(&response, addr).await;
+    }
+
+    fn register(&mut self, addr: SocketAddr, token: Vec<u8>, resource: Resource) {
+        self.observers.retain(|o| !(o.addr == addr && o.token == token));
+        if self.observers.len() >= MAX_OBSERVERS {
+            let evicted = self.observers.remove(0);
+            debug!("CoAP observer limit reached, evicting {}", evicted.addr);
+        }
+        info!("Registering CoAP observer [{}] for {:?}", addr, resource);
+        self.observers.push(Observer {
+            addr,
+            token,
+            resource,
+            last_message_id: 0,
+        });
+    }
+
+    async fn notify_observers(&mut self) {
+        if self.observers.is_empty() {
+            return;
+        }
+
+        self.observe_sequence = (self.observe_sequence + 1) & 0x00FF_FFFF;
+        let reading = self.render(Resource::Reading).await;
+        let summary = self.render(Resource::Summary).await;
+
+        for i in 0..self.observers.len() {
+            let payload = match self.observers[i].resource {
+                Resource::Reading => reading.clone(),
+                Resource::Summary => summary.clone(),
+            };
+            let Some(payload) = payload else {
+                continue;
+            };
+
+            let message_id = self.message_id();
+            let notification = Message {
+                message_type: MessageType::NonConfirmable,
+                code: code::CONTENT,
+                message_id,
+                token: self.observers[i].token.clone(),
+                options: vec![
+                    (option::OBSERVE, encode_uint(self.observe_sequence)),
+                    (option::CONTENT_FORMAT, encode_uint(CONTENT_FORMAT_JSON)),
+                    (option::MAX_AGE, encode_uint(MAX_AGE)),
+                ],
+                payload,
+            };
+            self.observers[i].last_message_id = message_id;
+            let addr = self.observers[i].addr;
+            self.send(&notification, addr).await;
+        }
+    }
+
+    /// Render a resource as JSON, or None if there are no readings yet
+    async fn render(&self, resource: Resource) -> Option<Vec<u8>> {
+        let history = self.history.read().await;
+        let value = match resource {
+            Resource::Reading => reading_json(&self.station_name, &history.latest()?, self.trace.as_ref()),
+            Resource::Summary => {
+                let since = SystemTime::now().checked_sub(SUMMARY_WINDOW);
+                let readings: Vec<f64> = history
+                    .query(since, None)
+                    .into_iter()
+                    .filter(|r| !r.invalid)
+                    .map(|r| r.distance)
+                    .collect();
+                let first = *readings.first()?;
+                let last = *readings.last()?;
+                let today = Date::local(SystemTime::now());
+                let totals = self.totals.lock().unwrap_or_else(|e| e.into_inner());
+                serde_json::json!({
+                    "station": self.station_name,
+                    "window": SUMMARY_WINDOW.as_secs(),
+                    "count": readings.len(),
+                    "min": readings.iter().cloned().fold(f64::INFINITY, f64::min),
+                    "max": readings.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+                    "mean": readings.iter().sum::<f64>() / readings.len() as f64,
+                    "change": last - first,
+                    "today": totals.day(today),
+                    "month": totals.month(today),
+                    "season": totals.season(today),
+                })
+            }
+        };
+        Some(value.to_string().into_bytes())
+    }
+
+    fn message_id(&mut self) -> u16 {
+        self.next_message_id = self.next_message_id.wrapping_add(1);
+        self.next_message_id
+    }
+
+    async fn send(&self, message: &Message, addr: SocketAddr) {
+        if let Err(e) = self.socket.send_to(&message.encode(), addr).await {
+            warn!("Error sending CoAP message to {}: {}", addr, e);
+        }
+    }
+}
+
+fn reset(message_id: u16) -> Message {
+    Message {
+        message_type: MessageType::Reset,
+        code: code::EMPTY,
+        message_id,
+        token: Vec::new(),
+        options: Vec::new(),
+        payload: Vec::new(),
+    }
+}
+
+/// The reading resource, with a span in the reading's trace if it is known
+fn reading_json(station_name: &str, latest: &AmendedReading, trace: Option<&TraceContext>) -> serde_json::Value {
+    let mut value = serde_json::json!({
+        "station": station_name,
+        "distance": latest.distance,
+        "timestamp": unix_seconds(latest.timestamp),
+    });
+    if let Some(trace) = trace {
+        value["traceparent"] = trace.child().to_string().into();
+    }
+    value
+}
+
+fn unix_seconds(timestamp: SystemTime) -> u64 {
+    timestamp.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_get_request() {
+        // CON GET, token 0xAB, message id 0x1234, Uri-Path "reading", Observe 0
+        let mut buf = vec![0x41, 0x01, 0x12, 0x34, 0xAB];
+        buf.push(0x60); // Observe (delta 6), empty value = 0
+        buf.push(0x57); // Uri-Path (delta 5), length 7
+        buf.extend_from_slice(b"reading");
+
+        let message = Message::parse(&buf).unwrap();
+        assert_eq!(message.message_type, MessageType::Confirmable);
+        assert_eq!(message.code, code::GET);
+        assert_eq!(message.message_id, 0x1234);
+        assert_eq!(message.token, vec![0xAB]);
+        assert_eq!(message.uri_path(), "reading");
+        assert_eq!(message.option(option::OBSERVE).map(decode_uint), Some(0));
+        assert!(message.payload.is_empty());
+    }
+
+    #[test]
+    fn test_encode_roundtrip() {
+        let message = Message {
+            message_type: MessageType::NonConfirmable,
+            code: code::CONTENT,
+            message_id: 7,
+            token: vec![1, 2, 3, 4],
+            options: vec![
+                (option::CONTENT_FORMAT, encode_uint(CONTENT_FORMAT_JSON)),
+                (option::OBSERVE, encode_uint(70000)),
+                (option::URI_PATH, b".well-known".to_vec()),
+                (option::URI_PATH, b"core".to_vec()),
+                // Exercise one- and two-byte extended deltas and lengths
+                (300, vec![0x55; 20]),
+                (1000, vec![0x66; 300]),
+            ],
+            payload: b"{\"distance\":1000}".to_vec(),
+        };
+
+        let parsed = Message::parse(&message.encode()).unwrap();
+        let mut expected = message.clone();
+        expected.options.sort_by_key(|(n, _)| *n);
+        assert_eq!(parsed, expected);
+        assert_eq!(parsed.uri_path(), ".well-known/core");
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed() {
+        assert!(Message::parse(&[0x40, 0x01]).is_err());
+        // Version 2
+        assert!(Message::parse(&[0x80, 0x01, 0, 0]).is_err());
+        // Token length 9
+        assert!(Message::parse(&[0x49, 0x01, 0, 0]).is_err());
+        // Truncated option value
+        assert!(Message::parse(&[0x40, 0x01, 0, 0, 0xB5, b'a']).is_err());
+        // Payload marker with no payload
+        assert!(Message::parse(&[0x40, 0x01, 0, 0, 0xFF]).is_err());
+    }
+
+    #[test]
+    fn test_uint_encoding() {
+        assert_eq!(encode_uint(0), Vec::<u8>::new());
+        assert_eq!(encode_uint(50), vec![50]);
+        assert_eq!(encode_uint(0x012345), vec![0x01, 0x23, 0x45]);
+        assert_eq!(decode_uint(&[]), 0);
+        assert_eq!(decode_uint(&[0x01, 0x23, 0x45]), 0x012345);
+    }
+
+    #[test]
+    fn test_reading_json() {
+        let latest = crate::history::AmendedReading {
+            timestamp: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            original_distance: 1234.5,
+            distance: 1234.5,
+            invalid: false,
+            amendment_ids: Vec::new(),
+        };
+        let value = reading_json("pole-1", &latest, None);
+        assert_eq!(value, serde_json::json!({"station": "pole-1", "distance": 1234.5, "timestamp": 1_700_000_000}));
+
+        let trace = TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+        let traceparent = reading_json("pole-1", &latest, Some(&trace))["traceparent"].as_str().map(TraceContext::parse);
+        let continued = traceparent.flatten().unwrap();
+        assert_eq!(continued.trace_id, trace.trace_id);
+        assert_ne!(continued.span_id, trace.span_id);
+    }
+}
@@ -0,0 +1,286 @@
+/// Speed-of-sound compensation
+///
+/// An ultrasonic sensor times the echo and converts it to a distance at a
+/// fixed speed of sound, but sound travels about 0.6 m/s (0.17%) slower for
+/// each °C colder, so a gauge that reads true at 20 °C reads 9% long at
+/// −30 °C: 18 cm over a 2 m mount. With an air temperature source, each raw
+/// reading is scaled by the ratio of the actual speed of sound to the one the
+/// sensor assumes before it reaches the filters. Humidity speeds sound up a
+/// little too, by about 0.4% from dry to saturated air, and is taken into
+/// account when a source for it is given.
+///
+/// Temperature and humidity change slowly, so the sources are read once a
+/// minute rather than with every reading.
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+/// Time between reads of the temperature and humidity sources
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Relative humidity (%) assumed without a humidity source, and for the
+/// sensor's own calibration
+pub const REFERENCE_HUMIDITY: f64 = 50.0;
+
+/// Speed of sound in air in m/s at `temperature` °C and `humidity` % relative
+/// humidity, linearized about 0 °C
+pub fn speed_of_sound(temperature: f64, humidity: f64) -> f64 {
+    331.4 + 0.606 * temperature + 0.0124 * humidity
+}
+
+/// Where a temperature (°C), relative humidity (%) or wind speed (m/s) comes from
+#[derive(Debug, Clone, PartialEq)]
+pub enum AmbientSource {
+    /// A constant, e.g. for a gauge indoors or a test bench
+    Fixed(f64),
+    /// A file containing the value, as written by a weather station agent
+    File(PathBuf),
+    /// A sysfs attribute in thousandths, such as a hwmon `temp1_input`,
+    /// a 1-Wire DS18B20's `temperature` or an IIO `in_humidityrelative_input`
+    Sysfs(PathBuf),
+}
+
+impl std::str::FromStr for AmbientSource {
+    type Err = String;
+
+    /// Parse `fixed:VALUE`, `file:PATH` or `sysfs:PATH`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid ambient source '{}'. Expected fixed:VALUE, file:PATH or sysfs:PATH", s);
+        let (kind, spec) = s.split_once(':').ok_or_else(invalid)?;
+        if spec.is_empty() {
+            return Err(invalid());
+        }
+        match kind.to_lowercase().as_str() {
+            "fixed" => match spec.parse::<f64>() {
+                Ok(value) if value.is_finite() => Ok(AmbientSource::Fixed(value)),
+                _ => Err(invalid()),
+            },
+            "file" => Ok(AmbientSource::File(PathBuf::from(spec))),
+            "sysfs" => Ok(AmbientSource::Sysfs(PathBuf::from(spec))),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl std::fmt::Display for AmbientSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AmbientSource::Fixed(value) => write!(f, "fixed:{}", value),
+            AmbientSource::File(path) => write!(f, "file:{}", path.display()),
+            AmbientSource::Sysfs(path) => write!(f, "sysfs:{}", path.display()),
+        }
+    }
+}
+
+fn read_number(path: &Path) -> io::Result<f64> {
+    let text = std::fs::read_to_string(path)?;
+    text.trim().parse().map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("{} does not hold a number: {:?}", path.display(), text.trim()))
+    })
+}
+
+impl AmbientSource {
+    pub fn read(&self) -> io::Result<f64> {
+        match self {
+            AmbientSource::Fixed(value) => Ok(*value),
+            AmbientSource::File(path) => read_number(path),
+            AmbientSource::Sysfs(path) => Ok(read_number(path)? / 1000.0),
+        }
+    }
+
+    pub fn is_fixed(&self) -> bool {
+        matches!(self, AmbientSource::Fixed(_))
+    }
+}
+
+/// Compensation settings
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompensationConfig {
+    /// Air temperature in °C
+    pub temperature: AmbientSource,
+    /// Relative humidity in %; `REFERENCE_HUMIDITY` is assumed without one
+    pub humidity: Option<AmbientSource>,
+    /// Temperature in °C at which the sensor's readings are true
+    pub reference_temperature: f64,
+}
+
+/// The conditions the readings are being corrected for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ambient {
+    pub temperature: f64,
+    /// None without a humidity source, or while it can't be read
+    pub humidity: Option<f64>,
+}
+
+/// Reads a source, logging when reads start and stop failing rather than on
+/// every sample
+#[derive(Debug)]
+pub struct Sampler {
+    name: &'static str,
+    source: AmbientSource,
+    failing: bool,
+}
+
+impl Sampler {
+    pub fn new(name: &'static str, source: AmbientSource) -> Self {
+        Self { name, source, failing: false }
+    }
+
+    pub fn sample(&mut self) -> Option<f64> {
+        match self.source.read() {
+            Ok(value) => {
+                if std::mem::replace(&mut self.failing, false) {
+                    info!("{} readable again from {}", self.name, self.source);
+                }
+                Some(value)
+            }
+            Err(e) => {
+                if !std::mem::replace(&mut self.failing, true) {
+                    warn!("Error reading {} from {}: {}", self.name.to_lowercase(), self.source, e);
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Corrects raw distances for the speed of sound
+#[derive(Debug)]
+pub struct SoundCompensation {
+    temperature: Sampler,
+    humidity: Option<Sampler>,
+    reference_speed: f64,
+    ambient: Option<Ambient>,
+    sampled_at: Option<Instant>,
+}
+
+impl SoundCompensation {
+    pub fn new(config: CompensationConfig) -> Self {
+        Self {
+            temperature: Sampler::new("Temperature", config.temperature),
+            humidity: config.humidity.map(|source| Sampler::new("Humidity", source)),
+            reference_speed: speed_of_sound(config.reference_temperature, REFERENCE_HUMIDITY),
+            ambient: None,
+            sampled_at: None,
+        }
+    }
+
+    /// Read the sources again if a minute has passed since they were last read
+    pub fn sample(&mut self, now: Instant) {
+        if self.sampled_at.is_some_and(|at| now.duration_since(at) < SAMPLE_INTERVAL) {
+            return;
+        }
+        self.sampled_at = Some(now);
+        self.ambient = self.temperature.sample().map(|temperature| Ambient {
+            temperature,
+            humidity: self.humidity.as_mut().and_then(Sampler::sample),
+        });
+    }
+
+    /// The conditions from the last sample, or None if the temperature
+    /// couldn't be read and readings are passing through uncorrected
+    pub fn ambient(&self) -> Option<Ambient> {
+        self.ambient
+    }
+
+    /// True if the temperature is a configured constant rather than measured
+    pub fn is_fixed(&self) -> bool {
+        self.temperature.source.is_fixed()
+    }
+
+    /// The reading scaled from the sensor's speed of sound to the actual one
+    pub fn correct(&self, raw: f64) -> f64 {
+        match self.ambient {
+            Some(ambient) => {
+                let speed = speed_of_sound(ambient.temperature, ambient.humidity.unwrap_or(REFERENCE_HUMIDITY));
+                raw * speed / self.reference_speed
+            }
+            None => raw,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("snowgauge-compensation-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn config(temperature: AmbientSource) -> CompensationConfig {
+        CompensationConfig { temperature, humidity: None, reference_temperature: 20.0 }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("fixed:-12.5".parse(), Ok(AmbientSource::Fixed(-12.5)));
+        assert_eq!("file:/run/air".parse(), Ok(AmbientSource::File(PathBuf::from("/run/air"))));
+        let sysfs: AmbientSource = "sysfs:/sys/class/hwmon/hwmon0/temp1_input".parse().unwrap();
+        assert_eq!(sysfs, AmbientSource::Sysfs(PathBuf::from("/sys/class/hwmon/hwmon0/temp1_input")));
+        assert_eq!(sysfs.to_string().parse(), Ok(sysfs));
+        assert!("fixed:cold".parse::<AmbientSource>().is_err());
+        assert!("file:".parse::<AmbientSource>().is_err());
+        assert!("/run/air".parse::<AmbientSource>().is_err());
+    }
+
+    #[test]
+    fn test_read() {
+        let dir = temp_dir("read");
+        let path = dir.join("temp1_input");
+        std::fs::write(&path, "-18250\n").unwrap();
+        assert_eq!(AmbientSource::Sysfs(path.clone()).read().unwrap(), -18.25);
+        assert_eq!(AmbientSource::File(path.clone()).read().unwrap(), -18250.0);
+        std::fs::write(&path, "crc error").unwrap();
+        assert!(AmbientSource::File(path).read().is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_correct() {
+        let mut compensation = SoundCompensation::new(config(AmbientSource::Fixed(20.0)));
+        // Uncorrected until sampled
+        assert_eq!(compensation.correct(2000.0), 2000.0);
+        compensation.sample(Instant::now());
+        assert_eq!(compensation.correct(2000.0), 2000.0);
+
+        let mut cold = SoundCompensation::new(config(AmbientSource::Fixed(-30.0)));
+        cold.sample(Instant::now());
+        let corrected = cold.correct(2000.0);
+        // 0.606 m/s per °C over 50 °C, from 344.14 m/s
+        assert!((corrected - 2000.0 * (1.0 - 30.3 / 344.14)).abs() < 1e-9, "{}", corrected);
+        assert!(2000.0 - corrected > 170.0);
+        assert_eq!(cold.ambient(), Some(Ambient { temperature: -30.0, humidity: None }));
+
+        let mut humid = SoundCompensation::new(CompensationConfig {
+            humidity: Some(AmbientSource::Fixed(100.0)),
+            ..config(AmbientSource::Fixed(20.0))
+        });
+        humid.sample(Instant::now());
+        assert!(humid.correct(2000.0) > 2000.0 && humid.correct(2000.0) < 2004.0);
+    }
+
+    #[test]
+    fn test_sample_interval_and_failure() {
+        let dir = temp_dir("sample");
+        let path = dir.join("temperature");
+        let mut compensation = SoundCompensation::new(config(AmbientSource::File(path.clone())));
+        let start = Instant::now();
+        compensation.sample(start);
+        assert_eq!(compensation.ambient(), None);
+        assert_eq!(compensation.correct(1500.0), 1500.0);
+
+        std::fs::write(&path, "-10").unwrap();
+        compensation.sample(start + Duration::from_secs(30));
+        assert_eq!(compensation.ambient(), None);
+        compensation.sample(start + SAMPLE_INTERVAL);
+        assert_eq!(compensation.ambient().map(|a| a.temperature), Some(-10.0));
+        assert!(compensation.correct(1500.0) < 1500.0);
+        assert!(!compensation.is_fixed());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
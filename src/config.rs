@@ -0,0 +1,109 @@
+/// The resolved command line configuration, for GetEffectiveConfig
+///
+/// Every option is reported with the value it resolved to and where that
+/// came from (its default, the environment, or the command line), read back
+/// from clap's matches so new options are covered without listing them here.
+/// Credentials are redacted.
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Command};
+
+/// Options whose values are never reported
+const REDACTED: &[&str] = &["snmp_community"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    Default,
+    Environment,
+    CommandLine,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Setting {
+    /// Long option name, e.g. `listen-addr`
+    pub name: String,
+    /// Empty for an unset option
+    pub values: Vec<String>,
+    /// None for an unset option
+    pub source: Option<Source>,
+    pub redacted: bool,
+}
+
+/// The top-level options of `command` as resolved in `matches`
+pub fn settings(command: &Command, matches: &ArgMatches) -> Vec<Setting> {
+    command
+        .get_arguments()
+        .filter(|arg| !arg.is_positional())
+        .map(|arg| {
+            let id = arg.get_id().as_str();
+            let redacted = REDACTED.contains(&id);
+            let values = match matches.get_raw(id) {
+                Some(_) if redacted => vec!["(redacted)".to_string()],
+                Some(raw) => raw.map(|v| v.to_string_lossy().into_owned()).collect(),
+                None => Vec::new(),
+            };
+            let source = matches.value_source(id).and_then(|source| match source {
+                ValueSource::DefaultValue => Some(Source::Default),
+                ValueSource::EnvVariable => Some(Source::Environment),
+                ValueSource::CommandLine => Some(Source::CommandLine),
+                _ => None,
+            });
+            Setting {
+                name: arg.get_long().unwrap_or(id).to_string(),
+                values,
+                source,
+                redacted,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Arg;
+
+    fn command() -> Command {
+        Command::new("test")
+            .arg(Arg::new("listen_addr").long("listen-addr").default_value("0.0.0.0:7669").value_delimiter(','))
+            .arg(Arg::new("port").long("port").env("SNOWGAUGE_TEST_PORT"))
+            .arg(Arg::new("snmp_community").long("snmp-community").default_value("public"))
+            .arg(Arg::new("baseline").long("baseline"))
+            .arg(Arg::new("debug").long("debug").action(clap::ArgAction::SetTrue))
+    }
+
+    fn setting<'a>(settings: &'a [Setting], name: &str) -> &'a Setting {
+        settings.iter().find(|s| s.name == name).unwrap()
+    }
+
+    #[test]
+    fn test_settings() {
+        std::env::set_var("SNOWGAUGE_TEST_PORT", "/dev/ttyUSB0");
+        let command = command();
+        let matches = command
+            .clone()
+            .get_matches_from(["test", "--listen-addr", "127.0.0.1:7669,[::1]:7669", "--debug"]);
+        let settings = settings(&command, &matches);
+        assert_eq!(settings.len(), 5);
+
+        let listen = setting(&settings, "listen-addr");
+        assert_eq!(listen.values, ["127.0.0.1:7669", "[::1]:7669"]);
+        assert_eq!(listen.source, Some(Source::CommandLine));
+
+        let port = setting(&settings, "port");
+        assert_eq!(port.values, ["/dev/ttyUSB0"]);
+        assert_eq!(port.source, Some(Source::Environment));
+
+        let debug = setting(&settings, "debug");
+        assert_eq!(debug.values, ["true"]);
+        assert_eq!(debug.source, Some(Source::CommandLine));
+
+        let baseline = setting(&settings, "baseline");
+        assert!(baseline.values.is_empty());
+        assert_eq!(baseline.source, None);
+
+        let community = setting(&settings, "snmp-community");
+        assert_eq!(community.values, ["(redacted)"]);
+        assert_eq!(community.source, Some(Source::Default));
+        assert!(community.redacted);
+    }
+}
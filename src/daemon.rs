@@ -0,0 +1,488 @@
+/// The daemon and its subcommands
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use clap::{CommandFactory, FromArgMatches};
+use log::{error, info, warn};
+use tokio::sync::{mpsc, watch};
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+
+use crate::args::{Args, BenchArgs, Command, TuneArgs};
+use crate::bench;
+#[cfg(target_os = "linux")]
+use crate::ble;
+use crate::coap;
+use crate::config;
+use crate::events::EventPublisher;
+use crate::frame::FrameCounters;
+use crate::hooks::Hooks;
+use crate::logging;
+use crate::lora;
+use crate::preset::Preset;
+use crate::sensor_filter::FilterType;
+use crate::server;
+use crate::service::SnowGaugeServiceImpl;
+use crate::snmp;
+use crate::snowgauge::EventKind;
+use crate::station::{filter_preset, start_station, Station};
+use crate::stations;
+use crate::supervisor::{Supervisor, SupervisorConfig, TaskState};
+use crate::tune;
+
+/// Run the `tune` subcommand and print the ranked candidates
+fn run_tune(args: TuneArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let read = |path: &PathBuf| -> Result<Vec<tune::Sample>, String> {
+        let csv = std::fs::read_to_string(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        tune::parse_csv(&csv).map_err(|e| format!("{}: {}", path.display(), e))
+    };
+    let samples = read(&args.input)?;
+    let reference = args.reference.as_ref().map(read).transpose()?;
+
+    // The trend filter fits a line per reading, too slow to sweep by default
+    let filter_types = match args.filter_type {
+        Some(filter_type) => vec![filter_type],
+        None => vec![FilterType::Exponential, FilterType::TrimmedMean, FilterType::Both, FilterType::Kalman, FilterType::Median,
+                     FilterType::Mode],
+    };
+    let configs = tune::candidates(&filter_types, args.init_period);
+    info!("Tuning {} configurations over {} readings against {}",
+          configs.len(), samples.len(),
+          match reference {
+              Some(ref r) => format!("{} reference observations", r.len()),
+              None => "a rolling median of the readings".to_string(),
+          });
+
+    let ranked = tune::tune(&configs, &samples, reference.as_deref());
+    let Some(best) = ranked.first() else {
+        return Err("no configuration produced output to compare; is the capture shorter than one batch?".into());
+    };
+
+    println!("{:<13} {:>6} {:>6} {:>6} {:>6} {:>6} {:>6} {:>6} {:>9} {:>9} {:>9}",
+             "filter", "alpha", "rate", "trim", "q", "window", "bin", "batch", "rmse mm", "bias mm", "noise mm");
+    for candidate in ranked.iter().take(args.top) {
+        let c = &candidate.config;
+        // Parameters the filter type does not use are shown as "-"
+        let show = |used: bool, value: f64| if used { value.to_string() } else { "-".to_string() };
+        let trimmed = matches!(c.filter_type, FilterType::TrimmedMean | FilterType::Both);
+        let kalman = c.filter_type == FilterType::Kalman;
+        let window = match c.filter_type {
+            FilterType::Median => Some(c.median_window),
+            FilterType::Trend => Some(c.trend_window),
+            _ => None,
+        };
+        let mode = c.filter_type == FilterType::Mode;
+        println!("{:<13} {:>6} {:>6} {:>6} {:>6} {:>6} {:>6} {:>6} {:>9.3} {:>9.3} {:>9.3}",
+                 c.filter_type.to_string(), show(c.uses_exponential(), c.alpha),
+                 show(c.uses_exponential(), c.rate_limit), show(trimmed, c.trim_percentage),
+                 show(kalman, c.process_noise), show(window.is_some(), window.unwrap_or_default() as f64),
+                 show(mode, c.mode_bin_width), c.batch_size,
+                 candidate.score.rmse, candidate.score.bias, candidate.score.noise);
+    }
+
+    let preset = Preset::new(args.name, best.config.clone());
+    match args.output {
+        Some(ref path) => {
+            preset.save(path)?;
+            info!("Wrote recommended filter preset to {}", path.display());
+        }
+        None => print!("{}", preset.to_json()),
+    }
+    Ok(())
+}
+
+/// Run the `bench-sensor` subcommand: sample for the configured duration
+/// (or until interrupted) and print the noise report
+async fn run_bench(args: BenchArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let cancel_token = CancellationToken::new();
+
+    let (_sensor_power_tx, sensor_power_rx) = watch::channel(true);
+    let (_release_port_tx, release_port_rx) = watch::channel(false);
+    let raw_frames = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let frame_counters = Arc::new(FrameCounters::default());
+    let source_cancel = cancel_token.clone();
+    let serial = args.serial();
+    let format = args.frame_format()?;
+    let checksum = args.frame_checksum;
+    let simulator = args.simulator;
+    let reader_counters = frame_counters.clone();
+    let source = tokio::spawn(async move {
+        let result = if args.simulator {
+            info!("Sampling the simulator for {}s", args.duration);
+            SnowGaugeServiceImpl::simulator(1000.0, tx, false, source_cancel).await
+        } else {
+            info!("Sampling {} ({}) for {}s; keep the target static", args.port, serial, args.duration);
+            let events = EventPublisher::new(String::new());
+            SnowGaugeServiceImpl::serial_reader(
+                args.port, serial, format, checksum, tx, false, args.sensor_power_line, sensor_power_rx, release_port_rx,
+                raw_frames, reader_counters, events, source_cancel,
+            )
+                .await
+        };
+        result.map_err(|e| e.to_string())
+    });
+
+    let start = Instant::now();
+    let deadline = time::sleep(Duration::from_secs(args.duration));
+    tokio::pin!(deadline);
+    let mut samples = Vec::new();
+    loop {
+        tokio::select! {
+            distance = rx.recv() => match distance {
+                Some(distance) => samples.push((start.elapsed().as_secs_f64(), distance)),
+                None => break,
+            },
+            _ = &mut deadline => break,
+            _ = tokio::signal::ctrl_c() => {
+                info!("Interrupted, reporting on the readings so far");
+                break;
+            }
+        }
+    }
+    cancel_token.cancel();
+    // The reader's errors were already logged; the samples are what matter here
+    let _ = source.await;
+
+    let Some(report) = bench::analyze(&samples) else {
+        return Err(format!("only {} readings received; at least 10 are needed", samples.len()).into());
+    };
+    let suggestion = report.suggest();
+
+    println!("Readings:         {} over {:.1}s ({:.2} per second)", report.samples, report.duration, report.frame_rate);
+    println!("Drift:            {:.2} mm/hour (removed before measuring noise)", report.drift_mm_per_hour);
+    println!("Noise std dev:    {:.2} mm ({:.2} mm robust, excluding spikes)", report.std_dev, report.robust_std_dev);
+    println!("Spikes:           {} ({:.2}% of readings, {:.2} per minute)",
+             report.spikes, report.spike_fraction * 100.0,
+             if report.duration > 0.0 { report.spikes as f64 * 60.0 / report.duration } else { 0.0 });
+    let percentiles: Vec<String> = bench::PERCENTILES
+        .iter()
+        .zip(&report.percentiles)
+        .map(|(p, v)| format!("p{}={:.1}", p, v))
+        .collect();
+    println!("Distance (mm):    {}", percentiles.join(" "));
+    if !simulator {
+        let frames = frame_counters.counts();
+        println!("Frames:           {} ({} badly framed, {} failed the checksum)",
+                 frames.frames, frames.invalid_framing, frames.checksum_failures);
+    }
+    println!();
+    println!("Suggested filter settings:");
+    println!("  --filter-alpha {} --trim-percentage {} --batch-size {}",
+             suggestion.alpha, suggestion.trim_percentage, suggestion.batch_size);
+    if report.frame_rate > 0.0 {
+        println!("  (one reading every {:.0}s at the measured frame rate)", suggestion.batch_size as f64 / report.frame_rate);
+    }
+    Ok(())
+}
+
+/// Run the daemon with the command line's configuration and `hooks`
+pub async fn run(hooks: Hooks) -> Result<(), Box<dyn std::error::Error>> {
+    let command = Args::command();
+    let matches = command.clone().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    // Initialize logger; SetLogLevel can change the filter later
+    logging::init(if args.debug { "debug" } else { "info" });
+
+    match args.command {
+        Some(Command::Tune(tune_args)) => return run_tune(tune_args),
+        Some(Command::BenchSensor(bench_args)) => return run_bench(bench_args).await,
+        None => {}
+    }
+
+    let stations = match args.stations.as_deref().map(|path| stations::load(path, &args.station_name)).transpose() {
+        Ok(stations) => stations.unwrap_or_default(),
+        Err(e) => {
+            error!("{}", e);
+            return Err(e.into());
+        }
+    };
+
+    if let Some(ref path) = args.export_filter_preset {
+        let preset = match filter_preset(&args).and_then(|preset| preset.config.validate().map(|_| preset)) {
+            Ok(preset) => preset,
+            Err(e) => {
+                error!("{}", e);
+                return Err(e.into());
+            }
+        };
+        if let Err(e) = preset.save(path) {
+            error!("{}", e);
+            return Err(e.into());
+        }
+        info!("Exported filter preset '{}' to {}", preset.name, path.display());
+        return Ok(());
+    }
+
+    info!("snowgauge {} ({}, {})", env!("CARGO_PKG_VERSION"), env!("SNOWGAUGE_GIT_DESCRIBE"), env!("SNOWGAUGE_TARGET"));
+
+    // Create cancellation token for coordinated shutdown
+    let cancel_token = CancellationToken::new();
+
+    // Every long-running task runs under the supervisor, which restarts it
+    // per --restart-policy and fails the health check after repeated crashes
+    let supervisor = Supervisor::new(
+        SupervisorConfig {
+            policy: args.restart_policy,
+            max_restarts: args.max_restarts,
+            restart_window: Duration::from_secs(args.restart_window),
+        },
+        cancel_token.clone(),
+    );
+
+    let settings = config::settings(&command, &matches);
+    let Station { service, data_source_task, processing_task } =
+        start_station(&args, settings.clone(), None, &hooks, &supervisor, &cancel_token)?;
+
+    let mut others = Vec::new();
+    for spec in &stations {
+        others.push(start_station(&args.for_station(spec), settings.clone(), Some(&service), &hooks, &supervisor, &cancel_token)?);
+    }
+
+    // Stations fusing others follow their latest results
+    let served: Vec<&SnowGaugeServiceImpl> = std::iter::once(&*service).chain(others.iter().map(|station| &*station.service)).collect();
+    for station in &served {
+        let partners: Result<Vec<_>, String> = station
+            .fuse_with
+            .iter()
+            .map(|name| {
+                served
+                    .iter()
+                    .find(|other| other.station_name == *name && other.station_name != station.station_name)
+                    .map(|other| (name.clone(), other.estimate.subscribe()))
+                    .ok_or_else(|| format!("station '{}' can't fuse with '{}', which isn't another station served here", station.station_name, name))
+            })
+            .collect();
+        match partners {
+            Ok(partners) => {
+                let _ = station.fusion_partners.set(partners);
+            }
+            Err(e) => {
+                error!("{}", e);
+                return Err(e.into());
+            }
+        }
+    }
+
+    // A station's tasks are named with a `for <station>` suffix; the rest,
+    // such as the output sinks, are the command line station's
+    let mut owners = vec![(String::new(), service.events.clone())];
+    owners.extend(others.iter().map(|station| (format!(" for {}", station.service.station_name), station.service.events.clone())));
+    supervisor.on_restart(move |task, outcome| {
+        let owner = owners.iter().filter(|(suffix, _)| task.ends_with(suffix.as_str())).max_by_key(|(suffix, _)| suffix.len());
+        if let Some((_, events)) = owner {
+            events.publish(EventKind::TaskRestarted, format!("{} {}, restarting", task, outcome));
+        }
+    });
+    if !others.is_empty() {
+        let _ = service.peers.set(others.iter().map(|station| (*station.service).clone()).collect());
+        let names: Vec<&str> = service.stations().map(|station| station.station_name.as_str()).collect();
+        info!("Serving {} stations: {}", names.len(), names.join(", "));
+    }
+
+    // Start the CoAP endpoint if configured. The socket is bound up front so
+    // a bad address fails at startup; restarts bind it again.
+    let coap_task = match args.coap_listen_addr {
+        Some(ref coap_addr) => {
+            let socket = tokio::net::UdpSocket::bind(coap_addr).await?;
+            info!("CoAP server listening on {}", socket.local_addr()?);
+            let mut socket = Some(socket);
+            let coap_addr = coap_addr.clone();
+            let service = Arc::clone(&service);
+            let cancel_token = cancel_token.clone();
+            Some(supervisor.spawn("CoAP server", move || {
+                let socket = socket.take();
+                let coap_addr = coap_addr.clone();
+                let service = Arc::clone(&service);
+                let cancel_token = cancel_token.clone();
+                async move {
+                    let socket = match socket {
+                        Some(socket) => socket,
+                        None => tokio::net::UdpSocket::bind(&coap_addr).await.map_err(|e| e.to_string())?,
+                    };
+                    let server = coap::CoapServer::new(socket, Arc::clone(&service.history), Arc::clone(&service.totals), service.station_name.clone());
+                    let readings = service.subscribe().await;
+                    server.run(readings, cancel_token).await;
+                    Ok(())
+                }
+            }))
+        }
+        None => None,
+    };
+
+    // Start the SNMP agent if configured
+    let snmp_task = match args.snmp_listen_addr {
+        Some(ref snmp_addr) => {
+            let socket = tokio::net::UdpSocket::bind(snmp_addr).await?;
+            info!("SNMP agent listening on {}", socket.local_addr()?);
+            if args.snmp_sensor_height.is_none() && service.baseline.borrow().is_none() {
+                info!("  No baseline or --snmp-sensor-height set, snow depth will be reported as missing until SetBaseline");
+            }
+            let mut socket = Some(socket);
+            let snmp_addr = snmp_addr.clone();
+            let community = args.snmp_community.clone();
+            let sensor_height = args.snmp_sensor_height;
+            let service = Arc::clone(&service);
+            let cancel_token = cancel_token.clone();
+            Some(supervisor.spawn("SNMP agent", move || {
+                let socket = socket.take();
+                let snmp_addr = snmp_addr.clone();
+                let community = community.clone();
+                let service = Arc::clone(&service);
+                let cancel_token = cancel_token.clone();
+                async move {
+                    let socket = match socket {
+                        Some(socket) => socket,
+                        None => tokio::net::UdpSocket::bind(&snmp_addr).await.map_err(|e| e.to_string())?,
+                    };
+                    let agent = snmp::SnmpAgent::new(
+                        socket,
+                        Arc::clone(&service.history),
+                        service.station_name.clone(),
+                        community,
+                        service.baseline.subscribe(),
+                        sensor_height,
+                    );
+                    agent.run(cancel_token).await;
+                    Ok(())
+                }
+            }))
+        }
+        None => None,
+    };
+
+    // Start the BLE advertiser if configured
+    #[cfg(target_os = "linux")]
+    let ble_task = if args.ble_advertise {
+        let mut advertiser = Some(ble::Advertiser::open(args.ble_hci_index, args.ble_company_id)?);
+        info!("Advertising readings over BLE on hci{}", args.ble_hci_index);
+        let (hci_index, company_id) = (args.ble_hci_index, args.ble_company_id);
+        let service = Arc::clone(&service);
+        let cancel_token = cancel_token.clone();
+        Some(supervisor.spawn("BLE advertiser", move || {
+            let advertiser = advertiser.take();
+            let service = Arc::clone(&service);
+            let cancel_token = cancel_token.clone();
+            async move {
+                let advertiser = match advertiser {
+                    Some(advertiser) => advertiser,
+                    None => ble::Advertiser::open(hci_index, company_id).map_err(|e| e.to_string())?,
+                };
+                let readings = service.subscribe().await;
+                ble::run(advertiser, readings, cancel_token).await;
+                Ok(())
+            }
+        }))
+    } else {
+        None
+    };
+    #[cfg(not(target_os = "linux"))]
+    let ble_task: Option<tokio::task::JoinHandle<()>> = if args.ble_advertise {
+        return Err("BLE advertising is only supported on Linux".into());
+    } else {
+        None
+    };
+
+    // Start the LoRa uplink if configured
+    let lora_task = match args.lora_port {
+        Some(ref lora_port) => {
+            let config = lora::LoraConfig {
+                port: lora_port.clone(),
+                baud_rate: args.lora_baud,
+                mode: args.lora_mode,
+                at_template: args.lora_at_template.clone(),
+                interval: Duration::from_secs(args.lora_interval.max(1)),
+                trace_context: args.lora_trace_context,
+            };
+            let service = Arc::clone(&service);
+            let cancel_token = cancel_token.clone();
+            Some(supervisor.spawn("LoRa uplink", move || {
+                let config = config.clone();
+                let service = Arc::clone(&service);
+                let cancel_token = cancel_token.clone();
+                async move {
+                    let readings = service.subscribe().await;
+                    lora::run(config, readings, cancel_token).await;
+                    Ok(())
+                }
+            }))
+        }
+        None => None,
+    };
+
+    let signal_token = cancel_token.clone();
+    tokio::spawn(async move {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for shutdown signal");
+        info!("Shutdown signal received, gracefully stopping...");
+        signal_token.cancel();
+    });
+
+    // Start gRPC server with graceful shutdown
+    server::serve(&args, &service, &hooks, &supervisor, &cancel_token).await?;
+
+    info!("Server stopped, waiting for background tasks to complete...");
+
+    // Wait for the data source task (serial reader or simulator) to finish
+    // When its supervisor exits, the last tx is dropped, which closes the channel
+    if let Err(e) = data_source_task.await {
+        error!("Data source supervisor panicked: {}", e);
+    }
+
+    // Wait for the processing task to finish
+    // It will complete once the channel is closed
+    if let Err(e) = processing_task.await {
+        error!("Processing supervisor panicked: {}", e);
+    }
+
+    for station in others {
+        if let Err(e) = station.data_source_task.await {
+            error!("Data source supervisor for {} panicked: {}", station.service.station_name, e);
+        }
+        if let Err(e) = station.processing_task.await {
+            error!("Processing supervisor for {} panicked: {}", station.service.station_name, e);
+        }
+    }
+
+    if let Some(task) = coap_task {
+        if let Err(e) = task.await {
+            error!("CoAP task panicked: {}", e);
+        }
+    }
+
+    if let Some(task) = snmp_task {
+        if let Err(e) = task.await {
+            error!("SNMP task panicked: {}", e);
+        }
+    }
+
+    if let Some(task) = ble_task {
+        if let Err(e) = task.await {
+            error!("BLE advertiser task panicked: {}", e);
+        }
+    }
+
+    if let Some(task) = lora_task {
+        if let Err(e) = task.await {
+            error!("LoRa uplink task panicked: {}", e);
+        }
+    }
+
+    for task in supervisor.tasks() {
+        if task.restarts > 0 || task.state == TaskState::Failed {
+            warn!(
+                "{} ended {:?} after {} restarts (last error: {})",
+                task.name,
+                task.state,
+                task.restarts,
+                task.last_error.as_deref().unwrap_or("none")
+            );
+        }
+    }
+
+    info!("All tasks completed, exiting");
+    Ok(())
+}
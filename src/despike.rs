@@ -0,0 +1,133 @@
+/// Hampel despiking stage
+///
+/// A reading further than k scaled median absolute deviations (MAD) from
+/// the median of the recent raw readings is discarded before it reaches the
+/// per-reading filter or the batch. The rate-limited exponential filter
+/// would otherwise take every spike into its state, a millimetre per reading
+/// at a time, and carry it into the following readings. Because the window
+/// holds the raw readings, discarded ones included, a real step in the
+/// surface is accepted again once it makes up half the window.
+use std::collections::VecDeque;
+
+/// Scales the MAD to the standard deviation of normally distributed readings
+const MAD_SCALE: f64 = 1.4826;
+
+/// Smallest deviation scale in mm, the sensor's resolution, so a window of
+/// identical readings doesn't reject the next millimetre of change
+const MIN_SCALE: f64 = 1.0;
+
+/// Readings needed before any are rejected
+const MIN_READINGS: usize = 3;
+
+#[derive(Debug, Clone)]
+pub struct Despiker {
+    window: usize,
+    threshold: f64,
+    recent: VecDeque<f64>,
+    reading_count: usize,
+}
+
+fn median(values: &mut [f64]) -> f64 {
+    values.sort_by(|a, b| a.total_cmp(b));
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    }
+}
+
+impl Despiker {
+    /// Reject readings more than `threshold` (k) scaled MADs from the median
+    /// of the last `window` readings
+    pub fn new(window: usize, threshold: f64) -> Self {
+        Self {
+            window,
+            threshold,
+            recent: VecDeque::with_capacity(window),
+            reading_count: 0,
+        }
+    }
+
+    /// Change the parameters, keeping the most recent readings that still fit
+    pub fn set_params(&mut self, window: usize, threshold: f64) {
+        self.window = window;
+        self.threshold = threshold;
+        while self.recent.len() > self.window {
+            self.recent.pop_front();
+        }
+    }
+
+    /// Record a raw reading, returning false if it is a spike to discard
+    pub fn accept(&mut self, raw: f64) -> bool {
+        self.reading_count += 1;
+        let accepted = if self.recent.len() < MIN_READINGS {
+            true
+        } else {
+            let mut values: Vec<f64> = self.recent.iter().copied().collect();
+            let center = median(&mut values);
+            let mut deviations: Vec<f64> = values.iter().map(|v| (v - center).abs()).collect();
+            let scale = (MAD_SCALE * median(&mut deviations)).max(MIN_SCALE);
+            (raw - center).abs() <= self.threshold * scale
+        };
+
+        if self.recent.len() >= self.window {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(raw);
+        accepted
+    }
+
+    pub fn reset(&mut self) {
+        self.recent.clear();
+        self.reading_count = 0;
+    }
+
+    pub fn reading_count(&self) -> usize {
+        self.reading_count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rejects_spikes() {
+        let mut despiker = Despiker::new(9, 3.0);
+        let readings = [1000.0, 1002.0, 999.0, 1001.0, 300.0, 1000.0, 998.0, 4999.0, 1001.0, 1003.0];
+        let accepted: Vec<bool> = readings.iter().map(|&r| despiker.accept(r)).collect();
+        assert_eq!(accepted, [true, true, true, true, false, true, true, false, true, true]);
+    }
+
+    #[test]
+    fn test_accepts_changes_on_a_steady_surface() {
+        let mut despiker = Despiker::new(9, 3.0);
+        for _ in 0..9 {
+            assert!(despiker.accept(1000.0));
+        }
+        // Within k mm of identical readings
+        assert!(despiker.accept(1003.0));
+        assert!(!despiker.accept(1010.0));
+    }
+
+    #[test]
+    fn test_step_accepted_after_half_window() {
+        let mut despiker = Despiker::new(9, 3.0);
+        for i in 0..9 {
+            despiker.accept(1000.0 + (i % 3) as f64);
+        }
+        let accepted: Vec<bool> = (0..6).map(|_| despiker.accept(900.0)).collect();
+        assert_eq!(accepted, [false, false, false, false, false, true]);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut despiker = Despiker::new(9, 3.0);
+        for _ in 0..9 {
+            despiker.accept(1000.0);
+        }
+        despiker.reset();
+        assert!(despiker.accept(500.0));
+    }
+}
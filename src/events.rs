@@ -0,0 +1,297 @@
+/// State change events for the StreamReadingEvents stream
+///
+/// Readings alone don't tell a client why the series changed: a filter
+/// reset, a new baseline or a sensor that dropped off the bus all look like
+/// steps or gaps. Those changes are published as events to every
+/// StreamReadingEvents client as they happen, interleaved with its readings.
+/// Events are not retained, so a client only sees the ones from while it is
+/// connected.
+///
+/// Storms are detected here too, from the snowfall rate in each batch
+/// reading, with hysteresis so a rate hovering around the threshold doesn't
+/// start and end a storm on every reading. Each storm is summarized (new
+/// snow, its water equivalent and peak rate) in its STORM_ENDED event and
+/// kept for GetStorms.
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::queue::{self, OverflowPolicy};
+use crate::snowgauge::{self, Event, EventKind};
+use crate::swe;
+use crate::trace::TraceContext;
+
+/// Storms kept for GetStorms, a few winters' worth
+const MAX_STORMS: usize = 500;
+
+/// Publishes events to the connected event stream subscribers
+///
+/// Cheap to clone, and usable from the blocking serial reader as well as
+/// async code.
+#[derive(Clone)]
+pub struct EventPublisher {
+    station_name: String,
+    subscribers: Arc<Mutex<Vec<queue::Sender<Event>>>>,
+}
+
+impl EventPublisher {
+    pub fn new(station_name: String) -> Self {
+        Self {
+            station_name,
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Receive every event published from now on by any of `publishers`,
+    /// in the order published
+    pub fn subscribe_all<'a>(
+        publishers: impl IntoIterator<Item = &'a EventPublisher>,
+        capacity: usize,
+        policy: OverflowPolicy,
+    ) -> queue::Receiver<Event> {
+        let (tx, rx) = queue::bounded(capacity, policy);
+        for publisher in publishers {
+            publisher.subscribers.lock().unwrap_or_else(|e| e.into_inner()).push(tx.clone());
+        }
+        rx
+    }
+
+    /// Publish an event starting its own trace
+    pub fn publish(&self, kind: EventKind, message: impl Into<String>) {
+        self.send(None, kind, message.into(), None);
+    }
+
+    /// Publish an event in the trace of the call or reading that caused it,
+    /// if it is known
+    pub fn publish_caused(&self, cause: Option<&TraceContext>, kind: EventKind, message: impl Into<String>) {
+        self.send(cause, kind, message.into(), None);
+    }
+
+    /// Publish a STORM_STARTED or STORM_ENDED event with the storm's summary,
+    /// in the trace of the reading that started or ended it
+    pub fn publish_storm(&self, cause: &TraceContext, kind: EventKind, message: impl Into<String>, storm: snowgauge::Storm) {
+        self.send(Some(cause), kind, message.into(), Some(storm));
+    }
+
+    fn send(&self, cause: Option<&TraceContext>, kind: EventKind, message: String, storm: Option<snowgauge::Storm>) {
+        let trace = cause.map_or_else(TraceContext::new_root, TraceContext::child);
+        let event = Event {
+            station_name: self.station_name.clone(),
+            timestamp: Some(SystemTime::now().into()),
+            kind: kind as i32,
+            message,
+            storm,
+            traceparent: trace.to_string(),
+        };
+        let mut subscribers = self.subscribers.lock().unwrap_or_else(|e| e.into_inner());
+        subscribers.retain(|subscriber| subscriber.send(Ok(event.clone())));
+    }
+}
+
+/// One storm, from the reading whose rate started it to the one whose rate
+/// ended it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Storm {
+    pub start: SystemTime,
+    /// None while the storm is underway
+    pub end: Option<SystemTime>,
+    /// Distance in mm when the storm started
+    pub start_distance: f64,
+    /// Deepest accumulation over `start_distance` so far, in mm; later
+    /// settling doesn't take it back
+    pub new_snow: f64,
+    /// Highest snowfall rate in mm/hr
+    pub peak_rate: f64,
+    /// Water equivalent of `new_snow` in mm, each mm of it at the snow
+    /// density when it fell; None without a density for all of it
+    pub water_equivalent: Option<f64>,
+}
+
+impl Storm {
+    pub fn to_proto(&self) -> snowgauge::Storm {
+        snowgauge::Storm {
+            start: Some(self.start.into()),
+            end: self.end.map(Into::into),
+            new_snow_mm: self.new_snow,
+            peak_rate_mm_per_hour: self.peak_rate,
+            ongoing: self.end.is_none(),
+            snow_water_equivalent_mm: self.water_equivalent,
+        }
+    }
+}
+
+/// Tracks whether a storm is underway from the snowfall rate, keeping the
+/// storms seen since startup
+#[derive(Debug)]
+pub struct StormDetector {
+    /// Rate in mm/hr at which a storm starts; it ends below half this
+    threshold: f64,
+    current: Option<Storm>,
+    storms: VecDeque<Storm>,
+}
+
+impl StormDetector {
+    pub fn new(threshold: f64) -> Self {
+        Self { threshold, current: None, storms: VecDeque::new() }
+    }
+
+    /// Update with the latest batch reading, snowfall rate and snow density
+    /// (kg/m³), returning STORM_STARTED or STORM_ENDED and the storm on a
+    /// change; an unknown rate changes nothing
+    pub fn update(
+        &mut self,
+        now: SystemTime,
+        rate: Option<f64>,
+        distance: f64,
+        density: Option<f64>,
+    ) -> Option<(EventKind, Storm)> {
+        if let Some(ref mut storm) = self.current {
+            // Distance is measured down from the sensor, so it shrinks as snow piles up
+            let new_snow = storm.new_snow.max(storm.start_distance - distance);
+            if new_snow > storm.new_snow {
+                storm.water_equivalent = storm
+                    .water_equivalent
+                    .zip(density)
+                    .map(|(water, density)| water + swe::water_equivalent(new_snow - storm.new_snow, density));
+            }
+            storm.new_snow = new_snow;
+            storm.peak_rate = storm.peak_rate.max(rate.unwrap_or_default());
+        }
+        let rate = rate?;
+        match self.current.take() {
+            None if rate >= self.threshold => {
+                let storm = Storm {
+                    start: now,
+                    end: None,
+                    start_distance: distance,
+                    new_snow: 0.0,
+                    peak_rate: rate,
+                    water_equivalent: density.map(|_| 0.0),
+                };
+                self.current = Some(storm.clone());
+                Some((EventKind::StormStarted, storm))
+            }
+            Some(mut storm) if rate < self.threshold / 2.0 => {
+                storm.end = Some(now);
+                if self.storms.len() >= MAX_STORMS {
+                    self.storms.pop_front();
+                }
+                self.storms.push_back(storm.clone());
+                Some((EventKind::StormEnded, storm))
+            }
+            current => {
+                self.current = current;
+                None
+            }
+        }
+    }
+
+    /// Storms overlapping `[start, end]` (either bound optional), oldest
+    /// first, including one underway
+    pub fn storms(&self, start: Option<SystemTime>, end: Option<SystemTime>) -> Vec<Storm> {
+        self.storms
+            .iter()
+            .chain(self.current.iter())
+            .filter(|s| s.end.is_none_or(|e| start.is_none_or(|start| e >= start)))
+            .filter(|s| end.is_none_or(|end| s.start <= end))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_publish() {
+        let events = EventPublisher::new("test".to_string());
+        events.publish(EventKind::FilterReset, "nobody listening");
+
+        let mut first = EventPublisher::subscribe_all([&events], 4, OverflowPolicy::DropOldest);
+        let second = EventPublisher::subscribe_all([&events], 4, OverflowPolicy::DropOldest);
+        events.publish(EventKind::BaselineChanged, "baseline 1834.5mm");
+        let event = first.try_recv().unwrap().unwrap();
+        assert_eq!(event.station_name, "test");
+        assert_eq!(event.kind(), EventKind::BaselineChanged);
+        assert_eq!(event.message, "baseline 1834.5mm");
+        assert!(first.try_recv().is_err());
+
+        // Subscribers that went away are dropped on the next publish
+        drop(second);
+        events.publish(EventKind::FilterReset, "");
+        assert_eq!(events.subscribers.lock().unwrap().len(), 1);
+        assert_eq!(first.try_recv().unwrap().unwrap().kind(), EventKind::FilterReset);
+    }
+
+    #[test]
+    fn test_subscribe_all() {
+        let (pole1, pole2) = (EventPublisher::new("pole-1".to_string()), EventPublisher::new("pole-2".to_string()));
+        let mut events = EventPublisher::subscribe_all([&pole1, &pole2], 4, OverflowPolicy::DropOldest);
+        pole2.publish(EventKind::FilterReset, "");
+        pole1.publish(EventKind::BaselineChanged, "baseline 1834.5mm");
+        let stations: Vec<String> = std::iter::from_fn(|| events.try_recv().ok()).map(|e| e.unwrap().station_name).collect();
+        assert_eq!(stations, vec!["pole-2", "pole-1"]);
+
+        // The stream stays open while any of the stations does
+        drop(pole1);
+        pole2.publish(EventKind::FilterReset, "");
+        assert_eq!(events.try_recv().unwrap().unwrap().station_name, "pole-2");
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_storm_hysteresis() {
+        let mut storm = StormDetector::new(10.0);
+        let mut update = |rate| storm.update(at(0), rate, 1000.0, None).map(|(kind, _)| kind);
+        assert_eq!(update(Some(4.0)), None);
+        assert_eq!(update(None), None);
+        assert_eq!(update(Some(12.0)), Some(EventKind::StormStarted));
+        assert_eq!(update(Some(15.0)), None);
+        // Dipping below the threshold isn't enough to end it
+        assert_eq!(update(Some(7.0)), None);
+        assert_eq!(update(None), None);
+        assert_eq!(update(Some(4.9)), Some(EventKind::StormEnded));
+        assert_eq!(update(Some(9.9)), None);
+    }
+
+    #[test]
+    fn test_storm_summary() {
+        let mut detector = StormDetector::new(10.0);
+        detector.update(at(0), Some(2.0), 2000.0, Some(100.0));
+        let (_, started) = detector.update(at(3600), Some(12.0), 1990.0, Some(100.0)).unwrap();
+        assert_eq!((started.start_distance, started.end), (1990.0, None));
+        detector.update(at(7200), Some(30.0), 1960.0, Some(100.0));
+        // Warmer, wetter snow
+        detector.update(at(10800), None, 1930.0, Some(150.0));
+        // Settling after the heaviest snow doesn't take it back
+        detector.update(at(14400), Some(6.0), 1940.0, Some(150.0));
+        assert_eq!(detector.storms(None, None)[0].end, None);
+
+        let (kind, storm) = detector.update(at(18000), Some(1.0), 1945.0, Some(150.0)).unwrap();
+        assert_eq!(kind, EventKind::StormEnded);
+        assert_eq!(storm, Storm {
+            start: at(3600),
+            end: Some(at(18000)),
+            start_distance: 1990.0,
+            new_snow: 60.0,
+            peak_rate: 30.0,
+            water_equivalent: Some(7.5),
+        });
+        assert!(storm.to_proto().end.is_some() && !storm.to_proto().ongoing);
+
+        assert_eq!(detector.storms(None, None), vec![storm]);
+        assert_eq!(detector.storms(Some(at(18000)), None).len(), 1);
+        assert!(detector.storms(Some(at(18001)), None).is_empty());
+        assert!(detector.storms(None, Some(at(3599))).is_empty());
+
+        // A density missing for some of the new snow leaves the total unknown
+        let (_, started) = detector.update(at(20000), Some(10.0), 1945.0, None).unwrap();
+        assert_eq!(started.water_equivalent, None);
+        detector.update(at(21000), Some(10.0), 1900.0, Some(100.0));
+        assert_eq!(detector.storms(Some(at(20000)), None)[0].water_equivalent, None);
+    }
+}
@@ -0,0 +1,566 @@
+/// Composable filter stages
+///
+/// A pipeline runs each raw reading through an ordered list of per-reading
+/// stages, each of which passes on a value (smoothed or not) or discards the
+/// reading, and collects what comes out into a batch that a final batch
+/// stage reduces to one reading: `hampel -> ema -> trimmed-mean`, say. Each
+/// `--filter-type` is a named list of stages; `--filter-pipeline` gives
+/// them directly. Adding a filter means implementing `Filter` or
+/// `BatchFilter` for it and giving it a `Stage`; the pipeline and the
+/// reading processor are generic over the stages.
+use crate::despike::Despiker;
+use crate::kalman::KalmanFilter;
+use crate::median::MedianFilter;
+use crate::pipeline::{self, BatchResult, FilterConfig};
+use crate::regression::TrendFilter;
+use crate::sensor_filter::{FilterType, SensorFilter};
+
+/// A per-reading stage
+pub trait Filter: Send {
+    /// Process one reading, returning the value for the next stage, or None
+    /// to discard the reading
+    fn update(&mut self, reading: f64) -> Option<f64>;
+
+    /// Apply changed parameters, keeping the stage's state
+    fn set_params(&mut self, config: &FilterConfig);
+
+    fn reset(&mut self);
+
+    /// False while the stage's output is still settling
+    fn is_initialized(&self) -> bool {
+        true
+    }
+
+    fn reading_count(&self) -> usize;
+
+    /// True if the stage's rate limit held back the last reading
+    fn was_rate_limited(&self) -> bool {
+        false
+    }
+}
+
+/// The stage that reduces a complete batch to one reading
+pub trait BatchFilter: Send {
+    fn aggregate(&self, batch: &mut [f64], config: &FilterConfig) -> BatchResult;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Discard readings outside the valid range (per reading)
+    Clamp,
+    /// Discard readings far from the rolling median (per reading)
+    Hampel,
+    /// Exponential weighted average with rate limiting (per reading)
+    Ema,
+    /// Kalman filter on distance and rate (per reading)
+    Kalman,
+    /// Rolling median (per reading)
+    Median,
+    /// Theil–Sen line through the recent readings (per reading)
+    Trend,
+    /// Average of the batch
+    Mean,
+    /// Average of the batch after trimming each end
+    TrimmedMean,
+    /// Center of the batch's most populated bin
+    Mode,
+}
+
+impl std::str::FromStr for Stage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "clamp" | "range" => Ok(Stage::Clamp),
+            "hampel" | "despike" => Ok(Stage::Hampel),
+            "ema" | "exponential" | "exp" => Ok(Stage::Ema),
+            "kalman" => Ok(Stage::Kalman),
+            "median" => Ok(Stage::Median),
+            "trend" | "theil-sen" => Ok(Stage::Trend),
+            "mean" | "average" => Ok(Stage::Mean),
+            "trimmed-mean" | "trimmed" | "trimmedmean" => Ok(Stage::TrimmedMean),
+            "mode" | "modal" => Ok(Stage::Mode),
+            _ => Err(format!(
+                "Invalid filter stage '{}'. Valid options: clamp, hampel, ema, kalman, median, trend, mean, trimmed-mean, mode",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Stage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Stage::Clamp => write!(f, "clamp"),
+            Stage::Hampel => write!(f, "hampel"),
+            Stage::Ema => write!(f, "ema"),
+            Stage::Kalman => write!(f, "kalman"),
+            Stage::Median => write!(f, "median"),
+            Stage::Trend => write!(f, "trend"),
+            Stage::Mean => write!(f, "mean"),
+            Stage::TrimmedMean => write!(f, "trimmed-mean"),
+            Stage::Mode => write!(f, "mode"),
+        }
+    }
+}
+
+impl Stage {
+    pub fn is_batch(self) -> bool {
+        matches!(self, Stage::Mean | Stage::TrimmedMean | Stage::Mode)
+    }
+
+    /// True for stages that only decide which readings go on, unchanged
+    pub fn discards_only(self) -> bool {
+        matches!(self, Stage::Clamp | Stage::Hampel)
+    }
+
+    /// Build a per-reading stage
+    ///
+    /// # Panics
+    /// For a batch stage
+    pub fn build(self, config: &FilterConfig) -> Box<dyn Filter> {
+        match self {
+            Stage::Clamp => Box::new(RangeClamp::new(config.min_distance, config.max_distance)),
+            Stage::Hampel => Box::new(Despiker::new(config.despike_window, config.despike_threshold)),
+            Stage::Ema => {
+                let mut filter = SensorFilter::with_params(config.init_period, config.rate_limit, config.alpha);
+                filter.set_adaptive(config.adaptive_noise, config.alpha_min);
+                Box::new(filter)
+            }
+            Stage::Kalman => Box::new(KalmanFilter::new(
+                config.init_period,
+                config.process_noise,
+                config.measurement_noise,
+            )),
+            Stage::Median => Box::new(MedianFilter::new(config.median_window)),
+            Stage::Trend => Box::new(TrendFilter::new(config.trend_window)),
+            Stage::Mean | Stage::TrimmedMean | Stage::Mode => panic!("{} is a batch stage", self),
+        }
+    }
+
+    /// Build a batch stage
+    ///
+    /// # Panics
+    /// For a per-reading stage
+    pub fn build_batch(self) -> Box<dyn BatchFilter> {
+        match self {
+            Stage::Mean => Box::new(Mean),
+            Stage::TrimmedMean => Box::new(TrimmedMean),
+            Stage::Mode => Box::new(Mode),
+            Stage::Clamp | Stage::Hampel | Stage::Ema | Stage::Kalman | Stage::Median | Stage::Trend => {
+                panic!("{} is a per-reading stage", self)
+            }
+        }
+    }
+
+    /// Check the parameters this stage uses
+    pub fn validate(self, config: &FilterConfig) -> Result<(), String> {
+        match self {
+            Stage::Clamp if config.min_distance <= 0.0 && config.max_distance <= 0.0 => {
+                return Err("the clamp stage needs min-distance or max-distance".to_string());
+            }
+            Stage::Hampel => {
+                if !config.despike_threshold.is_finite() || config.despike_threshold <= 0.0 {
+                    return Err(format!(
+                        "the hampel stage needs a positive despike-threshold, got {}",
+                        config.despike_threshold
+                    ));
+                }
+                if config.despike_window < 3 {
+                    return Err(format!("despike-window must be at least 3, got {}", config.despike_window));
+                }
+            }
+            Stage::Ema if config.adaptive_noise != 0.0 => {
+                if !config.adaptive_noise.is_finite() || config.adaptive_noise < 0.0 {
+                    return Err(format!("filter-adaptive-noise must not be negative, got {}", config.adaptive_noise));
+                }
+                if !(0.0..=config.alpha).contains(&config.alpha_min) {
+                    return Err(format!(
+                        "filter-alpha-min must be between 0.0 and filter-alpha ({}), got {}",
+                        config.alpha, config.alpha_min
+                    ));
+                }
+            }
+            Stage::Kalman => {
+                if !config.process_noise.is_finite() || config.process_noise < 0.0 {
+                    return Err(format!("kalman-process-noise must not be negative, got {}", config.process_noise));
+                }
+                if !config.measurement_noise.is_finite() || config.measurement_noise <= 0.0 {
+                    return Err(format!(
+                        "kalman-measurement-noise must be positive, got {}",
+                        config.measurement_noise
+                    ));
+                }
+            }
+            Stage::Median if config.median_window == 0 => {
+                return Err("median-window must be at least 1".to_string());
+            }
+            Stage::Trend if config.trend_window < 3 => {
+                return Err(format!("trend-window must be at least 3, got {}", config.trend_window));
+            }
+            Stage::Mode if !(config.mode_bin_width.is_finite() && config.mode_bin_width > 0.0) => {
+                return Err(format!("mode-bin-width must be positive, got {}", config.mode_bin_width));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// The stage with the parameters it uses, for logging
+    pub fn describe(self, config: &FilterConfig) -> String {
+        match self {
+            Stage::Clamp => match (config.min_distance > 0.0, config.max_distance > 0.0) {
+                (true, true) => format!(
+                    "clamp: discard readings outside {}-{} mm",
+                    config.min_distance, config.max_distance
+                ),
+                (true, false) => format!("clamp: discard readings below {} mm", config.min_distance),
+                _ => format!("clamp: discard readings above {} mm", config.max_distance),
+            },
+            Stage::Hampel => format!(
+                "hampel: discard readings over {} MADs from the median of {} readings",
+                config.despike_threshold, config.despike_window
+            ),
+            Stage::Ema => {
+                let mut description = format!(
+                    "ema: initialization period {} readings, rate limit {} mm/reading, alpha {}",
+                    config.init_period, config.rate_limit, config.alpha
+                );
+                if config.adaptive_noise > 0.0 {
+                    description += &format!(
+                        ", adapting down to {} as readings spread past {} mm std dev",
+                        config.alpha_min, config.adaptive_noise
+                    );
+                }
+                description
+            }
+            Stage::Kalman => format!(
+                "kalman: initialization period {} readings, process noise {} mm²/reading⁴, measurement noise {} mm²",
+                config.init_period, config.process_noise, config.measurement_noise
+            ),
+            Stage::Median => format!("median: window {} readings", config.median_window),
+            Stage::Trend => format!("trend: Theil–Sen line through the last {} readings", config.trend_window),
+            Stage::Mean => format!("mean: {}", batches(config)),
+            Stage::TrimmedMean => format!(
+                "trimmed-mean: {}, {}% trimmed from each end",
+                batches(config),
+                config.trim_percentage * 100.0
+            ),
+            Stage::Mode => format!("mode: {} in {} mm bins", batches(config), config.mode_bin_width),
+        }
+    }
+}
+
+/// How the batch stage's input is collected, for logging
+fn batches(config: &FilterConfig) -> String {
+    if config.is_sliding() {
+        format!("the last {} readings every {} readings", config.batch_size, config.step())
+    } else {
+        format!("batches of {} readings", config.batch_size)
+    }
+}
+
+/// Per-reading stages in order, then the batch stage
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterPipeline {
+    pub stages: Vec<Stage>,
+    pub batch: Stage,
+}
+
+impl FilterPipeline {
+    /// The stages of a filter type
+    pub fn for_type(filter_type: FilterType) -> Self {
+        let mut stages = Vec::new();
+        match filter_type {
+            FilterType::Exponential | FilterType::Both => stages.push(Stage::Ema),
+            FilterType::Kalman => stages.push(Stage::Kalman),
+            FilterType::Median => stages.push(Stage::Median),
+            FilterType::Trend => stages.push(Stage::Trend),
+            FilterType::None | FilterType::TrimmedMean | FilterType::Mode => {}
+        }
+        let batch = match filter_type {
+            FilterType::TrimmedMean | FilterType::Both => Stage::TrimmedMean,
+            FilterType::Mode => Stage::Mode,
+            _ => Stage::Mean,
+        };
+        Self { stages, batch }
+    }
+
+    pub fn contains(&self, stage: Stage) -> bool {
+        self.batch == stage || self.stages.contains(&stage)
+    }
+
+    /// Every stage, the batch stage last
+    pub fn iter(&self) -> impl Iterator<Item = Stage> + '_ {
+        self.stages.iter().copied().chain(std::iter::once(self.batch))
+    }
+}
+
+impl std::str::FromStr for FilterPipeline {
+    type Err = String;
+
+    /// Parse stage names separated by `->` or commas; without a batch stage
+    /// at the end, batches are averaged
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut stages: Vec<Stage> = s
+            .split(',')
+            .flat_map(|part| part.split("->"))
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()?;
+        if stages.is_empty() {
+            return Err("filter pipeline has no stages".to_string());
+        }
+        let batch = match stages.last() {
+            Some(&last) if last.is_batch() => {
+                stages.pop();
+                last
+            }
+            _ => Stage::Mean,
+        };
+        if let Some(stage) = stages.iter().find(|s| s.is_batch()) {
+            return Err(format!("batch stage {} must come last in the filter pipeline", stage));
+        }
+        Ok(Self { stages, batch })
+    }
+}
+
+impl std::fmt::Display for FilterPipeline {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<String> = self.iter().map(|stage| stage.to_string()).collect();
+        write!(f, "{}", names.join(" -> "))
+    }
+}
+
+/// Passes readings within [min, max] mm; a bound of 0 is left open
+#[derive(Debug, Clone)]
+pub struct RangeClamp {
+    min: f64,
+    max: f64,
+    reading_count: usize,
+}
+
+impl RangeClamp {
+    pub fn new(min: f64, max: f64) -> Self {
+        Self { min, max, reading_count: 0 }
+    }
+
+    fn contains(&self, reading: f64) -> bool {
+        (self.min <= 0.0 || reading >= self.min) && (self.max <= 0.0 || reading <= self.max)
+    }
+}
+
+impl Filter for RangeClamp {
+    fn update(&mut self, reading: f64) -> Option<f64> {
+        self.reading_count += 1;
+        self.contains(reading).then_some(reading)
+    }
+
+    fn set_params(&mut self, config: &FilterConfig) {
+        self.min = config.min_distance;
+        self.max = config.max_distance;
+    }
+
+    fn reset(&mut self) {
+        self.reading_count = 0;
+    }
+
+    fn reading_count(&self) -> usize {
+        self.reading_count
+    }
+}
+
+impl Filter for SensorFilter {
+    fn update(&mut self, reading: f64) -> Option<f64> {
+        Some(SensorFilter::update(self, reading))
+    }
+
+    fn set_params(&mut self, config: &FilterConfig) {
+        SensorFilter::set_params(self, config.init_period, config.rate_limit, config.alpha);
+        self.set_adaptive(config.adaptive_noise, config.alpha_min);
+    }
+
+    fn reset(&mut self) {
+        SensorFilter::reset(self)
+    }
+
+    fn is_initialized(&self) -> bool {
+        SensorFilter::is_initialized(self)
+    }
+
+    fn reading_count(&self) -> usize {
+        SensorFilter::reading_count(self)
+    }
+
+    fn was_rate_limited(&self) -> bool {
+        SensorFilter::was_rate_limited(self)
+    }
+}
+
+impl Filter for KalmanFilter {
+    fn update(&mut self, reading: f64) -> Option<f64> {
+        Some(KalmanFilter::update(self, reading))
+    }
+
+    fn set_params(&mut self, config: &FilterConfig) {
+        KalmanFilter::set_params(self, config.init_period, config.process_noise, config.measurement_noise);
+    }
+
+    fn reset(&mut self) {
+        KalmanFilter::reset(self)
+    }
+
+    fn is_initialized(&self) -> bool {
+        KalmanFilter::is_initialized(self)
+    }
+
+    fn reading_count(&self) -> usize {
+        KalmanFilter::reading_count(self)
+    }
+}
+
+impl Filter for MedianFilter {
+    fn update(&mut self, reading: f64) -> Option<f64> {
+        Some(MedianFilter::update(self, reading))
+    }
+
+    fn set_params(&mut self, config: &FilterConfig) {
+        self.set_window(config.median_window);
+    }
+
+    fn reset(&mut self) {
+        MedianFilter::reset(self)
+    }
+
+    fn is_initialized(&self) -> bool {
+        MedianFilter::is_initialized(self)
+    }
+
+    fn reading_count(&self) -> usize {
+        MedianFilter::reading_count(self)
+    }
+}
+
+impl Filter for TrendFilter {
+    fn update(&mut self, reading: f64) -> Option<f64> {
+        Some(TrendFilter::update(self, reading))
+    }
+
+    fn set_params(&mut self, config: &FilterConfig) {
+        self.set_window(config.trend_window);
+    }
+
+    fn reset(&mut self) {
+        TrendFilter::reset(self)
+    }
+
+    fn is_initialized(&self) -> bool {
+        TrendFilter::is_initialized(self)
+    }
+
+    fn reading_count(&self) -> usize {
+        TrendFilter::reading_count(self)
+    }
+}
+
+impl Filter for Despiker {
+    fn update(&mut self, reading: f64) -> Option<f64> {
+        self.accept(reading).then_some(reading)
+    }
+
+    fn set_params(&mut self, config: &FilterConfig) {
+        Despiker::set_params(self, config.despike_window, config.despike_threshold);
+    }
+
+    fn reset(&mut self) {
+        Despiker::reset(self)
+    }
+
+    fn reading_count(&self) -> usize {
+        Despiker::reading_count(self)
+    }
+}
+
+struct Mean;
+
+impl BatchFilter for Mean {
+    fn aggregate(&self, batch: &mut [f64], _config: &FilterConfig) -> BatchResult {
+        pipeline::mean(batch)
+    }
+}
+
+struct TrimmedMean;
+
+impl BatchFilter for TrimmedMean {
+    fn aggregate(&self, batch: &mut [f64], config: &FilterConfig) -> BatchResult {
+        pipeline::trimmed_mean(batch, config.trim_percentage)
+    }
+}
+
+struct Mode;
+
+impl BatchFilter for Mode {
+    fn aggregate(&self, batch: &mut [f64], config: &FilterConfig) -> BatchResult {
+        pipeline::modal_bin(batch, config.mode_bin_width)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pipeline() {
+        let pipeline: FilterPipeline = "hampel -> ema -> trimmed-mean".parse().unwrap();
+        assert_eq!(pipeline.stages, [Stage::Hampel, Stage::Ema]);
+        assert_eq!(pipeline.batch, Stage::TrimmedMean);
+        assert_eq!(pipeline.to_string(), "hampel -> ema -> trimmed-mean");
+        assert_eq!(pipeline.to_string().parse(), Ok(pipeline));
+
+        // Commas work too, and batches are averaged by default
+        let pipeline: FilterPipeline = "median,kalman".parse().unwrap();
+        assert_eq!(pipeline, FilterPipeline { stages: vec![Stage::Median, Stage::Kalman], batch: Stage::Mean });
+        assert_eq!("mode".parse(), Ok(FilterPipeline { stages: vec![], batch: Stage::Mode }));
+
+        assert!("".parse::<FilterPipeline>().is_err());
+        assert!("ema -> wavelet".parse::<FilterPipeline>().is_err());
+        assert!("trimmed-mean -> ema".parse::<FilterPipeline>().is_err());
+    }
+
+    #[test]
+    fn test_filter_types() {
+        let both = FilterPipeline::for_type(FilterType::Both);
+        assert_eq!(both.to_string(), "ema -> trimmed-mean");
+        assert_eq!(FilterPipeline::for_type(FilterType::None).to_string(), "mean");
+        assert_eq!(FilterPipeline::for_type(FilterType::Kalman).to_string(), "kalman -> mean");
+        assert!(both.contains(Stage::Ema) && both.contains(Stage::TrimmedMean));
+        assert!(!both.contains(Stage::Mean));
+    }
+
+    #[test]
+    fn test_validate_hampel() {
+        let config = FilterConfig::default();
+        assert!(Stage::Hampel.validate(&config).is_err());
+        let config = FilterConfig { despike_threshold: 3.0, ..config };
+        assert!(Stage::Hampel.validate(&config).is_ok());
+        assert!(Stage::Ema.validate(&config).is_ok());
+    }
+
+    #[test]
+    fn test_range_clamp() {
+        let mut clamp = RangeClamp::new(500.0, 5000.0);
+        let passed: Vec<Option<f64>> = [499.0, 500.0, 1800.0, 5000.0, 5001.0].iter().map(|&r| clamp.update(r)).collect();
+        assert_eq!(passed, [None, Some(500.0), Some(1800.0), Some(5000.0), None]);
+        assert_eq!(clamp.reading_count(), 5);
+
+        // An open bound passes everything on that side
+        clamp.set_params(&FilterConfig { min_distance: 0.0, max_distance: 5000.0, ..FilterConfig::default() });
+        assert_eq!(clamp.update(10.0), Some(10.0));
+        assert_eq!(clamp.update(6000.0), None);
+
+        let config = FilterConfig::default();
+        assert!(Stage::Clamp.validate(&config).is_err());
+        assert!(Stage::Clamp.validate(&FilterConfig { max_distance: 5000.0, ..config }).is_ok());
+    }
+}
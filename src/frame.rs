@@ -0,0 +1,601 @@
+/// Sensor frame parsing
+///
+/// MaxBotix serial sensors send each range as `R`, a fixed number of ASCII
+/// digits, and `\r`: three in cm for the XL-MaxSonar, four in mm for the
+/// MB7544 and most of the range, five in mm for the MB7360 and MB7369. Some
+/// serial adapters add a `\n`. The parser takes the byte stream a byte at a
+/// time and hands back each complete frame with the distance it holds in mm
+/// or why it was rejected. After a badly framed frame it resynchronizes at
+/// the next `R`.
+///
+/// Some variants append a checksum byte over the `R` and the digits before
+/// the `\r`; with a checksum configured, a frame whose checksum doesn't match
+/// is rejected rather than trusted.
+///
+/// With the `auto` format, the parser samples the stream until one of the
+/// known formats frames it cleanly, then parses the frames that follow.
+///
+/// Some snow-depth and laser sensors send NMEA-style sentences instead:
+/// `$`, comma-separated fields, `*`, the XOR of the characters between the
+/// `$` and the `*` in two hex digits, and `\r\n`. The distance is taken from
+/// a numeric field, in the unit of the field after it if that is `M`, `CM`,
+/// or `MM`, and in mm otherwise.
+///
+/// Other sensors can be described by a frame spec, a JSON file giving the
+/// delimiter ending each frame, a prefix it must start with, where the
+/// distance is within the rest of it, and the scale to mm.
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Deserialize;
+
+/// Consistent frames needed to detect a format
+const DETECT_FRAMES: usize = 3;
+
+/// Bytes sampled without detecting a format before they are given up on
+const DETECT_BYTES: usize = 256;
+
+/// Longest NMEA sentence, `$` to `\n`
+const MAX_SENTENCE: usize = 82;
+
+/// Longest frame read with a frame spec
+const MAX_FRAME: usize = 256;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameFormat {
+    /// Detected from the frames received
+    Auto,
+    /// `R`, `digits` digits of distance, and `\r`, or `\r\n` with `crlf`;
+    /// three digits are in cm, more in mm
+    Maxbotix { digits: usize, crlf: bool },
+    /// NMEA-style sentence with the distance in field `field`, counting the
+    /// sentence's address as field 0
+    Nmea { field: usize },
+    /// Described by a frame spec
+    Custom(FrameSpec),
+}
+
+impl std::str::FromStr for FrameFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_lowercase();
+        let (name, crlf) = match s.strip_suffix("-crlf") {
+            Some(name) => (name, true),
+            None => (s.as_str(), false),
+        };
+        match name {
+            "auto" if !crlf => Ok(FrameFormat::Auto),
+            "r3" => Ok(FrameFormat::Maxbotix { digits: 3, crlf }),
+            "r4" | "maxbotix" => Ok(FrameFormat::Maxbotix { digits: 4, crlf }),
+            "r5" => Ok(FrameFormat::Maxbotix { digits: 5, crlf }),
+            "nmea" if !crlf => Ok(FrameFormat::Nmea { field: 1 }),
+            _ => match name.strip_prefix("nmea:").map(str::parse) {
+                Some(Ok(field)) if !crlf && field > 0 => Ok(FrameFormat::Nmea { field }),
+                _ => Err(format!(
+                    "Invalid frame format '{}'. Valid options: auto, r3, r4, r5, r3-crlf, r4-crlf, r5-crlf, nmea, or nmea:FIELD",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for FrameFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameFormat::Auto => write!(f, "auto"),
+            FrameFormat::Maxbotix { digits, crlf } => write!(f, "r{}{}", digits, if *crlf { "-crlf" } else { "" }),
+            FrameFormat::Nmea { field: 1 } => write!(f, "nmea"),
+            FrameFormat::Nmea { field } => write!(f, "nmea:{}", field),
+            FrameFormat::Custom(_) => write!(f, "custom"),
+        }
+    }
+}
+
+/// A sensor's frames, as described in a frame spec file
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct FrameSpec {
+    /// Ends each frame, e.g. `"\r\n"`
+    pub delimiter: String,
+    /// Each frame starts with this; the offset counts from after it
+    #[serde(default)]
+    pub prefix: String,
+    /// Bytes from the end of the prefix to the distance
+    #[serde(default)]
+    pub offset: usize,
+    /// Bytes of distance; the rest of the frame if unset
+    #[serde(default)]
+    pub length: Option<usize>,
+    /// Multiplies the number read into mm, e.g. 10 for cm
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+impl FrameSpec {
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let spec: FrameSpec = serde_json::from_str(json).map_err(|e| format!("invalid frame spec: {}", e))?;
+        if spec.delimiter.is_empty() {
+            return Err("invalid frame spec: delimiter must not be empty".to_string());
+        }
+        if spec.length == Some(0) {
+            return Err("invalid frame spec: length must be at least 1".to_string());
+        }
+        if !spec.scale.is_finite() || spec.scale == 0.0 {
+            return Err(format!("invalid frame spec: scale must be a non-zero number, got {}", spec.scale));
+        }
+        Ok(spec)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let json = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read frame spec {}: {}", path.display(), e))?;
+        Self::from_json(&json).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Distance in mm from a frame, the delimiter removed
+    fn parse(&self, frame: &[u8], checksum: Checksum) -> Result<f64, FrameError> {
+        let body = frame.strip_prefix(self.prefix.as_bytes()).ok_or(FrameError::Framing)?;
+        if checksum != Checksum::None {
+            let (&received, _) = frame.split_last().ok_or(FrameError::Framing)?;
+            let expected = checksum.compute(&frame[..frame.len() - 1]);
+            if expected != received {
+                return Err(FrameError::Checksum { expected: expected.into(), received: received.into() });
+            }
+        }
+        let body = &body[..body.len().saturating_sub(checksum.len())];
+        let end = self.length.map_or(body.len(), |length| self.offset + length);
+        let value = body.get(self.offset..end).ok_or(FrameError::Framing)?;
+        let value = String::from_utf8_lossy(value);
+        value
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|value| value.is_finite())
+            .map(|value| value * self.scale)
+            .ok_or_else(|| FrameError::NoDistance(value.into_owned()))
+    }
+}
+
+/// Checksum byte appended to each frame
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Checksum {
+    #[default]
+    None,
+    /// XOR of the bytes before it
+    Xor,
+    /// Sum of the bytes before it, modulo 256
+    Sum,
+}
+
+impl std::str::FromStr for Checksum {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Checksum::None),
+            "xor" => Ok(Checksum::Xor),
+            "sum" => Ok(Checksum::Sum),
+            _ => Err(format!("Invalid frame checksum '{}'. Valid options: none, xor, sum", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Checksum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Checksum::None => write!(f, "none"),
+            Checksum::Xor => write!(f, "xor"),
+            Checksum::Sum => write!(f, "sum"),
+        }
+    }
+}
+
+impl Checksum {
+    fn len(&self) -> usize {
+        if *self == Checksum::None { 0 } else { 1 }
+    }
+
+    fn compute(&self, bytes: &[u8]) -> u8 {
+        match self {
+            Checksum::None => 0,
+            Checksum::Xor => bytes.iter().fold(0, |acc, b| acc ^ b),
+            Checksum::Sum => bytes.iter().fold(0, |acc: u8, &b| acc.wrapping_add(b)),
+        }
+    }
+}
+
+impl FrameFormat {
+    fn frame_len(&self, checksum: Checksum) -> usize {
+        match self {
+            // Nothing is framed until a format is detected
+            FrameFormat::Auto => 0,
+            FrameFormat::Maxbotix { digits, crlf } => digits + if *crlf { 3 } else { 2 } + checksum.len(),
+            // Sentences vary in length up to this
+            FrameFormat::Nmea { .. } => MAX_SENTENCE,
+            FrameFormat::Custom(_) => MAX_FRAME,
+        }
+    }
+
+    /// Unit the distances are sent in
+    pub fn unit(&self) -> &'static str {
+        match self {
+            FrameFormat::Maxbotix { digits: 3, .. } => "cm",
+            _ => "mm",
+        }
+    }
+
+    /// The known format that frames `sample` best, cleanly enough and ending
+    /// on the end of a frame
+    fn detect(sample: &[u8], checksum: Checksum) -> Option<FrameFormat> {
+        let mut best = None;
+        let mut best_score = 0;
+        for digits in 3..=5 {
+            for crlf in [false, true] {
+                let format = FrameFormat::Maxbotix { digits, crlf };
+                let mut parser = FrameParser::new(format.clone(), checksum);
+                let frames: Vec<Frame> = sample.iter().filter_map(|&b| parser.push(b)).collect();
+                // Checksum failures are framed correctly, and left to be counted
+                let framed = frames.iter().filter(|f| f.distance != Err(FrameError::Framing)).count();
+                if framed < DETECT_FRAMES || !parser.buf.is_empty() {
+                    continue;
+                }
+                // A format one byte short of the real one resynchronizes onto
+                // every other frame; each misframed frame counts against it
+                let score = framed as i64 - (frames.len() - framed) as i64;
+                if score > best_score {
+                    best = Some(format);
+                    best_score = score;
+                }
+            }
+        }
+        best
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameError {
+    /// Not a frame of the configured format; the parser resynchronizes
+    Framing,
+    /// No known format frames the bytes sampled
+    Undetected,
+    /// No number where the distance should be, with what was there instead
+    NoDistance(String),
+    Checksum { expected: u16, received: u16 },
+    /// The sensor reported an error or didn't answer
+    Device(String),
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::Framing => write!(f, "invalid framing, resynchronizing"),
+            FrameError::Undetected => write!(f, "no known frame format detected"),
+            FrameError::NoDistance(value) => write!(f, "no distance in {:?}", value),
+            FrameError::Device(message) => write!(f, "{}", message),
+            FrameError::Checksum { expected, received } => {
+                write!(f, "checksum mismatch: expected 0x{:02x}, received 0x{:02x}", expected, received)
+            }
+        }
+    }
+}
+
+/// A complete frame as received
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub bytes: Vec<u8>,
+    /// Distance in mm, or why the frame was rejected
+    pub distance: Result<f64, FrameError>,
+}
+
+/// Frames read from the sensor since startup, and how many were rejected;
+/// shared with GetStationInfo
+#[derive(Debug, Default)]
+pub struct FrameCounters {
+    frames: AtomicU64,
+    invalid_framing: AtomicU64,
+    checksum_failures: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FrameCounts {
+    pub frames: u64,
+    pub invalid_framing: u64,
+    pub checksum_failures: u64,
+}
+
+impl FrameCounters {
+    pub fn record(&self, frame: &Frame) {
+        self.frames.fetch_add(1, Ordering::Relaxed);
+        match frame.distance {
+            Ok(_) | Err(FrameError::Device(_)) => {}
+            Err(FrameError::Framing | FrameError::Undetected | FrameError::NoDistance(_)) => {
+                self.invalid_framing.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(FrameError::Checksum { .. }) => {
+                self.checksum_failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn counts(&self) -> FrameCounts {
+        FrameCounts {
+            frames: self.frames.load(Ordering::Relaxed),
+            invalid_framing: self.invalid_framing.load(Ordering::Relaxed),
+            checksum_failures: self.checksum_failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub struct FrameParser {
+    format: FrameFormat,
+    checksum: Checksum,
+    buf: Vec<u8>,
+}
+
+impl FrameParser {
+    pub fn new(format: FrameFormat, checksum: Checksum) -> Self {
+        let capacity = format.frame_len(checksum);
+        Self { format, checksum, buf: Vec::with_capacity(capacity) }
+    }
+
+    /// Format being parsed, `Auto` until one is detected
+    pub fn format(&self) -> &FrameFormat {
+        &self.format
+    }
+
+    /// Drop a partial frame, e.g. after the sensor was switched on or off
+    pub fn reset(&mut self) {
+        self.buf.clear();
+    }
+
+    /// Take the next byte, returning the frame it completes
+    pub fn push(&mut self, byte: u8) -> Option<Frame> {
+        self.buf.push(byte);
+        let (digits, crlf) = match self.format {
+            FrameFormat::Auto => return self.detect(),
+            FrameFormat::Nmea { field } => return self.push_nmea(field),
+            FrameFormat::Custom(_) => return self.push_custom(),
+            FrameFormat::Maxbotix { digits, crlf } => (digits, crlf),
+        };
+        if self.buf.len() < self.format.frame_len(self.checksum) {
+            return None;
+        }
+        let terminator: &[u8] = if crlf { b"\r\n" } else { b"\r" };
+        let scale = if digits == 3 { 10.0 } else { 1.0 };
+        let digits = &self.buf[1..=digits];
+        if self.buf[0] == b'R' && self.buf.ends_with(terminator) && digits.iter().all(u8::is_ascii_digit) {
+            let end = self.buf.len() - terminator.len() - self.checksum.len();
+            let (expected, received) = (self.checksum.compute(&self.buf[..end]), self.buf[end]);
+            let distance = if self.checksum != Checksum::None && received != expected {
+                Err(FrameError::Checksum { expected: expected.into(), received: received.into() })
+            } else {
+                // All ASCII digits, so it parses
+                Ok(std::str::from_utf8(digits).unwrap().parse::<f64>().unwrap() * scale)
+            };
+            // Framed correctly either way, so the next frame starts afresh
+            return Some(Frame { bytes: std::mem::take(&mut self.buf), distance });
+        }
+
+        let frame = Frame { bytes: self.buf.clone(), distance: Err(FrameError::Framing) };
+        // Keep from the next 'R', past the first byte so a frame starting
+        // with 'R' moves on
+        match self.buf.iter().skip(1).position(|&b| b == b'R') {
+            Some(pos) => {
+                self.buf.drain(..=pos);
+            }
+            None => self.buf.clear(),
+        }
+        Some(frame)
+    }
+
+    /// Take the byte just buffered as part of an NMEA sentence; anything
+    /// before a `$` is handed back as badly framed
+    fn push_nmea(&mut self, field: usize) -> Option<Frame> {
+        let len = self.buf.len();
+        if self.buf[len - 1] == b'$' && len > 1 {
+            let junk = self.buf.drain(..len - 1).collect();
+            return Some(Frame { bytes: junk, distance: Err(FrameError::Framing) });
+        }
+        if self.buf[len - 1] == b'\n' {
+            let bytes = std::mem::take(&mut self.buf);
+            let distance = parse_sentence(&bytes, field);
+            return Some(Frame { bytes, distance });
+        }
+        if len > MAX_SENTENCE {
+            return Some(Frame { bytes: std::mem::take(&mut self.buf), distance: Err(FrameError::Framing) });
+        }
+        None
+    }
+
+    /// Take the byte just buffered as part of a frame described by the spec
+    fn push_custom(&mut self) -> Option<Frame> {
+        let FrameFormat::Custom(ref spec) = self.format else { return None };
+        if let Some(frame) = self.buf.strip_suffix(spec.delimiter.as_bytes()) {
+            let distance = spec.parse(frame, self.checksum);
+            return Some(Frame { bytes: std::mem::take(&mut self.buf), distance });
+        }
+        if self.buf.len() >= MAX_FRAME {
+            return Some(Frame { bytes: std::mem::take(&mut self.buf), distance: Err(FrameError::Framing) });
+        }
+        None
+    }
+
+    /// Sample the stream for a known format, switching to it once detected;
+    /// the frames sampled are dropped, and a sample without a format is
+    /// handed back as a frame so it shows in the raw frames
+    fn detect(&mut self) -> Option<Frame> {
+        if matches!(self.buf.last(), Some(b'\r' | b'\n')) {
+            if let Some(format) = FrameFormat::detect(&self.buf, self.checksum) {
+                self.format = format;
+                self.buf.clear();
+                return None;
+            }
+        }
+        if self.buf.len() < DETECT_BYTES {
+            return None;
+        }
+        Some(Frame { bytes: std::mem::take(&mut self.buf), distance: Err(FrameError::Undetected) })
+    }
+}
+
+/// Distance in mm from a complete NMEA sentence
+fn parse_sentence(bytes: &[u8], field: usize) -> Result<f64, FrameError> {
+    let sentence = std::str::from_utf8(bytes).map_err(|_| FrameError::Framing)?;
+    let sentence = sentence.strip_prefix('$').ok_or(FrameError::Framing)?.trim_end_matches(['\r', '\n']);
+    let (body, checksum) = sentence.split_once('*').ok_or(FrameError::Framing)?;
+    let received = match checksum.len() {
+        2 => u8::from_str_radix(checksum, 16).map_err(|_| FrameError::Framing)?,
+        _ => return Err(FrameError::Framing),
+    };
+    let expected = Checksum::Xor.compute(body.as_bytes());
+    if expected != received {
+        return Err(FrameError::Checksum { expected: expected.into(), received: received.into() });
+    }
+
+    let mut fields = body.split(',').skip(field);
+    let value = fields.next().unwrap_or_default();
+    let distance: f64 = value
+        .trim()
+        .parse()
+        .ok()
+        .filter(|value: &f64| value.is_finite())
+        .ok_or_else(|| FrameError::NoDistance(value.to_string()))?;
+    let scale = match fields.next().map(|unit| unit.trim().to_uppercase()).as_deref() {
+        Some("M") => 1000.0,
+        Some("CM") => 10.0,
+        _ => 1.0,
+    };
+    Ok(distance * scale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(format: FrameFormat, bytes: &[u8]) -> Vec<Frame> {
+        parse_checked(format, Checksum::None, bytes)
+    }
+
+    fn parse_checked(format: FrameFormat, checksum: Checksum, bytes: &[u8]) -> Vec<Frame> {
+        let mut parser = FrameParser::new(format, checksum);
+        bytes.iter().filter_map(|&b| parser.push(b)).collect()
+    }
+
+    #[test]
+    fn test_maxbotix_frames() {
+        let four = "r4".parse().unwrap();
+        let frames = parse(four, b"R1834\rR0500\r");
+        assert_eq!(frames.iter().map(|f| f.distance.clone()).collect::<Vec<_>>(), vec![Ok(1834.0), Ok(500.0)]);
+        assert_eq!(frames[0].bytes, b"R1834\r");
+
+        let five: FrameFormat = "r5".parse().unwrap();
+        assert_eq!(parse(five.clone(), b"R04821\r")[0].distance, Ok(4821.0));
+        assert_eq!(five.to_string(), "r5");
+        assert!("r6".parse::<FrameFormat>().is_err());
+    }
+
+    #[test]
+    fn test_resynchronizes() {
+        let frames = parse(FrameFormat::Maxbotix { digits: 4, crlf: false }, b"34\rR1834\rR12x4\rR1000\r");
+        let distances: Vec<_> = frames.iter().map(|f| f.distance.is_ok()).collect();
+        // The partial frame, then realigned at its 'R'; the bad digits skipped to the next 'R'
+        assert_eq!(frames[0].bytes, b"34\rR18");
+        assert_eq!(distances, [false, true, false, true]);
+        assert_eq!(frames[3].distance, Ok(1000.0));
+    }
+
+    #[test]
+    fn test_checksum() {
+        let four = FrameFormat::Maxbotix { digits: 4, crlf: false };
+        // XOR of "R1834" is 0x5c, its sum modulo 256 is 0x22
+        let frames = parse_checked(four.clone(), Checksum::Xor, b"R1834\x5c\rR1834\x5d\rR0500\x57\r");
+        assert_eq!(frames[0].distance, Ok(1834.0));
+        assert_eq!(frames[1].distance, Err(FrameError::Checksum { expected: 0x5c, received: 0x5d }));
+        // A checksum failure is framed correctly, so the next frame follows on
+        assert_eq!(frames[2].distance, Ok(500.0));
+        assert_eq!(parse_checked(four.clone(), Checksum::Sum, b"R1834\x22\r")[0].distance, Ok(1834.0));
+        assert_eq!("XOR".parse(), Ok(Checksum::Xor));
+        assert!("crc16".parse::<Checksum>().is_err());
+
+        let counters = FrameCounters::default();
+        frames.iter().for_each(|frame| counters.record(frame));
+        counters.record(&parse(four, b"R12x4\r")[0]);
+        assert_eq!(counters.counts(), FrameCounts { frames: 4, invalid_framing: 1, checksum_failures: 1 });
+    }
+
+    #[test]
+    fn test_detects_format() {
+        // XL-MaxSonar frames are in cm
+        let mut parser = FrameParser::new(FrameFormat::Auto, Checksum::None);
+        let frames: Vec<Frame> = b"3\rR123\rR124\rR125\rR130\r".iter().filter_map(|&b| parser.push(b)).collect();
+        assert_eq!(parser.format(), &FrameFormat::Maxbotix { digits: 3, crlf: false });
+        assert_eq!(parser.format().unit(), "cm");
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].distance, Ok(1300.0));
+
+        // Not taken for 4-digit frames resynchronizing at every other 'R'
+        let crlf = b"34\r\nR1834\r\nR1835\r\nR1836\r\n";
+        assert_eq!(FrameFormat::detect(crlf, Checksum::None), Some("r4-crlf".parse().unwrap()));
+        assert_eq!(FrameFormat::detect(b"R04821\rR04822\rR04823\r", Checksum::None), Some("r5".parse().unwrap()));
+        // Too few frames, or not ending on one
+        assert_eq!(FrameFormat::detect(b"R1834\rR1835\r", Checksum::None), None);
+        assert_eq!(FrameFormat::detect(b"R1834\rR1835\rR1836\rR18\r", Checksum::None), None);
+        assert_eq!(FrameFormat::Maxbotix { digits: 5, crlf: true }.to_string(), "r5-crlf");
+        assert!("auto-crlf".parse::<FrameFormat>().is_err());
+
+        let mut parser = FrameParser::new(FrameFormat::Auto, Checksum::None);
+        let noise: Vec<Frame> = (0..DETECT_BYTES).filter_map(|_| parser.push(b'x')).collect();
+        assert_eq!(noise.len(), 1);
+        assert_eq!(noise[0].distance, Err(FrameError::Undetected));
+        assert_eq!(parser.format(), &FrameFormat::Auto);
+    }
+
+    #[test]
+    fn test_nmea_sentences() {
+        let nmea = "nmea".parse().unwrap();
+        // XOR of "SDDPT,1.834,M" is 0x3a
+        let frames = parse(nmea, b"M*00\r\n$SDDPT,1.834,M*3A\r\n$SDDPT,1.834,M*3B\r\n$SDDPT,x,M*62\r\n");
+        assert_eq!(frames[0].distance, Err(FrameError::Framing));
+        assert_eq!(frames[1].distance, Ok(1834.0));
+        assert_eq!(frames[1].bytes, b"$SDDPT,1.834,M*3A\r\n");
+        assert_eq!(frames[2].distance, Err(FrameError::Checksum { expected: 0x3a, received: 0x3b }));
+        assert_eq!(frames[3].distance, Err(FrameError::NoDistance("x".to_string())));
+
+        assert_eq!(parse_sentence(b"$XX,1,1834*3F\r\n", 2), Ok(1834.0));
+        assert_eq!(parse_sentence(b"$XX,1,183.4,CM*33\n", 2), Ok(1834.0));
+        // Without a checksum
+        assert_eq!(parse_sentence(b"$SDDPT,1.834,M\r\n", 1), Err(FrameError::Framing));
+        assert_eq!("nmea:2".parse(), Ok(FrameFormat::Nmea { field: 2 }));
+        assert_eq!(FrameFormat::Nmea { field: 2 }.to_string(), "nmea:2");
+        assert!("nmea:0".parse::<FrameFormat>().is_err());
+    }
+
+    #[test]
+    fn test_frame_spec() {
+        let spec = FrameSpec::from_json(r#"{"delimiter": "\r\n", "prefix": "D=", "offset": 2, "length": 5, "scale": 10}"#).unwrap();
+        let frames = parse(FrameFormat::Custom(spec), b"D=+ 183.4cm\r\nX=+ 183.4cm\r\nD=+ ab.4cm\r\nD=+\r\n");
+        assert_eq!(frames[0].distance, Ok(1834.0));
+        assert_eq!(frames[0].bytes, b"D=+ 183.4cm\r\n");
+        assert_eq!(frames[1].distance, Err(FrameError::Framing));
+        assert_eq!(frames[2].distance, Err(FrameError::NoDistance("ab.4c".to_string())));
+        // Too short to hold the distance
+        assert_eq!(frames[3].distance, Err(FrameError::Framing));
+
+        // The rest of the frame, checksummed
+        let spec = FrameSpec::from_json(r#"{"delimiter": "\n"}"#).unwrap();
+        assert_eq!(spec.scale, 1.0);
+        let frames = parse_checked(FrameFormat::Custom(spec), Checksum::Sum, b"1834\xd0\n1834\x00\n");
+        assert_eq!(frames[0].distance, Ok(1834.0));
+        assert_eq!(frames[1].distance, Err(FrameError::Checksum { expected: 0xd0, received: 0x00 }));
+
+        assert!(FrameSpec::from_json(r#"{"delimiter": ""}"#).is_err());
+        assert!(FrameSpec::from_json(r#"{"delimiter": "\n", "scale": 0}"#).is_err());
+        assert!(FrameSpec::from_json(r#"{"delimiter": "\n", "field": 2}"#).is_err());
+    }
+}
@@ -0,0 +1,147 @@
+/// Sensor fusion: one best-estimate distance from sensors on the same surface
+///
+/// A station fusing others (`--fuse-with`) combines each of its batch results
+/// with the latest batch results of those stations, weighting each by the
+/// inverse of its variance: the square of its sensor's noise, the standard
+/// deviation of a single reading, multiplied by `QUALITY_PENALTY` for every
+/// quality flag it carries. Results out of range, with the target lost, or
+/// from a stuck sensor are left out, as are those older than the station's
+/// maximum age. A laser and an ultrasonic sensor, say, then each carry the
+/// depth when the other is struggling, and the quieter one counts for more
+/// when both are well.
+use std::time::{Duration, SystemTime};
+
+use crate::quality::Quality;
+
+/// Variance factor for each quality flag on a result
+pub const QUALITY_PENALTY: f64 = 4.0;
+
+/// Flags for which a result is left out of the fusion
+const EXCLUDED: [Quality; 3] = [Quality::OUT_OF_RANGE, Quality::TARGET_LOST, Quality::STUCK_SENSOR];
+
+/// One sensor's latest batch result, as fused
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Estimate {
+    pub time: SystemTime,
+    /// mm, with the station's offset applied
+    pub distance: f64,
+    /// Standard deviation of a single reading in mm
+    pub noise: f64,
+    pub quality: Quality,
+}
+
+impl Estimate {
+    /// None for a result too suspect to use
+    fn variance(&self) -> Option<f64> {
+        if EXCLUDED.iter().any(|&flag| self.quality.contains(flag)) {
+            return None;
+        }
+        Some(self.noise.powi(2) * QUALITY_PENALTY.powi(self.quality.bits().count_ones() as i32))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fused {
+    pub distance: f64,
+    /// The flags of the results used
+    pub quality: Quality,
+    /// Each estimate's share of the distance, 0 for those left out
+    pub weights: Vec<f64>,
+}
+
+/// Fuse `estimates` as of `now`; None when none of them can be used
+pub fn fuse(estimates: &[Estimate], now: SystemTime, max_age: Duration) -> Option<Fused> {
+    let inverse: Vec<f64> = estimates
+        .iter()
+        .map(|estimate| {
+            let fresh = now.duration_since(estimate.time).map_or(true, |age| age <= max_age);
+            estimate.variance().filter(|_| fresh).map_or(0.0, |variance| 1.0 / variance)
+        })
+        .collect();
+    let total: f64 = inverse.iter().sum();
+    if total == 0.0 {
+        return None;
+    }
+
+    let weights: Vec<f64> = inverse.iter().map(|w| w / total).collect();
+    let mut quality = Quality::OK;
+    for (estimate, _) in estimates.iter().zip(&weights).filter(|(_, &w)| w > 0.0) {
+        quality.insert(estimate.quality);
+    }
+    Some(Fused {
+        distance: estimates.iter().zip(&weights).map(|(estimate, w)| estimate.distance * w).sum(),
+        quality,
+        weights,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn estimate(distance: f64, noise: f64, quality: Quality) -> Estimate {
+        Estimate { time: SystemTime::UNIX_EPOCH + Duration::from_secs(1000), distance, noise, quality }
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_inverse_variance() {
+        // A 2mm sensor counts four times as much as a 4mm one
+        let fused = fuse(&[estimate(1000.0, 2.0, Quality::OK), estimate(1050.0, 4.0, Quality::OK)], at(1000), Duration::from_secs(60)).unwrap();
+        assert!((fused.weights[0] - 0.8).abs() < 1e-9);
+        assert!((fused.weights[1] - 0.2).abs() < 1e-9);
+        assert!((fused.distance - 1010.0).abs() < 1e-9);
+        assert!(fused.quality.is_ok());
+
+        // Alone, a result stands as measured
+        let fused = fuse(&[estimate(1000.0, 2.0, Quality::OK)], at(1000), Duration::from_secs(60)).unwrap();
+        assert_eq!(fused.distance, 1000.0);
+        assert_eq!(fused.weights, vec![1.0]);
+    }
+
+    #[test]
+    fn test_quality_weighting() {
+        // High wind on the quieter sensor brings the two level
+        let fused = fuse(
+            &[estimate(1000.0, 2.0, Quality::HIGH_WIND), estimate(1050.0, 4.0, Quality::OK)],
+            at(1000),
+            Duration::from_secs(60),
+        )
+        .unwrap();
+        assert!((fused.weights[0] - 0.5).abs() < 1e-9);
+        assert!((fused.distance - 1025.0).abs() < 1e-9);
+        assert_eq!(fused.quality, Quality::HIGH_WIND);
+
+        // A lost target leaves the other sensor, and its flags, alone
+        let fused = fuse(
+            &[estimate(5000.0, 2.0, Quality::TARGET_LOST), estimate(1050.0, 4.0, Quality::OK)],
+            at(1000),
+            Duration::from_secs(60),
+        )
+        .unwrap();
+        assert_eq!(fused.weights, vec![0.0, 1.0]);
+        assert_eq!(fused.distance, 1050.0);
+        assert!(fused.quality.is_ok());
+
+        let unusable = [estimate(5000.0, 2.0, Quality::TARGET_LOST), estimate(30.0, 4.0, Quality::OUT_OF_RANGE)];
+        assert_eq!(fuse(&unusable, at(1000), Duration::from_secs(60)), None);
+    }
+
+    #[test]
+    fn test_max_age() {
+        let estimates = [estimate(1000.0, 2.0, Quality::OK), estimate(1050.0, 2.0, Quality::OK)];
+        let mut latest = estimates;
+        latest[0].time = at(1060);
+
+        // At the maximum age a result is still used, not past it
+        let fused = fuse(&latest, at(1060), Duration::from_secs(60)).unwrap();
+        assert_eq!(fused.weights, vec![0.5, 0.5]);
+        let fused = fuse(&latest, at(1061), Duration::from_secs(60)).unwrap();
+        assert_eq!(fused.weights, vec![1.0, 0.0]);
+        assert_eq!(fused.distance, 1000.0);
+        assert_eq!(fuse(&estimates, at(1061), Duration::from_secs(60)), None);
+    }
+}
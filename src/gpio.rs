@@ -0,0 +1,186 @@
+/// Pulse-width readings through the Linux GPIO character device
+///
+/// MaxBotix sensors also report each reading on their PW pin as a high
+/// pulse whose width is the distance, 1 µs per mm on the HRXL-MaxSonar.
+/// Wired straight to a Raspberry Pi's GPIO header the line's edges are
+/// watched through the v2 uAPI, which timestamps each edge in the kernel's
+/// interrupt handler, so the width doesn't suffer from the gauge being
+/// scheduled late. Sensors range continuously; each reading waits for the
+/// next whole pulse, so edges queued since the last one are dropped first.
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::frame::{Frame, FrameError};
+use crate::source::Rangefinder;
+
+/// `_IOWR(0xB4, 0x07, struct gpio_v2_line_request)`
+const GPIO_V2_GET_LINE_IOCTL: libc::c_ulong = 0xc250_b407;
+
+const GPIO_V2_LINE_FLAG_INPUT: u64 = 1 << 2;
+const GPIO_V2_LINE_FLAG_EDGE_RISING: u64 = 1 << 4;
+const GPIO_V2_LINE_FLAG_EDGE_FALLING: u64 = 1 << 5;
+
+const GPIO_V2_LINE_EVENT_RISING_EDGE: u32 = 1;
+const GPIO_V2_LINE_EVENT_FALLING_EDGE: u32 = 2;
+
+/// Size of `struct gpio_v2_line_event`
+const EVENT_SIZE: usize = 48;
+
+/// How long to wait for a whole pulse; sensors range several times a second
+const PULSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// `struct gpio_v2_line_config`, without attributes
+#[repr(C)]
+struct LineConfig {
+    flags: u64,
+    num_attrs: u32,
+    padding: [u32; 5],
+    attrs: [[u64; 3]; 10],
+}
+
+/// `struct gpio_v2_line_request`
+#[repr(C)]
+struct LineRequest {
+    offsets: [u32; 64],
+    consumer: [u8; 32],
+    config: LineConfig,
+    num_lines: u32,
+    event_buffer_size: u32,
+    padding: [u32; 5],
+    fd: i32,
+}
+
+const _: () = assert!(std::mem::size_of::<LineRequest>() == 592);
+
+/// Pairs rising and falling edges into pulse widths
+#[derive(Debug, Default)]
+struct PulseTimer {
+    rising: Option<u64>,
+}
+
+impl PulseTimer {
+    /// The edge `id` at `timestamp` ns, giving the pulse's width in ns when
+    /// it ends one
+    fn edge(&mut self, id: u32, timestamp: u64) -> Option<u64> {
+        match id {
+            GPIO_V2_LINE_EVENT_RISING_EDGE => {
+                self.rising = Some(timestamp);
+                None
+            }
+            // A falling edge without its rising edge ends a pulse already under way
+            GPIO_V2_LINE_EVENT_FALLING_EDGE => self.rising.take().map(|rising| timestamp.saturating_sub(rising)),
+            _ => None,
+        }
+    }
+}
+
+/// A sensor's PW output on a GPIO line
+pub struct PulseWidth {
+    line: File,
+    /// Pulse width in µs per mm
+    us_per_mm: f64,
+}
+
+impl PulseWidth {
+    /// Watch `line` on `chip`, e.g. `/dev/gpiochip0`
+    pub fn open(chip: &Path, line: u32, us_per_mm: f64) -> io::Result<Self> {
+        let chip = OpenOptions::new().read(true).write(true).open(chip)?;
+        let mut consumer = [0u8; 32];
+        consumer[..9].copy_from_slice(b"snowgauge");
+        let mut offsets = [0u32; 64];
+        offsets[0] = line;
+        let mut request = LineRequest {
+            offsets,
+            consumer,
+            config: LineConfig {
+                flags: GPIO_V2_LINE_FLAG_INPUT | GPIO_V2_LINE_FLAG_EDGE_RISING | GPIO_V2_LINE_FLAG_EDGE_FALLING,
+                num_attrs: 0,
+                padding: [0; 5],
+                attrs: [[0; 3]; 10],
+            },
+            num_lines: 1,
+            event_buffer_size: 0,
+            padding: [0; 5],
+            fd: -1,
+        };
+        // SAFETY: the request is laid out as the kernel's struct
+        // gpio_v2_line_request and outlives the call, which fills in its fd.
+        if unsafe { libc::ioctl(chip.as_raw_fd(), GPIO_V2_GET_LINE_IOCTL as _, &mut request as *mut LineRequest) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the kernel handed over a new fd that nothing else owns.
+        let line = unsafe { File::from_raw_fd(request.fd) };
+        Ok(Self { line, us_per_mm })
+    }
+
+    /// Wait up to `timeout` for edges to read
+    fn wait(&self, timeout: Duration) -> io::Result<bool> {
+        let mut fd = libc::pollfd { fd: self.line.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        // SAFETY: one pollfd, valid for the call.
+        let ready = unsafe { libc::poll(&mut fd, 1, timeout.as_millis() as libc::c_int) };
+        if ready < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ready > 0)
+    }
+
+    /// Edges read, as (id, timestamp in ns)
+    fn events(&mut self) -> io::Result<Vec<(u32, u64)>> {
+        let mut buf = [0u8; EVENT_SIZE * 16];
+        let n = self.line.read(&mut buf)?;
+        Ok(buf[..n]
+            .chunks_exact(EVENT_SIZE)
+            .map(|event| {
+                let timestamp = u64::from_ne_bytes(event[..8].try_into().unwrap());
+                (u32::from_ne_bytes(event[8..12].try_into().unwrap()), timestamp)
+            })
+            .collect())
+    }
+}
+
+impl Rangefinder for PulseWidth {
+    fn range(&mut self) -> io::Result<Frame> {
+        while self.wait(Duration::ZERO)? {
+            self.events()?;
+        }
+
+        let mut timer = PulseTimer::default();
+        let deadline = Instant::now() + PULSE_TIMEOUT;
+        loop {
+            if !self.wait(deadline.saturating_duration_since(Instant::now()))? {
+                let distance = Err(FrameError::Device("no pulse from the sensor".to_string()));
+                return Ok(Frame { bytes: Vec::new(), distance });
+            }
+            for (id, timestamp) in self.events()? {
+                if let Some(width) = timer.edge(id, timestamp) {
+                    let us = width as f64 / 1000.0;
+                    // The width in µs stands in for the frame's bytes
+                    let bytes = format!("{:.0}", us).into_bytes();
+                    return Ok(Frame { bytes, distance: Ok(us / self.us_per_mm) });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pulse_timer() {
+        let mut timer = PulseTimer::default();
+        // Joining partway through a pulse
+        assert_eq!(timer.edge(GPIO_V2_LINE_EVENT_FALLING_EDGE, 1_000_000), None);
+        assert_eq!(timer.edge(GPIO_V2_LINE_EVENT_RISING_EDGE, 150_000_000), None);
+        assert_eq!(timer.edge(GPIO_V2_LINE_EVENT_FALLING_EDGE, 151_834_000), Some(1_834_000));
+        assert_eq!(timer.edge(GPIO_V2_LINE_EVENT_FALLING_EDGE, 152_000_000), None);
+        // A missed falling edge starts the pulse over
+        timer.edge(GPIO_V2_LINE_EVENT_RISING_EDGE, 300_000_000);
+        timer.edge(GPIO_V2_LINE_EVENT_RISING_EDGE, 450_000_000);
+        assert_eq!(timer.edge(GPIO_V2_LINE_EVENT_FALLING_EDGE, 451_000_000), Some(1_000_000));
+    }
+}
@@ -0,0 +1,106 @@
+/// Standard gRPC health checking service (grpc.health.v1)
+///
+/// Reports SERVING while every supervised task is running or being
+/// restarted, and NOT_SERVING once the supervisor has given up on a task.
+/// Status is reported for the whole server ("") and for the snowgauge
+/// service by name.
+use tokio::sync::{mpsc, watch};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tonic::{Request, Response, Status};
+
+pub mod proto {
+    tonic::include_proto!("grpc.health.v1");
+}
+
+use proto::{
+    health_check_response::ServingStatus,
+    health_server::Health,
+    HealthCheckRequest, HealthCheckResponse,
+};
+
+const SERVICES: &[&str] = &["", "snowgauge.SnowGaugeService"];
+
+pub struct HealthService {
+    healthy: watch::Receiver<bool>,
+}
+
+impl HealthService {
+    pub fn new(healthy: watch::Receiver<bool>) -> Self {
+        Self { healthy }
+    }
+}
+
+fn status(service: &str, healthy: bool) -> ServingStatus {
+    if !SERVICES.contains(&service) {
+        ServingStatus::ServiceUnknown
+    } else if healthy {
+        ServingStatus::Serving
+    } else {
+        ServingStatus::NotServing
+    }
+}
+
+fn response(status: ServingStatus) -> HealthCheckResponse {
+    HealthCheckResponse { status: status as i32 }
+}
+
+#[tonic::async_trait]
+impl Health for HealthService {
+    type WatchStream = UnboundedReceiverStream<Result<HealthCheckResponse, Status>>;
+
+    async fn check(
+        &self,
+        request: Request<HealthCheckRequest>,
+    ) -> Result<Response<HealthCheckResponse>, Status> {
+        let service = request.into_inner().service;
+        match status(&service, *self.healthy.borrow()) {
+            ServingStatus::ServiceUnknown => Err(Status::not_found(format!("unknown service '{}'", service))),
+            status => Ok(Response::new(response(status))),
+        }
+    }
+
+    async fn watch(
+        &self,
+        request: Request<HealthCheckRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let service = request.into_inner().service;
+        let mut healthy = self.healthy.clone();
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        // Send the current status, then each change, until the client goes away
+        tokio::spawn(async move {
+            let mut last = None;
+            loop {
+                let current = status(&service, *healthy.borrow_and_update());
+                if last != Some(current) {
+                    if tx.send(Ok(response(current))).is_err() {
+                        break;
+                    }
+                    last = Some(current);
+                }
+                tokio::select! {
+                    changed = healthy.changed() => {
+                        if changed.is_err() {
+                            break;
+                        }
+                    }
+                    _ = tx.closed() => break,
+                }
+            }
+        });
+
+        Ok(Response::new(UnboundedReceiverStream::new(rx)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status() {
+        assert_eq!(status("", true), ServingStatus::Serving);
+        assert_eq!(status("snowgauge.SnowGaugeService", false), ServingStatus::NotServing);
+        assert_eq!(status("other.Service", true), ServingStatus::ServiceUnknown);
+    }
+}
@@ -0,0 +1,983 @@
+/// History of emitted readings
+///
+/// Readings are kept in a bounded buffer (oldest evicted first). Operator
+/// amendments never modify the stored values; they are recorded separately
+/// and applied when the history is queried, so the original data is always
+/// recoverable. Operator annotations are kept alongside the readings so
+/// context travels with the measurements.
+///
+/// The history also tracks the arrival of raw samples and records an explicit
+/// gap whenever none arrive for longer than the gap threshold, so downstream
+/// consumers can tell "sensor down" apart from "no data requested".
+/// Readings flagged by the anomaly detector are listed the same way.
+///
+/// Readings are held delta-encoded (see `store`), so timestamps are kept to
+/// the millisecond and distances to 0.01 mm. Amendments and annotations are
+/// dropped with the last reading they cover.
+///
+/// With `--history-file` the readings, amendments and annotations are also
+/// appended to a file as they are recorded, and read back at startup. The
+/// file is rewritten with just what is retained once it holds twice the
+/// history size, so it stays within a few bytes a reading on small flash
+/// devices. Gaps and anomalies start over with each run.
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::anomaly::Anomaly;
+use crate::store::{self, from_millis, to_millis, ReadingStore, BLOCK_SIZE};
+
+/// Tags of the history file's records besides the readings'
+const RECORD_AMENDMENT: u8 = 16;
+const RECORD_ANNOTATION: u8 = 17;
+/// The next IDs to assign, so pruned records' IDs aren't reused
+const RECORD_NEXT_IDS: u8 = 18;
+
+/// A reading as it was originally emitted
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredReading {
+    pub timestamp: SystemTime,
+    pub distance: f64,
+}
+
+/// Correction applied by an amendment
+#[derive(Debug, Clone, PartialEq)]
+pub enum Correction {
+    /// Mark readings in the range as invalid
+    Invalidate,
+    /// Add a fixed offset (mm) to readings in the range
+    Offset(f64),
+}
+
+/// A recorded correction over a time range (inclusive)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Amendment {
+    pub id: u64,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub correction: Correction,
+    pub reason: String,
+    pub created_at: SystemTime,
+    /// W3C trace context of the call that recorded it, or empty
+    pub traceparent: String,
+}
+
+impl Amendment {
+    fn covers(&self, timestamp: SystemTime) -> bool {
+        timestamp >= self.start && timestamp <= self.end
+    }
+}
+
+/// An amendment in the history file; times are milliseconds since the Unix epoch
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AmendmentRecord {
+    id: u64,
+    start: i64,
+    end: i64,
+    /// Absent for an invalidation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    offset_mm: Option<f64>,
+    reason: String,
+    created_at: i64,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    traceparent: String,
+}
+
+impl From<&Amendment> for AmendmentRecord {
+    fn from(amendment: &Amendment) -> Self {
+        Self {
+            id: amendment.id,
+            start: to_millis(amendment.start),
+            end: to_millis(amendment.end),
+            offset_mm: match amendment.correction {
+                Correction::Invalidate => None,
+                Correction::Offset(offset) => Some(offset),
+            },
+            reason: amendment.reason.clone(),
+            created_at: to_millis(amendment.created_at),
+            traceparent: amendment.traceparent.clone(),
+        }
+    }
+}
+
+impl From<AmendmentRecord> for Amendment {
+    fn from(record: AmendmentRecord) -> Self {
+        Self {
+            id: record.id,
+            start: from_millis(record.start),
+            end: from_millis(record.end),
+            correction: record.offset_mm.map_or(Correction::Invalidate, Correction::Offset),
+            reason: record.reason,
+            created_at: from_millis(record.created_at),
+            traceparent: record.traceparent,
+        }
+    }
+}
+
+/// Free-text operator note at a point in time (start == end) or over a range
+#[derive(Debug, Clone, PartialEq)]
+pub struct Annotation {
+    pub id: u64,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub text: String,
+    pub created_at: SystemTime,
+}
+
+/// An annotation in the history file; times are milliseconds since the Unix epoch
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AnnotationRecord {
+    id: u64,
+    start: i64,
+    end: i64,
+    text: String,
+    created_at: i64,
+}
+
+impl From<&Annotation> for AnnotationRecord {
+    fn from(annotation: &Annotation) -> Self {
+        Self {
+            id: annotation.id,
+            start: to_millis(annotation.start),
+            end: to_millis(annotation.end),
+            text: annotation.text.clone(),
+            created_at: to_millis(annotation.created_at),
+        }
+    }
+}
+
+impl From<AnnotationRecord> for Annotation {
+    fn from(record: AnnotationRecord) -> Self {
+        Self {
+            id: record.id,
+            start: from_millis(record.start),
+            end: from_millis(record.end),
+            text: record.text,
+            created_at: from_millis(record.created_at),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NextIds {
+    amendment: u64,
+    annotation: u64,
+}
+
+/// Frame a JSON record; serializing plain strings and numbers cannot fail
+fn json_record(tag: u8, value: &impl Serialize) -> Vec<u8> {
+    store::record(tag, &serde_json::to_vec(value).unwrap())
+}
+
+/// The history file, open for appending
+struct HistoryFile {
+    path: PathBuf,
+    file: File,
+    /// Readings written since the file was last rewritten, evicted ones included
+    readings: usize,
+}
+
+/// Period in which no raw samples arrived for longer than the gap threshold
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gap {
+    pub start: SystemTime,
+    pub end: SystemTime,
+    /// True if the gap is still open (no samples since `start`)
+    pub ongoing: bool,
+}
+
+impl Gap {
+    pub fn duration(&self) -> Duration {
+        self.end.duration_since(self.start).unwrap_or_default()
+    }
+}
+
+/// Gap totals since tracking started, kept as retention drops old gaps
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GapStats {
+    /// Gaps, including one still open
+    pub count: u64,
+    pub total_time: Duration,
+    pub longest: Duration,
+    /// When gap tracking started
+    pub since: Option<SystemTime>,
+}
+
+/// True if `[a_start, a_end]` overlaps the query range (either bound optional)
+fn overlaps(a_start: SystemTime, a_end: SystemTime, start: Option<SystemTime>, end: Option<SystemTime>) -> bool {
+    start.is_none_or(|s| a_end >= s) && end.is_none_or(|e| a_start <= e)
+}
+
+/// A stored reading with all applicable amendments applied
+#[derive(Debug, Clone, PartialEq)]
+pub struct AmendedReading {
+    pub timestamp: SystemTime,
+    /// Value as originally emitted
+    pub original_distance: f64,
+    /// Value after offset corrections
+    pub distance: f64,
+    /// True if any amendment marked this reading invalid
+    pub invalid: bool,
+    /// IDs of the amendments that touched this reading, in the order applied
+    pub amendment_ids: Vec<u64>,
+}
+
+pub struct History {
+    readings: ReadingStore,
+    amendments: Vec<Amendment>,
+    annotations: Vec<Annotation>,
+    gaps: Vec<Gap>,
+    /// Totals over the closed gaps, including those no longer retained
+    gap_stats: GapStats,
+    anomalies: Vec<Anomaly>,
+    capacity: usize,
+    gap_threshold: Duration,
+    tracking_since: Option<SystemTime>,
+    last_sample: Option<SystemTime>,
+    /// Sampling is intentionally stopped (scheduled power saving)
+    paused: bool,
+    next_amendment_id: u64,
+    next_annotation_id: u64,
+    file: Option<HistoryFile>,
+}
+
+impl History {
+    /// Create a history retaining at most `capacity` readings, recording a gap
+    /// whenever raw samples stop for longer than `gap_threshold`
+    pub fn new(capacity: usize, gap_threshold: Duration) -> Self {
+        Self {
+            readings: ReadingStore::new(),
+            amendments: Vec::new(),
+            annotations: Vec::new(),
+            gaps: Vec::new(),
+            gap_stats: GapStats::default(),
+            anomalies: Vec::new(),
+            capacity,
+            gap_threshold,
+            tracking_since: None,
+            last_sample: None,
+            paused: false,
+            next_amendment_id: 1,
+            next_annotation_id: 1,
+            file: None,
+        }
+    }
+
+    /// Keep the history in the file at `path`, reading back what it holds
+    ///
+    /// A record cut short at the end of the file, as by a power failure
+    /// while it was written, is dropped with a warning.
+    pub fn open_file(&mut self, path: &Path) -> Result<(), String> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(format!("failed to read history {}: {}", path.display(), e)),
+        };
+        let decoded = store::decode(&data);
+        if decoded.len < data.len() {
+            warn!("History {}: dropping {} bytes that can't be read at the end", path.display(), data.len() - decoded.len);
+        }
+        let invalid = |e: serde_json::Error| format!("{}: invalid history record: {}", path.display(), e);
+        let mut next_ids = NextIds::default();
+        for (tag, payload) in decoded.records {
+            match tag {
+                RECORD_AMENDMENT => {
+                    let amendment = Amendment::from(serde_json::from_slice::<AmendmentRecord>(payload).map_err(invalid)?);
+                    next_ids.amendment = next_ids.amendment.max(amendment.id + 1);
+                    self.amendments.push(amendment);
+                }
+                RECORD_ANNOTATION => {
+                    let annotation = Annotation::from(serde_json::from_slice::<AnnotationRecord>(payload).map_err(invalid)?);
+                    next_ids.annotation = next_ids.annotation.max(annotation.id + 1);
+                    self.annotations.push(annotation);
+                }
+                RECORD_NEXT_IDS => {
+                    let ids: NextIds = serde_json::from_slice(payload).map_err(invalid)?;
+                    next_ids.amendment = next_ids.amendment.max(ids.amendment);
+                    next_ids.annotation = next_ids.annotation.max(ids.annotation);
+                }
+                // Written by a later version
+                _ => {}
+            }
+        }
+        self.next_amendment_id = self.next_amendment_id.max(next_ids.amendment);
+        self.next_annotation_id = self.next_annotation_id.max(next_ids.annotation);
+        self.readings = decoded.store;
+        while self.readings.len() > self.capacity {
+            self.readings.pop_front();
+        }
+        self.prune();
+        self.rewrite(path)
+    }
+
+    /// Replace the history file with what is retained, atomically
+    fn rewrite(&mut self, path: &Path) -> Result<(), String> {
+        let next_ids = NextIds { amendment: self.next_amendment_id, annotation: self.next_annotation_id };
+        let mut data = json_record(RECORD_NEXT_IDS, &next_ids);
+        data.extend(self.readings.records());
+        for amendment in &self.amendments {
+            data.extend(json_record(RECORD_AMENDMENT, &AmendmentRecord::from(amendment)));
+        }
+        for annotation in &self.annotations {
+            data.extend(json_record(RECORD_ANNOTATION, &AnnotationRecord::from(annotation)));
+        }
+
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let file = std::fs::write(&tmp, data)
+            .and_then(|_| std::fs::rename(&tmp, path))
+            .and_then(|_| OpenOptions::new().append(true).open(path))
+            .map_err(|e| format!("failed to write history {}: {}", path.display(), e))?;
+        self.file = Some(HistoryFile { path: path.to_path_buf(), file, readings: self.readings.len() });
+        Ok(())
+    }
+
+    /// Append a record to the history file, if there is one
+    fn append(&mut self, record: &[u8]) {
+        let Some(file) = self.file.as_mut() else {
+            return;
+        };
+        if let Err(e) = file.file.write_all(record) {
+            warn!("Failed to write history {}: {}", file.path.display(), e);
+            // A partly written record would end the file; rewrite it next time
+            file.readings = usize::MAX;
+        }
+    }
+
+    /// Begin gap tracking at process start, so a sensor that is down from the
+    /// outset is counted as a gap rather than ignored
+    pub fn start_tracking(&mut self, now: SystemTime) {
+        if self.last_sample.is_none() {
+            self.tracking_since = Some(now);
+            self.last_sample = Some(now);
+        }
+    }
+
+    /// Stop gap tracking while the sensor is deliberately powered down
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    /// Resume gap tracking; the paused time is not counted as a gap
+    pub fn resume(&mut self, now: SystemTime) {
+        if self.paused {
+            self.paused = false;
+            if self.last_sample.is_some() {
+                self.last_sample = Some(now);
+            }
+        }
+    }
+
+    /// Note the arrival of a raw sample, returning the gap it closed if one
+    /// was open
+    pub fn record_sample(&mut self, timestamp: SystemTime) -> Option<Gap> {
+        if self.paused {
+            self.resume(timestamp);
+        }
+        let mut closed = None;
+        match self.last_sample {
+            Some(last) => {
+                if timestamp.duration_since(last).unwrap_or_default() > self.gap_threshold {
+                    let gap = Gap {
+                        start: last,
+                        end: timestamp,
+                        ongoing: false,
+                    };
+                    self.gap_stats.count += 1;
+                    self.gap_stats.total_time += gap.duration();
+                    self.gap_stats.longest = self.gap_stats.longest.max(gap.duration());
+                    self.gaps.push(gap.clone());
+                    closed = Some(gap);
+                }
+            }
+            None => self.tracking_since = Some(timestamp),
+        }
+        self.last_sample = Some(timestamp);
+        closed
+    }
+
+    /// The gap samples have stopped for as of `now`, if they have
+    pub fn open_gap(&self, now: SystemTime) -> Option<Gap> {
+        let last = self.last_sample.filter(|_| !self.paused)?;
+        (now.duration_since(last).unwrap_or_default() > self.gap_threshold).then_some(Gap {
+            start: last,
+            end: now,
+            ongoing: true,
+        })
+    }
+
+    /// Gap totals since tracking started, counting the open gap as of `now`
+    pub fn gap_stats(&self, now: SystemTime) -> GapStats {
+        let mut stats = GapStats { since: self.tracking_since, ..self.gap_stats.clone() };
+        if let Some(gap) = self.open_gap(now) {
+            stats.count += 1;
+            stats.total_time += gap.duration();
+            stats.longest = stats.longest.max(gap.duration());
+        }
+        stats
+    }
+
+    /// Record a newly emitted reading, evicting the oldest if full
+    pub fn push(&mut self, timestamp: SystemTime, distance: f64) {
+        if self.capacity == 0 {
+            return;
+        }
+        while self.readings.len() >= self.capacity {
+            self.readings.pop_front();
+        }
+        let record = self.readings.push(StoredReading { timestamp, distance });
+        self.prune();
+
+        let Some(file) = self.file.as_mut() else {
+            return;
+        };
+        file.readings = file.readings.saturating_add(1);
+        if file.readings > 2 * self.capacity.max(BLOCK_SIZE) {
+            let path = file.path.clone();
+            if let Err(e) = self.rewrite(&path) {
+                warn!("{}", e);
+            }
+        } else {
+            self.append(&record);
+        }
+    }
+
+    /// Drop what ends before the oldest retained reading, which is no longer useful
+    fn prune(&mut self) {
+        if let Some(oldest) = self.readings.front().map(|r| r.timestamp) {
+            self.gaps.retain(|g| g.end >= oldest);
+            self.anomalies.retain(|a| a.timestamp >= oldest);
+            self.amendments.retain(|a| a.end >= oldest);
+            self.annotations.retain(|a| a.end >= oldest);
+        }
+    }
+
+    /// Record an amendment over `[start, end]`
+    ///
+    /// Returns the recorded amendment, or an error describing why the
+    /// request was rejected.
+    pub fn amend(
+        &mut self,
+        start: SystemTime,
+        end: SystemTime,
+        correction: Correction,
+        reason: String,
+        traceparent: String,
+    ) -> Result<Amendment, String> {
+        if end < start {
+            return Err("amendment end must not be before start".to_string());
+        }
+        if reason.trim().is_empty() {
+            return Err("amendment reason is required".to_string());
+        }
+        if let Correction::Offset(offset) = correction {
+            if !offset.is_finite() {
+                return Err(format!("offset must be a finite number, got {}", offset));
+            }
+        }
+
+        let amendment = Amendment {
+            id: self.next_amendment_id,
+            start,
+            end,
+            correction,
+            reason,
+            created_at: store::millis_precision(SystemTime::now()),
+            traceparent,
+        };
+        self.next_amendment_id += 1;
+        self.amendments.push(amendment.clone());
+        self.append(&json_record(RECORD_AMENDMENT, &AmendmentRecord::from(&amendment)));
+        Ok(amendment)
+    }
+
+    /// Return readings in `[start, end]` (either bound optional) with amendments applied
+    pub fn query(&self, start: Option<SystemTime>, end: Option<SystemTime>) -> Vec<AmendedReading> {
+        self.readings
+            .range(start, end)
+            .map(|r| self.apply(&r))
+            .collect()
+    }
+
+    /// Return the most recent reading with amendments applied
+    pub fn latest(&self) -> Option<AmendedReading> {
+        self.readings.back().map(|r| self.apply(&r))
+    }
+
+    /// Return amendments overlapping `[start, end]` (either bound optional)
+    pub fn amendments(&self, start: Option<SystemTime>, end: Option<SystemTime>) -> Vec<Amendment> {
+        self.amendments
+            .iter()
+            .filter(|a| overlaps(a.start, a.end, start, end))
+            .cloned()
+            .collect()
+    }
+
+    /// Record an annotation at `start`, or over `[start, end]` if `end` is given
+    pub fn annotate(
+        &mut self,
+        start: SystemTime,
+        end: Option<SystemTime>,
+        text: String,
+    ) -> Result<Annotation, String> {
+        let end = end.unwrap_or(start);
+        if end < start {
+            return Err("annotation end must not be before start".to_string());
+        }
+        if text.trim().is_empty() {
+            return Err("annotation text is required".to_string());
+        }
+
+        let annotation = Annotation {
+            id: self.next_annotation_id,
+            start,
+            end,
+            text,
+            created_at: store::millis_precision(SystemTime::now()),
+        };
+        self.next_annotation_id += 1;
+        self.annotations.push(annotation.clone());
+        self.append(&json_record(RECORD_ANNOTATION, &AnnotationRecord::from(&annotation)));
+        Ok(annotation)
+    }
+
+    /// Return annotations overlapping `[start, end]` (either bound optional)
+    pub fn annotations(&self, start: Option<SystemTime>, end: Option<SystemTime>) -> Vec<Annotation> {
+        self.annotations
+            .iter()
+            .filter(|a| overlaps(a.start, a.end, start, end))
+            .cloned()
+            .collect()
+    }
+
+    /// Record a reading flagged by the anomaly detector
+    pub fn record_anomaly(&mut self, anomaly: Anomaly) {
+        self.anomalies.push(anomaly);
+    }
+
+    /// Return anomalies in `[start, end]` (either bound optional)
+    pub fn anomalies(&self, start: Option<SystemTime>, end: Option<SystemTime>) -> Vec<Anomaly> {
+        self.anomalies
+            .iter()
+            .filter(|a| overlaps(a.timestamp, a.timestamp, start, end))
+            .cloned()
+            .collect()
+    }
+
+    /// Return gaps overlapping `[start, end]` (either bound optional), including
+    /// the currently open gap if samples have stopped as of `now`
+    pub fn gaps(&self, start: Option<SystemTime>, end: Option<SystemTime>, now: SystemTime) -> Vec<Gap> {
+        let mut gaps: Vec<Gap> = self
+            .gaps
+            .iter()
+            .filter(|g| overlaps(g.start, g.end, start, end))
+            .cloned()
+            .collect();
+
+        if let Some(gap) = self.open_gap(now).filter(|g| overlaps(g.start, g.end, start, end)) {
+            gaps.push(gap);
+        }
+
+        gaps
+    }
+
+    /// Total gap time within the window and the fraction of the window that
+    /// was covered by samples
+    ///
+    /// Unset bounds default to the start of tracking (or the oldest retained
+    /// reading, if later) and `now`.
+    pub fn completeness(&self, start: Option<SystemTime>, end: Option<SystemTime>, now: SystemTime) -> (Duration, f64) {
+        let oldest = self.readings.front().map(|r| r.timestamp);
+        let window_start = match start {
+            Some(s) => s,
+            None => match (self.tracking_since, oldest) {
+                (Some(t), Some(o)) => t.max(o),
+                (Some(t), None) => t,
+                (None, Some(o)) => o,
+                (None, None) => return (Duration::ZERO, 1.0),
+            },
+        };
+        let window_end = end.unwrap_or(now);
+        let window = window_end.duration_since(window_start).unwrap_or_default();
+        if window.is_zero() {
+            return (Duration::ZERO, 1.0);
+        }
+
+        let gap_time: Duration = self
+            .gaps(Some(window_start), Some(window_end), now)
+            .iter()
+            .map(|g| {
+                let clipped_start = g.start.max(window_start);
+                let clipped_end = g.end.min(window_end);
+                clipped_end.duration_since(clipped_start).unwrap_or_default()
+            })
+            .sum();
+        let gap_time = gap_time.min(window);
+
+        (gap_time, 1.0 - gap_time.as_secs_f64() / window.as_secs_f64())
+    }
+
+    fn apply(&self, reading: &StoredReading) -> AmendedReading {
+        let mut amended = AmendedReading {
+            timestamp: reading.timestamp,
+            original_distance: reading.distance,
+            distance: reading.distance,
+            invalid: false,
+            amendment_ids: Vec::new(),
+        };
+
+        for amendment in self.amendments.iter().filter(|a| a.covers(reading.timestamp)) {
+            match amendment.correction {
+                Correction::Invalidate => amended.invalid = true,
+                Correction::Offset(offset) => amended.distance += offset,
+            }
+            amended.amendment_ids.push(amendment.id);
+        }
+
+        amended
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_capacity_evicts_oldest() {
+        let mut history = History::new(3, Duration::from_secs(60));
+        for i in 0..5 {
+            history.push(at(i), 1000.0 + i as f64);
+        }
+
+        let readings = history.query(None, None);
+        assert_eq!(readings.len(), 3);
+        assert_eq!(readings[0].original_distance, 1002.0);
+        assert_eq!(readings[2].original_distance, 1004.0);
+        assert_eq!(history.latest().map(|r| r.timestamp), Some(at(4)));
+    }
+
+    #[test]
+    fn test_offset_preserves_original() {
+        let mut history = History::new(10, Duration::from_secs(60));
+        for i in 0..5 {
+            history.push(at(i * 10), 1000.0);
+        }
+
+        let amendment = history
+            .amend(at(10), at(30), Correction::Offset(-12.5), "leaning mast".to_string(), String::new())
+            .unwrap();
+
+        let readings = history.query(None, None);
+        assert_eq!(readings[0].distance, 1000.0);
+        assert!(readings[0].amendment_ids.is_empty());
+        for reading in &readings[1..4] {
+            assert_eq!(reading.original_distance, 1000.0);
+            assert_eq!(reading.distance, 987.5);
+            assert_eq!(reading.amendment_ids, vec![amendment.id]);
+        }
+        assert_eq!(readings[4].distance, 1000.0);
+    }
+
+    #[test]
+    fn test_invalidate_and_offset_stack() {
+        let mut history = History::new(10, Duration::from_secs(60));
+        history.push(at(5), 1000.0);
+
+        history.amend(at(0), at(10), Correction::Offset(2.0), "first".to_string(), String::new()).unwrap();
+        history.amend(at(0), at(10), Correction::Offset(3.0), "second".to_string(), String::new()).unwrap();
+        history.amend(at(5), at(5), Correction::Invalidate, "bird on board".to_string(), String::new()).unwrap();
+
+        let reading = &history.query(None, None)[0];
+        assert_eq!(reading.distance, 1005.0);
+        assert!(reading.invalid);
+        assert_eq!(reading.amendment_ids, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_query_range() {
+        let mut history = History::new(10, Duration::from_secs(60));
+        for i in 0..10 {
+            history.push(at(i), i as f64);
+        }
+
+        let readings = history.query(Some(at(3)), Some(at(6)));
+        assert_eq!(readings.len(), 4);
+        assert_eq!(readings[0].original_distance, 3.0);
+        assert_eq!(readings[3].original_distance, 6.0);
+    }
+
+    #[test]
+    fn test_amend_validation() {
+        let mut history = History::new(10, Duration::from_secs(60));
+        assert!(history.amend(at(10), at(5), Correction::Invalidate, "reason".to_string(), String::new()).is_err());
+        assert!(history.amend(at(0), at(5), Correction::Invalidate, "  ".to_string(), String::new()).is_err());
+        assert!(history.amend(at(0), at(5), Correction::Offset(f64::NAN), "reason".to_string(), String::new()).is_err());
+        assert!(history.amendments(None, None).is_empty());
+    }
+
+    #[test]
+    fn test_amendments_overlap_filter() {
+        let mut history = History::new(10, Duration::from_secs(60));
+        history.amend(at(0), at(10), Correction::Invalidate, "a".to_string(), String::new()).unwrap();
+        history.amend(at(20), at(30), Correction::Invalidate, "b".to_string(), String::new()).unwrap();
+
+        assert_eq!(history.amendments(Some(at(5)), Some(at(15))).len(), 1);
+        assert_eq!(history.amendments(Some(at(5)), Some(at(25))).len(), 2);
+        assert_eq!(history.amendments(Some(at(31)), None).len(), 0);
+    }
+
+    #[test]
+    fn test_annotations() {
+        let mut history = History::new(10, Duration::from_secs(60));
+        let point = history.annotate(at(10), None, "cleared rime ice".to_string()).unwrap();
+        assert_eq!(point.start, point.end);
+        history
+            .annotate(at(20), Some(at(40)), "replaced battery".to_string())
+            .unwrap();
+
+        assert!(history.annotate(at(20), Some(at(10)), "backwards".to_string()).is_err());
+        assert!(history.annotate(at(20), None, "".to_string()).is_err());
+
+        assert_eq!(history.annotations(None, None).len(), 2);
+        assert_eq!(history.annotations(Some(at(11)), Some(at(19))).len(), 0);
+        assert_eq!(history.annotations(Some(at(30)), None)[0].text, "replaced battery");
+        assert_eq!(history.annotations(None, Some(at(10)))[0].id, point.id);
+    }
+
+    #[test]
+    fn test_gap_detection() {
+        let mut history = History::new(10, Duration::from_secs(60));
+        history.start_tracking(at(0));
+        history.record_sample(at(30));
+        history.record_sample(at(60));
+        // Sensor drops out for five minutes
+        history.record_sample(at(360));
+        history.record_sample(at(361));
+
+        let gaps = history.gaps(None, None, at(400));
+        assert_eq!(gaps.len(), 1);
+        assert_eq!(gaps[0], Gap { start: at(60), end: at(360), ongoing: false });
+
+        assert!(history.gaps(Some(at(361)), None, at(400)).is_empty());
+    }
+
+    #[test]
+    fn test_gap_stats() {
+        let mut history = History::new(2, Duration::from_secs(60));
+        history.start_tracking(at(0));
+        assert!(history.record_sample(at(30)).is_none());
+        let closed = history.record_sample(at(330)).unwrap();
+        assert_eq!((closed.start, closed.duration()), (at(30), Duration::from_secs(300)));
+        history.record_sample(at(430));
+        // Retention drops the gaps with the readings, but not from the totals
+        for i in 0..3 {
+            history.push(at(500 + i), 1000.0);
+        }
+        assert!(history.gaps(None, None, at(480)).is_empty());
+
+        let stats = history.gap_stats(at(480));
+        let expected = GapStats {
+            count: 2,
+            total_time: Duration::from_secs(400),
+            longest: Duration::from_secs(300),
+            since: Some(at(0)),
+        };
+        assert_eq!(stats, expected);
+        // The open gap counts as of now
+        assert!(history.open_gap(at(480)).is_none());
+        let stats = history.gap_stats(at(930));
+        assert_eq!((stats.count, stats.longest), (3, Duration::from_secs(500)));
+    }
+
+    #[test]
+    fn test_ongoing_gap() {
+        let mut history = History::new(10, Duration::from_secs(60));
+        history.start_tracking(at(0));
+        history.record_sample(at(10));
+
+        assert!(history.gaps(None, None, at(60)).is_empty());
+
+        let gaps = history.gaps(None, None, at(200));
+        assert_eq!(gaps, vec![Gap { start: at(10), end: at(200), ongoing: true }]);
+    }
+
+    #[test]
+    fn test_startup_gap() {
+        let mut history = History::new(10, Duration::from_secs(60));
+        history.start_tracking(at(0));
+        history.record_sample(at(120));
+
+        let gaps = history.gaps(None, None, at(121));
+        assert_eq!(gaps, vec![Gap { start: at(0), end: at(120), ongoing: false }]);
+    }
+
+    #[test]
+    fn test_paused_time_is_not_a_gap() {
+        let mut history = History::new(10, Duration::from_secs(60));
+        history.start_tracking(at(0));
+        history.record_sample(at(10));
+        history.pause();
+        assert!(history.gaps(None, None, at(500)).is_empty());
+
+        history.resume(at(900));
+        history.record_sample(at(910));
+        assert!(history.gaps(None, None, at(920)).is_empty());
+        assert_eq!(history.completeness(None, None, at(920)).1, 1.0);
+
+        // Samples stopping after the resume are a gap again
+        assert_eq!(history.gaps(None, None, at(1000)).len(), 1);
+    }
+
+    #[test]
+    fn test_completeness() {
+        let mut history = History::new(10, Duration::from_secs(60));
+        history.start_tracking(at(0));
+        history.record_sample(at(100));
+        history.record_sample(at(400));
+        history.record_sample(at(1000));
+
+        // Gaps: 0-100 (100s), 100-400 (300s), 400-1000 (600s); window 0-1000
+        let (gap_time, completeness) = history.completeness(None, None, at(1000));
+        assert_eq!(gap_time, Duration::from_secs(1000));
+        assert_eq!(completeness, 0.0);
+
+        // Window 300-500 overlaps 100s of the second gap and 100s of the third
+        let (gap_time, completeness) = history.completeness(Some(at(300)), Some(at(500)), at(1000));
+        assert_eq!(gap_time, Duration::from_secs(200));
+        assert_eq!(completeness, 0.0);
+
+        let mut history = History::new(10, Duration::from_secs(60));
+        history.start_tracking(at(0));
+        for i in 1..=10 {
+            history.record_sample(at(i * 10));
+        }
+        history.record_sample(at(200));
+        let (gap_time, completeness) = history.completeness(None, None, at(200));
+        assert_eq!(gap_time, Duration::from_secs(100));
+        assert!((completeness - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_anomalies_pruned_with_readings() {
+        let mut history = History::new(3, Duration::from_secs(60));
+        for i in 1..=3 {
+            history.push(at(i), 1000.0);
+        }
+        history.record_anomaly(Anomaly {
+            timestamp: at(2),
+            distance: 1000.0,
+            expected: 1100.0,
+            score: -7.0,
+        });
+        assert_eq!(history.anomalies(Some(at(2)), Some(at(2))).len(), 1);
+        assert!(history.anomalies(Some(at(3)), None).is_empty());
+
+        history.push(at(4), 1000.0);
+        history.push(at(5), 1000.0);
+        assert!(history.anomalies(None, None).is_empty());
+    }
+
+    #[test]
+    fn test_amendments_and_annotations_pruned_with_readings() {
+        let mut history = History::new(3, Duration::from_secs(60));
+        for i in 1..=3 {
+            history.push(at(i), 1000.0);
+        }
+        history.amend(at(1), at(1), Correction::Invalidate, "bird".to_string(), String::new()).unwrap();
+        history.amend(at(1), at(2), Correction::Offset(5.0), "mast".to_string(), String::new()).unwrap();
+        history.annotate(at(1), None, "cleared rime ice".to_string()).unwrap();
+        history.annotate(at(2), Some(at(10)), "storm".to_string()).unwrap();
+
+        // Evicting the reading at 1s drops what covers only it
+        history.push(at(4), 1000.0);
+        let ids: Vec<u64> = history.amendments(None, None).iter().map(|a| a.id).collect();
+        assert_eq!(ids, vec![2]);
+        assert_eq!(history.annotations(None, None).len(), 1);
+        history.push(at(5), 1000.0);
+        assert!(history.amendments(None, None).is_empty());
+        assert_eq!(history.annotations(None, None)[0].text, "storm");
+    }
+
+    fn history_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("snowgauge-history-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_history_file() {
+        let path = history_file("reload");
+        let mut history = History::new(1000, Duration::from_secs(60));
+        history.open_file(&path).unwrap();
+        for i in 0..300 {
+            history.push(at(i * 30), 1000.0 + (i % 7) as f64 * 0.25);
+        }
+        let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01".to_string();
+        history.amend(at(300), at(600), Correction::Offset(-12.5), "leaning mast".to_string(), traceparent).unwrap();
+        history.amend(at(900), at(900), Correction::Invalidate, "bird on board".to_string(), String::new()).unwrap();
+        history.annotate(at(1200), None, "cleared rime ice".to_string()).unwrap();
+        // A few bytes a reading
+        assert!(std::fs::metadata(&path).unwrap().len() < 300 * 4 + 400);
+
+        let mut reloaded = History::new(1000, Duration::from_secs(60));
+        reloaded.open_file(&path).unwrap();
+        assert_eq!(reloaded.query(None, None), history.query(None, None));
+        assert_eq!(reloaded.amendments(None, None), history.amendments(None, None));
+        assert_eq!(reloaded.annotations(None, None), history.annotations(None, None));
+        assert_eq!(reloaded.amend(at(0), at(1), Correction::Invalidate, "r".to_string(), String::new()).unwrap().id, 3);
+        assert_eq!(reloaded.annotate(at(0), None, "t".to_string()).unwrap().id, 2);
+
+        // A write cut short loses only the record it was writing
+        reloaded.push(at(9000), 1001.0);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[2, 0x80]).unwrap();
+        let mut cut = History::new(1000, Duration::from_secs(60));
+        cut.open_file(&path).unwrap();
+        assert_eq!(cut.query(None, None).len(), 301);
+        assert_eq!(cut.latest().unwrap().original_distance, 1001.0);
+        cut.push(at(9030), 1002.0);
+        let mut again = History::new(1000, Duration::from_secs(60));
+        again.open_file(&path).unwrap();
+        assert_eq!(again.latest().unwrap().original_distance, 1002.0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_history_file_rewritten() {
+        let path = history_file("rewrite");
+        let mut history = History::new(3, Duration::from_secs(60));
+        history.open_file(&path).unwrap();
+        history.push(at(1), 1000.0);
+        history.amend(at(1), at(1), Correction::Invalidate, "bird".to_string(), String::new()).unwrap();
+        for i in 2..2000 {
+            history.push(at(i), 1000.0 + i as f64);
+        }
+        // Rewritten with just the retained readings whenever it reaches twice a block
+        assert!(std::fs::metadata(&path).unwrap().len() < 2500);
+
+        let mut reloaded = History::new(3, Duration::from_secs(60));
+        reloaded.open_file(&path).unwrap();
+        let distances: Vec<f64> = reloaded.query(None, None).iter().map(|r| r.original_distance).collect();
+        assert_eq!(distances, vec![2997.0, 2998.0, 2999.0]);
+        assert!(reloaded.amendments(None, None).is_empty());
+        // The pruned amendment's ID isn't reused
+        assert_eq!(reloaded.amend(at(0), at(1), Correction::Invalidate, "r".to_string(), String::new()).unwrap().id, 2);
+
+        // A smaller history size takes effect on reload
+        let mut smaller = History::new(1, Duration::from_secs(60));
+        smaller.open_file(&path).unwrap();
+        assert_eq!(smaller.query(None, None).len(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,236 @@
+/// Extension points for embedding snowgauge as a library
+///
+/// `snowgauge::run` takes a `Hooks` with whatever an integrator adds to the
+/// daemon without patching it: tower layers wrapped around every gRPC call,
+/// interceptors run after the access list, and functions on each station's
+/// reading pipeline. A pre-filter hook sees every corrected raw reading
+/// before the filter pipeline and may change or drop it; a post-batch hook
+/// sees every filtered reading, interpolated ones included, before it is
+/// stored for replay and sent to clients.
+use std::sync::Arc;
+
+use tonic::body::BoxBody;
+use tonic::{Request, Status};
+use tower::util::BoxCloneService;
+use tower::ServiceExt;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::snowgauge::Reading;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// An HTTP-level gRPC service, as the server's layers see it
+pub type HttpService = BoxCloneService<http::Request<BoxBody>, http::Response<BoxBody>, BoxError>;
+
+type Wrap = Arc<dyn Fn(HttpService) -> HttpService + Send + Sync>;
+type Intercept = Arc<dyn Fn(Request<()>) -> Result<Request<()>, Status> + Send + Sync>;
+type PreFilter = Arc<dyn Fn(&str, f64) -> Option<f64> + Send + Sync>;
+type PostBatch = Arc<dyn Fn(&mut Reading) + Send + Sync>;
+
+#[derive(Clone, Default)]
+pub struct Hooks {
+    layers: Vec<Wrap>,
+    interceptors: Vec<Intercept>,
+    pipeline: PipelineHooks,
+}
+
+impl Hooks {
+    /// Wrap every gRPC call in `layer`; the first layer added is the outermost
+    pub fn with_layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<HttpService> + Send + Sync + 'static,
+        L::Service: Service<http::Request<BoxBody>, Response = http::Response<BoxBody>> + Clone + Send + 'static,
+        <L::Service as Service<http::Request<BoxBody>>>::Error: Into<BoxError>,
+        <L::Service as Service<http::Request<BoxBody>>>::Future: Send + 'static,
+    {
+        self.layers.push(Arc::new(move |inner| BoxCloneService::new(layer.layer(inner).map_err(Into::into))));
+        self
+    }
+
+    /// Run `interceptor` on every SnowGaugeService call the access list
+    /// admits, in the order added; an error fails the call with its status
+    pub fn with_interceptor<F>(mut self, interceptor: F) -> Self
+    where
+        F: Fn(Request<()>) -> Result<Request<()>, Status> + Send + Sync + 'static,
+    {
+        self.interceptors.push(Arc::new(interceptor));
+        self
+    }
+
+    /// Pass every corrected raw reading, with its station's name, through
+    /// `hook` before filtering; None drops the reading
+    pub fn with_pre_filter<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&str, f64) -> Option<f64> + Send + Sync + 'static,
+    {
+        self.pipeline.pre_filter.push(Arc::new(hook));
+        self
+    }
+
+    /// Pass every filtered reading through `hook` before it is published
+    pub fn with_post_batch<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&mut Reading) + Send + Sync + 'static,
+    {
+        self.pipeline.post_batch.push(Arc::new(hook));
+        self
+    }
+
+    /// The layers, as one layer for the server
+    pub(crate) fn server_layer(&self) -> ServerLayer {
+        ServerLayer(self.layers.clone())
+    }
+
+    /// Run the interceptors on an admitted call
+    #[allow(clippy::result_large_err)]
+    fn intercept(&self, request: Request<()>) -> Result<Request<()>, Status> {
+        self.interceptors.iter().try_fold(request, |request, interceptor| interceptor(request))
+    }
+
+    /// `admit`, then the interceptors on the calls it admits
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn interceptor<F>(&self, admit: F) -> impl Fn(Request<()>) -> Result<Request<()>, Status> + Clone
+    where
+        F: Fn(Request<()>) -> Result<Request<()>, Status> + Clone,
+    {
+        let hooks = self.clone();
+        move |request| hooks.intercept(admit(request)?)
+    }
+
+    pub(crate) fn pipeline(&self) -> &PipelineHooks {
+        &self.pipeline
+    }
+}
+
+/// Applies the registered layers around the server's routes
+#[derive(Clone)]
+pub(crate) struct ServerLayer(Vec<Wrap>);
+
+impl<S> Layer<S> for ServerLayer
+where
+    S: Service<http::Request<BoxBody>, Response = http::Response<BoxBody>> + Clone + Send + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+{
+    type Service = HttpService;
+
+    fn layer(&self, inner: S) -> HttpService {
+        let inner = BoxCloneService::new(inner.map_err(Into::into));
+        self.0.iter().rev().fold(inner, |service, wrap| wrap(service))
+    }
+}
+
+/// The reading pipeline's hooks, in the order added
+#[derive(Clone, Default)]
+pub(crate) struct PipelineHooks {
+    pre_filter: Vec<PreFilter>,
+    post_batch: Vec<PostBatch>,
+}
+
+impl PipelineHooks {
+    pub(crate) fn pre_filter(&self, station: &str, distance: f64) -> Option<f64> {
+        self.pre_filter.iter().try_fold(distance, |distance, hook| hook(station, distance))
+    }
+
+    pub(crate) fn post_batch(&self, reading: &mut Reading) {
+        for hook in &self.post_batch {
+            hook(reading);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use std::task::{Context, Poll};
+    use tower::service_fn;
+    use tower_layer::layer_fn;
+
+    #[test]
+    fn test_pipeline_hooks() {
+        let hooks = Hooks::default()
+            .with_pre_filter(|_, distance| Some(distance + 10.0))
+            .with_pre_filter(|station, distance| (station == "pole-1").then_some(distance * 2.0))
+            .with_post_batch(|reading| reading.value += 1.0)
+            .with_post_batch(|reading| reading.station_name.push_str(" (checked)"));
+
+        // In the order added, a None ending the chain
+        assert_eq!(hooks.pipeline().pre_filter("pole-1", 5.0), Some(30.0));
+        assert_eq!(hooks.pipeline().pre_filter("pole-2", 5.0), None);
+        assert_eq!(PipelineHooks::default().pre_filter("pole-1", 5.0), Some(5.0));
+
+        let mut reading = Reading { station_name: "pole-1".to_string(), value: 1.0, ..Default::default() };
+        hooks.pipeline().post_batch(&mut reading);
+        assert_eq!(reading.value, 2.0);
+        assert_eq!(reading.station_name, "pole-1 (checked)");
+    }
+
+    #[test]
+    #[allow(clippy::result_large_err)]
+    fn test_interceptors() {
+        let hooks = Hooks::default()
+            .with_interceptor(|mut request| {
+                request.metadata_mut().insert("org", "acme".parse().unwrap());
+                Ok(request)
+            })
+            .with_interceptor(|request| match request.metadata().get("authorization") {
+                Some(_) => Ok(request),
+                None => Err(Status::unauthenticated("no token")),
+            });
+
+        let mut request = Request::new(());
+        request.metadata_mut().insert("authorization", "Bearer t".parse().unwrap());
+        let request = hooks.intercept(request).unwrap();
+        assert_eq!(request.metadata().get("org").unwrap(), "acme");
+
+        let denied = hooks.intercept(Request::new(())).unwrap_err();
+        assert_eq!(denied.code(), tonic::Code::Unauthenticated);
+        assert!(Hooks::default().intercept(Request::new(())).is_ok());
+    }
+
+    /// Adds `name` to the response's `x-layers` header on the way out
+    #[derive(Clone)]
+    struct Tag<S>(&'static str, S);
+
+    impl<S: Service<http::Request<BoxBody>, Response = http::Response<BoxBody>, Error = BoxError>> Service<http::Request<BoxBody>> for Tag<S>
+    where
+        S::Future: Send + 'static,
+    {
+        type Response = http::Response<BoxBody>;
+        type Error = BoxError;
+        type Future = std::pin::Pin<Box<dyn std::future::Future<Output = Result<Self::Response, BoxError>> + Send>>;
+
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+            self.1.poll_ready(cx)
+        }
+
+        fn call(&mut self, request: http::Request<BoxBody>) -> Self::Future {
+            let name = self.0;
+            let response = self.1.call(request);
+            Box::pin(async move {
+                let mut response = response.await?;
+                let layers = match response.headers().get("x-layers") {
+                    Some(inner) => format!("{},{}", inner.to_str().unwrap(), name),
+                    None => name.to_string(),
+                };
+                response.headers_mut().insert("x-layers", layers.parse().unwrap());
+                Ok(response)
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_layer() {
+        let hooks = Hooks::default()
+            .with_layer(layer_fn(|inner| Tag("outer", inner)))
+            .with_layer(layer_fn(|inner| Tag("inner", inner)));
+        let routes = service_fn(|_: http::Request<BoxBody>| async { Ok::<_, Infallible>(Status::ok("").into_http()) });
+
+        let service = hooks.server_layer().layer(routes);
+        let response = service.oneshot(http::Request::new(tonic::body::empty_body())).await.unwrap();
+        // The inner layer finishes first
+        assert_eq!(response.headers().get("x-layers").unwrap(), "inner,outer");
+    }
+}
@@ -0,0 +1,193 @@
+/// Gap interpolation for continuous output
+///
+/// When the sensor drops out for a few minutes, as while the serial reader
+/// backs off reconnecting, no batch readings are emitted and time-series
+/// consumers see a hole. With interpolation on, the first batch reading
+/// after the dropout is preceded by readings at the usual spacing, on the
+/// straight line between the readings either side of the gap, so the series
+/// stays regularly spaced. They are flagged `INTERPOLATED` and are not kept
+/// in history. Gaps longer than the maximum are left alone: a straight line
+/// across hours of missing data says nothing about what the snow did.
+///
+/// The derived products, the snowfall rate and totals, can bridge short
+/// gaps too, by `Interpolation`: the rate is fitted with points filled in
+/// across them, and the totals count them at the rate either side.
+use std::time::{Duration, SystemTime};
+
+/// How the derived products bridge a gap in the batch readings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interpolation {
+    /// Leave gaps alone
+    None,
+    /// On the straight line across the gap
+    Linear,
+    /// At the last value before the gap
+    Hold,
+}
+
+impl std::str::FromStr for Interpolation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Self::None),
+            "linear" => Ok(Self::Linear),
+            "hold" => Ok(Self::Hold),
+            _ => Err(format!("Invalid interpolation '{}'. Valid options: none, linear, hold", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Interpolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::None => "none",
+            Self::Linear => "linear",
+            Self::Hold => "hold",
+        })
+    }
+}
+
+/// The usual spacing of a series, for telling its gaps
+#[derive(Debug, Default)]
+pub struct Spacing {
+    /// Spacing between the last two readings that weren't a gap apart
+    interval: Option<Duration>,
+}
+
+impl Spacing {
+    /// Note the time `elapsed` since the last reading, returning the usual
+    /// spacing if it makes a gap: over one and a half times the usual one
+    pub fn gap(&mut self, elapsed: Duration) -> Option<Duration> {
+        match self.interval {
+            Some(interval) if !interval.is_zero() && elapsed > interval * 3 / 2 => Some(interval),
+            _ => {
+                self.interval = Some(elapsed);
+                None
+            }
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.interval = None;
+    }
+}
+
+pub struct GapFiller {
+    max_gap: Duration,
+    hold: bool,
+    last: Option<(SystemTime, f64)>,
+    spacing: Spacing,
+}
+
+impl GapFiller {
+    pub fn new(max_gap: Duration) -> Self {
+        Self { max_gap, hold: false, last: None, spacing: Spacing::default() }
+    }
+
+    /// A filler holding the last value across gaps rather than drawing a line
+    pub fn holding(max_gap: Duration) -> Self {
+        Self { hold: true, ..Self::new(max_gap) }
+    }
+
+    /// Note an emitted reading, returning the interpolated `(timestamp,
+    /// distance)` readings that go before it if it ends a gap
+    pub fn update(&mut self, timestamp: SystemTime, distance: f64) -> Vec<(SystemTime, f64)> {
+        let mut filled = Vec::new();
+        if let Some((last_timestamp, last_distance)) = self.last {
+            let elapsed = timestamp.duration_since(last_timestamp).unwrap_or_default();
+            if let Some(interval) = self.spacing.gap(elapsed).filter(|_| elapsed <= self.max_gap) {
+                let mut at = interval;
+                while at + interval / 2 < elapsed {
+                    let fraction = if self.hold { 0.0 } else { at.as_secs_f64() / elapsed.as_secs_f64() };
+                    filled.push((last_timestamp + at, last_distance + fraction * (distance - last_distance)));
+                    at += interval;
+                }
+            }
+        }
+        self.last = Some((timestamp, distance));
+        filled
+    }
+
+    /// Forget the series, for a deliberate break such as a pause, an idle
+    /// schedule period, or a change in batch spacing
+    pub fn reset(&mut self) {
+        self.last = None;
+        self.spacing.reset();
+    }
+}
+
+/// `series` with points filled in across its gaps of up to `max_gap`
+pub fn fill(series: &[(SystemTime, f64)], interpolation: Interpolation, max_gap: Duration) -> Vec<(SystemTime, f64)> {
+    let mut filler = match interpolation {
+        Interpolation::None => return series.to_vec(),
+        Interpolation::Linear => GapFiller::new(max_gap),
+        Interpolation::Hold => GapFiller::holding(max_gap),
+    };
+    let mut filled = Vec::with_capacity(series.len());
+    for &(timestamp, value) in series {
+        filled.extend(filler.update(timestamp, value));
+        filled.push((timestamp, value));
+    }
+    filled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn test_fills_short_gap() {
+        let mut filler = GapFiller::new(Duration::from_secs(600));
+        assert!(filler.update(at(0), 1000.0).is_empty());
+        assert!(filler.update(at(30), 1000.0).is_empty());
+        // Readings should have come at 60, 90 and 120
+        let filled = filler.update(at(150), 988.0);
+        assert_eq!(filled, vec![(at(60), 997.0), (at(90), 994.0), (at(120), 991.0)]);
+        // The usual spacing is unchanged by the gap
+        assert!(filler.update(at(180), 988.0).is_empty());
+        assert_eq!(filler.update(at(240), 988.0), vec![(at(210), 988.0)]);
+    }
+
+    #[test]
+    fn test_ignores_jitter_and_long_gaps() {
+        let mut filler = GapFiller::new(Duration::from_secs(120));
+        filler.update(at(0), 1000.0);
+        filler.update(at(30), 1000.0);
+        assert!(filler.update(at(70), 1000.0).is_empty());
+        assert!(filler.update(at(400), 900.0).is_empty());
+        // Nor is anything bridged across a reset
+        filler.update(at(430), 900.0);
+        filler.reset();
+        assert!(filler.update(at(520), 900.0).is_empty());
+    }
+
+    #[test]
+    fn test_fill() {
+        let series = [(at(0), 1000.0), (at(30), 1000.0), (at(120), 994.0), (at(150), 994.0), (at(400), 990.0)];
+        let max_gap = Duration::from_secs(90);
+        assert_eq!(fill(&series, Interpolation::None, max_gap), series.to_vec());
+        // The 90s gap is bridged, the 250s one is too long
+        let linear = fill(&series, Interpolation::Linear, max_gap);
+        assert_eq!(&linear[2..4], [(at(60), 998.0), (at(90), 996.0)]);
+        assert_eq!(linear.len(), series.len() + 2);
+        let hold = fill(&series, Interpolation::Hold, max_gap);
+        assert_eq!(&hold[2..4], [(at(60), 1000.0), (at(90), 1000.0)]);
+        assert_eq!(hold.len(), series.len() + 2);
+        // Just over the maximum, nothing is bridged
+        assert_eq!(fill(&series, Interpolation::Linear, Duration::from_secs(89)), series.to_vec());
+    }
+
+    #[test]
+    fn test_interpolation_parse() {
+        assert_eq!("linear".parse::<Interpolation>(), Ok(Interpolation::Linear));
+        assert_eq!("HOLD".parse::<Interpolation>(), Ok(Interpolation::Hold));
+        assert_eq!(Interpolation::None.to_string(), "none");
+        assert!("spline".parse::<Interpolation>().is_err());
+    }
+}
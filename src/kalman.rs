@@ -0,0 +1,165 @@
+/// One-dimensional Kalman filter tracking distance and its rate of change
+///
+/// The state is the distance to the surface and how fast it is changing
+/// (mm per reading), under a constant-rate model with random changes of rate
+/// (process noise) and independent sensor noise on each reading
+/// (measurement noise). Since the filter knows the surface is moving, it
+/// follows steady accumulation without the lag of the exponential filter,
+/// whose output trails a ramp by rate × (1 − alpha) / alpha and can move no
+/// faster than its rate limit.
+///
+/// Depth is the baseline less the distance, so the rate is the snowfall
+/// rate with the sign flipped.
+use log::debug;
+
+pub struct KalmanFilter {
+    /// Distance (mm) and rate (mm per reading); None until the first reading
+    state: Option<[f64; 2]>,
+    covariance: [[f64; 2]; 2],
+    reading_count: usize,
+    init_period: usize,
+    /// Variance of the change in rate between readings (mm² per reading⁴)
+    process_noise: f64,
+    /// Variance of a single reading about the true distance (mm²)
+    measurement_noise: f64,
+}
+
+impl KalmanFilter {
+    pub fn new(init_period: usize, process_noise: f64, measurement_noise: f64) -> Self {
+        Self {
+            state: None,
+            covariance: [[0.0; 2]; 2],
+            reading_count: 0,
+            init_period,
+            process_noise,
+            measurement_noise,
+        }
+    }
+
+    /// Change the noise parameters, keeping the current estimate
+    pub fn set_params(&mut self, init_period: usize, process_noise: f64, measurement_noise: f64) {
+        self.init_period = init_period;
+        self.process_noise = process_noise;
+        self.measurement_noise = measurement_noise;
+    }
+
+    /// Process a new reading, returning the estimated distance
+    pub fn update(&mut self, raw_reading: f64) -> f64 {
+        self.reading_count += 1;
+        let r = self.measurement_noise;
+
+        let Some([distance, rate]) = self.state else {
+            // Start at the first reading, with no idea of the rate yet
+            self.state = Some([raw_reading, 0.0]);
+            self.covariance = [[r, 0.0], [0.0, r]];
+            debug!("Kalman filter initialized with first reading: {:.2}mm", raw_reading);
+            return raw_reading;
+        };
+
+        // Predict one reading ahead: x = F x, P = F P Fᵀ + Q with F = [[1, 1], [0, 1]]
+        // and Q the discrete white-noise acceleration model
+        let q = self.process_noise;
+        let [[p00, p01], [p10, p11]] = self.covariance;
+        let predicted = [distance + rate, rate];
+        let p00 = p00 + p01 + p10 + p11 + q / 4.0;
+        let p01 = p01 + p11 + q / 2.0;
+        let p10 = p10 + p11 + q / 2.0;
+        let p11 = p11 + q;
+
+        // Correct with the reading
+        let innovation = raw_reading - predicted[0];
+        let s = p00 + r;
+        let (k0, k1) = (p00 / s, p10 / s);
+        let state = [predicted[0] + k0 * innovation, predicted[1] + k1 * innovation];
+        self.covariance = [[(1.0 - k0) * p00, (1.0 - k0) * p01], [p10 - k1 * p00, p11 - k1 * p01]];
+        self.state = Some(state);
+
+        if self.reading_count <= self.init_period {
+            debug!(
+                "Kalman filter initializing ({}/{}): raw={:.2}mm, estimate={:.2}mm, rate={:.3}mm/reading",
+                self.reading_count, self.init_period, raw_reading, state[0], state[1]
+            );
+        }
+        state[0]
+    }
+
+    pub fn reset(&mut self) {
+        debug!("Kalman filter reset");
+        self.state = None;
+        self.reading_count = 0;
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.reading_count >= self.init_period
+    }
+
+    /// Estimated rate of change of distance in mm per reading
+    #[cfg(test)]
+    pub fn rate(&self) -> Option<f64> {
+        self.state.map(|[_, rate]| rate)
+    }
+
+    pub fn reading_count(&self) -> usize {
+        self.reading_count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensor_filter::SensorFilter;
+
+    /// Deterministic noise of up to ±3 mm
+    fn noise(i: usize) -> f64 {
+        ((i * 7919) % 7) as f64 - 3.0
+    }
+
+    #[test]
+    fn test_first_reading() {
+        let mut filter = KalmanFilter::new(40, 0.001, 4.0);
+        assert_eq!(filter.update(1000.0), 1000.0);
+        assert_eq!(filter.rate(), Some(0.0));
+        assert!(!filter.is_initialized());
+    }
+
+    #[test]
+    fn test_smooths_static_target() {
+        let mut filter = KalmanFilter::new(40, 0.001, 4.0);
+        let estimates: Vec<f64> = (0..200).map(|i| filter.update(1000.0 + noise(i))).collect();
+        assert!(filter.is_initialized());
+        assert!(estimates[100..].iter().all(|e| (e - 1000.0).abs() < 1.0), "{:?}", &estimates[100..]);
+        assert!(filter.rate().unwrap().abs() < 0.05);
+    }
+
+    #[test]
+    fn test_tracks_accumulation_without_lag() {
+        // Heavy snowfall: the surface closes in by 0.5 mm per reading
+        let truth = |i: usize| 2000.0 - 0.5 * i as f64;
+        let mut kalman = KalmanFilter::new(40, 0.001, 4.0);
+        let mut ema = SensorFilter::with_params(40, 1.0, 0.2);
+        let (mut kalman_error, mut ema_error) = (0.0, 0.0);
+        for i in 0..400 {
+            let reading = truth(i) + noise(i);
+            let k = kalman.update(reading);
+            let e = ema.update(reading);
+            if i >= 200 {
+                kalman_error += (k - truth(i)).abs();
+                ema_error += (e - truth(i)).abs();
+            }
+        }
+        assert!((kalman.rate().unwrap() + 0.5).abs() < 0.05);
+        assert!(kalman_error / 200.0 < 1.0, "kalman mean error {}", kalman_error / 200.0);
+        assert!(kalman_error < ema_error / 2.0, "kalman {} vs ema {}", kalman_error, ema_error);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut filter = KalmanFilter::new(5, 0.001, 4.0);
+        for i in 0..10 {
+            filter.update(1000.0 - i as f64);
+        }
+        filter.reset();
+        assert_eq!(filter.reading_count(), 0);
+        assert_eq!(filter.update(500.0), 500.0);
+    }
+}
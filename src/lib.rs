@@ -0,0 +1,69 @@
+mod acl;
+mod anomaly;
+mod args;
+mod baseline;
+mod battery;
+mod bench;
+#[cfg(target_os = "linux")]
+mod ble;
+mod board;
+mod calibration;
+mod coap;
+mod compensation;
+mod config;
+mod daemon;
+mod despike;
+mod events;
+mod filter;
+mod frame;
+mod fusion;
+mod gpio;
+mod health;
+mod history;
+mod hooks;
+mod i2c;
+mod interpolate;
+mod kalman;
+mod logging;
+mod lora;
+mod median;
+mod metrics;
+mod modbus;
+mod mqtt;
+mod offset;
+mod pipeline;
+mod preset;
+mod processing;
+mod quality;
+mod queue;
+mod ratelimit;
+mod regression;
+mod remote;
+mod schedule;
+mod sensor_filter;
+mod serial;
+mod server;
+mod service;
+mod settling;
+mod snmp;
+mod source;
+mod station;
+mod stations;
+mod store;
+mod stream;
+mod supervisor;
+mod swe;
+mod totals;
+mod trace;
+mod trend;
+mod tune;
+mod wind;
+
+pub use daemon::run;
+pub use hooks::{BoxError, Hooks, HttpService};
+
+// Generated code: StreamMessage's Reading variant outweighs its Event one
+#[allow(clippy::large_enum_variant)]
+pub mod snowgauge {
+    tonic::include_proto!("snowgauge");
+}
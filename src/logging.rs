@@ -0,0 +1,167 @@
+/// Logger with a filter that can be changed at runtime
+///
+/// env_logger fixes its filter when the logger is built, so the active
+/// logger sits behind a lock and SetLogLevel swaps in one built from the new
+/// filter. Filters use the RUST_LOG syntax: a default level and/or
+/// `module=level` directives, comma-separated.
+use std::sync::{OnceLock, RwLock};
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+static LOGGER: OnceLock<ReloadableLogger> = OnceLock::new();
+
+struct State {
+    filter: String,
+    logger: env_logger::Logger,
+    /// Bumped on every change, so a timed revert can tell it was superseded
+    generation: u64,
+}
+
+pub struct ReloadableLogger {
+    state: RwLock<State>,
+}
+
+fn build(filter: &str) -> env_logger::Logger {
+    env_logger::Builder::new().parse_filters(filter).build()
+}
+
+fn apply(state: &mut State, filter: &str) -> String {
+    state.logger = build(filter);
+    state.generation += 1;
+    log::set_max_level(state.logger.filter());
+    std::mem::replace(&mut state.filter, filter.to_string())
+}
+
+/// Check a filter up front, since env_logger only complains on stderr
+pub fn validate(filter: &str) -> Result<(), String> {
+    let invalid = |why: &str| Err(format!("Invalid log filter '{}': {}", filter, why));
+    let directives = filter.split('/').next().unwrap_or_default();
+    if directives.trim().is_empty() {
+        return invalid("no directives");
+    }
+    for directive in directives.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        let (module, level) = match directive.split_once('=') {
+            Some((module, level)) => (module.trim(), Some(level.trim())),
+            None if directive.parse::<LevelFilter>().is_ok() => continue,
+            None => (directive, None),
+        };
+        if module.is_empty() || !module.chars().all(|c| c.is_alphanumeric() || c == '_' || c == ':') {
+            return invalid(&format!("bad module name in '{}'", directive));
+        }
+        if level.is_some_and(|l| l.parse::<LevelFilter>().is_err()) {
+            return invalid(&format!("bad level in '{}'", directive));
+        }
+    }
+    Ok(())
+}
+
+impl ReloadableLogger {
+    fn new(filter: &str) -> Self {
+        Self {
+            state: RwLock::new(State {
+                filter: filter.to_string(),
+                logger: build(filter),
+                generation: 0,
+            }),
+        }
+    }
+
+    pub fn filter(&self) -> String {
+        self.state.read().unwrap_or_else(|e| e.into_inner()).filter.clone()
+    }
+
+    /// Switch to `filter`, returning the previous filter and the new generation
+    pub fn set_filter(&self, filter: &str) -> Result<(String, u64), String> {
+        validate(filter)?;
+        let mut state = self.state.write().unwrap_or_else(|e| e.into_inner());
+        let previous = apply(&mut state, filter);
+        Ok((previous, state.generation))
+    }
+
+    /// Switch back to `filter` unless the filter changed again since `generation`
+    pub fn restore(&self, generation: u64, filter: &str) -> bool {
+        let mut state = self.state.write().unwrap_or_else(|e| e.into_inner());
+        if state.generation != generation {
+            return false;
+        }
+        apply(&mut state, filter);
+        true
+    }
+}
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.state.read().unwrap_or_else(|e| e.into_inner()).logger.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.state.read().unwrap_or_else(|e| e.into_inner()).logger.log(record)
+    }
+
+    fn flush(&self) {
+        self.state.read().unwrap_or_else(|e| e.into_inner()).logger.flush()
+    }
+}
+
+/// Install the logger, filtered by RUST_LOG or else `default_filter`
+pub fn init(default_filter: &str) {
+    let filter = std::env::var("RUST_LOG").unwrap_or_else(|_| default_filter.to_string());
+    let logger = LOGGER.get_or_init(|| ReloadableLogger::new(&filter));
+    if log::set_logger(logger).is_ok() {
+        log::set_max_level(logger.state.read().unwrap_or_else(|e| e.into_inner()).logger.filter());
+    }
+}
+
+/// The installed logger, if `init` was called
+pub fn logger() -> Option<&'static ReloadableLogger> {
+    LOGGER.get()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    fn enabled(logger: &ReloadableLogger, target: &str, level: Level) -> bool {
+        logger.enabled(&Metadata::builder().target(target).level(level).build())
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(validate("debug").is_ok());
+        assert!(validate("info,snowgauge::metrics=warn").is_ok());
+        assert!(validate("snowgauge").is_ok());
+        assert!(validate("warn,snowgauge=trace/Raw").is_ok());
+        assert!(validate("").is_err());
+        assert!(validate("snowgauge=loud").is_err());
+        assert!(validate("=debug").is_err());
+        assert!(validate("snow gauge").is_err());
+    }
+
+    #[test]
+    fn test_set_filter() {
+        let logger = ReloadableLogger::new("info");
+        assert!(!enabled(&logger, "snowgauge", Level::Debug));
+
+        let (previous, generation) = logger.set_filter("info,snowgauge=debug").unwrap();
+        assert_eq!(previous, "info");
+        assert_eq!(logger.filter(), "info,snowgauge=debug");
+        assert!(enabled(&logger, "snowgauge", Level::Debug));
+        assert!(!enabled(&logger, "tonic", Level::Debug));
+
+        assert!(logger.set_filter("snowgauge=verbose").is_err());
+        assert_eq!(logger.filter(), "info,snowgauge=debug");
+
+        assert!(logger.restore(generation, &previous));
+        assert!(!enabled(&logger, "snowgauge", Level::Debug));
+    }
+
+    #[test]
+    fn test_restore_superseded() {
+        let logger = ReloadableLogger::new("info");
+        let (previous, generation) = logger.set_filter("debug").unwrap();
+        logger.set_filter("trace").unwrap();
+        assert!(!logger.restore(generation, &previous));
+        assert_eq!(logger.filter(), "trace");
+    }
+}
@@ -0,0 +1,286 @@
+/// LoRa serial uplink output
+///
+/// Transmits compact binary readings through a LoRa radio module attached to
+/// a second serial port, so gauges far from any IP connectivity can report to
+/// a base station. Transmission is limited to one frame per interval (the
+/// latest reading) to respect the band's duty-cycle limits.
+///
+/// Frame layout (11 bytes, little-endian):
+/// - byte 0: sync byte 0xA5
+/// - byte 1: frame format version (1)
+/// - bytes 2-3: sequence number
+/// - bytes 4-7: reading time, Unix seconds
+/// - bytes 8-9: distance in mm
+/// - byte 10: CRC-8 (polynomial 0x07) over bytes 0-9
+///
+/// With trace context on, frames are version 2 (36 bytes): the reading's
+/// trace ID, a new span ID and the trace flags, W3C's binary layout, follow
+/// the distance in bytes 10-34, and the CRC over bytes 0-34 is byte 35.
+use log::{debug, error, info, warn};
+use std::io::{Read, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+
+use crate::snowgauge::Reading;
+use crate::stream::ClientReceiver;
+use crate::trace::TraceContext;
+
+const SYNC: u8 = 0xA5;
+const FRAME_VERSION: u8 = 1;
+/// Frame format version with trace context
+const TRACE_FRAME_VERSION: u8 = 2;
+
+/// How readings are handed to the radio module
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoraMode {
+    /// Write frames as-is (transparent-mode modules)
+    Raw,
+    /// Wrap frames in an AT command built from a template
+    At,
+}
+
+impl std::str::FromStr for LoraMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "raw" => Ok(LoraMode::Raw),
+            "at" => Ok(LoraMode::At),
+            _ => Err(format!("Invalid LoRa mode '{}'. Valid options: raw, at", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for LoraMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoraMode::Raw => write!(f, "raw"),
+            LoraMode::At => write!(f, "at"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LoraConfig {
+    pub port: String,
+    pub baud_rate: u32,
+    pub mode: LoraMode,
+    /// AT command with `{len}` and `{hex}` placeholders
+    pub at_template: String,
+    /// Minimum time between transmissions
+    pub interval: Duration,
+    /// Send version 2 frames, with the reading's trace context
+    pub trace_context: bool,
+}
+
+/// CRC-8 with polynomial 0x07, initial value 0
+pub fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |mut crc, byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+/// Encode a reading as a binary uplink frame, version 2 if it carries `trace`
+pub fn encode_frame(sequence: u16, timestamp: SystemTime, distance_mm: i32, trace: Option<&TraceContext>) -> Vec<u8> {
+    let seconds = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as u32;
+    let distance = distance_mm.clamp(0, u16::MAX as i32) as u16;
+
+    let mut frame = vec![SYNC, if trace.is_some() { TRACE_FRAME_VERSION } else { FRAME_VERSION }];
+    frame.extend_from_slice(&sequence.to_le_bytes());
+    frame.extend_from_slice(&seconds.to_le_bytes());
+    frame.extend_from_slice(&distance.to_le_bytes());
+    if let Some(trace) = trace {
+        frame.extend_from_slice(&trace.trace_id);
+        frame.extend_from_slice(&trace.span_id);
+        frame.push(trace.trace_flags);
+    }
+    frame.push(crc8(&frame));
+    frame
+}
+
+/// Build an AT command by substituting the hex-encoded frame into the template
+pub fn at_command(template: &str, frame: &[u8]) -> String {
+    let hex: String = frame.iter().map(|b| format!("{:02X}", b)).collect();
+    template
+        .replace("{len}", &hex.len().to_string())
+        .replace("{hex}", &hex)
+}
+
+/// Transmit the latest reading once per interval until shutdown
+pub async fn run(
+    config: LoraConfig,
+    mut readings: ClientReceiver,
+    cancel_token: CancellationToken,
+) {
+    info!(
+        "Starting LoRa uplink on {} ({} mode, one frame per {:?})",
+        config.port, config.mode, config.interval
+    );
+
+    let mut latest: Option<Reading> = None;
+    let mut sequence: u16 = 0;
+    let mut port: Option<Box<dyn serialport::SerialPort>> = None;
+    let mut interval = time::interval(config.interval);
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = cancel_token.cancelled() => {
+                info!("LoRa uplink received shutdown signal");
+                break;
+            }
+            reading = readings.recv() => {
+                match reading {
+                    Some(Ok(reading)) => latest = Some(reading),
+                    _ => break,
+                }
+            }
+            _ = interval.tick() => {
+                let Some(reading) = latest.take() else {
+                    continue;
+                };
+
+                let timestamp = reading
+                    .timestamp
+                    .and_then(|t| SystemTime::try_from(t).ok())
+                    .unwrap_or_else(SystemTime::now);
+                // The frame is a span of the reading's trace, or starts one
+                let trace = config.trace_context.then(|| {
+                    TraceContext::parse(&reading.traceparent).map_or_else(TraceContext::new_root, |trace| trace.child())
+                });
+                let frame = encode_frame(sequence, timestamp, reading.distance_mm.round() as i32, trace.as_ref());
+                let payload = match config.mode {
+                    LoraMode::Raw => frame,
+                    LoraMode::At => format!("{}\r\n", at_command(&config.at_template, &frame)).into_bytes(),
+                };
+
+                let config_clone = config.clone();
+                let result = tokio::task::spawn_blocking(move || {
+                    let result = transmit(&mut port, &config_clone, &payload);
+                    (port, result)
+                })
+                .await;
+
+                match result {
+                    Ok((returned_port, Ok(()))) => {
+                        port = returned_port;
+                        debug!("LoRa frame {} transmitted (distance={:.2}mm)", sequence, reading.distance_mm);
+                        sequence = sequence.wrapping_add(1);
+                    }
+                    Ok((_, Err(e))) => {
+                        // Drop the port so it is reopened before the next frame
+                        port = None;
+                        error!("Error transmitting LoRa frame: {}", e);
+                    }
+                    Err(e) => {
+                        port = None;
+                        error!("LoRa transmit task panicked: {}", e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Write one payload, opening the port first if needed
+fn transmit(
+    port: &mut Option<Box<dyn serialport::SerialPort>>,
+    config: &LoraConfig,
+    payload: &[u8],
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    if port.is_none() {
+        let opened = serialport::new(&config.port, config.baud_rate)
+            .timeout(Duration::from_secs(1))
+            .open()?;
+        info!("LoRa serial port {} opened", config.port);
+        *port = Some(opened);
+    }
+    let Some(p) = port.as_mut() else {
+        return Err("LoRa serial port unavailable".into());
+    };
+
+    p.write_all(payload)?;
+    p.flush()?;
+
+    if config.mode == LoraMode::At {
+        // Modules answer with a status line such as "+OK" or "+ERR=..."
+        let mut response = [0u8; 64];
+        match p.read(&mut response) {
+            Ok(n) => {
+                let response = String::from_utf8_lossy(&response[..n]);
+                if response.contains("ERR") {
+                    warn!("LoRa module rejected frame: {}", response.trim());
+                } else {
+                    debug!("LoRa module response: {}", response.trim());
+                }
+            }
+            Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                debug!("No response from LoRa module");
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc8() {
+        // CRC-8/SMBUS check value
+        assert_eq!(crc8(b"123456789"), 0xF4);
+        assert_eq!(crc8(&[]), 0);
+    }
+
+    #[test]
+    fn test_encode_frame() {
+        let timestamp = UNIX_EPOCH + Duration::from_secs(0x6543_2100);
+        let frame = encode_frame(0x0102, timestamp, 1234, None);
+
+        assert_eq!(frame.len(), 11);
+        assert_eq!(&frame[..10], &[SYNC, FRAME_VERSION, 0x02, 0x01, 0x00, 0x21, 0x43, 0x65, 0xD2, 0x04]);
+        assert_eq!(frame[10], crc8(&frame[..10]));
+
+        // Negative and oversized distances are clamped
+        assert_eq!(&encode_frame(0, timestamp, -1, None)[8..10], &[0, 0]);
+        assert_eq!(&encode_frame(0, timestamp, 70_000, None)[8..10], &[0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn test_encode_trace_frame() {
+        let timestamp = UNIX_EPOCH + Duration::from_secs(0x6543_2100);
+        let trace = TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+        let frame = encode_frame(0x0102, timestamp, 1234, Some(&trace));
+
+        assert_eq!(frame.len(), 36);
+        assert_eq!(&frame[..2], &[SYNC, TRACE_FRAME_VERSION]);
+        assert_eq!(&frame[2..10], &encode_frame(0x0102, timestamp, 1234, None)[2..10]);
+        assert_eq!(&frame[10..26], &trace.trace_id);
+        assert_eq!(&frame[26..34], &trace.span_id);
+        assert_eq!(frame[34], 0x01);
+        assert_eq!(frame[35], crc8(&frame[..35]));
+    }
+
+    #[test]
+    fn test_at_command() {
+        let command = at_command("AT+SEND=0,{len},{hex}", &[0xA5, 0x01, 0xFF]);
+        assert_eq!(command, "AT+SEND=0,6,A501FF");
+
+        assert_eq!(at_command("radio tx {hex}", &[0x0A]), "radio tx 0A");
+    }
+
+    #[test]
+    fn test_mode_from_str() {
+        assert_eq!("RAW".parse::<LoraMode>(), Ok(LoraMode::Raw));
+        assert_eq!("at".parse::<LoraMode>(), Ok(LoraMode::At));
+        assert!("transparent".parse::<LoraMode>().is_err());
+    }
+}
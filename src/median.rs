@@ -0,0 +1,114 @@
+/// Rolling median filter
+///
+/// Each output is the median of the last `window` readings. A snowflake
+/// crossing the beam makes the MB7544 report one short reading; an average
+/// (or the exponential filter, within its rate limit) moves toward it, while
+/// the median ignores it entirely as long as fewer than half the window are
+/// spikes. A step change in the surface comes through whole after half a
+/// window.
+use std::collections::VecDeque;
+
+pub struct MedianFilter {
+    window: usize,
+    recent: VecDeque<f64>,
+    reading_count: usize,
+}
+
+impl MedianFilter {
+    pub fn new(window: usize) -> Self {
+        let window = window.max(1);
+        Self {
+            window,
+            recent: VecDeque::with_capacity(window),
+            reading_count: 0,
+        }
+    }
+
+    /// Change the window, keeping the most recent readings that still fit
+    pub fn set_window(&mut self, window: usize) {
+        self.window = window.max(1);
+        while self.recent.len() > self.window {
+            self.recent.pop_front();
+        }
+    }
+
+    /// Process a new reading, returning the median of the window so far
+    pub fn update(&mut self, raw_reading: f64) -> f64 {
+        self.reading_count += 1;
+        if self.recent.len() >= self.window {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(raw_reading);
+
+        let mut sorted: Vec<f64> = self.recent.iter().copied().collect();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        let mid = sorted.len() / 2;
+        if sorted.len().is_multiple_of(2) {
+            (sorted[mid - 1] + sorted[mid]) / 2.0
+        } else {
+            sorted[mid]
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.recent.clear();
+        self.reading_count = 0;
+    }
+
+    /// True once the window is full
+    pub fn is_initialized(&self) -> bool {
+        self.recent.len() >= self.window
+    }
+
+    pub fn reading_count(&self) -> usize {
+        self.reading_count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rejects_single_spikes() {
+        let mut filter = MedianFilter::new(5);
+        let readings = [1000.0, 1001.0, 1000.0, 300.0, 1000.0, 999.0, 1000.0, 5000.0, 1001.0, 1000.0];
+        let outputs: Vec<f64> = readings.iter().map(|&r| filter.update(r)).collect();
+        assert!(outputs[2..].iter().all(|o| (o - 1000.0).abs() <= 1.0), "{:?}", outputs);
+    }
+
+    #[test]
+    fn test_passes_step_after_half_window() {
+        let mut filter = MedianFilter::new(5);
+        for _ in 0..5 {
+            filter.update(1000.0);
+        }
+        assert!(filter.is_initialized());
+        assert_eq!(filter.update(900.0), 1000.0);
+        assert_eq!(filter.update(900.0), 1000.0);
+        assert_eq!(filter.update(900.0), 900.0);
+    }
+
+    #[test]
+    fn test_partial_window_and_reset() {
+        let mut filter = MedianFilter::new(5);
+        assert_eq!(filter.update(1000.0), 1000.0);
+        assert_eq!(filter.update(1010.0), 1005.0);
+        assert!(!filter.is_initialized());
+        filter.reset();
+        assert_eq!(filter.reading_count(), 0);
+        assert_eq!(filter.update(500.0), 500.0);
+    }
+
+    #[test]
+    fn test_set_window() {
+        let mut filter = MedianFilter::new(5);
+        for r in [1.0, 2.0, 3.0, 4.0, 5.0] {
+            filter.update(r);
+        }
+        filter.set_window(3);
+        assert!(filter.is_initialized());
+        // 4, 5 and the new reading remain
+        assert_eq!(filter.update(6.0), 5.0);
+    }
+}
@@ -0,0 +1,328 @@
+/// Per-RPC logging and request counters
+///
+/// Every gRPC call, on any listener, passes through `MetricsLayer`, which
+/// logs the method, peer and duration once the call is over and keeps
+/// counters reported by GetStationInfo, for the station named in the call's
+/// `station` metadata or the command line's. A call is over when its
+/// response body is finished or dropped, so stream durations cover the whole
+/// stream and a stream the client walks away from is logged as CANCELLED.
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use http_body::{Body, Frame, SizeHint};
+use log::info;
+use tonic::Code;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::stations::STATION_METADATA;
+
+#[derive(Debug, Default)]
+pub struct RpcMetrics {
+    requests: AtomicU64,
+    errors: AtomicU64,
+    active_streams: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RpcCounts {
+    /// Calls started, streams included
+    pub requests: u64,
+    /// Calls that ended with a status other than OK or CANCELLED
+    pub errors: u64,
+    pub active_streams: u64,
+}
+
+impl RpcMetrics {
+    pub fn counts(&self) -> RpcCounts {
+        RpcCounts {
+            requests: self.requests.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            active_streams: self.active_streams.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Streaming RPCs, counted in `active_streams` while open
+fn is_stream(method: &str) -> bool {
+    let name = method.rsplit('/').next().unwrap_or_default();
+    name.starts_with("Stream") || name == "Watch" || name == "ServerReflectionInfo"
+}
+
+/// The status in a `grpc-status` header or trailer
+fn grpc_status(headers: &http::HeaderMap) -> Option<Code> {
+    let status = headers.get("grpc-status")?.to_str().ok()?.parse::<i32>().ok()?;
+    Some(Code::from(status))
+}
+
+/// One call in progress, logged and counted when dropped
+struct Call {
+    metrics: Arc<RpcMetrics>,
+    method: String,
+    peer: String,
+    started: Instant,
+    stream: bool,
+    status: Option<Code>,
+}
+
+impl Call {
+    fn start<B>(metrics: Arc<RpcMetrics>, request: &http::Request<B>) -> Self {
+        let method = request.uri().path().to_string();
+        let stream = is_stream(&method);
+        metrics.requests.fetch_add(1, Ordering::Relaxed);
+        if stream {
+            metrics.active_streams.fetch_add(1, Ordering::Relaxed);
+        }
+        Self {
+            metrics,
+            method,
+            peer: peer(request),
+            started: Instant::now(),
+            stream,
+            status: None,
+        }
+    }
+}
+
+impl Drop for Call {
+    fn drop(&mut self) {
+        // A body dropped before its trailers means the client went away
+        let status = self.status.unwrap_or(Code::Cancelled);
+        if status != Code::Ok && status != Code::Cancelled {
+            self.metrics.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        if self.stream {
+            self.metrics.active_streams.fetch_sub(1, Ordering::Relaxed);
+        }
+        info!("gRPC {} from [{}]: {:?} after {:?}", self.method, self.peer, status, self.started.elapsed());
+    }
+}
+
+fn peer<B>(request: &http::Request<B>) -> String {
+    let extensions = request.extensions();
+    if let Some(addr) = extensions.get::<tonic::transport::server::TcpConnectInfo>().and_then(|info| info.remote_addr()) {
+        return addr.to_string();
+    }
+    #[cfg(unix)]
+    if extensions.get::<tonic::transport::server::UdsConnectInfo>().is_some() {
+        return "unix socket".to_string();
+    }
+    "unknown".to_string()
+}
+
+/// Wraps the server's routes with `MetricsService`
+#[derive(Clone)]
+pub struct MetricsLayer {
+    metrics: Arc<RpcMetrics>,
+    /// Counters of the other stations, by name
+    stations: Arc<HashMap<String, Arc<RpcMetrics>>>,
+}
+
+impl MetricsLayer {
+    /// Count calls against `metrics`, the command line station's
+    pub fn new(metrics: Arc<RpcMetrics>) -> Self {
+        Self { metrics, stations: Arc::default() }
+    }
+
+    /// Count calls naming one of `stations` in their metadata against its
+    /// counters instead
+    pub fn with_stations(self, stations: impl IntoIterator<Item = (String, Arc<RpcMetrics>)>) -> Self {
+        Self { stations: Arc::new(stations.into_iter().collect()), ..self }
+    }
+}
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = MetricsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MetricsService {
+            inner,
+            metrics: self.metrics.clone(),
+            stations: self.stations.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MetricsService<S> {
+    inner: S,
+    metrics: Arc<RpcMetrics>,
+    stations: Arc<HashMap<String, Arc<RpcMetrics>>>,
+}
+
+impl<S> MetricsService<S> {
+    /// Counters of the station a call is for; a station not served here
+    /// counts against the command line's, as its call is refused there
+    fn metrics<B>(&self, request: &http::Request<B>) -> Arc<RpcMetrics> {
+        let station = request.headers().get(STATION_METADATA).and_then(|station| station.to_str().ok());
+        station.and_then(|station| self.stations.get(station)).unwrap_or(&self.metrics).clone()
+    }
+}
+
+impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for MetricsService<S>
+where
+    S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+    ResBody: Body + Unpin + Send + 'static,
+{
+    type Response = http::Response<MetricsBody<ResBody>>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<ReqBody>) -> Self::Future {
+        let mut call = Call::start(self.metrics(&request), &request);
+        let response = self.inner.call(request);
+        Box::pin(async move {
+            match response.await {
+                Ok(response) => {
+                    // Errors before any message come back trailers-only
+                    call.status = grpc_status(response.headers());
+                    Ok(response.map(|inner| MetricsBody { inner, call }))
+                }
+                Err(e) => {
+                    call.status = Some(Code::Unknown);
+                    drop(call);
+                    Err(e)
+                }
+            }
+        })
+    }
+}
+
+/// A response body that ends its call once finished or dropped
+pub struct MetricsBody<B> {
+    inner: B,
+    call: Call,
+}
+
+impl<B: Body + Unpin> Body for MetricsBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        let frame = Pin::new(&mut this.inner).poll_frame(cx);
+        match &frame {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(status) = frame.trailers_ref().and_then(grpc_status) {
+                    this.call.status = Some(status);
+                }
+            }
+            Poll::Ready(Some(Err(_))) => this.call.status = Some(Code::Internal),
+            Poll::Ready(None) if this.call.status.is_none() => this.call.status = Some(Code::Unknown),
+            _ => {}
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use tonic::body::BoxBody;
+
+    /// Answers every call with `status`, trailers-only like tonic's errors
+    #[derive(Clone)]
+    struct Respond(Code);
+
+    impl Service<http::Request<()>> for Respond {
+        type Response = http::Response<BoxBody>;
+        type Error = Infallible;
+        type Future = std::future::Ready<Result<Self::Response, Infallible>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _request: http::Request<()>) -> Self::Future {
+            std::future::ready(Ok(tonic::Status::new(self.0, "test").into_http()))
+        }
+    }
+
+    fn request(path: &str) -> http::Request<()> {
+        http::Request::builder().uri(path).body(()).unwrap()
+    }
+
+    #[test]
+    fn test_is_stream() {
+        assert!(is_stream("/snowgauge.SnowGaugeService/StreamReading"));
+        assert!(is_stream("/snowgauge.SnowGaugeService/StreamReadingBidi"));
+        assert!(is_stream("/grpc.health.v1.Health/Watch"));
+        assert!(!is_stream("/snowgauge.SnowGaugeService/GetHistory"));
+        assert!(!is_stream("/grpc.health.v1.Health/Check"));
+    }
+
+    #[test]
+    fn test_grpc_status() {
+        let mut headers = http::HeaderMap::new();
+        assert_eq!(grpc_status(&headers), None);
+        headers.insert("grpc-status", "0".parse().unwrap());
+        assert_eq!(grpc_status(&headers), Some(Code::Ok));
+        headers.insert("grpc-status", "7".parse().unwrap());
+        assert_eq!(grpc_status(&headers), Some(Code::PermissionDenied));
+    }
+
+    #[tokio::test]
+    async fn test_counts_errors() {
+        let metrics = Arc::new(RpcMetrics::default());
+        let mut denied = MetricsLayer::new(metrics.clone()).layer(Respond(Code::PermissionDenied));
+        drop(denied.call(request("/snowgauge.SnowGaugeService/GetHistory")).await.unwrap());
+        let mut ok = MetricsLayer::new(metrics.clone()).layer(Respond(Code::Ok));
+        drop(ok.call(request("/snowgauge.SnowGaugeService/GetHistory")).await.unwrap());
+        assert_eq!(metrics.counts(), RpcCounts { requests: 2, errors: 1, active_streams: 0 });
+    }
+
+    #[tokio::test]
+    async fn test_active_streams() {
+        let metrics = Arc::new(RpcMetrics::default());
+        let mut service = MetricsLayer::new(metrics.clone()).layer(Respond(Code::Ok));
+        let first = service.call(request("/snowgauge.SnowGaugeService/StreamReading")).await.unwrap();
+        let second = service.call(request("/snowgauge.SnowGaugeService/StreamReadingBatch")).await.unwrap();
+        assert_eq!(metrics.counts().active_streams, 2);
+        drop(first);
+        assert_eq!(metrics.counts().active_streams, 1);
+
+        // Walking away from a stream isn't an error
+        let mut abandoned = MetricsLayer::new(metrics.clone()).layer(Respond(Code::Cancelled));
+        drop(abandoned.call(request("/snowgauge.SnowGaugeService/StreamReading")).await.unwrap());
+        drop(second);
+        assert_eq!(metrics.counts(), RpcCounts { requests: 3, errors: 0, active_streams: 0 });
+    }
+
+    #[tokio::test]
+    async fn test_counts_per_station() {
+        let (primary, other) = (Arc::new(RpcMetrics::default()), Arc::new(RpcMetrics::default()));
+        let layer = MetricsLayer::new(primary.clone()).with_stations([("pole-2".to_string(), other.clone())]);
+        let mut service = layer.layer(Respond(Code::Ok));
+        let for_station = |station: &str| {
+            let mut request = request("/snowgauge.SnowGaugeService/GetHistory");
+            request.headers_mut().insert(STATION_METADATA, station.parse().unwrap());
+            request
+        };
+        drop(service.call(for_station("pole-2")).await.unwrap());
+        drop(service.call(for_station("pole-2")).await.unwrap());
+        drop(service.call(request("/snowgauge.SnowGaugeService/GetHistory")).await.unwrap());
+        drop(service.call(for_station("pole-9")).await.unwrap());
+        assert_eq!(other.counts().requests, 2);
+        assert_eq!(primary.counts().requests, 2);
+    }
+}
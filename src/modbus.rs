@@ -0,0 +1,271 @@
+/// Modbus RTU level sensors
+///
+/// Industrial ultrasonic and radar level sensors report their distance in
+/// Modbus registers. The gauge polls a holding or input register, or a pair
+/// of them for 32-bit values (high word first), from one unit on the serial
+/// line, and scales the value to mm. A Modbus exception or a unit that
+/// doesn't answer rejects that reading without reopening the port.
+use std::io::{self, Read, Write};
+
+use serialport::{ClearBuffer, SerialPort};
+
+use crate::frame::{Frame, FrameError};
+use crate::source::Rangefinder;
+
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+
+/// A function code with this bit set is an exception response
+const EXCEPTION: u8 = 0x80;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegisterType {
+    Holding,
+    Input,
+}
+
+impl std::str::FromStr for RegisterType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "holding" => Ok(RegisterType::Holding),
+            "input" => Ok(RegisterType::Input),
+            _ => Err(format!("Invalid register type '{}'. Valid options: holding, input", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataType {
+    U16,
+    I16,
+    U32,
+    I32,
+    F32,
+}
+
+impl std::str::FromStr for DataType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "u16" => Ok(DataType::U16),
+            "i16" => Ok(DataType::I16),
+            "u32" => Ok(DataType::U32),
+            "i32" => Ok(DataType::I32),
+            "f32" => Ok(DataType::F32),
+            _ => Err(format!("Invalid data type '{}'. Valid options: u16, i16, u32, i32, f32", s)),
+        }
+    }
+}
+
+impl DataType {
+    fn registers(&self) -> u16 {
+        match self {
+            DataType::U16 | DataType::I16 => 1,
+            DataType::U32 | DataType::I32 | DataType::F32 => 2,
+        }
+    }
+
+    /// The value of big-endian register data
+    fn decode(&self, data: &[u8]) -> f64 {
+        match self {
+            DataType::U16 => f64::from(u16::from_be_bytes([data[0], data[1]])),
+            DataType::I16 => f64::from(i16::from_be_bytes([data[0], data[1]])),
+            DataType::U32 => f64::from(u32::from_be_bytes([data[0], data[1], data[2], data[3]])),
+            DataType::I32 => f64::from(i32::from_be_bytes([data[0], data[1], data[2], data[3]])),
+            DataType::F32 => f64::from(f32::from_be_bytes([data[0], data[1], data[2], data[3]])),
+        }
+    }
+}
+
+/// The register holding the distance, and how to read it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModbusConfig {
+    /// Unit (slave) address, 1 to 247
+    pub unit: u8,
+    pub register_type: RegisterType,
+    /// Register number, counting from 0
+    pub register: u16,
+    pub data_type: DataType,
+    /// Multiplies the value into mm
+    pub scale: f64,
+}
+
+impl ModbusConfig {
+    fn function(&self) -> u8 {
+        match self.register_type {
+            RegisterType::Holding => READ_HOLDING_REGISTERS,
+            RegisterType::Input => READ_INPUT_REGISTERS,
+        }
+    }
+
+    /// The read request, CRC included
+    fn request(&self) -> Vec<u8> {
+        let mut request = vec![self.unit, self.function()];
+        request.extend(self.register.to_be_bytes());
+        request.extend(self.data_type.registers().to_be_bytes());
+        request.extend(crc(&request).to_le_bytes());
+        request
+    }
+
+    /// Length of a normal response
+    fn response_len(&self) -> usize {
+        5 + 2 * usize::from(self.data_type.registers())
+    }
+
+    /// Distance in mm from the response to a request
+    fn parse(&self, response: &[u8]) -> Result<f64, FrameError> {
+        if response.len() < 5 || response[0] != self.unit || response[1] & !EXCEPTION != self.function() {
+            return Err(FrameError::Framing);
+        }
+        let (body, received) = response.split_at(response.len() - 2);
+        let (expected, received) = (crc(body), u16::from_le_bytes([received[0], received[1]]));
+        if expected != received {
+            return Err(FrameError::Checksum { expected, received });
+        }
+        if response[1] & EXCEPTION != 0 {
+            return Err(FrameError::Device(format!("Modbus exception: {}", exception(response[2]))));
+        }
+        let data = &body[3..];
+        if usize::from(response[2]) != data.len() || data.len() != 2 * usize::from(self.data_type.registers()) {
+            return Err(FrameError::Framing);
+        }
+        Ok(self.data_type.decode(data) * self.scale)
+    }
+}
+
+fn exception(code: u8) -> String {
+    match code {
+        0x01 => "illegal function".to_string(),
+        0x02 => "illegal data address".to_string(),
+        0x03 => "illegal data value".to_string(),
+        0x04 => "server device failure".to_string(),
+        0x06 => "server device busy".to_string(),
+        code => format!("code 0x{:02x}", code),
+    }
+}
+
+/// Modbus CRC-16, sent low byte first
+fn crc(bytes: &[u8]) -> u16 {
+    let mut crc = 0xffff;
+    for &byte in bytes {
+        crc ^= u16::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xa001 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+/// A level sensor polled over Modbus RTU
+pub struct ModbusSensor {
+    port: Box<dyn SerialPort>,
+    config: ModbusConfig,
+}
+
+impl ModbusSensor {
+    pub fn new(port: Box<dyn SerialPort>, config: ModbusConfig) -> Self {
+        Self { port, config }
+    }
+}
+
+impl Rangefinder for ModbusSensor {
+    fn range(&mut self) -> io::Result<Frame> {
+        // Drop anything left over from a late reply
+        let _ = self.port.clear(ClearBuffer::Input);
+        self.port.write_all(&self.config.request())?;
+
+        let mut response = Vec::new();
+        let mut buf = [0u8; 64];
+        while response.len() < self.config.response_len() {
+            match self.port.read(&mut buf) {
+                Ok(n) => response.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                    let distance = Err(FrameError::Device("no response from the Modbus unit".to_string()));
+                    return Ok(Frame { bytes: response, distance });
+                }
+                Err(e) => return Err(e),
+            }
+            // Exception responses are shorter
+            if response.len() >= 5 && response[1] & EXCEPTION != 0 {
+                response.truncate(5);
+                break;
+            }
+        }
+        let distance = self.config.parse(&response);
+        Ok(Frame { bytes: response, distance })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remote::{Remote, RemotePort};
+    use crate::serial::SerialConfig;
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    fn config(data_type: DataType) -> ModbusConfig {
+        ModbusConfig { unit: 1, register_type: RegisterType::Holding, register: 0, data_type, scale: 1.0 }
+    }
+
+    fn response(body: &[u8]) -> Vec<u8> {
+        let mut response = body.to_vec();
+        response.extend(crc(body).to_le_bytes());
+        response
+    }
+
+    #[test]
+    fn test_request() {
+        assert_eq!(config(DataType::U16).request(), [0x01, 0x03, 0x00, 0x00, 0x00, 0x01, 0x84, 0x0a]);
+        let input = ModbusConfig { unit: 17, register_type: RegisterType::Input, register: 0x0108, ..config(DataType::F32) };
+        assert_eq!(&input.request()[..6], [0x11, 0x04, 0x01, 0x08, 0x00, 0x02]);
+    }
+
+    #[test]
+    fn test_parse() {
+        let cm = ModbusConfig { scale: 10.0, ..config(DataType::U16) };
+        assert_eq!(cm.parse(&response(&[0x01, 0x03, 0x02, 0x00, 0xb7])), Ok(1830.0));
+        let metres = ModbusConfig { scale: 1000.0, ..config(DataType::F32) };
+        let mut body = vec![0x01, 0x03, 0x04];
+        body.extend(1.834f32.to_be_bytes());
+        assert!((metres.parse(&response(&body)).unwrap() - 1834.0).abs() < 1e-3);
+        assert_eq!(config(DataType::I32).parse(&response(&[0x01, 0x03, 0x04, 0xff, 0xff, 0xff, 0xfe])), Ok(-2.0));
+
+        let exception = response(&[0x01, 0x83, 0x02]);
+        assert_eq!(cm.parse(&exception), Err(FrameError::Device("Modbus exception: illegal data address".to_string())));
+        let mut corrupted = response(&[0x01, 0x03, 0x02, 0x00, 0xb7]);
+        corrupted[4] = 0xb8;
+        assert!(matches!(cm.parse(&corrupted), Err(FrameError::Checksum { .. })));
+        // Another unit's reply, or the wrong length
+        assert_eq!(cm.parse(&response(&[0x02, 0x03, 0x02, 0x00, 0xb7])), Err(FrameError::Framing));
+        assert_eq!(cm.parse(&response(&[0x01, 0x03, 0x04, 0x00, 0xb7, 0x00, 0x00])), Err(FrameError::Framing));
+    }
+
+    #[test]
+    fn test_poll() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let remote = Remote::parse(&format!("tcp://{}", listener.local_addr().unwrap())).unwrap();
+        let port = RemotePort::connect("test", &remote, SerialConfig::default(), Duration::from_millis(200)).unwrap();
+        let mut sensor = ModbusSensor::new(Box::new(port), config(DataType::U16));
+        let (mut stream, _) = listener.accept().unwrap();
+        let server = std::thread::spawn(move || {
+            let mut request = [0u8; 8];
+            stream.read_exact(&mut request).unwrap();
+            // Answered in two parts
+            let reply = response(&[0x01, 0x03, 0x02, 0x07, 0x2a]);
+            stream.write_all(&reply[..3]).unwrap();
+            stream.write_all(&reply[3..]).unwrap();
+            stream.read_exact(&mut request).unwrap();
+            // Kept open so the request times out
+            (request, stream)
+        });
+
+        assert_eq!(sensor.range().unwrap().distance, Ok(1834.0));
+        // The second request goes unanswered
+        assert_eq!(sensor.range().unwrap().distance, Err(FrameError::Device("no response from the Modbus unit".to_string())));
+        assert_eq!(server.join().unwrap().0, config(DataType::U16).request()[..]);
+    }
+}
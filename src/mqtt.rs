@@ -0,0 +1,335 @@
+/// Distances published to an MQTT broker
+///
+/// Some sites already have a sensor on an ESP32 or similar publishing each
+/// raw distance to a topic. The gauge subscribes to it with a minimal MQTT
+/// 3.1.1 client at QoS 0, and each message's payload, a plain number or a
+/// field of a JSON object, becomes a reading in mm. The broker is pinged
+/// while the topic is quiet so the connection stays up.
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use crate::frame::{Frame, FrameError};
+use crate::source::Rangefinder;
+
+const DEFAULT_PORT: u16 = 1883;
+
+/// How long connecting to each address may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a wait for a message lasts before handing back to the reader
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+
+// Packet types, in the high nibble of the first byte
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+
+/// The broker and topic, and how to read a distance from a message
+#[derive(Debug, Clone, PartialEq)]
+pub struct MqttConfig {
+    /// `host` or `host:port`, optionally with `mqtt://`
+    pub broker: String,
+    pub topic: String,
+    /// JSON field holding the distance, with dots between nested names
+    pub field: Option<String>,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Multiplies the value into mm
+    pub scale: f64,
+}
+
+impl MqttConfig {
+    /// The broker's `host:port`
+    fn address(&self) -> String {
+        let broker = self.broker.strip_prefix("mqtt://").unwrap_or(&self.broker).trim_end_matches('/');
+        if broker.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok()) {
+            broker.to_string()
+        } else {
+            format!("{}:{}", broker, DEFAULT_PORT)
+        }
+    }
+
+    fn connect_packet(&self) -> Vec<u8> {
+        let mut flags = 0x02; // clean session
+        let mut body = string(b"MQTT");
+        body.push(4); // protocol level 3.1.1
+        let mut payload = string(self.client_id.as_bytes());
+        if let Some(ref username) = self.username {
+            flags |= 0x80;
+            payload.extend(string(username.as_bytes()));
+        }
+        if let Some(ref password) = self.password {
+            flags |= 0x40;
+            payload.extend(string(password.as_bytes()));
+        }
+        body.push(flags);
+        body.extend((KEEP_ALIVE.as_secs() as u16).to_be_bytes());
+        body.extend(payload);
+        packet(CONNECT, &body)
+    }
+
+    fn subscribe_packet(&self) -> Vec<u8> {
+        let mut body = 1u16.to_be_bytes().to_vec();
+        body.extend(string(self.topic.as_bytes()));
+        body.push(0); // QoS 0
+        packet(SUBSCRIBE, &body)
+    }
+
+    /// Distance in mm from a message's payload
+    fn parse(&self, payload: &[u8]) -> Result<f64, FrameError> {
+        let text = String::from_utf8_lossy(payload);
+        let text = text.trim();
+        let value = match self.field {
+            None => text.parse().ok(),
+            Some(ref field) => serde_json::from_str::<serde_json::Value>(text).ok().and_then(|json| {
+                let value = json.pointer(&format!("/{}", field.replace('.', "/")))?.clone();
+                value.as_f64().or_else(|| value.as_str()?.trim().parse().ok())
+            }),
+        };
+        value.map(|value: f64| value * self.scale).ok_or_else(|| FrameError::NoDistance(text.to_string()))
+    }
+}
+
+/// A length-prefixed string
+fn string(bytes: &[u8]) -> Vec<u8> {
+    let mut string = (bytes.len() as u16).to_be_bytes().to_vec();
+    string.extend_from_slice(bytes);
+    string
+}
+
+/// A packet of `kind` with its remaining length
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![kind];
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        if len == 0 {
+            packet.push(byte);
+            break;
+        }
+        packet.push(byte | 0x80);
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+fn protocol_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// A subscription to the topic a sensor's readings are published on
+pub struct MqttSubscriber {
+    stream: TcpStream,
+    config: MqttConfig,
+    last_sent: Instant,
+}
+
+impl MqttSubscriber {
+    pub fn connect(config: &MqttConfig) -> io::Result<Self> {
+        let address = config.address();
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("{} did not resolve", address));
+        let mut connected = None;
+        for address in address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+                Ok(stream) => {
+                    connected = Some(stream);
+                    break;
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        let stream = connected.ok_or(last_error)?;
+        stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+        let mut subscriber = Self { stream, config: config.clone(), last_sent: Instant::now() };
+
+        subscriber.send(&config.connect_packet())?;
+        match subscriber.read_packet()? {
+            (CONNACK, body) if body.len() == 2 && body[1] == 0 => {}
+            (CONNACK, body) => {
+                let code = body.get(1).copied().unwrap_or_default();
+                return Err(io::Error::new(io::ErrorKind::ConnectionRefused, format!("broker refused the connection: {}", refusal(code))));
+            }
+            (kind, _) => return Err(protocol_error(format!("expected CONNACK, got packet type 0x{:02x}", kind))),
+        }
+        subscriber.send(&config.subscribe_packet())?;
+        loop {
+            match subscriber.read_packet()? {
+                (SUBACK, body) if body.get(2) == Some(&0x80) => {
+                    return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("broker refused the subscription to {}", config.topic)));
+                }
+                (SUBACK, _) => break,
+                // Retained messages can arrive ahead of the SUBACK; the next will do
+                (kind, _) if kind & 0xf0 == PUBLISH => {}
+                (kind, _) => return Err(protocol_error(format!("expected SUBACK, got packet type 0x{:02x}", kind))),
+            }
+        }
+        subscriber.stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        Ok(subscriber)
+    }
+
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.stream.write_all(packet)?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    /// The next packet's first byte and body
+    fn read_packet(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        let mut kind = [0u8];
+        self.stream.read_exact(&mut kind)?;
+        self.read_body(kind[0])
+    }
+
+    fn read_body(&mut self, kind: u8) -> io::Result<(u8, Vec<u8>)> {
+        let (mut len, mut shift) = (0usize, 0);
+        loop {
+            let mut byte = [0u8];
+            self.stream.read_exact(&mut byte)?;
+            len |= usize::from(byte[0] & 0x7f) << shift;
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+            if shift > 21 {
+                return Err(protocol_error("invalid remaining length".to_string()));
+            }
+        }
+        let mut body = vec![0u8; len];
+        self.stream.read_exact(&mut body)?;
+        Ok((kind, body))
+    }
+}
+
+/// Why CONNACK refused a connection
+fn refusal(code: u8) -> String {
+    match code {
+        1 => "unacceptable protocol version".to_string(),
+        2 => "client identifier rejected".to_string(),
+        3 => "server unavailable".to_string(),
+        4 => "bad user name or password".to_string(),
+        5 => "not authorized".to_string(),
+        code => format!("code {}", code),
+    }
+}
+
+/// The payload of a PUBLISH packet's body
+fn publish_payload(kind: u8, body: &[u8]) -> Option<&[u8]> {
+    let topic_len = usize::from(u16::from_be_bytes([*body.first()?, *body.get(1)?]));
+    // QoS 1 and 2 messages carry a packet identifier after the topic
+    let start = 2 + topic_len + if kind & 0x06 != 0 { 2 } else { 0 };
+    body.get(start..)
+}
+
+impl Rangefinder for MqttSubscriber {
+    /// Wait for the next message, or WouldBlock if none arrives in a second
+    fn range(&mut self) -> io::Result<Frame> {
+        loop {
+            if self.last_sent.elapsed() >= KEEP_ALIVE / 2 {
+                self.send(&[PINGREQ, 0])?;
+            }
+            let mut kind = [0u8];
+            match self.stream.read(&mut kind) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "broker closed the connection")),
+                Ok(_) => {}
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock, "no message"));
+                }
+                Err(e) => return Err(e),
+            }
+            let (kind, body) = self.read_body(kind[0])?;
+            match kind & 0xf0 {
+                PUBLISH => {
+                    let payload = publish_payload(kind, &body).ok_or_else(|| protocol_error("invalid PUBLISH".to_string()))?;
+                    let distance = self.config.parse(payload);
+                    return Ok(Frame { bytes: payload.to_vec(), distance });
+                }
+                PINGRESP => {}
+                kind => return Err(protocol_error(format!("unexpected packet type 0x{:02x}", kind))),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn config(broker: &str, field: Option<&str>) -> MqttConfig {
+        MqttConfig {
+            broker: broker.to_string(),
+            topic: "snow/+/distance".to_string(),
+            field: field.map(str::to_string),
+            client_id: "gauge".to_string(),
+            username: None,
+            password: None,
+            scale: 1.0,
+        }
+    }
+
+    #[test]
+    fn test_packets() {
+        assert_eq!(config("mqtt://broker.local/", None).address(), "broker.local:1883");
+        assert_eq!(config("10.0.0.9:8883", None).address(), "10.0.0.9:8883");
+        assert_eq!(packet(PINGRESP, &[]), [0xd0, 0x00]);
+        assert_eq!(&packet(PUBLISH, &[0; 200])[..3], [0x30, 0xc8, 0x01]);
+
+        let connect = MqttConfig { username: Some("u".to_string()), password: Some("p".to_string()), ..config("b", None) };
+        assert_eq!(
+            connect.connect_packet(),
+            [0x10, 23, 0, 4, b'M', b'Q', b'T', b'T', 4, 0xc2, 0, 60, 0, 5, b'g', b'a', b'u', b'g', b'e', 0, 1, b'u', 0, 1, b'p']
+        );
+        let subscribe = config("b", None).subscribe_packet();
+        assert_eq!(&subscribe[..6], [0x82, 20, 0, 1, 0, 15]);
+        assert_eq!(subscribe.last(), Some(&0));
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(config("b", None).parse(b" 1834\n"), Ok(1834.0));
+        let cm = MqttConfig { scale: 10.0, ..config("b", Some("sensor.cm")) };
+        assert_eq!(cm.parse(br#"{"sensor": {"cm": 183.4}, "rssi": -71}"#), Ok(1834.0));
+        assert_eq!(cm.parse(br#"{"sensor": {"cm": "183"}}"#), Ok(1830.0));
+        assert_eq!(cm.parse(br#"{"sensor": {}}"#), Err(FrameError::NoDistance(r#"{"sensor": {}}"#.to_string())));
+        assert!(config("b", None).parse(b"out of range").is_err());
+        assert_eq!(publish_payload(0x32, &[0, 1, b't', 0, 7, b'4', b'2']), Some(&b"42"[..]));
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let broker = listener.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut connect = [0u8; 19];
+            stream.read_exact(&mut connect).unwrap();
+            stream.write_all(&[CONNACK, 2, 0, 0]).unwrap();
+            let mut subscribe = [0u8; 22];
+            stream.read_exact(&mut subscribe).unwrap();
+            stream.write_all(&[SUBACK, 3, 0, 1, 0]).unwrap();
+            let mut body = string(b"snow/1/distance");
+            body.extend_from_slice(b"1834");
+            stream.write_all(&packet(PUBLISH, &body)).unwrap();
+            // Kept open while the topic is quiet
+            std::thread::sleep(Duration::from_millis(1500));
+            (connect, subscribe)
+        });
+
+        let mut subscriber = MqttSubscriber::connect(&config(&broker, None)).unwrap();
+        let frame = subscriber.range().unwrap();
+        assert_eq!((frame.bytes, frame.distance), (b"1834".to_vec(), Ok(1834.0)));
+        assert_eq!(subscriber.range().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        let (connect, subscribe) = server.join().unwrap();
+        assert_eq!(connect[..], config(&broker, None).connect_packet()[..]);
+        assert_eq!(subscribe[..], config(&broker, None).subscribe_packet()[..]);
+    }
+}
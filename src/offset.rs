@@ -0,0 +1,109 @@
+/// Manual offset correction
+///
+/// When a ruler or snow stake disagrees with the gauge by a steady amount,
+/// an operator can correct the output with `ApplyOffset` rather than
+/// retuning the filter or moving the baseline: the offset is added to every
+/// batch reading's distance from then on. It is saved as JSON to
+/// `--offset-file` so a correction survives restarts, with when it was set
+/// and why.
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Offset {
+    /// Added to each distance, in mm; negative when the gauge reads long
+    pub distance: f64,
+    pub set_at: SystemTime,
+    /// The operator's note, e.g. the reference it was taken against
+    pub reason: String,
+}
+
+/// On-disk representation
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OffsetFile {
+    offset_mm: f64,
+    /// Milliseconds since the Unix epoch
+    set_at: u64,
+    #[serde(default)]
+    reason: String,
+}
+
+impl Offset {
+    pub fn new(distance: f64, set_at: SystemTime, reason: String) -> Result<Self, String> {
+        if !distance.is_finite() {
+            return Err(format!("Offset must be a number of mm, got {}", distance));
+        }
+        Ok(Self { distance, set_at, reason })
+    }
+
+    pub fn apply(&self, distance: f64) -> f64 {
+        distance + self.distance
+    }
+
+    pub fn to_json(&self) -> String {
+        let file = OffsetFile {
+            offset_mm: self.distance,
+            set_at: self.set_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+            reason: self.reason.clone(),
+        };
+        // Serializing plain strings and numbers cannot fail
+        serde_json::to_string_pretty(&file).unwrap() + "\n"
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let file: OffsetFile = serde_json::from_str(json).map_err(|e| format!("invalid offset: {}", e))?;
+        Self::new(file.offset_mm, UNIX_EPOCH + Duration::from_millis(file.set_at), file.reason)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let json = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read offset {}: {}", path.display(), e))?;
+        Self::from_json(&json).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Write the offset, replacing any existing file atomically
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, self.to_json())
+            .and_then(|_| std::fs::rename(&tmp, path))
+            .map_err(|e| format!("failed to write offset {}: {}", path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offset() -> Offset {
+        Offset::new(-12.5, UNIX_EPOCH + Duration::from_millis(1_700_000_000_123), "stake at 412 mm".to_string()).unwrap()
+    }
+
+    #[test]
+    fn test_json_roundtrip() {
+        let json = offset().to_json();
+        assert!(json.contains("\"offsetMm\": -12.5"));
+        assert!(json.contains("\"reason\": \"stake at 412 mm\""));
+        assert_eq!(Offset::from_json(&json).unwrap(), offset());
+        assert!(Offset::from_json("{\"offsetMm\": 3.0, \"setAt\": 0}").is_ok());
+        assert!(Offset::from_json("not json").is_err());
+    }
+
+    #[test]
+    fn test_apply() {
+        assert_eq!(offset().apply(1500.0), 1487.5);
+        assert!(Offset::new(f64::INFINITY, SystemTime::now(), String::new()).is_err());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("snowgauge-offset-{}.json", std::process::id()));
+        offset().save(&path).unwrap();
+        assert_eq!(Offset::load(&path).unwrap(), offset());
+        std::fs::remove_file(&path).unwrap();
+        assert!(Offset::load(&path).is_err());
+    }
+}
@@ -0,0 +1,1190 @@
+/// Reading pipeline: per-reading filter stages followed by a batch stage
+///
+/// A pipeline is built from a `FilterConfig`, whose stages are given by its
+/// filter type or an explicit `FilterPipeline`. The data sources feed raw
+/// readings to the processor, which runs them through the production
+/// pipeline and, in comparison mode, through a candidate pipeline as well.
+/// Each batch result carries statistics and quality flags for its raw
+/// readings.
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::filter::{BatchFilter, Filter, FilterPipeline, Stage};
+use crate::quality::{Quality, QualityChecks, QualityMonitor};
+use crate::sensor_filter::FilterType;
+
+/// Kalman filter defaults, for the MB7544's few-mm noise and snowfall rates
+/// of up to tens of mm/hr at one reading per second
+pub const DEFAULT_PROCESS_NOISE: f64 = 0.001;
+pub const DEFAULT_MEASUREMENT_NOISE: f64 = 4.0;
+
+/// Adaptive alpha floor, for strong smoothing in wind
+pub const DEFAULT_ALPHA_MIN: f64 = 0.05;
+
+/// Median filter window: rejects up to two consecutive spikes
+pub const DEFAULT_MEDIAN_WINDOW: usize = 5;
+
+/// Mode filter bin width in mm, a few times the MB7544's 1 mm resolution
+pub const DEFAULT_MODE_BIN_WIDTH: f64 = 5.0;
+
+/// Trend filter window: five minutes at one reading per second
+pub const DEFAULT_TREND_WINDOW: usize = 300;
+
+/// Despiking window: long enough for a stable median at one reading per second
+pub const DEFAULT_DESPIKE_WINDOW: usize = 15;
+
+/// Filter and batching parameters for one pipeline
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterConfig {
+    pub filter_type: FilterType,
+    /// Stages to run in place of those of `filter_type`
+    pub pipeline: Option<FilterPipeline>,
+    /// Exponential filter initialization period (number of readings)
+    pub init_period: usize,
+    /// Exponential filter rate limit (maximum change per reading in mm)
+    pub rate_limit: f64,
+    /// Exponential filter smoothing factor; with adaptive alpha, its value
+    /// when recent readings agree
+    pub alpha: f64,
+    /// Standard deviation (mm) of recent raw readings at which the adaptive
+    /// alpha is midway between `alpha` and `alpha_min`; 0 keeps alpha fixed
+    pub adaptive_noise: f64,
+    /// Smallest adaptive alpha, approached as recent readings spread out
+    pub alpha_min: f64,
+    /// Percentage trimmed from each end of a batch (trimmed-mean modes)
+    pub trim_percentage: f64,
+    /// Number of readings collected before averaging
+    pub batch_size: usize,
+    /// Readings between batch results; below `batch_size`, each result
+    /// covers the last `batch_size` readings (a sliding window). 0 means
+    /// `batch_size`, for back-to-back batches
+    pub batch_step: usize,
+    /// Kalman filter variance of the change in rate between readings (mm² per reading⁴)
+    pub process_noise: f64,
+    /// Kalman filter variance of a single reading (mm²)
+    pub measurement_noise: f64,
+    /// Median filter window (number of readings)
+    pub median_window: usize,
+    /// Width in mm of the bins a batch is counted into (mode filter)
+    pub mode_bin_width: f64,
+    /// Readings the trend filter fits its line to
+    pub trend_window: usize,
+    /// Scaled MADs from the rolling median beyond which a raw reading is
+    /// discarded before filtering; 0 disables despiking
+    pub despike_threshold: f64,
+    /// Raw readings the despiking median and MAD are taken over
+    pub despike_window: usize,
+    /// Shortest valid raw reading in mm; shorter ones are discarded before
+    /// filtering. 0 leaves the range open at this end
+    pub min_distance: f64,
+    /// Longest valid raw reading in mm; 0 leaves the range open at this end
+    pub max_distance: f64,
+    /// Batch results within this many mm of the last emitted value emit
+    /// that value again; 0 emits every result as is
+    pub dead_band: f64,
+}
+
+impl Default for FilterConfig {
+    /// The command line defaults
+    fn default() -> Self {
+        Self {
+            filter_type: FilterType::Both,
+            pipeline: None,
+            init_period: 40,
+            rate_limit: 1.0,
+            alpha: 0.2,
+            adaptive_noise: 0.0,
+            alpha_min: DEFAULT_ALPHA_MIN,
+            trim_percentage: 0.15,
+            batch_size: 30,
+            batch_step: 0,
+            process_noise: DEFAULT_PROCESS_NOISE,
+            measurement_noise: DEFAULT_MEASUREMENT_NOISE,
+            median_window: DEFAULT_MEDIAN_WINDOW,
+            mode_bin_width: DEFAULT_MODE_BIN_WIDTH,
+            trend_window: DEFAULT_TREND_WINDOW,
+            despike_threshold: 0.0,
+            despike_window: DEFAULT_DESPIKE_WINDOW,
+            min_distance: 0.0,
+            max_distance: 0.0,
+            dead_band: 0.0,
+        }
+    }
+}
+
+impl FilterConfig {
+    /// Check parameters are within their supported ranges
+    pub fn validate(&self) -> Result<(), String> {
+        if self.trim_percentage < 0.0 || self.trim_percentage > 0.5 {
+            return Err(format!(
+                "trim-percentage must be between 0.0 and 0.5, got {}",
+                self.trim_percentage
+            ));
+        }
+        if self.batch_size < 10 {
+            return Err(format!("batch-size must be at least 10, got {}", self.batch_size));
+        }
+        if self.batch_step > self.batch_size {
+            return Err(format!(
+                "batch-step must not be more than batch-size ({}), got {}",
+                self.batch_size, self.batch_step
+            ));
+        }
+        if !self.dead_band.is_finite() || self.dead_band < 0.0 {
+            return Err(format!("dead-band must not be negative, got {}", self.dead_band));
+        }
+        if !self.despike_threshold.is_finite() || self.despike_threshold < 0.0 {
+            return Err(format!("despike-threshold must not be negative, got {}", self.despike_threshold));
+        }
+        for (name, bound) in [("min-distance", self.min_distance), ("max-distance", self.max_distance)] {
+            if !bound.is_finite() || bound < 0.0 {
+                return Err(format!("{} must not be negative, got {}", name, bound));
+            }
+        }
+        if self.min_distance > 0.0 && self.max_distance > 0.0 && self.min_distance >= self.max_distance {
+            return Err(format!(
+                "min-distance must be below max-distance, got {} and {}",
+                self.min_distance, self.max_distance
+            ));
+        }
+        self.stages().iter().try_for_each(|stage| stage.validate(self))
+    }
+
+    /// The stages readings go through: the explicit pipeline, or those of
+    /// the filter type after range clamping and despiking if they are enabled
+    pub fn stages(&self) -> FilterPipeline {
+        if let Some(ref pipeline) = self.pipeline {
+            return pipeline.clone();
+        }
+        let mut pipeline = FilterPipeline::for_type(self.filter_type);
+        let mut discard = Vec::new();
+        if self.min_distance > 0.0 || self.max_distance > 0.0 {
+            discard.push(Stage::Clamp);
+        }
+        if self.despike_threshold > 0.0 {
+            discard.push(Stage::Hampel);
+        }
+        pipeline.stages.splice(0..0, discard);
+        pipeline
+    }
+
+    /// Readings between batch results
+    pub fn step(&self) -> usize {
+        match self.batch_step {
+            0 => self.batch_size,
+            step => step.min(self.batch_size),
+        }
+    }
+
+    /// True if batch results overlap
+    pub fn is_sliding(&self) -> bool {
+        self.step() < self.batch_size
+    }
+
+    /// True if this configuration applies the per-reading exponential filter
+    pub fn uses_exponential(&self) -> bool {
+        self.stages().contains(Stage::Ema)
+    }
+}
+
+/// Summary of the raw readings behind a batch result
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BatchStats {
+    pub count: usize,
+    pub min: f64,
+    pub max: f64,
+    /// Sample standard deviation; 0 for fewer than two readings
+    pub std_dev: f64,
+    /// Raw readings discarded as spikes, included in the figures above
+    pub rejected: usize,
+    /// Raw readings discarded as outside the valid range, also included
+    pub out_of_range: usize,
+    /// Raw readings discarded as reporting no target, also included
+    pub no_target: usize,
+}
+
+impl BatchStats {
+    pub fn from_raw(raw: &[f64]) -> Self {
+        if raw.is_empty() {
+            return Self::default();
+        }
+        let n = raw.len() as f64;
+        let mean = raw.iter().sum::<f64>() / n;
+        let std_dev = if raw.len() < 2 {
+            0.0
+        } else {
+            (raw.iter().map(|r| (r - mean) * (r - mean)).sum::<f64>() / (n - 1.0)).sqrt()
+        };
+        Self {
+            count: raw.len(),
+            min: raw.iter().copied().fold(f64::INFINITY, f64::min),
+            max: raw.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            std_dev,
+            rejected: 0,
+            out_of_range: 0,
+            no_target: 0,
+        }
+    }
+}
+
+/// Raw readings behind a pipeline's batches since startup, and how many
+/// were discarded; shared with GetStationInfo
+#[derive(Debug, Default)]
+pub struct RejectionCounters {
+    readings: AtomicU64,
+    out_of_range: AtomicU64,
+    spikes: AtomicU64,
+    no_target: AtomicU64,
+    rate_limited: AtomicU64,
+    trimmed: AtomicU64,
+    warming_up: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RejectionCounts {
+    pub readings: u64,
+    /// Outside --min-distance and --max-distance
+    pub out_of_range: u64,
+    /// Discarded by the hampel stage
+    pub spikes: u64,
+    /// At the sensor's maximum range, reported when no echo comes back
+    pub no_target: u64,
+    /// Held back by the exponential stage's rate limit
+    pub rate_limited: u64,
+    /// Left out of a batch result by a trimmed-mean batch stage
+    pub trimmed: u64,
+    /// Accepted while a per-reading stage was still initializing
+    pub warming_up: u64,
+}
+
+impl RejectionCounters {
+    /// Add the readings behind a batch result
+    pub fn record(&self, tally: &RejectionCounts) {
+        self.readings.fetch_add(tally.readings, Ordering::Relaxed);
+        self.out_of_range.fetch_add(tally.out_of_range, Ordering::Relaxed);
+        self.spikes.fetch_add(tally.spikes, Ordering::Relaxed);
+        self.no_target.fetch_add(tally.no_target, Ordering::Relaxed);
+        self.rate_limited.fetch_add(tally.rate_limited, Ordering::Relaxed);
+        self.trimmed.fetch_add(tally.trimmed, Ordering::Relaxed);
+        self.warming_up.fetch_add(tally.warming_up, Ordering::Relaxed);
+    }
+
+    pub fn counts(&self) -> RejectionCounts {
+        RejectionCounts {
+            readings: self.readings.load(Ordering::Relaxed),
+            out_of_range: self.out_of_range.load(Ordering::Relaxed),
+            spikes: self.spikes.load(Ordering::Relaxed),
+            no_target: self.no_target.load(Ordering::Relaxed),
+            rate_limited: self.rate_limited.load(Ordering::Relaxed),
+            trimmed: self.trimmed.load(Ordering::Relaxed),
+            warming_up: self.warming_up.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Result of averaging a completed batch
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchResult {
+    pub average: f64,
+    /// Number of readings in the batch, not counting discarded spikes
+    pub count: usize,
+    /// Number of readings trimmed from each end (trimmed-mean modes only)
+    pub trimmed: usize,
+    pub quality: Quality,
+    /// Spread of the batch's raw (unfiltered) readings
+    pub stats: BatchStats,
+    /// Raw readings since the previous result; the same readings as `stats`
+    /// unless batches overlap
+    pub tally: RejectionCounts,
+}
+
+/// What became of a raw reading in the window
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Outcome {
+    /// Passed every per-reading stage, with this value
+    Accepted(f64),
+    Spike,
+    OutOfRange,
+    NoTarget,
+}
+
+pub struct Pipeline {
+    config: FilterConfig,
+    stages: Vec<(Stage, Box<dyn Filter>)>,
+    batch_filter: Box<dyn BatchFilter>,
+    /// Last per-reading value, returned in place of a discarded reading
+    last_filtered: Option<f64>,
+    /// Last batch result value emitted, for the dead-band
+    last_emitted: Option<f64>,
+    /// Raw readings of the current batch and what became of them, for the
+    /// batch stage, its statistics and the quality checks
+    window: VecDeque<(f64, Outcome)>,
+    /// Readings accepted since the last batch result
+    since_result: usize,
+    /// Raw readings since the last batch result
+    tally: RejectionCounts,
+    quality: QualityMonitor,
+    /// Trim percentage for the current batch, set while it is collected in high wind
+    high_wind: Option<f64>,
+}
+
+impl Pipeline {
+    pub fn new(config: FilterConfig) -> Self {
+        Self::with_quality_checks(config, QualityChecks::default())
+    }
+
+    pub fn with_quality_checks(config: FilterConfig, checks: QualityChecks) -> Self {
+        let pipeline = config.stages();
+        Self {
+            stages: pipeline.stages.iter().map(|&stage| (stage, stage.build(&config))).collect(),
+            batch_filter: pipeline.batch.build_batch(),
+            config,
+            last_filtered: None,
+            last_emitted: None,
+            window: VecDeque::new(),
+            since_result: 0,
+            tally: RejectionCounts::default(),
+            quality: QualityMonitor::new(checks),
+            high_wind: None,
+        }
+    }
+
+    /// Discard the partial batch and filter state, e.g. after the sensor was
+    /// powered down and earlier readings no longer describe the surface
+    pub fn reset(&mut self) {
+        for (_, filter) in self.stages.iter_mut() {
+            filter.reset();
+        }
+        self.last_filtered = None;
+        self.last_emitted = None;
+        self.restart_batch();
+        self.quality.reset();
+    }
+
+    fn restart_batch(&mut self) {
+        self.window.clear();
+        self.since_result = 0;
+        self.tally = RejectionCounts::default();
+        self.high_wind = None;
+    }
+
+    /// Mark the current batch as collected in high wind: its result is
+    /// flagged, and a trimmed-mean batch stage trims at least
+    /// `trim_percentage` from each end
+    pub fn set_high_wind(&mut self, trim_percentage: f64) {
+        self.high_wind = Some(trim_percentage);
+    }
+
+    /// Switch to `config`, keeping the filter state and partial batch where
+    /// they still apply
+    ///
+    /// Stages in both configurations keep their state and take the new
+    /// parameters. Adding or removing a stage that changes readings, or
+    /// changing the batch stage, starts the batch over. A partial batch
+    /// already at or over a smaller batch size is averaged with the next
+    /// reading.
+    pub fn reconfigure(&mut self, config: FilterConfig) {
+        let pipeline = config.stages();
+        let mut restart = pipeline.batch != self.config.stages().batch;
+        let mut previous = std::mem::take(&mut self.stages);
+        for &stage in &pipeline.stages {
+            let filter = match previous.iter().position(|(s, _)| *s == stage) {
+                Some(i) => {
+                    let (_, mut filter) = previous.remove(i);
+                    filter.set_params(&config);
+                    filter
+                }
+                None => {
+                    restart |= !stage.discards_only();
+                    stage.build(&config)
+                }
+            };
+            self.stages.push((stage, filter));
+        }
+        restart |= previous.iter().any(|(stage, _)| !stage.discards_only());
+
+        if restart {
+            self.last_filtered = None;
+            self.restart_batch();
+        }
+        self.batch_filter = pipeline.batch.build_batch();
+        self.config = config;
+    }
+
+    pub fn config(&self) -> &FilterConfig {
+        &self.config
+    }
+
+    /// Readings seen by the last per-reading stage, if there is one
+    pub fn reading_count(&self) -> Option<usize> {
+        self.stages.last().map(|(_, filter)| filter.reading_count())
+    }
+
+    /// True once every per-reading stage has settled
+    pub fn is_initialized(&self) -> bool {
+        self.stages.iter().all(|(_, filter)| filter.is_initialized())
+    }
+
+    /// True while the sensor has reported no target for a run of readings
+    pub fn target_lost(&self) -> bool {
+        self.quality.target_lost()
+    }
+
+    /// Feed one raw reading through the pipeline
+    ///
+    /// Returns the per-reading filtered value, and the batch result once
+    /// `batch_size` readings have been collected, or with a batch step,
+    /// every `batch_step` readings after that over the last `batch_size`. A
+    /// discarded reading doesn't count toward the batch, and its per-reading
+    /// value is the last one. Readings at the sensor's maximum range report
+    /// no target rather than a distance, and are discarded ahead of every
+    /// stage. Losing the target starts the batch over, and the readings
+    /// while it stays lost are counted but left out of the next batch.
+    pub fn push(&mut self, raw: f64) -> (f64, Option<BatchResult>) {
+        let was_lost = self.target_lost();
+        self.quality.record(raw);
+        self.tally.readings += 1;
+        let outcome = self.filter(raw);
+        if self.target_lost() {
+            // Otherwise the window keeps every reading of an outage, and the
+            // first batch after it describes hours of no-target readings
+            if !was_lost {
+                self.window.clear();
+                self.since_result = 0;
+            }
+            return (self.last_filtered.unwrap_or(raw), None);
+        }
+        self.window.push_back((raw, outcome));
+        let Outcome::Accepted(filtered) = outcome else {
+            return (self.last_filtered.unwrap_or(raw), None);
+        };
+        if !self.is_initialized() {
+            self.tally.warming_up += 1;
+        }
+        self.last_filtered = Some(filtered);
+        self.since_result += 1;
+
+        let sliding = self.config.is_sliding();
+        if sliding {
+            self.trim_window();
+        }
+        let mut batch: Vec<f64> = self
+            .window
+            .iter()
+            .filter_map(|(_, outcome)| match outcome {
+                Outcome::Accepted(value) => Some(*value),
+                _ => None,
+            })
+            .collect();
+        if batch.len() < self.config.batch_size || self.since_result < self.config.step() {
+            return (filtered, None);
+        }
+
+        let mut result = match self.high_wind {
+            Some(trim_percentage) if trim_percentage > self.config.trim_percentage => {
+                let windy = FilterConfig { trim_percentage, ..self.config.clone() };
+                self.batch_filter.aggregate(&mut batch, &windy)
+            }
+            _ => self.batch_filter.aggregate(&mut batch, &self.config),
+        };
+        let raw: Vec<f64> = self.window.iter().map(|(raw, _)| *raw).collect();
+        let count = |kind: Outcome| self.window.iter().filter(|(_, outcome)| *outcome == kind).count();
+        result.stats = BatchStats {
+            rejected: count(Outcome::Spike),
+            out_of_range: count(Outcome::OutOfRange),
+            no_target: count(Outcome::NoTarget),
+            ..BatchStats::from_raw(&raw)
+        };
+        let warming_up = !self.is_initialized();
+        result.quality = self.quality.assess(&raw, &result.stats, result.average, warming_up);
+        if self.high_wind.take().is_some() {
+            result.quality.insert(Quality::HIGH_WIND);
+        }
+        result.average = self.apply_dead_band(result.average);
+        self.tally.trimmed += 2 * result.trimmed as u64;
+        result.tally = std::mem::take(&mut self.tally);
+        self.since_result = 0;
+        if !sliding {
+            self.window.clear();
+        }
+
+        (filtered, Some(result))
+    }
+
+    /// The value to emit for a batch result: the last one emitted, unless
+    /// the result has moved further than the dead-band from it
+    fn apply_dead_band(&mut self, average: f64) -> f64 {
+        match self.last_emitted {
+            Some(last) if (average - last).abs() <= self.config.dead_band => last,
+            _ => {
+                self.last_emitted = Some(average);
+                average
+            }
+        }
+    }
+
+    /// Run a raw reading through the per-reading stages
+    fn filter(&mut self, raw: f64) -> Outcome {
+        if self.quality.is_no_target(raw) {
+            self.tally.no_target += 1;
+            return Outcome::NoTarget;
+        }
+        let mut filtered = raw;
+        for (stage, filter) in self.stages.iter_mut() {
+            match filter.update(filtered) {
+                Some(value) => {
+                    filtered = value;
+                    if filter.was_rate_limited() {
+                        self.tally.rate_limited += 1;
+                    }
+                }
+                None if *stage == Stage::Clamp => {
+                    self.tally.out_of_range += 1;
+                    return Outcome::OutOfRange;
+                }
+                None => {
+                    self.tally.spikes += 1;
+                    return Outcome::Spike;
+                }
+            }
+        }
+        Outcome::Accepted(filtered)
+    }
+
+    /// Drop the oldest accepted readings, with the discarded readings that
+    /// followed them, until the window holds `batch_size` accepted readings
+    fn trim_window(&mut self) {
+        let is_accepted = |entry: &(f64, Outcome)| matches!(entry.1, Outcome::Accepted(_));
+        let accepted = self.window.iter().filter(|entry| is_accepted(entry)).count();
+        let mut excess = accepted.saturating_sub(self.config.batch_size);
+        if excess == 0 {
+            return;
+        }
+        while excess > 0 {
+            if self.window.pop_front().is_some_and(|entry| is_accepted(&entry)) {
+                excess -= 1;
+            }
+        }
+        while self.window.front().is_some_and(|entry| !is_accepted(entry)) {
+            self.window.pop_front();
+        }
+    }
+}
+
+/// Average the batch
+pub(crate) fn mean(batch: &[f64]) -> BatchResult {
+    let n = batch.len();
+    BatchResult {
+        average: batch.iter().sum::<f64>() / n as f64,
+        count: n,
+        trimmed: 0,
+        quality: Quality::OK,
+        stats: BatchStats::default(),
+        tally: RejectionCounts::default(),
+    }
+}
+
+/// Sort the batch and average it after discarding `trim_percentage` from each end
+pub(crate) fn trimmed_mean(batch: &mut [f64], trim_percentage: f64) -> BatchResult {
+    let n = batch.len();
+
+    // Sort with NaN-safe comparison
+    // NaN values are sorted to the end, treating them as larger than any number
+    batch.sort_by(|a, b| {
+        a.partial_cmp(b).unwrap_or_else(|| {
+            match (a.is_nan(), b.is_nan()) {
+                (false, true) => std::cmp::Ordering::Less,
+                (true, false) => std::cmp::Ordering::Greater,
+                _ => std::cmp::Ordering::Equal,
+            }
+        })
+    });
+
+    // 15% trim on each end removes ~4-5 readings from each tail (8-10 total from batch of 30)
+    // This accounts for sensor noise spikes and environmental interference
+    // while preserving enough data points for statistical validity
+    let trim = (trim_percentage * n as f64) as usize;
+
+    let trimmed = if n > 2 * trim { &batch[trim..n - trim] } else { &batch[..] };
+
+    BatchResult {
+        average: trimmed.iter().sum::<f64>() / trimmed.len() as f64,
+        count: n,
+        trimmed: trim,
+        quality: Quality::OK,
+        stats: BatchStats::default(),
+        tally: RejectionCounts::default(),
+    }
+}
+
+/// Count the batch into bins `bin_width` mm wide and take the center of the
+/// most populated one
+///
+/// MaxBotix recommend a mode filter for their sensors: the readings of a
+/// still surface pile up in one or two bins whatever the outliers do. Ties
+/// go to the bin nearest the batch median, and NaN readings are ignored.
+pub(crate) fn modal_bin(batch: &mut [f64], bin_width: f64) -> BatchResult {
+    let n = batch.len();
+    batch.sort_by(|a, b| a.total_cmp(b));
+    let readings: Vec<f64> = batch.iter().copied().filter(|r| !r.is_nan()).collect();
+    let median = readings.get(readings.len() / 2).copied().unwrap_or(f64::NAN);
+
+    // The batch is sorted, so each bin's readings are consecutive
+    let mut best: Option<(usize, f64)> = None;
+    let mut i = 0;
+    while i < readings.len() {
+        let bin = (readings[i] / bin_width).floor();
+        let count = readings[i..].iter().take_while(|r| (*r / bin_width).floor() == bin).count();
+        let center = (bin + 0.5) * bin_width;
+        let better = match best {
+            None => true,
+            Some((best_count, best_center)) => {
+                count > best_count || (count == best_count && (center - median).abs() < (best_center - median).abs())
+            }
+        };
+        if better {
+            best = Some((count, center));
+        }
+        i += count;
+    }
+
+    BatchResult {
+        average: best.map_or(f64::NAN, |(_, center)| center),
+        count: n,
+        trimmed: 0,
+        quality: Quality::OK,
+        stats: BatchStats::default(),
+        tally: RejectionCounts::default(),
+    }
+}
+
+/// Running statistics on the difference between two pipelines' per-reading values
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Divergence {
+    samples: u64,
+    sum_difference: f64,
+    sum_abs_difference: f64,
+    sum_squared_difference: f64,
+    max_abs_difference: f64,
+}
+
+impl Divergence {
+    /// Record one pair of filtered values from the same raw reading
+    pub fn record(&mut self, primary: f64, candidate: f64) {
+        let difference = candidate - primary;
+        self.samples += 1;
+        self.sum_difference += difference;
+        self.sum_abs_difference += difference.abs();
+        self.sum_squared_difference += difference * difference;
+        self.max_abs_difference = self.max_abs_difference.max(difference.abs());
+    }
+
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    /// Mean of (candidate - primary); positive means the candidate reads longer
+    pub fn mean_difference(&self) -> f64 {
+        self.mean(self.sum_difference)
+    }
+
+    pub fn mean_abs_difference(&self) -> f64 {
+        self.mean(self.sum_abs_difference)
+    }
+
+    pub fn rms_difference(&self) -> f64 {
+        self.mean(self.sum_squared_difference).sqrt()
+    }
+
+    pub fn max_abs_difference(&self) -> f64 {
+        self.max_abs_difference
+    }
+
+    fn mean(&self, sum: f64) -> f64 {
+        if self.samples == 0 {
+            0.0
+        } else {
+            sum / self.samples as f64
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stages(pipeline: &Pipeline) -> Vec<Stage> {
+        pipeline.stages.iter().map(|(stage, _)| *stage).collect()
+    }
+
+    fn config(filter_type: FilterType) -> FilterConfig {
+        FilterConfig {
+            filter_type,
+            batch_size: 10,
+            ..FilterConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_batch_emitted_after_batch_size() {
+        let mut pipeline = Pipeline::new(config(FilterType::None));
+        for _ in 0..9 {
+            assert!(pipeline.push(1000.0).1.is_none());
+        }
+        let (filtered, result) = pipeline.push(1000.0);
+        assert_eq!(filtered, 1000.0);
+        let stats = BatchStats { count: 10, min: 1000.0, max: 1000.0, std_dev: 0.0, rejected: 0, out_of_range: 0, no_target: 0 };
+        let tally = RejectionCounts { readings: 10, ..RejectionCounts::default() };
+        assert_eq!(result, Some(BatchResult { average: 1000.0, count: 10, trimmed: 0, quality: Quality::OK, stats, tally }));
+
+        // Batch starts over
+        assert!(pipeline.push(1000.0).1.is_none());
+    }
+
+    #[test]
+    fn test_trimmed_mean_discards_spikes() {
+        let mut pipeline = Pipeline::new(config(FilterType::TrimmedMean));
+        let readings = [1000.0, 1001.0, 999.0, 4999.0, 1000.0, 1000.0, 1001.0, 999.0, 0.0, 1000.0];
+        let result = readings.iter().filter_map(|r| pipeline.push(*r).1).next().unwrap();
+        assert_eq!(result.trimmed, 1);
+        assert!((result.average - 1000.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_trimmed_mean_handles_nan() {
+        let mut batch = vec![1.0, f64::NAN, 2.0, 3.0];
+        let result = trimmed_mean(&mut batch, 0.25);
+        assert!(batch[3].is_nan());
+        assert_eq!(result.average, 2.5);
+    }
+
+    #[test]
+    fn test_mode_takes_busiest_bin() {
+        let mut pipeline = Pipeline::new(config(FilterType::Mode));
+        assert!(pipeline.reading_count().is_none());
+        let readings = [1001.0, 1003.0, 300.0, 1012.0, 1004.0, 1000.0, 4999.0, 1013.0, 1002.0, 1011.0];
+        let result = readings.iter().filter_map(|r| pipeline.push(*r).1).next().unwrap();
+        assert_eq!(result.average, 1002.5);
+        assert_eq!(result.count, 10);
+
+        // Ties go to the bin nearest the median
+        let mut batch = vec![990.0, 991.0, 1000.0, 1001.0, 1010.0, 1011.0];
+        assert_eq!(modal_bin(&mut batch, 5.0).average, 1002.5);
+        let mut batch = vec![1006.0, f64::NAN, 1007.0, 1000.0];
+        assert_eq!(modal_bin(&mut batch, 5.0).average, 1007.5);
+        assert!(modal_bin(&mut [f64::NAN], 5.0).average.is_nan());
+    }
+
+    #[test]
+    fn test_exponential_applied_per_reading() {
+        let mut pipeline = Pipeline::new(config(FilterType::Exponential));
+        pipeline.push(1000.0);
+        let (filtered, _) = pipeline.push(1010.0);
+        assert_eq!(filtered, 1001.0);
+        assert!(pipeline.reading_count().is_some());
+        assert!(Pipeline::new(config(FilterType::TrimmedMean)).reading_count().is_none());
+    }
+
+    #[test]
+    fn test_tally_filter_internals() {
+        let mut pipeline = Pipeline::new(FilterConfig { init_period: 2, ..config(FilterType::Both) });
+        pipeline.push(1000.0);
+        pipeline.push(1010.0);
+        let result = (0..8).find_map(|_| pipeline.push(1001.0).1).unwrap();
+        // One reading before the filter settled, one held back, and 15% of 10 trimmed from each end
+        let tally = RejectionCounts { readings: 10, rate_limited: 1, trimmed: 2, warming_up: 1, ..RejectionCounts::default() };
+        assert_eq!(result.tally, tally);
+    }
+
+    #[test]
+    fn test_kalman_applied_per_reading() {
+        let mut pipeline = Pipeline::new(config(FilterType::Kalman));
+        assert_eq!(pipeline.push(1000.0).0, 1000.0);
+        let (filtered, _) = pipeline.push(1010.0);
+        assert!(filtered > 1000.0 && filtered < 1010.0);
+        assert_eq!(stages(&pipeline), [Stage::Kalman]);
+
+        // Noise parameters change in place
+        pipeline.reconfigure(FilterConfig { measurement_noise: 1.0, ..config(FilterType::Kalman) });
+        assert_eq!(pipeline.reading_count().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_median_applied_per_reading() {
+        let mut pipeline = Pipeline::new(config(FilterType::Median));
+        let readings = [1000.0, 1001.0, 999.0, 4999.0, 1000.0, 1000.0, 300.0, 999.0, 1001.0, 1000.0];
+        let filtered: Vec<f64> = readings.iter().map(|r| pipeline.push(*r).0).collect();
+        assert!(filtered.iter().all(|f| (f - 1000.0).abs() <= 1.0), "{:?}", filtered);
+        assert_eq!(stages(&pipeline), [Stage::Median]);
+
+        // A smaller window applies in place
+        pipeline.reconfigure(FilterConfig { median_window: 3, ..config(FilterType::Median) });
+        assert_eq!(pipeline.reading_count().unwrap(), 10);
+        assert!(pipeline.is_initialized());
+    }
+
+    #[test]
+    fn test_trend_applied_per_reading() {
+        let mut pipeline = Pipeline::new(FilterConfig { trend_window: 10, ..config(FilterType::Trend) });
+        // A steady ramp comes through as is, spikes and all
+        let filtered: Vec<f64> = (0..15)
+            .map(|i| pipeline.push(if i == 12 { 300.0 } else { 1000.0 - i as f64 }).0)
+            .collect();
+        assert_eq!(&filtered[..12], (0..12).map(|i| 1000.0 - i as f64).collect::<Vec<_>>());
+        assert_eq!(&filtered[12..], [988.0, 987.0, 986.0]);
+        assert_eq!(stages(&pipeline), [Stage::Trend]);
+        assert!(pipeline.is_initialized());
+
+        pipeline.reconfigure(FilterConfig { trend_window: 5, ..config(FilterType::Trend) });
+        assert_eq!(pipeline.reading_count().unwrap(), 15);
+    }
+
+    #[test]
+    fn test_despiking_keeps_spikes_out_of_filter() {
+        let despiking = FilterConfig { despike_threshold: 3.0, despike_window: 9, ..config(FilterType::Exponential) };
+        let mut pipeline = Pipeline::new(despiking.clone());
+        let mut results = Vec::new();
+        for i in 0..12 {
+            let raw = if i == 5 || i == 8 { 300.0 } else { 1000.0 + (i % 2) as f64 };
+            let (filtered, result) = pipeline.push(raw);
+            assert!((filtered - 1000.0).abs() <= 1.0, "reading {}: {}", i, filtered);
+            results.extend(result);
+        }
+        // The two spikes don't count toward the batch, but show in its stats
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].count, 10);
+        assert_eq!(results[0].stats.count, 12);
+        assert_eq!(results[0].stats.rejected, 2);
+        assert_eq!(results[0].stats.min, 300.0);
+
+        // Turning despiking off lets the next spike through
+        pipeline.reconfigure(FilterConfig { despike_threshold: 0.0, ..despiking });
+        let before = pipeline.push(1000.0).0;
+        assert_eq!(pipeline.push(300.0).0, before - 1.0);
+    }
+
+    #[test]
+    fn test_clamp_discards_out_of_range() {
+        let clamped = FilterConfig { min_distance: 500.0, max_distance: 4900.0, ..config(FilterType::Kalman) };
+        assert!(clamped.validate().is_ok());
+        let mut pipeline = Pipeline::new(clamped.clone());
+        assert_eq!(stages(&pipeline), [Stage::Clamp, Stage::Kalman]);
+
+        let mut results = Vec::new();
+        for i in 0..13 {
+            let raw = match i {
+                3 => 4950.0,
+                6 | 7 => 200.0,
+                _ => 1000.0,
+            };
+            let (filtered, result) = pipeline.push(raw);
+            assert_eq!(filtered, 1000.0, "reading {}", i);
+            results.extend(result);
+        }
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].count, 10);
+        assert_eq!(results[0].average, 1000.0);
+        assert_eq!((results[0].stats.count, results[0].stats.out_of_range, results[0].stats.rejected), (13, 3, 0));
+        assert_eq!((results[0].stats.min, results[0].stats.max), (200.0, 4950.0));
+
+        let counters = RejectionCounters::default();
+        counters.record(&results[0].tally);
+        assert_eq!(counters.counts(), RejectionCounts {
+            readings: 13,
+            out_of_range: 3,
+            spikes: 0,
+            no_target: 0,
+            rate_limited: 0,
+            trimmed: 0,
+            // Still within the Kalman stage's initialization period
+            warming_up: 10,
+        });
+
+        // Clamping goes ahead of despiking, and the bounds must be in order
+        let both = FilterConfig { despike_threshold: 3.0, ..clamped.clone() };
+        assert_eq!(both.stages().to_string(), "clamp -> hampel -> kalman -> mean");
+        assert!(FilterConfig { min_distance: 5000.0, ..clamped.clone() }.validate().is_err());
+        assert!(FilterConfig { max_distance: -1.0, ..clamped }.validate().is_err());
+    }
+
+    #[test]
+    fn test_explicit_pipeline() {
+        let custom = FilterConfig {
+            pipeline: Some("hampel -> median -> ema -> trimmed-mean".parse().unwrap()),
+            despike_threshold: 3.0,
+            despike_window: 9,
+            trim_percentage: 0.1,
+            ..config(FilterType::None)
+        };
+        assert!(custom.validate().is_ok());
+        assert!(custom.uses_exponential());
+        let mut pipeline = Pipeline::new(custom.clone());
+        assert_eq!(stages(&pipeline), [Stage::Hampel, Stage::Median, Stage::Ema]);
+
+        let readings = [1000.0, 1001.0, 999.0, 1000.0, 300.0, 1000.0, 1001.0, 1000.0, 999.0, 1000.0, 1001.0];
+        let result = readings.iter().filter_map(|r| pipeline.push(*r).1).next().unwrap();
+        assert_eq!(result.stats.rejected, 1);
+        assert_eq!(result.trimmed, 1);
+        assert!((result.average - 1000.0).abs() < 1.0);
+
+        // Dropping the median keeps the other stages' state, but restarts the batch
+        pipeline.push(1000.0);
+        pipeline.reconfigure(FilterConfig {
+            pipeline: Some("hampel -> ema -> trimmed-mean".parse().unwrap()),
+            ..custom.clone()
+        });
+        assert_eq!(stages(&pipeline), [Stage::Hampel, Stage::Ema]);
+        assert_eq!(pipeline.reading_count(), Some(11));
+        for _ in 0..9 {
+            assert!(pipeline.push(1000.0).1.is_none());
+        }
+        assert_eq!(pipeline.push(1000.0).1.unwrap().count, 10);
+
+        // The hampel stage needs a threshold
+        assert!(FilterConfig { despike_threshold: 0.0, ..custom }.validate().is_err());
+    }
+
+    #[test]
+    fn test_sliding_window() {
+        let sliding = FilterConfig { batch_step: 2, ..config(FilterType::None) };
+        assert!(sliding.is_sliding());
+        let mut pipeline = Pipeline::new(sliding.clone());
+        let mut results = Vec::new();
+        for i in 0..16 {
+            let raw = if i == 12 { 5000.0 } else { 1000.0 + i as f64 };
+            if let (_, Some(result)) = pipeline.push(raw) {
+                results.push((i, result));
+            }
+        }
+        // The first result once the window fills, then one every two readings
+        let at: Vec<usize> = results.iter().map(|(i, _)| *i).collect();
+        assert_eq!(at, [9, 11, 14]);
+        assert_eq!(results[0].1.average, 1004.5);
+        assert_eq!(results[1].1.average, 1006.5);
+        // Readings 4 to 14, less the no-target reading
+        assert_eq!(results[2].1.average, 1008.7);
+        assert_eq!((results[2].1.count, results[2].1.stats.count, results[2].1.stats.no_target), (10, 11, 1));
+        // Each raw reading is tallied once
+        let tally: u64 = results.iter().map(|(_, r)| r.tally.readings).sum();
+        assert_eq!(tally, 15);
+        assert_eq!(results[2].1.tally, RejectionCounts { readings: 3, no_target: 1, ..RejectionCounts::default() });
+
+        assert!(!config(FilterType::None).is_sliding());
+        assert!(FilterConfig { batch_step: 11, ..sliding }.validate().is_err());
+    }
+
+    #[test]
+    fn test_dead_band() {
+        let mut pipeline = Pipeline::new(FilterConfig { dead_band: 2.0, ..config(FilterType::None) });
+        let mut emitted = Vec::new();
+        for average in [1000.0, 1001.0, 999.0, 1002.0, 1002.5, 997.0, 998.0] {
+            emitted.extend((0..10).filter_map(|_| pipeline.push(average).1).map(|r| r.average));
+        }
+        // Dithering within 2 mm of the last emitted value is held back
+        assert_eq!(emitted, [1000.0, 1000.0, 1000.0, 1000.0, 1002.5, 997.0, 997.0]);
+
+        // A reset forgets the last emitted value
+        pipeline.reset();
+        assert_eq!((0..10).filter_map(|_| pipeline.push(998.0).1).next().unwrap().average, 998.0);
+        assert!(FilterConfig { dead_band: -1.0, ..config(FilterType::None) }.validate().is_err());
+    }
+
+    #[test]
+    fn test_high_wind() {
+        // Gusts of blowing snow through the beam read short
+        let readings = [1000.0, 400.0, 1000.0, 1000.0, 400.0, 1000.0, 1000.0, 400.0, 1000.0, 1000.0];
+        let mut pipeline = Pipeline::new(config(FilterType::TrimmedMean));
+        let calm = readings.iter().filter_map(|r| pipeline.push(*r).1).next().unwrap();
+        assert_eq!((calm.average, calm.trimmed), (850.0, 1));
+        assert!(!calm.quality.contains(Quality::HIGH_WIND));
+
+        pipeline.set_high_wind(0.3);
+        let windy = readings.iter().filter_map(|r| pipeline.push(*r).1).next().unwrap();
+        assert_eq!((windy.average, windy.trimmed), (1000.0, 3));
+        assert!(windy.quality.contains(Quality::HIGH_WIND));
+
+        // The flag covers one batch, and never narrows the usual trim
+        let next = readings.iter().filter_map(|r| pipeline.push(*r).1).next().unwrap();
+        assert!(!next.quality.contains(Quality::HIGH_WIND));
+        pipeline.set_high_wind(0.0);
+        assert_eq!(readings.iter().filter_map(|r| pipeline.push(*r).1).next().unwrap().trimmed, 1);
+    }
+
+    #[test]
+    fn test_batch_stats_cover_raw_readings() {
+        let mut pipeline = Pipeline::new(config(FilterType::Exponential));
+        let readings = [1000.0, 1010.0, 990.0, 1000.0, 1020.0, 980.0, 1000.0, 1000.0, 1000.0, 1000.0];
+        let result = readings.iter().filter_map(|r| pipeline.push(*r).1).next().unwrap();
+        // The rate-limited filter hides the spread that the raw readings show
+        assert!((result.average - 1000.0).abs() < 1.0);
+        assert_eq!(result.stats.count, 10);
+        assert_eq!(result.stats.min, 980.0);
+        assert_eq!(result.stats.max, 1020.0);
+        assert!((result.stats.std_dev - 10.541).abs() < 0.001);
+
+        assert_eq!(BatchStats::from_raw(&[]), BatchStats::default());
+        assert_eq!(BatchStats::from_raw(&[5.0]).std_dev, 0.0);
+    }
+
+    #[test]
+    fn test_quality_flags() {
+        let mut pipeline = Pipeline::new(config(FilterType::Exponential));
+        let qualities: Vec<Quality> = (0..50).filter_map(|_| pipeline.push(1000.0).1).map(|r| r.quality).collect();
+        // Warming up for the first init_period (40) readings
+        assert_eq!(qualities, vec![Quality::FILTER_WARMING_UP, Quality::FILTER_WARMING_UP,
+                                   Quality::FILTER_WARMING_UP, Quality::OK, Quality::OK]);
+
+        let mut pipeline = Pipeline::new(config(FilterType::TrimmedMean));
+        let result = (0..10).filter_map(|_| pipeline.push(250.0).1).next().unwrap();
+        assert!(result.quality.contains(Quality::OUT_OF_RANGE));
+        assert!(!result.quality.contains(Quality::FILTER_WARMING_UP));
+    }
+
+    #[test]
+    fn test_no_target_readings_left_out() {
+        let mut pipeline = Pipeline::new(config(FilterType::TrimmedMean));
+        for _ in 0..10 {
+            assert!(pipeline.push(5000.0).1.is_none());
+        }
+        assert!(pipeline.target_lost());
+
+        let results: Vec<BatchResult> = (0..10).filter_map(|_| pipeline.push(1000.0).1).collect();
+        assert!(!pipeline.target_lost());
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].average, 1000.0);
+        // The batch started over when the target was lost
+        assert_eq!((results[0].stats.count, results[0].stats.no_target), (10, 0));
+        assert_eq!(results[0].tally.no_target, 10);
+        assert!(results[0].quality.contains(Quality::TARGET_LOST));
+        assert!(!results[0].quality.contains(Quality::OUT_OF_RANGE));
+    }
+
+    #[test]
+    fn test_long_target_loss() {
+        let mut pipeline = Pipeline::new(config(FilterType::TrimmedMean));
+        for _ in 0..5 {
+            pipeline.push(1010.0);
+        }
+        // Hours without an echo
+        for _ in 0..20_000 {
+            assert!(pipeline.push(5000.0).1.is_none());
+        }
+        assert!(pipeline.window.len() < 10);
+
+        let results: Vec<BatchResult> = (0..10).filter_map(|_| pipeline.push(1000.0).1).collect();
+        assert_eq!(results.len(), 1);
+        let stats = &results[0].stats;
+        assert_eq!((stats.count, stats.no_target, stats.max, stats.std_dev), (10, 0, 1000.0, 0.0));
+        assert_eq!(results[0].average, 1000.0);
+        assert_eq!((results[0].tally.readings, results[0].tally.no_target), (20_015, 20_000));
+        assert!(results[0].quality.contains(Quality::TARGET_LOST));
+    }
+
+    #[test]
+    fn test_reset_discards_partial_batch() {
+        let mut pipeline = Pipeline::new(config(FilterType::Exponential));
+        for _ in 0..5 {
+            pipeline.push(1000.0);
+        }
+        pipeline.reset();
+        assert_eq!(pipeline.reading_count().unwrap(), 0);
+
+        // Filter reinitializes from the first reading after the reset
+        assert_eq!(pipeline.push(900.0).0, 900.0);
+        for _ in 0..8 {
+            assert!(pipeline.push(900.0).1.is_none());
+        }
+        assert_eq!(pipeline.push(900.0).1.unwrap().count, 10);
+    }
+
+    #[test]
+    fn test_reconfigure_keeps_state() {
+        let mut pipeline = Pipeline::new(config(FilterType::Exponential));
+        for _ in 0..5 {
+            pipeline.push(1000.0);
+        }
+        pipeline.reconfigure(FilterConfig {
+            rate_limit: 5.0,
+            batch_size: 6,
+            ..config(FilterType::Exponential)
+        });
+        assert_eq!(pipeline.reading_count().unwrap(), 5);
+
+        // The new rate limit applies from the current filtered value
+        let (filtered, result) = pipeline.push(1100.0);
+        assert_eq!(filtered, 1005.0);
+        assert_eq!(result.unwrap().count, 6);
+
+        // A new filter type starts over
+        pipeline.push(1000.0);
+        pipeline.reconfigure(config(FilterType::None));
+        assert!(pipeline.reading_count().is_none());
+        assert_eq!(pipeline.push(900.0).0, 900.0);
+        for _ in 0..8 {
+            assert!(pipeline.push(900.0).1.is_none());
+        }
+        assert_eq!(pipeline.push(900.0).1.unwrap().count, 10);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(config(FilterType::Both).validate().is_ok());
+
+        let mut bad = config(FilterType::Both);
+        bad.trim_percentage = 0.6;
+        assert!(bad.validate().is_err());
+
+        let mut bad = config(FilterType::Both);
+        bad.batch_size = 5;
+        assert!(bad.validate().is_err());
+
+        // Kalman noise only matters to the Kalman filter
+        let mut kalman = config(FilterType::Kalman);
+        kalman.measurement_noise = 0.0;
+        assert!(kalman.validate().is_err());
+        kalman.filter_type = FilterType::Both;
+        assert!(kalman.validate().is_ok());
+
+        let mut median = config(FilterType::Median);
+        median.median_window = 0;
+        assert!(median.validate().is_err());
+
+        let mut trend = config(FilterType::Trend);
+        trend.trend_window = 2;
+        assert!(trend.validate().is_err());
+
+        let mut adaptive = config(FilterType::Exponential);
+        adaptive.adaptive_noise = 5.0;
+        assert!(adaptive.validate().is_ok());
+        adaptive.alpha_min = 0.3;
+        assert!(adaptive.validate().is_err());
+        adaptive.adaptive_noise = 0.0;
+        assert!(adaptive.validate().is_ok());
+
+        let mut mode = config(FilterType::Mode);
+        mode.mode_bin_width = 0.0;
+        assert!(mode.validate().is_err());
+
+        let mut despiking = config(FilterType::Both);
+        despiking.despike_window = 1;
+        assert!(despiking.validate().is_ok());
+        despiking.despike_threshold = 3.0;
+        assert!(despiking.validate().is_err());
+        despiking.despike_threshold = -1.0;
+        assert!(despiking.validate().is_err());
+    }
+
+    #[test]
+    fn test_divergence() {
+        let mut divergence = Divergence::default();
+        assert_eq!(divergence.mean_abs_difference(), 0.0);
+
+        divergence.record(1000.0, 1002.0);
+        divergence.record(1000.0, 998.0);
+        assert_eq!(divergence.samples(), 2);
+        assert_eq!(divergence.mean_difference(), 0.0);
+        assert_eq!(divergence.mean_abs_difference(), 2.0);
+        assert_eq!(divergence.rms_difference(), 2.0);
+        assert_eq!(divergence.max_abs_difference(), 2.0);
+    }
+}
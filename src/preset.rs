@@ -0,0 +1,286 @@
+/// Named filter presets
+///
+/// A preset is the production filter configuration under a name, stored as
+/// JSON with the same field names as the `FilterPreset` message, so a tuning
+/// worked out on one gauge can be exported and applied across a fleet either
+/// as a file (`--filter-preset`) or over gRPC (`ApplyFilterPreset`).
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::pipeline::{
+    FilterConfig, DEFAULT_ALPHA_MIN, DEFAULT_DESPIKE_WINDOW, DEFAULT_MEASUREMENT_NOISE, DEFAULT_MEDIAN_WINDOW, DEFAULT_MODE_BIN_WIDTH,
+    DEFAULT_PROCESS_NOISE, DEFAULT_TREND_WINDOW,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Preset {
+    pub name: String,
+    pub config: FilterConfig,
+}
+
+/// On-disk representation
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PresetFile {
+    name: String,
+    filter_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pipeline: Option<String>,
+    init_period: usize,
+    rate_limit: f64,
+    alpha: f64,
+    trim_percentage: f64,
+    batch_size: usize,
+    // Presets from before sliding windows have back-to-back batches
+    #[serde(default)]
+    batch_step: usize,
+    // Presets from before the Kalman filter don't have these
+    #[serde(default = "default_process_noise")]
+    process_noise: f64,
+    #[serde(default = "default_measurement_noise")]
+    measurement_noise: f64,
+    // and presets from before the median and mode filters don't have these
+    #[serde(default = "default_median_window")]
+    median_window: usize,
+    #[serde(default = "default_mode_bin_width")]
+    mode_bin_width: f64,
+    // or despiking, which stays off for them
+    #[serde(default)]
+    despike_threshold: f64,
+    #[serde(default = "default_despike_window")]
+    despike_window: usize,
+    // or adaptive alpha, also off for them
+    #[serde(default)]
+    adaptive_noise: f64,
+    #[serde(default = "default_alpha_min")]
+    alpha_min: f64,
+    // or range clamping, which leaves the range open
+    #[serde(default)]
+    min_distance: f64,
+    #[serde(default)]
+    max_distance: f64,
+    // or a dead-band
+    #[serde(default)]
+    dead_band: f64,
+    // or the trend filter
+    #[serde(default = "default_trend_window")]
+    trend_window: usize,
+}
+
+fn default_process_noise() -> f64 {
+    DEFAULT_PROCESS_NOISE
+}
+
+fn default_measurement_noise() -> f64 {
+    DEFAULT_MEASUREMENT_NOISE
+}
+
+fn default_median_window() -> usize {
+    DEFAULT_MEDIAN_WINDOW
+}
+
+fn default_mode_bin_width() -> f64 {
+    DEFAULT_MODE_BIN_WIDTH
+}
+
+fn default_trend_window() -> usize {
+    DEFAULT_TREND_WINDOW
+}
+
+fn default_alpha_min() -> f64 {
+    DEFAULT_ALPHA_MIN
+}
+
+fn default_despike_window() -> usize {
+    DEFAULT_DESPIKE_WINDOW
+}
+
+impl Preset {
+    pub fn new(name: String, config: FilterConfig) -> Self {
+        Self { name, config }
+    }
+
+    pub fn to_json(&self) -> String {
+        let file = PresetFile {
+            name: self.name.clone(),
+            filter_type: self.config.filter_type.to_string(),
+            pipeline: self.config.pipeline.as_ref().map(|p| p.to_string()),
+            init_period: self.config.init_period,
+            rate_limit: self.config.rate_limit,
+            alpha: self.config.alpha,
+            trim_percentage: self.config.trim_percentage,
+            batch_size: self.config.batch_size,
+            batch_step: self.config.batch_step,
+            process_noise: self.config.process_noise,
+            measurement_noise: self.config.measurement_noise,
+            median_window: self.config.median_window,
+            mode_bin_width: self.config.mode_bin_width,
+            despike_threshold: self.config.despike_threshold,
+            despike_window: self.config.despike_window,
+            adaptive_noise: self.config.adaptive_noise,
+            alpha_min: self.config.alpha_min,
+            min_distance: self.config.min_distance,
+            max_distance: self.config.max_distance,
+            dead_band: self.config.dead_band,
+            trend_window: self.config.trend_window,
+        };
+        // Serializing plain strings and numbers cannot fail
+        serde_json::to_string_pretty(&file).unwrap() + "\n"
+    }
+
+    /// Parse and validate a preset
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        let file: PresetFile = serde_json::from_str(json).map_err(|e| format!("invalid filter preset: {}", e))?;
+        let preset = Self {
+            name: file.name,
+            config: FilterConfig {
+                filter_type: file.filter_type.parse()?,
+                pipeline: file.pipeline.as_deref().map(str::parse).transpose()?,
+                init_period: file.init_period,
+                rate_limit: file.rate_limit,
+                alpha: file.alpha,
+                trim_percentage: file.trim_percentage,
+                batch_size: file.batch_size,
+                batch_step: file.batch_step,
+                process_noise: file.process_noise,
+                measurement_noise: file.measurement_noise,
+                median_window: file.median_window,
+                mode_bin_width: file.mode_bin_width,
+                despike_threshold: file.despike_threshold,
+                despike_window: file.despike_window,
+                adaptive_noise: file.adaptive_noise,
+                alpha_min: file.alpha_min,
+                min_distance: file.min_distance,
+                max_distance: file.max_distance,
+                dead_band: file.dead_band,
+                trend_window: file.trend_window,
+            },
+        };
+        preset.config.validate()?;
+        Ok(preset)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let json = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read filter preset {}: {}", path.display(), e))?;
+        Self::from_json(&json).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Write the preset, replacing any existing file atomically
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, self.to_json())
+            .and_then(|_| std::fs::rename(&tmp, path))
+            .map_err(|e| format!("failed to write filter preset {}: {}", path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sensor_filter::FilterType;
+
+    fn preset() -> Preset {
+        Preset::new(
+            "windy-ridge".to_string(),
+            FilterConfig {
+                filter_type: FilterType::Both,
+                pipeline: None,
+                init_period: 40,
+                rate_limit: 2.5,
+                alpha: 0.1,
+                trim_percentage: 0.2,
+                batch_size: 60,
+                batch_step: 10,
+                process_noise: 0.01,
+                measurement_noise: 9.0,
+                median_window: 7,
+                mode_bin_width: 2.0,
+                despike_threshold: 3.5,
+                despike_window: 21,
+                adaptive_noise: 4.0,
+                alpha_min: 0.02,
+                min_distance: 500.0,
+                max_distance: 5000.0,
+                dead_band: 1.5,
+                trend_window: 120,
+            },
+        )
+    }
+
+    #[test]
+    fn test_json_roundtrip() {
+        let json = preset().to_json();
+        assert!(json.contains("\"filterType\": \"both\""));
+        assert!(json.contains("\"trimPercentage\": 0.2"));
+        assert_eq!(Preset::from_json(&json).unwrap(), preset());
+        assert!(!json.contains("pipeline"));
+
+        let mut custom = preset();
+        custom.config.pipeline = Some("ema -> mode".parse().unwrap());
+        let json = custom.to_json();
+        assert!(json.contains("\"pipeline\": \"ema -> mode\""), "{}", json);
+        assert_eq!(Preset::from_json(&json).unwrap(), custom);
+        assert!(Preset::from_json(&json.replace("ema -> mode", "mode -> ema")).is_err());
+    }
+
+    #[test]
+    fn test_rejects_invalid() {
+        let json = preset().to_json();
+        assert!(Preset::from_json(&json.replace("\"both\"", "\"wavelet\"")).is_err());
+        assert!(Preset::from_json(&json.replace("\"batchSize\": 60", "\"batchSize\": 5")).is_err());
+        assert!(Preset::from_json(&json.replace("\"alpha\": 0.1,", "")).is_err());
+        assert!(Preset::from_json("not json").is_err());
+    }
+
+    #[test]
+    fn test_older_preset_gets_defaults() {
+        let json = preset().to_json();
+        let json = json.replace(",\n  \"trendWindow\": 120", "");
+        assert_eq!(Preset::from_json(&json).unwrap().config.trend_window, DEFAULT_TREND_WINDOW);
+        let json = json.replace(",\n  \"batchStep\": 10", "");
+        assert!(!json.contains("batchStep"), "{}", json);
+        assert_eq!(Preset::from_json(&json).unwrap().config.batch_step, 0);
+
+        let json = json.replace(",\n  \"deadBand\": 1.5", "");
+        assert_eq!(Preset::from_json(&json).unwrap().config.dead_band, 0.0);
+        let json = json.replace(",\n  \"minDistance\": 500.0,\n  \"maxDistance\": 5000.0", "");
+        assert!(!json.contains("Distance"), "{}", json);
+        let config = Preset::from_json(&json).unwrap().config;
+        assert_eq!((config.min_distance, config.max_distance), (0.0, 0.0));
+
+        let json = json.replace(",\n  \"processNoise\": 0.01,\n  \"measurementNoise\": 9.0", "");
+        assert!(!json.contains("processNoise") && !json.contains("measurementNoise"), "{}", json);
+        let config = Preset::from_json(&json).unwrap().config;
+        assert_eq!(config.process_noise, DEFAULT_PROCESS_NOISE);
+        assert_eq!(config.measurement_noise, DEFAULT_MEASUREMENT_NOISE);
+        assert_eq!(config.median_window, 7);
+
+        let json = json.replace(",\n  \"adaptiveNoise\": 4.0,\n  \"alphaMin\": 0.02", "");
+        assert!(!json.contains("adaptiveNoise"), "{}", json);
+        let config = Preset::from_json(&json).unwrap().config;
+        assert_eq!((config.adaptive_noise, config.alpha_min), (0.0, DEFAULT_ALPHA_MIN));
+
+        let json = json.replace(",\n  \"despikeThreshold\": 3.5,\n  \"despikeWindow\": 21", "");
+        assert!(!json.contains("despike"), "{}", json);
+        let config = Preset::from_json(&json).unwrap().config;
+        assert_eq!((config.despike_threshold, config.despike_window), (0.0, DEFAULT_DESPIKE_WINDOW));
+
+        let json = json.replace(",\n  \"medianWindow\": 7,\n  \"modeBinWidth\": 2.0", "");
+        assert!(!json.contains("medianWindow") && !json.contains("modeBinWidth"), "{}", json);
+        let config = Preset::from_json(&json).unwrap().config;
+        assert_eq!(config.median_window, DEFAULT_MEDIAN_WINDOW);
+        assert_eq!(config.mode_bin_width, DEFAULT_MODE_BIN_WIDTH);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("snowgauge-preset-{}.json", std::process::id()));
+        preset().save(&path).unwrap();
+        assert_eq!(Preset::load(&path).unwrap(), preset());
+        std::fs::remove_file(&path).unwrap();
+        assert!(Preset::load(&path).is_err());
+    }
+}
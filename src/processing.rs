@@ -0,0 +1,762 @@
+/// Each station's reading pipeline: the tasks reading its sensor and the
+/// one turning its raw readings into batch readings
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use log::{debug, error, info, warn};
+use rand::Rng;
+use tokio::sync::{mpsc, watch};
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+
+use crate::battery::BatteryMonitor;
+use crate::calibration;
+use crate::compensation::SoundCompensation;
+use crate::events::EventPublisher;
+use crate::frame::{Checksum, FrameCounters, FrameFormat, FrameParser};
+use crate::interpolate::GapFiller;
+use crate::pipeline::{Divergence, Pipeline};
+use crate::quality::Quality;
+use crate::schedule::{self, Phase, PowerLine, Scheduler};
+use crate::serial::SerialConfig;
+use crate::server::accumulation_to_proto;
+use crate::service::{measurements, publish_frame, RawFrameChannels, SnowGaugeServiceImpl};
+use crate::settling::Decrease;
+use crate::snowgauge::{self, raw_frame, EventKind, Reading, TemperatureSource, Unit};
+use crate::source::Rangefinder;
+use crate::store;
+use crate::totals::Date;
+use crate::trace::TraceContext;
+use crate::wind::WindMonitor;
+
+/// Time between saves of the snowfall totals to --totals-file
+const TOTALS_SAVE_INTERVAL: Duration = Duration::from_secs(600);
+
+impl SnowGaugeServiceImpl {
+    /// Run raw readings through the filter pipeline and broadcast batch results
+    ///
+    /// In comparison mode every raw reading is also fed to the candidate
+    /// pipeline, and both pipelines' batch results are published on the
+    /// comparison stream.
+    ///
+    /// With a measurement schedule, readings are only accepted while the
+    /// schedule is measuring, and never while acquisition is paused;
+    /// `sensor_power` tells the data source when to power the sensor.
+    ///
+    /// When the production filter configuration changes the pipeline is
+    /// reconfigured, keeping its state unless the filter type changed.
+    /// ResetFilter discards the state of both pipelines.
+    pub async fn process_readings(
+        &self,
+        receiver: &mut mpsc::UnboundedReceiver<f64>,
+        log_distance: bool,
+        sensor_power: &watch::Sender<bool>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut filter = self.filter.subscribe();
+        let mut paused = self.paused.subscribe();
+        let mut primary = Pipeline::with_quality_checks(filter.borrow_and_update().config.clone(), self.quality_checks.clone());
+        let mut candidate = self
+            .compare_config
+            .clone()
+            .map(|config| Pipeline::with_quality_checks(config, self.quality_checks.clone()));
+        let mut divergence = Divergence::default();
+        let mut scheduler = self.schedule.clone().map(Scheduler::new);
+        let mut anomaly_detector = self.anomaly_detector.clone();
+        let mut direction_hysteresis = self.direction_hysteresis.clone();
+        let mut settling_detector = self.settling_detector.clone();
+        let mut target_lost = false;
+        let mut battery = self.battery_voltage.clone().map(BatteryMonitor::new);
+        let mut compensation = self.compensation.clone().map(SoundCompensation::new);
+        let mut wind = self.wind.clone().map(WindMonitor::new);
+        let mut gap_filler = self.interpolate_max_gap.map(GapFiller::new);
+        let mut totals_saved_at = Instant::now();
+        let mut schedule_tick = time::interval(Duration::from_secs(1));
+        let mut gap_tick = time::interval(Duration::from_secs(1));
+        let mut in_gap = false;
+
+        info!("Initializing filter pipeline: {}", primary.config().stages());
+
+        loop {
+            let raw_distance = tokio::select! {
+                raw_distance = receiver.recv() => match raw_distance {
+                    Some(raw_distance) => raw_distance,
+                    None => break,
+                },
+                _ = schedule_tick.tick(), if scheduler.is_some() => {
+                    if let Some(ref mut scheduler) = scheduler {
+                        let previous = scheduler.phase();
+                        let now = SystemTime::now();
+                        let phase = scheduler.update(now, schedule::local_minute_of_day(now));
+                        if phase != previous {
+                            info!("Measurement schedule: {:?} -> {:?}", previous, phase);
+                        }
+                        if phase.is_measuring() != previous.is_measuring() && !*paused.borrow() {
+                            self.set_measuring(phase.is_measuring(), &mut primary, &mut candidate, &mut gap_filler, sensor_power).await;
+                        }
+                    }
+                    continue;
+                }
+                _ = gap_tick.tick() => {
+                    // A gap ended by a pause rather than by readings is dropped unannounced
+                    let open = self.history.read().await.open_gap(SystemTime::now());
+                    if let Some(gap) = open.as_ref().filter(|_| !in_gap) {
+                        let detail = format!("no raw readings for {}s", gap.duration().as_secs());
+                        warn!("Gap started: {}", detail);
+                        self.events.publish(EventKind::GapStarted, detail);
+                    }
+                    in_gap = open.is_some();
+                    continue;
+                }
+                Ok(()) = filter.changed() => {
+                    let preset = filter.borrow_and_update().clone();
+                    primary.reconfigure(preset.config);
+                    divergence = Divergence::default();
+                    // The batch spacing may have changed
+                    if let Some(f) = gap_filler.as_mut() {
+                        f.reset();
+                    }
+                    self.totals.lock().unwrap_or_else(|e| e.into_inner()).interrupt();
+                    self.events.publish_caused(self.cause().as_ref(), EventKind::FilterChanged, format!("filter preset '{}' applied", preset.name));
+                    continue;
+                }
+                Ok(()) = paused.changed() => {
+                    let pause = *paused.borrow_and_update();
+                    info!("Acquisition {}", if pause { "paused" } else { "resumed" });
+                    if pause {
+                        let detail = if *self.release_port.borrow() { ", serial port closed" } else { "" };
+                        self.events.publish_caused(self.cause().as_ref(), EventKind::AcquisitionPaused, format!("acquisition paused{}", detail));
+                    } else {
+                        self.events.publish_caused(self.cause().as_ref(), EventKind::AcquisitionResumed, "acquisition resumed");
+                    }
+                    let scheduled = scheduler.as_ref().is_none_or(|s| s.phase().is_measuring());
+                    self.set_measuring(scheduled && !pause, &mut primary, &mut candidate, &mut gap_filler, sensor_power).await;
+                    continue;
+                }
+                _ = self.filter_reset.notified() => {
+                    info!("Resetting filter state");
+                    primary.reset();
+                    if let Some(c) = candidate.as_mut() {
+                        c.reset();
+                    }
+                    divergence = Divergence::default();
+                    self.events.publish_caused(self.cause().as_ref(), EventKind::FilterReset, "filter state reset");
+                    continue;
+                }
+            };
+
+            if *paused.borrow() || scheduler.as_ref().is_some_and(|s| !s.phase().is_measuring()) {
+                continue;
+            }
+
+            if let Some(gap) = self.history.write().await.record_sample(SystemTime::now()) {
+                in_gap = false;
+                let detail = format!("raw readings resumed after {}s", gap.duration().as_secs());
+                info!("Gap ended: {}", detail);
+                self.events.publish(EventKind::GapEnded, detail);
+            }
+
+            let raw_reading = Reading {
+                station_name: self.station_name.clone(),
+                distance: raw_distance as i32,
+                timestamp: Some(SystemTime::now().into()),
+                value: raw_distance,
+                distance_mm: raw_distance,
+                measurements: measurements(raw_distance, *self.baseline.borrow(), None, None),
+                unit: Unit::Millimeters as i32,
+                ..Default::default()
+            };
+            self.broadcast_reading(raw_reading, true).await;
+
+            // Max-range readings mean no echo and go on as they are, so the
+            // pipelines still recognize them
+            let mut corrected = raw_distance;
+            if raw_distance < self.quality_checks.max_distance {
+                if let Some(ref mut c) = compensation {
+                    c.sample(Instant::now());
+                    corrected = c.correct(corrected);
+                }
+                if let Some(ref curve) = self.calibration {
+                    corrected = curve.apply(corrected);
+                }
+                if self.mount_angle != 0.0 {
+                    corrected = calibration::plumb(corrected, self.mount_angle);
+                }
+            }
+
+            let Some(corrected) = self.hooks.pre_filter(&self.station_name, corrected) else {
+                continue;
+            };
+
+            if let Some(ref mut wind) = wind {
+                if wind.update(Instant::now()) {
+                    primary.set_high_wind(wind.trim_percentage());
+                    if let Some(c) = candidate.as_mut() {
+                        c.set_high_wind(wind.trim_percentage());
+                    }
+                }
+            }
+
+            let (distance, batch) = primary.push(corrected);
+            if primary.target_lost() != target_lost {
+                target_lost = !target_lost;
+                if target_lost {
+                    let readings = self.quality_checks.target_lost_readings;
+                    warn!("Target lost: {} consecutive readings at the sensor's maximum range", readings);
+                    self.events.publish(EventKind::TargetLost, format!("no target for {} readings", readings));
+                } else {
+                    info!("Target reacquired at {:.2}mm", raw_distance);
+                    self.events.publish(EventKind::TargetReacquired, format!("target reacquired at {:.1}mm", raw_distance));
+                }
+            }
+            if log_distance {
+                if let Some(count) = primary.reading_count() {
+                    if corrected != raw_distance {
+                        info!("Raw: {:.2}mm, Corrected: {:.2}mm, Filtered: {:.2}mm (readings: {})",
+                              raw_distance, corrected, distance, count);
+                    } else {
+                        info!("Raw: {:.2}mm, Filtered: {:.2}mm (readings: {})", raw_distance, distance, count);
+                    }
+                }
+            }
+
+            let candidate_batch = candidate.as_mut().map(|c| {
+                let (candidate_distance, candidate_batch) = c.push(corrected);
+                divergence.record(distance, candidate_distance);
+                candidate_batch
+            });
+            let offset = self.offset.borrow().clone();
+            if let Some(Some(mut result)) = candidate_batch {
+                if let Some(ref offset) = offset {
+                    result.average = offset.apply(result.average);
+                }
+                info!("Candidate filter result: {:.2}mm (from {} readings, mean abs divergence {:.2}mm)",
+                      result.average, result.count, divergence.mean_abs_difference());
+                self.broadcast_comparison("candidate", result.average, &divergence).await;
+            }
+
+            let Some(mut result) = batch else {
+                continue;
+            };
+            if let Some(ref offset) = offset {
+                result.average = offset.apply(result.average);
+            }
+            let fusion = self.fuse(&mut result);
+
+            info!("Filter result: {:.2}mm (from {} readings by {}{})",
+                  result.average, result.count, primary.config().stages(),
+                  if result.trimmed > 0 { format!(", trimmed {} from each end", result.trimmed) } else { String::new() });
+            if result.stats.out_of_range > 0 {
+                info!("Discarded {} out-of-range readings from the batch", result.stats.out_of_range);
+            }
+            if result.stats.rejected > 0 {
+                info!("Discarded {} spikes from the batch", result.stats.rejected);
+            }
+            if result.stats.no_target > 0 {
+                info!("Discarded {} no-target readings from the batch", result.stats.no_target);
+            }
+            self.rejections.record(&result.tally);
+            if !result.quality.is_ok() {
+                info!("Reading quality: {}", result.quality);
+            }
+            if result.quality.contains(Quality::HIGH_WIND) {
+                if let Some(speed) = wind.as_ref().and_then(|w| w.speed()) {
+                    info!("Batch collected in high wind, last measured {:.1}m/s", speed);
+                }
+            }
+
+            // Match the history's precision so clients can amend by timestamp
+            let now = store::millis_precision(SystemTime::now());
+            self.history.write().await.push(now, result.average);
+
+            // The first reading after a call changing the readings continues
+            // its trace; others start one consumers can continue. The
+            // reading's events are in its trace.
+            let cause = self.cause.lock().unwrap_or_else(|e| e.into_inner()).take();
+            let trace = cause.map_or_else(TraceContext::new_root, |cause| cause.child());
+            debug!("Emitting reading {:.2}mm with traceparent {}", result.average, trace);
+
+            if let Some(anomaly) = anomaly_detector.as_mut().and_then(|d| d.check(now, result.average)) {
+                warn!("Anomalous reading: {:.2}mm, expected about {:.2}mm (robust z-score {:.1})",
+                      anomaly.distance, anomaly.expected, anomaly.score);
+                self.events.publish_caused(Some(&trace), EventKind::AnomalyDetected, format!(
+                    "reading {:.1}mm, expected about {:.1}mm (robust z-score {:.1})",
+                    anomaly.distance, anomaly.expected, anomaly.score
+                ));
+                self.history.write().await.record_anomaly(anomaly);
+                result.quality.insert(Quality::ANOMALOUS);
+            }
+
+            let mut snowfall_rate = self.snowfall_rate(now).await;
+            if let Some(ref mut hysteresis) = direction_hysteresis {
+                // Distance is measured down from the sensor, so its negative grows with depth
+                hysteresis.update(-result.average);
+                snowfall_rate = snowfall_rate.map(|rate| hysteresis.apply(rate));
+            }
+            let ambient = compensation.as_ref().and_then(|c| c.ambient());
+            let decrease = settling_detector
+                .as_mut()
+                .and_then(|s| s.update(now, snowfall_rate, ambient.map(|a| a.temperature)));
+            if let Some(decrease) = decrease {
+                debug!("Depth decrease classified as {:?}", decrease);
+                snowfall_rate = Some(0.0);
+            }
+            let accumulation = {
+                let mut totals = self.totals.lock().unwrap_or_else(|e| e.into_inner());
+                totals.update(now, Date::local(now), snowfall_rate);
+                if let Some(ref path) = self.totals_path {
+                    if totals_saved_at.elapsed() >= TOTALS_SAVE_INTERVAL {
+                        totals_saved_at = Instant::now();
+                        if let Err(e) = totals.save(path) {
+                            warn!("{}", e);
+                        }
+                    }
+                }
+                accumulation_to_proto(&totals, now)
+            };
+            let density = self.snow_density.borrow().and_then(|m| m.density(ambient.map(|a| a.temperature)));
+            let storm = self.storm_detector.as_ref().and_then(|s| {
+                s.lock().unwrap_or_else(|e| e.into_inner()).update(now, snowfall_rate, result.average, density)
+            });
+            if let Some((kind, storm)) = storm {
+                let detail = if kind == EventKind::StormStarted {
+                    format!("storm started, snowfall rate {:.1}mm/hr", snowfall_rate.unwrap_or_default())
+                } else {
+                    let hours = storm.end.and_then(|end| end.duration_since(storm.start).ok()).unwrap_or_default();
+                    let water = storm.water_equivalent.map(|w| format!(" ({:.1}mm water)", w)).unwrap_or_default();
+                    format!("storm ended after {:.1}h: {:.1}mm new snow{}, peak rate {:.1}mm/hr",
+                            hours.as_secs_f64() / 3600.0, storm.new_snow, water, storm.peak_rate)
+                };
+                info!("{}", detail);
+                self.events.publish_storm(&trace, kind, detail, storm.to_proto());
+            }
+            let new_snow = {
+                let mut board = self.board.lock().unwrap_or_else(|e| e.into_inner());
+                if let Some(period) = board.update(now, schedule::local_minute_of_day(now), result.average) {
+                    self.publish_board_cleared(&trace, Some(&period));
+                }
+                board.new_snow(result.average)
+            };
+
+            let temperature_source = match compensation {
+                Some(ref c) if ambient.is_some() && c.is_fixed() => TemperatureSource::Fixed,
+                Some(_) if ambient.is_some() => TemperatureSource::Sensor,
+                _ => TemperatureSource::None,
+            };
+            let mut reading = Reading {
+                station_name: self.station_name.clone(),
+                distance: result.average as i32,
+                system_uptime: None,
+                application_uptime: None,
+                timestamp: Some(now.into()),
+                traceparent: trace.to_string(),
+                value: result.average,
+                distance_mm: result.average,
+                measurements: measurements(result.average, *self.baseline.borrow(), density, new_snow),
+                unit: Unit::Millimeters as i32,
+                sequence: 0,
+                quality: result.quality.bits(),
+                battery_voltage: battery.as_mut().and_then(|b| b.sample()),
+                batch_stats: Some(snowgauge::BatchStats {
+                    count: result.stats.count as u32,
+                    min_mm: result.stats.min,
+                    max_mm: result.stats.max,
+                    std_dev_mm: result.stats.std_dev,
+                    rejected: result.stats.rejected as u32,
+                    out_of_range: result.stats.out_of_range as u32,
+                    no_target: result.stats.no_target as u32,
+                }),
+                snowfall_rate_mm_per_hour: snowfall_rate,
+                temperature_c: ambient.map(|a| a.temperature),
+                temperature_source: temperature_source as i32,
+                relative_humidity: ambient.and_then(|a| a.humidity),
+                offset_mm: offset.map(|o| o.distance).filter(|&d| d != 0.0),
+                settling_rate_mm_per_hour: match decrease {
+                    Some(Decrease::Settling(rate)) => Some(rate),
+                    _ => None,
+                },
+                melt_rate_mm_per_hour: match decrease {
+                    Some(Decrease::Melt(rate)) => Some(rate),
+                    _ => None,
+                },
+                accumulation: Some(accumulation),
+                fusion,
+            };
+
+            if let Some(ref mut filler) = gap_filler {
+                let filled = filler.update(now, result.average);
+                if !filled.is_empty() {
+                    info!("Interpolated {} readings over a gap in the batch readings", filled.len());
+                }
+                for (timestamp, distance) in filled {
+                    let mut interpolated = self.interpolated_reading(timestamp, distance);
+                    self.hooks.post_batch(&mut interpolated);
+                    self.broadcast_reading(interpolated, false).await;
+                }
+            }
+            self.hooks.post_batch(&mut reading);
+            self.broadcast_reading(reading, false).await;
+
+            if candidate.is_some() {
+                self.broadcast_comparison("primary", result.average, &divergence).await;
+            }
+
+            if let Some(ref mut scheduler) = scheduler {
+                if scheduler.phase() == Phase::Burst {
+                    scheduler.batch_complete();
+                    info!("Measurement burst complete, sensor idle until the next burst");
+                    self.set_measuring(false, &mut primary, &mut candidate, &mut gap_filler, sensor_power).await;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Start or stop scheduled measurement
+    ///
+    /// Starting discards any partial batch and filter state left from before
+    /// the sensor went idle, so a batch never mixes readings from separate
+    /// measurement periods. Idle time is excluded from gap tracking and is
+    /// never interpolated over.
+    async fn set_measuring(
+        &self,
+        measuring: bool,
+        primary: &mut Pipeline,
+        candidate: &mut Option<Pipeline>,
+        gap_filler: &mut Option<GapFiller>,
+        sensor_power: &watch::Sender<bool>,
+    ) {
+        if let Some(f) = gap_filler.as_mut() {
+            f.reset();
+        }
+        self.totals.lock().unwrap_or_else(|e| e.into_inner()).interrupt();
+        if measuring {
+            primary.reset();
+            if let Some(c) = candidate.as_mut() {
+                c.reset();
+            }
+            self.history.write().await.resume(SystemTime::now());
+        } else {
+            self.history.write().await.pause();
+        }
+        sensor_power.send_replace(measuring);
+    }
+
+    /// Read from serial port with exponential backoff on errors
+    #[allow(clippy::too_many_arguments)]
+    pub async fn serial_reader(
+        port_name: String,
+        serial: SerialConfig,
+        format: FrameFormat,
+        checksum: Checksum,
+        sender: mpsc::UnboundedSender<f64>,
+        log_distance: bool,
+        power_line: PowerLine,
+        mut sensor_power: watch::Receiver<bool>,
+        release_port: watch::Receiver<bool>,
+        raw_frames: RawFrameChannels,
+        frame_counters: Arc<FrameCounters>,
+        events: EventPublisher,
+        cancel_token: CancellationToken,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Spawn blocking task for serial I/O and await its completion
+        // This task will be cancelled when the cancel_token is triggered
+        let cancel_token_clone = cancel_token.clone();
+        let handle = tokio::task::spawn_blocking(move || {
+            let mut backoff = Duration::from_secs(1);
+            const MAX_BACKOFF: Duration = Duration::from_secs(60);
+            // Set on a failure, so only the first attempt of a run of retries
+            // and the recovery are published as events
+            let mut disconnected = false;
+
+            'reconnect: loop {
+                if cancel_token_clone.is_cancelled() {
+                    info!("Serial reader received shutdown signal");
+                    return;
+                }
+
+                // Leave the port closed while acquisition is paused with closePort
+                if *release_port.borrow() {
+                    std::thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+
+                // Shorter timeout for responsiveness
+                match serial.open(&port_name, Duration::from_secs(1)) {
+                    Ok(mut port) => {
+                        info!("Serial port opened successfully");
+                        if std::mem::replace(&mut disconnected, false) {
+                            events.publish(EventKind::SensorReconnected, format!("{} opened", port_name));
+                        }
+                        backoff = Duration::from_secs(1); // Reset backoff on successful connection
+
+                        let mut buf = [0u8; 64];
+                        let mut parser = FrameParser::new(format.clone(), checksum);
+                        let mut apply_power = true;
+
+                        loop {
+                            if cancel_token_clone.is_cancelled() {
+                                info!("Serial reader received shutdown signal");
+                                return;
+                            }
+
+                            if *release_port.borrow() {
+                                info!("Closing serial port while acquisition is paused");
+                                continue 'reconnect;
+                            }
+
+                            // Follow the measurement schedule on the sensor's control line
+                            if apply_power || sensor_power.has_changed().unwrap_or(false) {
+                                let on = *sensor_power.borrow_and_update();
+                                if let Err(e) = power_line.set(port.as_mut(), on) {
+                                    error!("Error setting sensor power line: {}", e);
+                                }
+                                apply_power = false;
+                                parser.reset();
+                            }
+
+                            match port.read(&mut buf) {
+                                Ok(n) => {
+                                    let detecting = *parser.format() == FrameFormat::Auto;
+                                    for frame in buf[..n].iter().filter_map(|&b| parser.push(b)) {
+                                        frame_counters.record(&frame);
+                                        match frame.distance {
+                                            Ok(raw_distance) => {
+                                                publish_frame(&raw_frames, &frame.bytes, raw_frame::Result::Distance(raw_distance));
+                                                if log_distance {
+                                                    info!("Received measurement: distance={}", raw_distance);
+                                                }
+
+                                                // Nothing is wanted while the sensor is switched off
+                                                if *sensor_power.borrow() && sender.send(raw_distance).is_err() {
+                                                    error!("Processing channel closed, stopping serial reader");
+                                                    return;
+                                                }
+                                            }
+                                            Err(e) => {
+                                                error!("Invalid frame {:?}: {}", frame.bytes, e);
+                                                publish_frame(&raw_frames, &frame.bytes, raw_frame::Result::Error(e.to_string()));
+                                            }
+                                        }
+                                    }
+                                    let detected = parser.format();
+                                    if detecting && *detected != FrameFormat::Auto {
+                                        info!("Detected {} frames on {}, distances in {}", detected, port_name, detected.unit());
+                                    }
+                                }
+                                Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                                    // Timeout is expected, continue loop to check cancellation
+                                    continue;
+                                }
+                                Err(e) => {
+                                    error!("Error reading from serial port: {}", e);
+                                    publish_frame(&raw_frames, &[], raw_frame::Result::Error(format!("read error: {}", e)));
+                                    events.publish(EventKind::SensorDisconnected, format!("read error on {}: {}", port_name, e));
+                                    disconnected = true;
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        error!("Error opening serial port: {}, retrying in {:?}", e, backoff);
+                        publish_frame(&raw_frames, &[], raw_frame::Result::Error(format!("failed to open {}: {}", port_name, e)));
+                        if !std::mem::replace(&mut disconnected, true) {
+                            events.publish(EventKind::SensorDisconnected, format!("failed to open {}: {}", port_name, e));
+                        }
+                    }
+                }
+
+                // Sleep with cancellation check
+                let sleep_until = Instant::now() + backoff;
+                while Instant::now() < sleep_until {
+                    if cancel_token_clone.is_cancelled() {
+                        info!("Serial reader received shutdown signal during backoff");
+                        return;
+                    }
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+                backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+            }
+        });
+
+        // Wait for the blocking task to complete
+        handle.await?;
+        Ok(())
+    }
+
+    /// Poll a sensor that ranges on request, reopening it with exponential
+    /// backoff on errors
+    #[allow(clippy::too_many_arguments)]
+    pub async fn polled_reader(
+        name: String,
+        open: impl Fn() -> std::io::Result<Box<dyn Rangefinder>> + Send + 'static,
+        interval: Duration,
+        sender: mpsc::UnboundedSender<f64>,
+        log_distance: bool,
+        sensor_power: watch::Receiver<bool>,
+        release_port: watch::Receiver<bool>,
+        raw_frames: RawFrameChannels,
+        frame_counters: Arc<FrameCounters>,
+        events: EventPublisher,
+        cancel_token: CancellationToken,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let handle = tokio::task::spawn_blocking(move || {
+            let mut backoff = Duration::from_secs(1);
+            const MAX_BACKOFF: Duration = Duration::from_secs(60);
+            let mut disconnected = false;
+
+            'reconnect: loop {
+                if cancel_token.is_cancelled() {
+                    info!("Sensor reader received shutdown signal");
+                    return;
+                }
+
+                // Leave the sensor closed while acquisition is paused with closePort
+                if *release_port.borrow() {
+                    std::thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+
+                match open() {
+                    Ok(mut sensor) => {
+                        info!("Sensor {} opened", name);
+                        if std::mem::replace(&mut disconnected, false) {
+                            events.publish(EventKind::SensorReconnected, format!("{} opened", name));
+                        }
+                        backoff = Duration::from_secs(1);
+
+                        loop {
+                            if cancel_token.is_cancelled() {
+                                info!("Sensor reader received shutdown signal");
+                                return;
+                            }
+                            if *release_port.borrow() {
+                                info!("Closing the sensor while acquisition is paused");
+                                continue 'reconnect;
+                            }
+
+                            let started = Instant::now();
+                            // Nothing is wanted while the sensor is switched off, so it isn't asked
+                            if *sensor_power.borrow() {
+                                match sensor.range() {
+                                    Ok(frame) => {
+                                        frame_counters.record(&frame);
+                                        match frame.distance {
+                                            Ok(distance) => {
+                                                publish_frame(&raw_frames, &frame.bytes, raw_frame::Result::Distance(distance));
+                                                if log_distance {
+                                                    info!("Received measurement: distance={}", distance);
+                                                }
+                                                if sender.send(distance).is_err() {
+                                                    error!("Processing channel closed, stopping sensor reader");
+                                                    return;
+                                                }
+                                            }
+                                            Err(e) => {
+                                                error!("Invalid reading {:?} from {}: {}", frame.bytes, name, e);
+                                                publish_frame(&raw_frames, &frame.bytes, raw_frame::Result::Error(e.to_string()));
+                                            }
+                                        }
+                                    }
+                                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                                    Err(e) => {
+                                        error!("Error reading sensor {}: {}", name, e);
+                                        publish_frame(&raw_frames, &[], raw_frame::Result::Error(format!("read error: {}", e)));
+                                        events.publish(EventKind::SensorDisconnected, format!("read error on {}: {}", name, e));
+                                        disconnected = true;
+                                        break;
+                                    }
+                                }
+                            }
+                            std::thread::sleep(interval.saturating_sub(started.elapsed()));
+                        }
+                    }
+                    Err(e) => {
+                        error!("Error opening sensor {}: {}, retrying in {:?}", name, e, backoff);
+                        publish_frame(&raw_frames, &[], raw_frame::Result::Error(format!("failed to open {}: {}", name, e)));
+                        if !std::mem::replace(&mut disconnected, true) {
+                            events.publish(EventKind::SensorDisconnected, format!("failed to open {}: {}", name, e));
+                        }
+                    }
+                }
+
+                let sleep_until = Instant::now() + backoff;
+                while Instant::now() < sleep_until {
+                    if cancel_token.is_cancelled() {
+                        info!("Sensor reader received shutdown signal during backoff");
+                        return;
+                    }
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+                backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+            }
+        });
+
+        handle.await?;
+        Ok(())
+    }
+
+    /// Simulator generates synthetic snowfall data
+    pub async fn simulator(
+        base_distance: f64,
+        sender: mpsc::UnboundedSender<f64>,
+        log_distance: bool,
+        cancel_token: CancellationToken,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        info!("Starting simulator with base_distance={}", base_distance);
+        let start_time = Instant::now();
+
+        let mut interval = time::interval(Duration::from_secs(1));
+
+        loop {
+            tokio::select! {
+                _ = cancel_token.cancelled() => {
+                    info!("Simulator received shutdown signal");
+                    break;
+                }
+                _ = interval.tick() => {
+                    let elapsed = start_time.elapsed();
+                    let elapsed_minutes = elapsed.as_secs_f64() / 60.0;
+
+                    // Snowfall rate: 120mm/hour = 2mm/minute
+                    let snowfall_mm = elapsed_minutes * 2.0;
+                    let base_current_distance = base_distance - snowfall_mm;
+
+                    // Add sinusoidal variations
+                    let sine_component = 3.0 * (2.0 * std::f64::consts::PI * elapsed_minutes / 8.0).sin();
+                    let fast_sine_component = 1.5 * (2.0 * std::f64::consts::PI * elapsed_minutes / 2.0).sin();
+
+                    // Add random variation (±1mm)
+                    let random_component = {
+                        let mut rng = rand::thread_rng();
+                        (rng.gen::<f64>() - 0.5) * 2.0
+                    };
+
+                    let mut current_distance = base_current_distance + sine_component + fast_sine_component + random_component;
+
+                    if current_distance < 0.0 {
+                        current_distance = 0.0;
+                    }
+
+                    if log_distance {
+                        info!(
+                            "Simulated measurement: distance={:.2}, base_distance={:.2}, snowfall_mm={:.2}, variation={:.2}",
+                            current_distance,
+                            base_current_distance,
+                            snowfall_mm,
+                            current_distance - base_current_distance
+                        );
+                    }
+
+                    if sender.send(current_distance).is_err() {
+                        error!("Processing channel closed, stopping simulator");
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,345 @@
+/// Quality flags on emitted readings
+///
+/// Each batch reading carries the set of checks it failed, so consumers can
+/// discard or down-weight suspect data. The checks look at the raw readings
+/// behind the batch: the MaxBotix sensors report their maximum range when no
+/// echo comes back and clamp close targets to their minimum, noisy batches
+/// (falling snow, a swaying mount) spread widely, and a sensor whose output
+/// never changes at all has most likely stopped measuring.
+use std::fmt;
+
+use crate::pipeline::BatchStats;
+
+/// Bitmask of failed checks; empty for a reading that passed them all
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Quality(u32);
+
+impl Quality {
+    pub const OK: Quality = Quality(0);
+    /// The exponential filter is still within its initialization period
+    pub const FILTER_WARMING_UP: Quality = Quality(1);
+    /// The batch result is outside the sensor's measuring range
+    pub const OUT_OF_RANGE: Quality = Quality(2);
+    /// Most of the batch, or a run of consecutive readings, reported no target
+    pub const TARGET_LOST: Quality = Quality(4);
+    /// The raw readings' standard deviation is over the variance threshold
+    pub const HIGH_VARIANCE: Quality = Quality(8);
+    /// The sensor has reported the same raw value for too long
+    pub const STUCK_SENSOR: Quality = Quality(16);
+    /// The result is out of line with the recent emitted series (set by the
+    /// anomaly detector, not these checks)
+    pub const ANOMALOUS: Quality = Quality(32);
+    /// The batch was collected while the wind was over the high-wind
+    /// threshold (set by the pipeline when told so, not these checks)
+    pub const HIGH_WIND: Quality = Quality(64);
+    /// Interpolated over a gap in the batch readings rather than measured
+    pub const INTERPOLATED: Quality = Quality(128);
+
+    const NAMES: [(Quality, &'static str); 8] = [
+        (Quality::FILTER_WARMING_UP, "filter-warming-up"),
+        (Quality::OUT_OF_RANGE, "out-of-range"),
+        (Quality::TARGET_LOST, "target-lost"),
+        (Quality::HIGH_VARIANCE, "high-variance"),
+        (Quality::STUCK_SENSOR, "stuck-sensor"),
+        (Quality::ANOMALOUS, "anomalous"),
+        (Quality::HIGH_WIND, "high-wind"),
+        (Quality::INTERPOLATED, "interpolated"),
+    ];
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn is_ok(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(self, flag: Quality) -> bool {
+        self.0 & flag.0 == flag.0
+    }
+
+    pub fn insert(&mut self, flag: Quality) {
+        self.0 |= flag.0;
+    }
+}
+
+impl fmt::Display for Quality {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_ok() {
+            return write!(f, "ok");
+        }
+        let names: Vec<&str> = Self::NAMES
+            .iter()
+            .filter(|(flag, _)| self.contains(*flag))
+            .map(|(_, name)| *name)
+            .collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+/// Thresholds for the quality checks
+#[derive(Debug, Clone, PartialEq)]
+pub struct QualityChecks {
+    /// Shortest distance the sensor measures, in mm
+    pub min_distance: f64,
+    /// Longest distance the sensor measures, in mm; also reported for no target
+    pub max_distance: f64,
+    /// Standard deviation (mm) of a batch's raw readings above which it is
+    /// flagged as high variance; 0 disables the check
+    pub variance_threshold: f64,
+    /// Identical consecutive raw readings after which the sensor is flagged
+    /// as stuck; 0 disables the check
+    pub stuck_readings: usize,
+    /// Consecutive max-range raw readings after which the target counts as
+    /// lost; 0 disables the check
+    pub target_lost_readings: usize,
+}
+
+impl Default for QualityChecks {
+    /// MB7544 range, with thresholds suited to a 1 Hz reading rate
+    fn default() -> Self {
+        Self {
+            min_distance: 300.0,
+            max_distance: 5000.0,
+            variance_threshold: 25.0,
+            stuck_readings: 1800,
+            target_lost_readings: 10,
+        }
+    }
+}
+
+impl QualityChecks {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.min_distance.is_nan() || self.min_distance < 0.0 || self.min_distance >= self.max_distance {
+            return Err(format!(
+                "sensor-min-distance must be at least 0 and below sensor-max-distance, got {} and {}",
+                self.min_distance, self.max_distance
+            ));
+        }
+        if self.variance_threshold.is_nan() || self.variance_threshold < 0.0 {
+            return Err(format!("variance-threshold must not be negative, got {}", self.variance_threshold));
+        }
+        Ok(())
+    }
+}
+
+/// Applies the quality checks to each batch's raw readings
+#[derive(Debug, Clone)]
+pub struct QualityMonitor {
+    checks: QualityChecks,
+    last_raw: Option<f64>,
+    /// Consecutive raw readings equal to `last_raw`, carried across batches
+    unchanged: usize,
+    /// Consecutive raw readings that reported no target
+    no_target: usize,
+    /// Set once `no_target` reaches the threshold, until a target is seen again
+    target_lost: bool,
+    /// The target was lost at some point during the current batch
+    lost_in_batch: bool,
+}
+
+impl QualityMonitor {
+    pub fn new(checks: QualityChecks) -> Self {
+        Self {
+            checks,
+            last_raw: None,
+            unchanged: 0,
+            no_target: 0,
+            target_lost: false,
+            lost_in_batch: false,
+        }
+    }
+
+    /// Record one raw reading, for the stuck sensor and target checks
+    pub fn record(&mut self, raw: f64) {
+        if self.last_raw == Some(raw) {
+            self.unchanged += 1;
+        } else {
+            self.last_raw = Some(raw);
+            self.unchanged = 1;
+        }
+
+        if self.is_no_target(raw) {
+            self.no_target += 1;
+            let threshold = self.checks.target_lost_readings;
+            if threshold > 0 && self.no_target >= threshold {
+                self.target_lost = true;
+                self.lost_in_batch = true;
+            }
+        } else {
+            self.no_target = 0;
+            self.target_lost = false;
+        }
+    }
+
+    /// True for the reading the sensor reports when no echo comes back
+    pub fn is_no_target(&self, raw: f64) -> bool {
+        raw >= self.checks.max_distance
+    }
+
+    /// True from the end of a run of no-target readings until the next
+    /// reading with a target
+    pub fn target_lost(&self) -> bool {
+        self.target_lost
+    }
+
+    /// Flags for a batch with result `average` from the raw readings `raw`
+    /// summarized by `stats`, starting the next batch
+    pub fn assess(&mut self, raw: &[f64], stats: &BatchStats, average: f64, warming_up: bool) -> Quality {
+        let mut quality = Quality::OK;
+        if warming_up {
+            quality.insert(Quality::FILTER_WARMING_UP);
+        }
+        if average.is_nan() || average < self.checks.min_distance || average >= self.checks.max_distance {
+            quality.insert(Quality::OUT_OF_RANGE);
+        }
+        let no_target = raw.iter().filter(|&&r| r >= self.checks.max_distance).count();
+        if (!raw.is_empty() && no_target * 2 >= raw.len()) || self.lost_in_batch {
+            quality.insert(Quality::TARGET_LOST);
+        }
+        self.lost_in_batch = self.target_lost;
+        if self.checks.variance_threshold > 0.0 && stats.std_dev > self.checks.variance_threshold {
+            quality.insert(Quality::HIGH_VARIANCE);
+        }
+        if self.checks.stuck_readings > 0 && self.unchanged >= self.checks.stuck_readings {
+            quality.insert(Quality::STUCK_SENSOR);
+        }
+        quality
+    }
+
+    /// Forget the runs of unchanged and no-target readings; a lost target
+    /// stays lost until the sensor sees one again
+    pub fn reset(&mut self) {
+        *self = Self {
+            target_lost: self.target_lost,
+            ..Self::new(self.checks.clone())
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn monitor() -> QualityMonitor {
+        QualityMonitor::new(QualityChecks {
+            stuck_readings: 25,
+            ..QualityChecks::default()
+        })
+    }
+
+    fn batch(monitor: &mut QualityMonitor, readings: impl IntoIterator<Item = f64>) -> Quality {
+        let readings: Vec<f64> = readings.into_iter().collect();
+        for &raw in &readings {
+            monitor.record(raw);
+        }
+        let average = readings.iter().sum::<f64>() / readings.len() as f64;
+        monitor.assess(&readings, &BatchStats::from_raw(&readings), average, false)
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Quality::OK.to_string(), "ok");
+        let mut quality = Quality::HIGH_VARIANCE;
+        quality.insert(Quality::FILTER_WARMING_UP);
+        assert_eq!(quality.to_string(), "filter-warming-up,high-variance");
+        assert_eq!(quality.bits(), 9);
+        quality.insert(Quality::ANOMALOUS);
+        assert_eq!(quality.to_string(), "filter-warming-up,high-variance,anomalous");
+    }
+
+    #[test]
+    fn test_clean_batch() {
+        let mut monitor = monitor();
+        let quality = batch(&mut monitor, (0..10).map(|i| 1000.0 + (i % 3) as f64));
+        assert!(quality.is_ok());
+        let readings = [1000.0; 10];
+        let quality = monitor.assess(&readings, &BatchStats::from_raw(&readings), 1000.0, true);
+        assert_eq!(quality, Quality::FILTER_WARMING_UP);
+    }
+
+    #[test]
+    fn test_range_and_target() {
+        let mut monitor = monitor();
+        assert_eq!(batch(&mut monitor, (0..10).map(|i| 250.0 + i as f64)), Quality::OUT_OF_RANGE);
+
+        // A few dropouts are left to the trimmed mean
+        let quality = batch(&mut monitor, (0..10).map(|i| if i < 2 { 5000.0 } else { 1000.0 + i as f64 }));
+        assert!(!quality.contains(Quality::TARGET_LOST));
+
+        let quality = batch(&mut monitor, (0..10).map(|i| if i < 5 { 5000.0 } else { 4990.0 + i as f64 }));
+        assert!(quality.contains(Quality::TARGET_LOST));
+        assert!(!quality.contains(Quality::OUT_OF_RANGE));
+    }
+
+    #[test]
+    fn test_target_lost_after_run() {
+        let mut monitor = monitor();
+        for _ in 0..9 {
+            monitor.record(5000.0);
+        }
+        assert!(!monitor.target_lost());
+        monitor.record(5000.0);
+        assert!(monitor.target_lost());
+
+        // A reset doesn't bring the target back, but a reading does
+        monitor.reset();
+        monitor.record(5000.0);
+        assert!(monitor.target_lost());
+        monitor.record(1000.0);
+        assert!(!monitor.target_lost());
+
+        // The batch the run ended in is flagged, even with mostly good readings
+        let quality = batch(&mut monitor, (0..30).map(|i| if i < 10 { 5000.0 } else { 1000.0 }));
+        assert!(quality.contains(Quality::TARGET_LOST));
+        assert!(batch(&mut monitor, [1000.0, 1001.0, 1002.0]).is_ok());
+
+        let mut unchecked = QualityMonitor::new(QualityChecks { target_lost_readings: 0, ..QualityChecks::default() });
+        for _ in 0..100 {
+            unchecked.record(5000.0);
+        }
+        assert!(!unchecked.target_lost());
+    }
+
+    #[test]
+    fn test_high_variance() {
+        let mut monitor = monitor();
+        let quality = batch(&mut monitor, (0..10).map(|i| if i % 2 == 0 { 1000.0 } else { 1080.0 }));
+        assert_eq!(quality, Quality::HIGH_VARIANCE);
+
+        let mut unchecked = QualityMonitor::new(QualityChecks {
+            variance_threshold: 0.0,
+            ..QualityChecks::default()
+        });
+        assert!(batch(&mut unchecked, (0..10).map(|i| if i % 2 == 0 { 1000.0 } else { 1080.0 })).is_ok());
+    }
+
+    #[test]
+    fn test_stuck_sensor_spans_batches() {
+        let mut monitor = monitor();
+        assert!(batch(&mut monitor, [1234.0; 10]).is_ok());
+        assert!(batch(&mut monitor, [1234.0; 10]).is_ok());
+        assert_eq!(batch(&mut monitor, [1234.0; 10]), Quality::STUCK_SENSOR);
+
+        // Any change starts the count over
+        assert!(batch(&mut monitor, [1235.0; 10]).is_ok());
+        monitor.reset();
+        assert!(batch(&mut monitor, [1235.0; 20]).is_ok());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(QualityChecks::default().validate().is_ok());
+        let inverted = QualityChecks {
+            min_distance: 5000.0,
+            max_distance: 300.0,
+            ..QualityChecks::default()
+        };
+        assert!(inverted.validate().is_err());
+        let negative = QualityChecks {
+            variance_threshold: -1.0,
+            ..QualityChecks::default()
+        };
+        assert!(negative.validate().is_err());
+    }
+}
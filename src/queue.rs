@@ -0,0 +1,352 @@
+/// Bounded per-client stream queues
+///
+/// Broadcasts never wait on a client, so each client's items queue up until
+/// its stream takes them. A queue holds at most `capacity` items; once a
+/// client falls that far behind, the overflow policy decides whether it
+/// loses its oldest items, its newest, or its stream (which then ends with
+/// RESOURCE_EXHAUSTED). Either way a stalled client can no longer grow the
+/// server's memory without bound.
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+
+use tokio_stream::Stream;
+use tonic::Status;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    /// Discard the oldest queued item to make room
+    DropOldest,
+    /// Discard the new item
+    DropNewest,
+    /// End the client's stream
+    Disconnect,
+}
+
+impl std::str::FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "drop-oldest" | "dropoldest" => Ok(OverflowPolicy::DropOldest),
+            "drop-newest" | "dropnewest" => Ok(OverflowPolicy::DropNewest),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            _ => Err(format!(
+                "Invalid overflow policy '{}'. Valid options: drop-oldest, drop-newest, disconnect",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for OverflowPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OverflowPolicy::DropOldest => write!(f, "drop-oldest"),
+            OverflowPolicy::DropNewest => write!(f, "drop-newest"),
+            OverflowPolicy::Disconnect => write!(f, "disconnect"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TryRecvError {
+    Empty,
+    Disconnected,
+}
+
+struct State<T> {
+    items: VecDeque<Result<T, Status>>,
+    /// Nothing more will be queued: every sender is gone or the stream was closed
+    closed: bool,
+    senders: usize,
+    receiver_alive: bool,
+    dropped: u64,
+    waker: Option<Waker>,
+}
+
+struct Shared<T> {
+    capacity: usize,
+    policy: OverflowPolicy,
+    state: Mutex<State<T>>,
+}
+
+impl<T> Shared<T> {
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn close(&self, status: Option<Status>) {
+        let mut state = self.lock();
+        if state.closed {
+            return;
+        }
+        state.closed = true;
+        if let Some(status) = status {
+            state.items.push_back(Err(status));
+        }
+        wake(&mut state);
+    }
+}
+
+fn wake<T>(state: &mut State<T>) {
+    if let Some(waker) = state.waker.take() {
+        waker.wake();
+    }
+}
+
+/// A queue of at most `capacity` (at least 1) items
+pub fn bounded<T>(capacity: usize, policy: OverflowPolicy) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        capacity: capacity.max(1),
+        policy,
+        state: Mutex::new(State {
+            items: VecDeque::new(),
+            closed: false,
+            senders: 1,
+            receiver_alive: true,
+            dropped: 0,
+            waker: None,
+        }),
+    });
+    (Sender { shared: shared.clone() }, Receiver { shared })
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Queue an item, applying the overflow policy if the queue is full
+    ///
+    /// Returns false once the stream is over: the receiver is gone, the
+    /// stream was closed, or this item overflowed under
+    /// `OverflowPolicy::Disconnect`.
+    pub fn send(&self, item: Result<T, Status>) -> bool {
+        let mut state = self.shared.lock();
+        if state.closed || !state.receiver_alive {
+            return false;
+        }
+        if state.items.len() >= self.shared.capacity {
+            match self.shared.policy {
+                OverflowPolicy::DropOldest => {
+                    state.items.pop_front();
+                    state.dropped += 1;
+                }
+                OverflowPolicy::DropNewest => {
+                    state.dropped += 1;
+                    return true;
+                }
+                OverflowPolicy::Disconnect => {
+                    // The final status goes over capacity by one, so the
+                    // client learns why its stream ended
+                    state.items.push_back(Err(Status::resource_exhausted("stream client fell too far behind")));
+                    state.closed = true;
+                    wake(&mut state);
+                    return false;
+                }
+            }
+        }
+        state.items.push_back(item);
+        wake(&mut state);
+        true
+    }
+
+    pub fn is_closed(&self) -> bool {
+        let state = self.shared.lock();
+        state.closed || !state.receiver_alive
+    }
+
+    /// Items discarded by the drop policies so far
+    #[cfg(test)]
+    pub fn dropped(&self) -> u64 {
+        self.shared.lock().dropped
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.senders -= 1;
+        if state.senders == 0 {
+            state.closed = true;
+            wake(&mut state);
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// The next item, or None once the queue is empty and closed
+    pub async fn recv(&mut self) -> Option<Result<T, Status>> {
+        std::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<Result<T, Status>>> {
+        let mut state = self.shared.lock();
+        if let Some(item) = state.items.pop_front() {
+            return Poll::Ready(Some(item));
+        }
+        if state.closed {
+            return Poll::Ready(None);
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    pub fn try_recv(&mut self) -> Result<Result<T, Status>, TryRecvError> {
+        let mut state = self.shared.lock();
+        match state.items.pop_front() {
+            Some(item) => Ok(item),
+            None if state.closed => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shared.lock().items.is_empty()
+    }
+
+    /// A handle that can end the stream, without counting as a sender
+    pub fn closer(&self) -> Closer<T> {
+        Closer {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = Result<T, Status>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.receiver_alive = false;
+        state.items.clear();
+    }
+}
+
+pub struct Closer<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Closer<T> {
+    /// End the stream once the queued items have been received
+    pub fn close(&self) {
+        self.shared.close(None);
+    }
+
+    /// End the stream with `status` after the queued items
+    pub fn close_with(&self, status: Status) {
+        self.shared.close(Some(status));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain(rx: &mut Receiver<u32>) -> Vec<Result<u32, tonic::Code>> {
+        let mut items = Vec::new();
+        while let Ok(item) = rx.try_recv() {
+            items.push(item.map_err(|s| s.code()));
+        }
+        items
+    }
+
+    #[test]
+    fn test_parse_policy() {
+        assert_eq!("drop-oldest".parse::<OverflowPolicy>().unwrap(), OverflowPolicy::DropOldest);
+        assert_eq!("Disconnect".parse::<OverflowPolicy>().unwrap(), OverflowPolicy::Disconnect);
+        assert!("block".parse::<OverflowPolicy>().is_err());
+        assert_eq!(OverflowPolicy::DropNewest.to_string().parse::<OverflowPolicy>().unwrap(), OverflowPolicy::DropNewest);
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let (tx, mut rx) = bounded(3, OverflowPolicy::DropOldest);
+        for i in 0..5 {
+            assert!(tx.send(Ok(i)));
+        }
+        assert_eq!(tx.dropped(), 2);
+        assert_eq!(drain(&mut rx), vec![Ok(2), Ok(3), Ok(4)]);
+    }
+
+    #[test]
+    fn test_drop_newest() {
+        let (tx, mut rx) = bounded(3, OverflowPolicy::DropNewest);
+        for i in 0..5 {
+            assert!(tx.send(Ok(i)));
+        }
+        assert_eq!(tx.dropped(), 2);
+        assert_eq!(drain(&mut rx), vec![Ok(0), Ok(1), Ok(2)]);
+        assert!(tx.send(Ok(5)));
+        assert_eq!(drain(&mut rx), vec![Ok(5)]);
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let (tx, mut rx) = bounded(2, OverflowPolicy::Disconnect);
+        assert!(tx.send(Ok(0)));
+        assert!(tx.send(Ok(1)));
+        assert!(!tx.send(Ok(2)));
+        assert!(tx.is_closed());
+        assert!(!tx.send(Ok(3)));
+        assert_eq!(drain(&mut rx), vec![Ok(0), Ok(1), Err(tonic::Code::ResourceExhausted)]);
+        assert_eq!(rx.try_recv().unwrap_err(), TryRecvError::Disconnected);
+    }
+
+    #[tokio::test]
+    async fn test_end_of_stream() {
+        let (tx, mut rx) = bounded(4, OverflowPolicy::DropOldest);
+        let other = tx.clone();
+        tx.send(Ok(1));
+        drop(tx);
+        assert_eq!(rx.try_recv().unwrap().unwrap(), 1);
+        assert_eq!(rx.try_recv().unwrap_err(), TryRecvError::Empty);
+        drop(other);
+        assert!(rx.recv().await.is_none());
+
+        let (tx, rx) = bounded::<u32>(4, OverflowPolicy::DropOldest);
+        drop(rx);
+        assert!(tx.is_closed());
+        assert!(!tx.send(Ok(1)));
+    }
+
+    #[tokio::test]
+    async fn test_closer() {
+        let (tx, mut rx) = bounded(4, OverflowPolicy::DropOldest);
+        let closer = rx.closer();
+        let waiting = tokio::spawn(async move {
+            let first = rx.recv().await.map(|r| r.map_err(|s| s.code()));
+            let second = rx.recv().await.map(|r| r.map_err(|s| s.code()));
+            let end = rx.recv().await.is_none();
+            (first, second, end)
+        });
+        tx.send(Ok(7));
+        closer.close_with(Status::deadline_exceeded("gone quiet"));
+        assert!(tx.is_closed());
+        assert_eq!(
+            waiting.await.unwrap(),
+            (Some(Ok(7)), Some(Err(tonic::Code::DeadlineExceeded)), true)
+        );
+    }
+}
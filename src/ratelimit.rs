@@ -0,0 +1,159 @@
+/// Per-client rate limiting of the unary RPCs
+///
+/// Each client address gets a token bucket holding up to `burst` requests
+/// and refilling at `rate` requests per second, so an occasional burst of
+/// queries goes through but a poller hammering GetHistory can't starve the
+/// streams on a small board. Streams are not limited; they are capped by
+/// `--max-clients` instead.
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use log::warn;
+use tonic::{Request, Status};
+
+/// Buckets tracked before full (idle) ones are forgotten, then the least
+/// recently used
+const MAX_BUCKETS: usize = 1024;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    /// Keyed by client address; requests without one share a bucket
+    buckets: Mutex<HashMap<Option<IpAddr>, Bucket>>,
+}
+
+impl RateLimiter {
+    /// Allow `rate` requests per second per client, in bursts of up to `burst`
+    pub fn new(rate: f64, burst: usize) -> Self {
+        Self {
+            rate,
+            burst: burst.max(1) as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token from `client`'s bucket, or false if it is empty
+    pub fn try_acquire(&self, client: Option<IpAddr>, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(&client) {
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, b| b.tokens + now.duration_since(b.updated).as_secs_f64() * rate < burst);
+            // With every client active, room is made at the expense of the
+            // longest quiet
+            if buckets.len() >= MAX_BUCKETS {
+                let mut oldest: Vec<(Instant, Option<IpAddr>)> = buckets.iter().map(|(client, b)| (b.updated, *client)).collect();
+                oldest.sort_unstable();
+                for (_, client) in oldest.into_iter().take(buckets.len() + 1 - MAX_BUCKETS) {
+                    buckets.remove(&client);
+                }
+            }
+        }
+
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Fail with RESOURCE_EXHAUSTED if the request's client is over its rate
+    #[allow(clippy::result_large_err)]
+    pub fn check<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        let client = request.remote_addr().map(|addr| addr.ip());
+        if self.try_acquire(client, Instant::now()) {
+            return Ok(());
+        }
+        warn!("Rate limiting gRPC requests from [{}]",
+              client.map(|ip| ip.to_string()).unwrap_or_else(|| "unknown".to_string()));
+        Err(Status::resource_exhausted(format!(
+            "rate limit exceeded ({} requests per second)", self.rate
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn test_burst_then_refill() {
+        let limiter = RateLimiter::new(2.0, 5);
+        let start = Instant::now();
+        let client = ip("10.0.0.1");
+        assert_eq!((0..10).filter(|_| limiter.try_acquire(client, start)).count(), 5);
+
+        // Two tokens a second
+        let later = start + Duration::from_millis(1500);
+        assert_eq!((0..10).filter(|_| limiter.try_acquire(client, later)).count(), 3);
+
+        // Never more than the burst, however long the client was idle
+        let much_later = later + Duration::from_secs(3600);
+        assert_eq!((0..10).filter(|_| limiter.try_acquire(client, much_later)).count(), 5);
+    }
+
+    #[test]
+    fn test_clients_limited_separately() {
+        let limiter = RateLimiter::new(1.0, 2);
+        let now = Instant::now();
+        assert!(limiter.try_acquire(ip("10.0.0.1"), now));
+        assert!(limiter.try_acquire(ip("10.0.0.1"), now));
+        assert!(!limiter.try_acquire(ip("10.0.0.1"), now));
+        assert!(limiter.try_acquire(ip("10.0.0.2"), now));
+        assert!(limiter.try_acquire(None, now));
+    }
+
+    #[test]
+    fn test_idle_buckets_forgotten() {
+        let limiter = RateLimiter::new(1.0, 2);
+        let now = Instant::now();
+        for i in 0..MAX_BUCKETS {
+            let client = Some(IpAddr::from([10, 0, (i / 256) as u8, (i % 256) as u8]));
+            assert!(limiter.try_acquire(client, now));
+        }
+        let later = now + Duration::from_secs(10);
+        assert!(limiter.try_acquire(ip("192.168.0.1"), later));
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_active_buckets_bounded() {
+        let limiter = RateLimiter::new(0.1, 2);
+        let start = Instant::now();
+        let client = |i: usize| Some(IpAddr::from([10, 0, (i / 256) as u8, (i % 256) as u8]));
+        // 1025 clients, each still short of a full bucket when the next comes
+        for i in 0..=MAX_BUCKETS {
+            assert!(limiter.try_acquire(client(i), start + Duration::from_millis(i as u64)));
+        }
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), MAX_BUCKETS);
+        assert!(!buckets.contains_key(&client(0)));
+        assert!(buckets.contains_key(&client(1)) && buckets.contains_key(&client(MAX_BUCKETS)));
+    }
+
+    #[test]
+    fn test_check() {
+        let limiter = RateLimiter::new(1.0, 1);
+        let request = Request::new(());
+        assert!(limiter.check(&request).is_ok());
+        assert_eq!(limiter.check(&request).unwrap_err().code(), tonic::Code::ResourceExhausted);
+    }
+}
@@ -0,0 +1,138 @@
+/// Rolling Theil–Sen trend filter
+///
+/// Each output is the value, at the newest reading, of a Theil–Sen line
+/// fitted to the last `window` readings. An average or the exponential
+/// filter trails steady accumulation by about half its window (or by
+/// rate × (1 − alpha) / alpha); the fitted line follows the ramp, so the
+/// output has little lag however long the window. Since the slope is the
+/// median of the pairwise slopes and the line goes through the median
+/// residual, spikes making up less than about a third of the window barely
+/// move it. A step change in the surface comes through once it makes up
+/// about half the window.
+use std::collections::VecDeque;
+
+use crate::trend;
+
+pub struct TrendFilter {
+    window: usize,
+    recent: VecDeque<f64>,
+    reading_count: usize,
+}
+
+impl TrendFilter {
+    pub fn new(window: usize) -> Self {
+        let window = window.max(1);
+        Self {
+            window,
+            recent: VecDeque::with_capacity(window),
+            reading_count: 0,
+        }
+    }
+
+    /// Change the window, keeping the most recent readings that still fit
+    pub fn set_window(&mut self, window: usize) {
+        self.window = window.max(1);
+        while self.recent.len() > self.window {
+            self.recent.pop_front();
+        }
+    }
+
+    /// Process a new reading, returning the fitted value at it
+    ///
+    /// Readings pass through unchanged until there are three to fit.
+    pub fn update(&mut self, raw_reading: f64) -> f64 {
+        self.reading_count += 1;
+        if self.recent.len() >= self.window {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(raw_reading);
+
+        let points: Vec<(f64, f64)> = self.recent.iter().enumerate().map(|(i, &r)| (i as f64, r)).collect();
+        match trend::theil_sen(&points) {
+            Some(fit) => fit.value_at((points.len() - 1) as f64),
+            None => raw_reading,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.recent.clear();
+        self.reading_count = 0;
+    }
+
+    /// True once the window is full
+    pub fn is_initialized(&self) -> bool {
+        self.recent.len() >= self.window
+    }
+
+    pub fn reading_count(&self) -> usize {
+        self.reading_count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::median::MedianFilter;
+
+    /// Deterministic noise of up to ±3 mm
+    fn noise(i: usize) -> f64 {
+        ((i * 7919) % 7) as f64 - 3.0
+    }
+
+    #[test]
+    fn test_tracks_ramp_without_lag() {
+        // Heavy snowfall: the surface closes in by 0.5 mm per reading
+        let truth = |i: usize| 2000.0 - 0.5 * i as f64;
+        let mut trend = TrendFilter::new(60);
+        let mut median = MedianFilter::new(60);
+        let (mut trend_error, mut median_error) = (0.0, 0.0);
+        for i in 0..300 {
+            let reading = truth(i) + noise(i);
+            let t = trend.update(reading);
+            let m = median.update(reading);
+            if i >= 100 {
+                trend_error += (t - truth(i)).abs();
+                median_error += (m - truth(i)).abs();
+            }
+        }
+        assert!(trend.is_initialized());
+        assert!(trend_error / 200.0 < 1.5, "trend mean error {}", trend_error / 200.0);
+        // The median lags by a quarter of its window's travel, 15 mm
+        assert!(median_error / 200.0 > 10.0, "median mean error {}", median_error / 200.0);
+    }
+
+    #[test]
+    fn test_ignores_spikes() {
+        let mut filter = TrendFilter::new(20);
+        for i in 0..20 {
+            filter.update(1000.0 + noise(i));
+        }
+        for spike in [300.0, 4999.0, 300.0] {
+            assert!((filter.update(spike) - 1000.0).abs() <= 2.0);
+        }
+    }
+
+    #[test]
+    fn test_partial_window_and_reset() {
+        let mut filter = TrendFilter::new(10);
+        assert_eq!(filter.update(1000.0), 1000.0);
+        assert_eq!(filter.update(990.0), 990.0);
+        assert_eq!(filter.update(980.0), 980.0);
+        assert!(!filter.is_initialized());
+        filter.reset();
+        assert_eq!(filter.reading_count(), 0);
+        assert_eq!(filter.update(500.0), 500.0);
+    }
+
+    #[test]
+    fn test_set_window() {
+        let mut filter = TrendFilter::new(5);
+        for r in [1.0, 2.0, 3.0, 4.0, 5.0] {
+            filter.update(r);
+        }
+        filter.set_window(3);
+        assert!(filter.is_initialized());
+        // 4, 5 and the new reading remain
+        assert_eq!(filter.update(9.0), 9.0);
+    }
+}
@@ -0,0 +1,458 @@
+/// Sensors on Ethernet serial servers
+///
+/// `tcp://host:port` reads a raw TCP port, as ser2net and most serial
+/// servers offer, where the server's own line settings apply and there are
+/// no control lines. `rfc2217://host:port` speaks the RFC 2217 telnet com
+/// port option instead: the line settings are sent on connecting, and DTR
+/// and RTS can be driven for `--sensor-power-line`. Either way the port
+/// reads like a local one, so the serial reader needs no changes for it.
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+
+use crate::serial::SerialConfig;
+
+/// How long connecting to each address may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Telnet commands and options (RFC 854, 856, 858 and 2217)
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+const BINARY: u8 = 0;
+const SUPPRESS_GO_AHEAD: u8 = 3;
+const COM_PORT_OPTION: u8 = 44;
+
+// Com port option commands, and the SET-CONTROL values used
+const SET_BAUDRATE: u8 = 1;
+const SET_DATASIZE: u8 = 2;
+const SET_PARITY: u8 = 3;
+const SET_STOPSIZE: u8 = 4;
+const SET_CONTROL: u8 = 5;
+const BREAK_ON: u8 = 5;
+const BREAK_OFF: u8 = 6;
+const DTR_ON: u8 = 8;
+const DTR_OFF: u8 = 9;
+const RTS_ON: u8 = 11;
+const RTS_OFF: u8 = 12;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Remote {
+    /// `host:port`
+    pub address: String,
+    /// Telnet with the com port option rather than raw TCP
+    pub rfc2217: bool,
+}
+
+impl Remote {
+    /// The remote port `port` names, or None for a local device
+    pub fn parse(port: &str) -> Option<Self> {
+        let (address, rfc2217) = match port.strip_prefix("tcp://") {
+            Some(address) => (address, false),
+            None => (port.strip_prefix("rfc2217://")?, true),
+        };
+        Some(Self { address: address.trim_end_matches('/').to_string(), rfc2217 })
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum State {
+    #[default]
+    Data,
+    Iac,
+    /// After WILL, WONT, DO or DONT, waiting for the option
+    Negotiate(u8),
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+/// Separates the data from the telnet commands in what the server sends
+#[derive(Debug, Default)]
+struct Telnet {
+    state: State,
+}
+
+impl Telnet {
+    /// Append the data in `input` to `data`, and the answers to the
+    /// server's negotiation to `replies`
+    fn decode(&mut self, input: &[u8], data: &mut Vec<u8>, replies: &mut Vec<u8>) {
+        for &byte in input {
+            self.state = match (self.state, byte) {
+                (State::Data, IAC) => State::Iac,
+                (State::Data, _) => {
+                    data.push(byte);
+                    State::Data
+                }
+                (State::Iac, IAC) => {
+                    data.push(IAC);
+                    State::Data
+                }
+                (State::Iac, SB) => State::Subnegotiation,
+                (State::Iac, WILL | WONT | DO | DONT) => State::Negotiate(byte),
+                // Other commands stand alone
+                (State::Iac, _) => State::Data,
+                (State::Negotiate(command), option) => {
+                    replies.extend(reply(command, option).iter().flatten());
+                    State::Data
+                }
+                // The server's com port notifications aren't needed
+                (State::Subnegotiation, IAC) => State::SubnegotiationIac,
+                (State::Subnegotiation, _) => State::Subnegotiation,
+                (State::SubnegotiationIac, SE) => State::Data,
+                (State::SubnegotiationIac, _) => State::Subnegotiation,
+            };
+        }
+    }
+}
+
+/// Refuse the options the server asks for or offers beyond those used,
+/// which were offered or asked for on connecting
+fn reply(command: u8, option: u8) -> Option<[u8; 3]> {
+    let used = matches!(option, BINARY | SUPPRESS_GO_AHEAD | COM_PORT_OPTION);
+    match command {
+        DO if !used => Some([IAC, WONT, option]),
+        WILL if !used => Some([IAC, DONT, option]),
+        _ => None,
+    }
+}
+
+/// A com port option command with its value
+fn com_port(command: u8, value: &[u8]) -> Vec<u8> {
+    let mut message = vec![IAC, SB, COM_PORT_OPTION, command];
+    for &byte in value {
+        message.push(byte);
+        if byte == IAC {
+            message.push(IAC);
+        }
+    }
+    message.extend([IAC, SE]);
+    message
+}
+
+/// Negotiation and line settings sent on connecting
+fn handshake(config: &SerialConfig) -> Vec<u8> {
+    let mut message = vec![IAC, WILL, BINARY, IAC, DO, BINARY, IAC, DO, SUPPRESS_GO_AHEAD, IAC, WILL, COM_PORT_OPTION];
+    message.extend(com_port(SET_BAUDRATE, &config.baud_rate.to_be_bytes()));
+    message.extend(com_port(SET_DATASIZE, &[u8::from(config.data_bits)]));
+    message.extend(com_port(SET_PARITY, &[parity_value(config.parity)]));
+    message.extend(com_port(SET_STOPSIZE, &[stop_bits_value(config.stop_bits)]));
+    message
+}
+
+fn parity_value(parity: Parity) -> u8 {
+    match parity {
+        Parity::None => 1,
+        Parity::Odd => 2,
+        Parity::Even => 3,
+    }
+}
+
+fn stop_bits_value(stop_bits: StopBits) -> u8 {
+    match stop_bits {
+        StopBits::One => 1,
+        StopBits::Two => 2,
+    }
+}
+
+fn unsupported(what: &str) -> serialport::Error {
+    serialport::Error::new(serialport::ErrorKind::Unknown, format!("{} not supported over raw TCP; use rfc2217://", what))
+}
+
+pub struct RemotePort {
+    name: String,
+    stream: TcpStream,
+    config: SerialConfig,
+    timeout: Duration,
+    /// None for raw TCP
+    telnet: Option<Telnet>,
+    /// Data received but not yet read
+    pending: Vec<u8>,
+}
+
+impl RemotePort {
+    pub fn connect(name: &str, remote: &Remote, config: SerialConfig, timeout: Duration) -> io::Result<Self> {
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("{} did not resolve", remote.address));
+        let mut connected = None;
+        for address in remote.address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+                Ok(stream) => {
+                    connected = Some(stream);
+                    break;
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        let mut stream = connected.ok_or(last_error)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_nodelay(true)?;
+        if remote.rfc2217 {
+            stream.write_all(&handshake(&config))?;
+        }
+        Ok(Self {
+            name: name.to_string(),
+            stream,
+            config,
+            timeout,
+            telnet: remote.rfc2217.then(Telnet::default),
+            pending: Vec::new(),
+        })
+    }
+
+    /// Send a com port option command, if the port speaks RFC 2217
+    fn command(&self, what: &str, command: u8, value: &[u8]) -> serialport::Result<()> {
+        if self.telnet.is_none() {
+            return Err(unsupported(what));
+        }
+        (&self.stream).write_all(&com_port(command, value))?;
+        Ok(())
+    }
+}
+
+impl Read for RemotePort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut received = [0u8; 256];
+        while self.pending.is_empty() {
+            let n = match self.stream.read(&mut received) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by the server")),
+                Ok(n) => n,
+                // A read timeout, reported as a serial port reports one
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Err(io::ErrorKind::TimedOut.into()),
+                Err(e) => return Err(e),
+            };
+            match self.telnet {
+                Some(ref mut telnet) => {
+                    let mut replies = Vec::new();
+                    telnet.decode(&received[..n], &mut self.pending, &mut replies);
+                    self.stream.write_all(&replies)?;
+                }
+                None => self.pending.extend_from_slice(&received[..n]),
+            }
+        }
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for RemotePort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.telnet.is_none() {
+            return self.stream.write(buf);
+        }
+        let mut escaped = Vec::with_capacity(buf.len());
+        for &byte in buf {
+            escaped.push(byte);
+            if byte == IAC {
+                escaped.push(IAC);
+            }
+        }
+        self.stream.write_all(&escaped)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl SerialPort for RemotePort {
+    fn name(&self) -> Option<String> {
+        Some(self.name.clone())
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(self.config.baud_rate)
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        Ok(self.config.data_bits)
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        Ok(FlowControl::None)
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        Ok(self.config.parity)
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        Ok(self.config.stop_bits)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.command("Line settings", SET_BAUDRATE, &baud_rate.to_be_bytes())?;
+        self.config.baud_rate = baud_rate;
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, data_bits: DataBits) -> serialport::Result<()> {
+        self.command("Line settings", SET_DATASIZE, &[u8::from(data_bits)])?;
+        self.config.data_bits = data_bits;
+        Ok(())
+    }
+
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> serialport::Result<()> {
+        match flow_control {
+            FlowControl::None => Ok(()),
+            _ => Err(serialport::Error::new(serialport::ErrorKind::InvalidInput, "flow control is not supported")),
+        }
+    }
+
+    fn set_parity(&mut self, parity: Parity) -> serialport::Result<()> {
+        self.command("Line settings", SET_PARITY, &[parity_value(parity)])?;
+        self.config.parity = parity;
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, stop_bits: StopBits) -> serialport::Result<()> {
+        self.command("Line settings", SET_STOPSIZE, &[stop_bits_value(stop_bits)])?;
+        self.config.stop_bits = stop_bits;
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.stream.set_read_timeout(Some(timeout))?;
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, level: bool) -> serialport::Result<()> {
+        self.command("Control lines", SET_CONTROL, &[if level { RTS_ON } else { RTS_OFF }])
+    }
+
+    fn write_data_terminal_ready(&mut self, level: bool) -> serialport::Result<()> {
+        self.command("Control lines", SET_CONTROL, &[if level { DTR_ON } else { DTR_OFF }])
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Err(serialport::Error::new(serialport::ErrorKind::Unknown, "reading control lines is not supported"))
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Err(serialport::Error::new(serialport::ErrorKind::Unknown, "reading control lines is not supported"))
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Err(serialport::Error::new(serialport::ErrorKind::Unknown, "reading control lines is not supported"))
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Err(serialport::Error::new(serialport::ErrorKind::Unknown, "reading control lines is not supported"))
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        Ok(self.pending.len() as u32)
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    fn clear(&self, _buffer_to_clear: ClearBuffer) -> serialport::Result<()> {
+        // The server's buffers can't be reached
+        Ok(())
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Err(serialport::Error::new(serialport::ErrorKind::Unknown, "remote ports can't be cloned"))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        self.command("Break", SET_CONTROL, &[BREAK_ON])
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        self.command("Break", SET_CONTROL, &[BREAK_OFF])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Remote::parse("tcp://tower-1:4001"), Some(Remote { address: "tower-1:4001".to_string(), rfc2217: false }));
+        assert_eq!(Remote::parse("rfc2217://10.0.0.5:2217/").unwrap().address, "10.0.0.5:2217");
+        assert!(Remote::parse("rfc2217://10.0.0.5:2217").unwrap().rfc2217);
+        assert_eq!(Remote::parse("/dev/ttyUSB0"), None);
+    }
+
+    #[test]
+    fn test_telnet_decode() {
+        let mut telnet = Telnet::default();
+        let (mut data, mut replies) = (Vec::new(), Vec::new());
+        // Data split around an escaped 0xff, a WILL ECHO, an acknowledged
+        // DO COM-PORT-OPTION and a baud rate notification
+        telnet.decode(&[b'R', b'1', IAC, WILL, 1, b'8', IAC, IAC, IAC, DO, COM_PORT_OPTION], &mut data, &mut replies);
+        telnet.decode(&[IAC, SB, COM_PORT_OPTION, 101, 0, 0, IAC], &mut data, &mut replies);
+        telnet.decode(&[IAC, 0x80, IAC, SE, b'\r'], &mut data, &mut replies);
+        assert_eq!(data, [b'R', b'1', b'8', IAC, b'\r']);
+        assert_eq!(replies, [IAC, DONT, 1]);
+        assert_eq!(com_port(SET_CONTROL, &[IAC]), [IAC, SB, COM_PORT_OPTION, SET_CONTROL, IAC, IAC, IAC, SE]);
+    }
+
+    #[test]
+    fn test_rfc2217_port() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(&[b'R', b'1', b'8', IAC, DO, 1, b'3', b'4', b'\r']).unwrap();
+            let mut received = vec![0u8; 64];
+            let mut total = 0;
+            // The handshake, the refusal of ECHO and DTR on
+            while !received[..total].ends_with(&com_port(SET_CONTROL, &[DTR_ON])) {
+                total += stream.read(&mut received[total..]).unwrap();
+            }
+            received.truncate(total);
+            received
+        });
+
+        let remote = Remote::parse(&format!("rfc2217://{}", address)).unwrap();
+        let mut port = RemotePort::connect("test", &remote, SerialConfig::default(), Duration::from_secs(5)).unwrap();
+        let mut frame = Vec::new();
+        let mut buf = [0u8; 4];
+        while frame.len() < 6 {
+            let n = port.read(&mut buf).unwrap();
+            frame.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(frame, b"R1834\r");
+        port.write_data_terminal_ready(true).unwrap();
+
+        let received = server.join().unwrap();
+        let mut expected = handshake(&SerialConfig::default());
+        expected.extend([IAC, WONT, 1]);
+        expected.extend(com_port(SET_CONTROL, &[DTR_ON]));
+        assert_eq!(received, expected);
+        // The server has hung up
+        assert_eq!(port.read(&mut buf).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_raw_port() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let remote = Remote::parse(&format!("tcp://{}", listener.local_addr().unwrap())).unwrap();
+        let mut port = RemotePort::connect("test", &remote, SerialConfig::default(), Duration::from_millis(50)).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = [0u8; 8];
+        assert_eq!(port.read(&mut buf).unwrap_err().kind(), io::ErrorKind::TimedOut);
+        stream.write_all(&[b'R', IAC, b'\r']).unwrap();
+        assert_eq!(port.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], [b'R', IAC, b'\r']);
+        assert!(port.write_request_to_send(true).is_err());
+    }
+}
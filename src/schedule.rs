@@ -0,0 +1,362 @@
+/// Scheduled measurement windows for power saving
+///
+/// Inside a configured window (e.g. 06:00-22:00 local time) the sensor
+/// measures continuously. Outside all windows it is powered down and woken
+/// once per interval for a burst that lasts one full batch, so each sparse
+/// reading is still a complete batch of consecutive samples.
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Daily window in local time, `[start, end)` in minutes after midnight
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Window {
+    start: u32,
+    end: u32,
+}
+
+impl Window {
+    /// True if `minute` falls in the window; windows may wrap past midnight
+    pub fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            minute >= self.start && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+impl std::str::FromStr for Window {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| format!("Invalid schedule window '{}', expected HH:MM-HH:MM", s))?;
+        let window = Window {
+            start: parse_time(start.trim())?,
+            end: parse_time(end.trim())?,
+        };
+        if window.start == window.end {
+            return Err(format!("Schedule window '{}' is empty", s));
+        }
+        Ok(window)
+    }
+}
+
+impl std::fmt::Display for Window {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+/// Parse `HH:MM` (24:00 is accepted as end of day) into minutes after midnight
+fn parse_time(s: &str) -> Result<u32, String> {
+    let invalid = || format!("Invalid time '{}', expected HH:MM", s);
+    let (hours, minutes) = s.split_once(':').ok_or_else(invalid)?;
+    let hours: u32 = hours.parse().map_err(|_| invalid())?;
+    let minutes: u32 = minutes.parse().map_err(|_| invalid())?;
+    if minutes >= 60 || hours > 24 || (hours == 24 && minutes != 0) {
+        return Err(invalid());
+    }
+    Ok(hours * 60 + minutes)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    windows: Vec<Window>,
+    /// Time between sparse bursts outside the windows
+    interval: Duration,
+}
+
+impl Schedule {
+    /// Parse a comma-separated list of windows, e.g. "06:00-22:00"
+    pub fn parse(spec: &str, interval: Duration) -> Result<Self, String> {
+        let windows = spec
+            .split(',')
+            .filter(|w| !w.trim().is_empty())
+            .map(|w| w.trim().parse())
+            .collect::<Result<Vec<Window>, String>>()?;
+        if windows.is_empty() {
+            return Err("Schedule has no measurement windows".to_string());
+        }
+        if interval.is_zero() {
+            return Err("schedule-interval must be greater than 0".to_string());
+        }
+        Ok(Self { windows, interval })
+    }
+
+    pub fn in_window(&self, minute_of_day: u32) -> bool {
+        self.windows.iter().any(|w| w.contains(minute_of_day))
+    }
+
+}
+
+impl std::fmt::Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let windows: Vec<String> = self.windows.iter().map(|w| w.to_string()).collect();
+        write!(f, "continuous {}, one batch every {:?} otherwise", windows.join(", "), self.interval)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    /// Inside a window: measuring continuously
+    Continuous,
+    /// Outside the windows: measuring until one batch completes
+    Burst,
+    /// Outside the windows: sensor off until the next burst
+    Idle,
+}
+
+impl Phase {
+    /// True if the sensor should be powered and readings accepted
+    pub fn is_measuring(&self) -> bool {
+        *self != Phase::Idle
+    }
+}
+
+pub struct Scheduler {
+    schedule: Schedule,
+    phase: Phase,
+    /// Start of the last burst, or the last time measurement was continuous
+    last_burst: Option<SystemTime>,
+}
+
+impl Scheduler {
+    pub fn new(schedule: Schedule) -> Self {
+        Self {
+            schedule,
+            phase: Phase::Continuous,
+            last_burst: None,
+        }
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// Advance the schedule to `now` and return the current phase
+    ///
+    /// Starting outside a window begins with a burst, so a reading is
+    /// available soon after startup.
+    pub fn update(&mut self, now: SystemTime, minute_of_day: u32) -> Phase {
+        if self.schedule.in_window(minute_of_day) {
+            self.phase = Phase::Continuous;
+            self.last_burst = Some(now);
+            return self.phase;
+        }
+
+        let due = self
+            .last_burst
+            .is_none_or(|last| now.duration_since(last).unwrap_or_default() >= self.schedule.interval);
+        self.phase = match self.phase {
+            Phase::Burst => Phase::Burst,
+            Phase::Continuous if self.last_burst.is_some() => Phase::Idle,
+            _ if due => {
+                self.last_burst = Some(now);
+                Phase::Burst
+            }
+            _ => Phase::Idle,
+        };
+        self.phase
+    }
+
+    /// End the current burst once its batch has been emitted
+    pub fn batch_complete(&mut self) {
+        if self.phase == Phase::Burst {
+            self.phase = Phase::Idle;
+        }
+    }
+}
+
+/// Serial control line wired to the sensor's ranging/power input
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PowerLine {
+    None,
+    Rts,
+    Dtr,
+}
+
+impl std::str::FromStr for PowerLine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(PowerLine::None),
+            "rts" => Ok(PowerLine::Rts),
+            "dtr" => Ok(PowerLine::Dtr),
+            _ => Err(format!("Invalid power line '{}'. Valid options: none, rts, dtr", s)),
+        }
+    }
+}
+
+impl PowerLine {
+    /// Assert (sensor on) or deassert (sensor off) the line
+    pub fn set(&self, port: &mut dyn serialport::SerialPort, on: bool) -> serialport::Result<()> {
+        match self {
+            PowerLine::None => Ok(()),
+            PowerLine::Rts => port.write_request_to_send(on),
+            PowerLine::Dtr => port.write_data_terminal_ready(on),
+        }
+    }
+}
+
+/// Minutes after local midnight for `time`
+#[cfg(unix)]
+pub fn local_minute_of_day(time: SystemTime) -> u32 {
+    let seconds = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as libc::time_t;
+    // SAFETY: localtime_r only writes to the tm we pass; tm is plain data so
+    // a zeroed value is valid.
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&seconds, &mut tm) }.is_null() {
+        return utc_minute_of_day(time);
+    }
+    (tm.tm_hour * 60 + tm.tm_min) as u32
+}
+
+/// Minutes after local midnight for `time` (UTC where local time is unavailable)
+#[cfg(not(unix))]
+pub fn local_minute_of_day(time: SystemTime) -> u32 {
+    utc_minute_of_day(time)
+}
+
+fn utc_minute_of_day(time: SystemTime) -> u32 {
+    let seconds = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    ((seconds % 86_400) / 60) as u32
+}
+
+/// Local calendar date of `time` as (year, month, day)
+#[cfg(unix)]
+pub fn local_date(time: SystemTime) -> (i32, u32, u32) {
+    let seconds = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as libc::time_t;
+    // SAFETY: as in local_minute_of_day
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&seconds, &mut tm) }.is_null() {
+        return utc_date(time);
+    }
+    (tm.tm_year + 1900, (tm.tm_mon + 1) as u32, tm.tm_mday as u32)
+}
+
+/// Local calendar date of `time` (UTC where local time is unavailable)
+#[cfg(not(unix))]
+pub fn local_date(time: SystemTime) -> (i32, u32, u32) {
+    utc_date(time)
+}
+
+/// Civil date from days since the epoch, after Howard Hinnant's `civil_from_days`
+fn utc_date(time: SystemTime) -> (i32, u32, u32) {
+    let days = (time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 86_400) as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = (year_of_era + era * 400 + i64::from(month <= 2)) as i32;
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPARSE: Duration = Duration::from_secs(900);
+
+    fn at(minutes: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(minutes * 60)
+    }
+
+    #[test]
+    fn test_parse_windows() {
+        let schedule = Schedule::parse("06:00-22:00", SPARSE).unwrap();
+        assert!(!schedule.in_window(359));
+        assert!(schedule.in_window(360));
+        assert!(schedule.in_window(1319));
+        assert!(!schedule.in_window(1320));
+
+        // Wrapping past midnight, multiple windows
+        let schedule = Schedule::parse("22:00-02:00, 12:00-13:00", SPARSE).unwrap();
+        assert!(schedule.in_window(23 * 60));
+        assert!(schedule.in_window(60));
+        assert!(schedule.in_window(12 * 60 + 30));
+        assert!(!schedule.in_window(3 * 60));
+        assert_eq!(schedule.to_string(), "continuous 22:00-02:00, 12:00-13:00, one batch every 900s otherwise");
+
+        assert!(Schedule::parse("00:00-24:00", SPARSE).is_ok());
+        assert!(Schedule::parse("", SPARSE).is_err());
+        assert!(Schedule::parse("6-22", SPARSE).is_err());
+        assert!(Schedule::parse("06:00-06:00", SPARSE).is_err());
+        assert!(Schedule::parse("06:60-22:00", SPARSE).is_err());
+        assert!(Schedule::parse("06:00-22:00", Duration::ZERO).is_err());
+    }
+
+    #[test]
+    fn test_bursts_outside_window() {
+        let mut scheduler = Scheduler::new(Schedule::parse("06:00-22:00", SPARSE).unwrap());
+
+        // Starting outside the window bursts immediately
+        assert_eq!(scheduler.update(at(0), 0), Phase::Burst);
+        assert_eq!(scheduler.update(at(1), 1), Phase::Burst);
+        scheduler.batch_complete();
+        assert_eq!(scheduler.phase(), Phase::Idle);
+        assert_eq!(scheduler.update(at(14), 14), Phase::Idle);
+
+        // Next burst is due one interval after the previous one started
+        assert_eq!(scheduler.update(at(15), 15), Phase::Burst);
+        scheduler.batch_complete();
+        assert_eq!(scheduler.update(at(16), 16), Phase::Idle);
+    }
+
+    #[test]
+    fn test_window_transitions() {
+        let mut scheduler = Scheduler::new(Schedule::parse("06:00-22:00", SPARSE).unwrap());
+
+        assert_eq!(scheduler.update(at(360), 360), Phase::Continuous);
+        assert!(scheduler.phase().is_measuring());
+        scheduler.batch_complete();
+        assert_eq!(scheduler.phase(), Phase::Continuous);
+        assert_eq!(scheduler.update(at(1319), 1319), Phase::Continuous);
+
+        // Leaving the window goes idle; the first burst follows one interval later
+        assert_eq!(scheduler.update(at(1320), 1320), Phase::Idle);
+        assert!(!scheduler.phase().is_measuring());
+        assert_eq!(scheduler.update(at(1333), 1333), Phase::Idle);
+        assert_eq!(scheduler.update(at(1334), 1334), Phase::Burst);
+
+        // Entering a window during a burst switches to continuous measurement
+        let mut scheduler = Scheduler::new(Schedule::parse("06:00-22:00", SPARSE).unwrap());
+        assert_eq!(scheduler.update(at(359), 359), Phase::Burst);
+        assert_eq!(scheduler.update(at(360), 360), Phase::Continuous);
+    }
+
+    #[test]
+    fn test_power_line_from_str() {
+        assert_eq!("RTS".parse::<PowerLine>(), Ok(PowerLine::Rts));
+        assert_eq!("dtr".parse::<PowerLine>(), Ok(PowerLine::Dtr));
+        assert_eq!("none".parse::<PowerLine>(), Ok(PowerLine::None));
+        assert!("gpio".parse::<PowerLine>().is_err());
+    }
+
+    #[test]
+    fn test_utc_minute_of_day() {
+        assert_eq!(utc_minute_of_day(at(0)), 0);
+        assert_eq!(utc_minute_of_day(at(1441)), 1);
+        assert_eq!(utc_minute_of_day(UNIX_EPOCH + Duration::from_secs(3600 * 6 + 59)), 360);
+    }
+
+    #[test]
+    fn test_utc_date() {
+        assert_eq!(utc_date(UNIX_EPOCH), (1970, 1, 1));
+        assert_eq!(utc_date(at(1440 * 59)), (1970, 3, 1));
+        // 2024-02-29T23:59:59Z and the next second
+        assert_eq!(utc_date(UNIX_EPOCH + Duration::from_secs(1_709_251_199)), (2024, 2, 29));
+        assert_eq!(utc_date(UNIX_EPOCH + Duration::from_secs(1_709_251_200)), (2024, 3, 1));
+    }
+}
@@ -4,8 +4,18 @@
 /// - Recent-biased exponential weighted average
 /// - Rate limited to 1mm maximum change per reading
 /// - 40-reading initialization period for stabilization
+///
+/// Optionally, alpha adapts to the spread of the recent raw readings: the
+/// filter follows readings that agree with each other (real accumulation) at
+/// the configured alpha, and smooths harder, down to a minimum alpha, as they
+/// spread out in wind or falling snow.
+use std::collections::VecDeque;
+
 use log::debug;
 
+/// Raw readings whose spread sets the adaptive alpha
+const ADAPTIVE_WINDOW: usize = 10;
+
 /// Filter type selection
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum FilterType {
@@ -17,6 +27,14 @@ pub enum FilterType {
     TrimmedMean,
     /// Apply both exponential filtering per-reading AND trimmed mean on batch
     Both,
+    /// Kalman filter tracking distance and rate per-reading, batch averaged
+    Kalman,
+    /// Rolling median of recent readings per-reading, batch averaged
+    Median,
+    /// Collect batch and take the center of its most populated bin
+    Mode,
+    /// Theil–Sen line through recent readings per-reading, batch averaged
+    Trend,
 }
 
 impl std::str::FromStr for FilterType {
@@ -28,8 +46,12 @@ impl std::str::FromStr for FilterType {
             "exponential" | "exp" | "ema" => Ok(FilterType::Exponential),
             "trimmed" | "trimmed-mean" | "trimmedmean" => Ok(FilterType::TrimmedMean),
             "both" | "combined" => Ok(FilterType::Both),
+            "kalman" => Ok(FilterType::Kalman),
+            "median" => Ok(FilterType::Median),
+            "mode" | "modal" => Ok(FilterType::Mode),
+            "trend" | "theil-sen" => Ok(FilterType::Trend),
             _ => Err(format!(
-                "Invalid filter type '{}'. Valid options: none, exponential, trimmed-mean, both",
+                "Invalid filter type '{}'. Valid options: none, exponential, trimmed-mean, both, kalman, median, mode, trend",
                 s
             )),
         }
@@ -43,6 +65,10 @@ impl std::fmt::Display for FilterType {
             FilterType::Exponential => write!(f, "exponential"),
             FilterType::TrimmedMean => write!(f, "trimmed-mean"),
             FilterType::Both => write!(f, "both"),
+            FilterType::Kalman => write!(f, "kalman"),
+            FilterType::Median => write!(f, "median"),
+            FilterType::Mode => write!(f, "mode"),
+            FilterType::Trend => write!(f, "trend"),
         }
     }
 }
@@ -63,6 +89,20 @@ pub struct SensorFilter {
     /// Smoothing factor (alpha) for exponential weighted average
     /// Higher alpha = more weight to recent readings (typical range 0.1-0.3)
     alpha: f64,
+
+    /// Standard deviation (mm) of the recent raw readings at which the
+    /// adaptive alpha is midway between `alpha` and `alpha_min`; 0 keeps
+    /// alpha fixed
+    adaptive_noise: f64,
+
+    /// Smallest adaptive alpha, approached as the readings spread out
+    alpha_min: f64,
+
+    /// Recent raw readings, for the adaptive alpha
+    recent: VecDeque<f64>,
+
+    /// Whether the rate limit held back the last reading
+    rate_limited: bool,
 }
 
 impl SensorFilter {
@@ -84,15 +124,53 @@ impl SensorFilter {
             init_period,
             max_rate_limit_mm,
             alpha: alpha.clamp(0.0, 1.0),
+            adaptive_noise: 0.0,
+            alpha_min: 0.0,
+            recent: VecDeque::with_capacity(ADAPTIVE_WINDOW),
+            rate_limited: false,
         }
     }
 
+    /// Adapt alpha to the spread of recent readings, from `alpha` when they
+    /// agree down toward `alpha_min`; an `adaptive_noise` of 0 keeps alpha fixed
+    pub fn set_adaptive(&mut self, adaptive_noise: f64, alpha_min: f64) {
+        self.adaptive_noise = adaptive_noise;
+        self.alpha_min = alpha_min.clamp(0.0, 1.0);
+    }
+
+    /// Alpha for the next reading
+    ///
+    /// With the standard deviation σ of the recent readings and the
+    /// adaptive noise n, alpha is alpha_min + (alpha − alpha_min) / (1 + (σ/n)²).
+    pub fn current_alpha(&self) -> f64 {
+        if self.adaptive_noise <= 0.0 || self.recent.len() < 2 {
+            return self.alpha;
+        }
+        let n = self.recent.len() as f64;
+        let mean = self.recent.iter().sum::<f64>() / n;
+        let variance = self.recent.iter().map(|r| (r - mean) * (r - mean)).sum::<f64>() / (n - 1.0);
+        let ratio = variance / (self.adaptive_noise * self.adaptive_noise);
+        self.alpha_min + (self.alpha - self.alpha_min) / (1.0 + ratio)
+    }
+
+    /// Change the parameters, keeping the current filtered value
+    pub fn set_params(&mut self, init_period: usize, max_rate_limit_mm: f64, alpha: f64) {
+        self.init_period = init_period;
+        self.max_rate_limit_mm = max_rate_limit_mm;
+        self.alpha = alpha.clamp(0.0, 1.0);
+    }
+
     /// Process a new sensor reading through the filter
     ///
     /// Returns the filtered value. During the initialization period,
     /// the filter builds up its state and may return less stable values.
     pub fn update(&mut self, raw_reading: f64) -> f64 {
         self.reading_count += 1;
+        if self.recent.len() >= ADAPTIVE_WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(raw_reading);
+        self.rate_limited = false;
 
         match self.filtered_value {
             None => {
@@ -103,19 +181,21 @@ impl SensorFilter {
             }
             Some(current) => {
                 // Apply exponential weighted average
-                let ema_value = self.alpha * raw_reading + (1.0 - self.alpha) * current;
+                let alpha = self.current_alpha();
+                let ema_value = alpha * raw_reading + (1.0 - alpha) * current;
 
                 // Apply rate limiting (1mm max change per reading)
                 let delta = ema_value - current;
                 let limited_delta = delta.clamp(-self.max_rate_limit_mm, self.max_rate_limit_mm);
                 let new_value = current + limited_delta;
+                self.rate_limited = (delta - limited_delta).abs() > 0.001;
 
                 if self.reading_count <= self.init_period {
                     debug!(
                         "Filter initializing ({}/{}): raw={:.2}mm, ema={:.2}mm, rate_limited={:.2}mm",
                         self.reading_count, self.init_period, raw_reading, ema_value, new_value
                     );
-                } else if (delta - limited_delta).abs() > 0.001 {
+                } else if self.rate_limited {
                     debug!(
                         "Rate limit applied: raw={:.2}mm, ema={:.2}mm, delta={:.2}mm, limited={:.2}mm, final={:.2}mm",
                         raw_reading, ema_value, delta, limited_delta, new_value
@@ -128,15 +208,15 @@ impl SensorFilter {
         }
     }
 
-    #[cfg(test)]
     /// Reset the filter (equivalent to bringing RX pin low on MB7544)
     pub fn reset(&mut self) {
         debug!("Filter reset");
         self.filtered_value = None;
         self.reading_count = 0;
+        self.recent.clear();
+        self.rate_limited = false;
     }
 
-    #[cfg(test)]
     /// Check if the filter has completed its initialization period
     pub fn is_initialized(&self) -> bool {
         self.reading_count >= self.init_period
@@ -152,6 +232,11 @@ impl SensorFilter {
     pub fn reading_count(&self) -> usize {
         self.reading_count
     }
+
+    /// True if the rate limit held back the last reading's change
+    pub fn was_rate_limited(&self) -> bool {
+        self.rate_limited
+    }
 }
 
 impl Default for SensorFilter {
@@ -167,7 +252,7 @@ mod tests {
     #[test]
     fn test_filter_initialization() {
         let mut filter = SensorFilter::new();
-        assert_eq!(filter.is_initialized(), false);
+        assert!(!filter.is_initialized());
 
         // Process first reading
         let result = filter.update(1000.0);
@@ -184,6 +269,7 @@ mod tests {
         // Try to jump 10mm - should be limited to 1mm
         let result = filter.update(1010.0);
         assert_eq!(result, 1001.0);
+        assert!(filter.was_rate_limited());
 
         // Try to drop 10mm - should be limited to -1mm
         let result = filter.update(990.0);
@@ -201,6 +287,30 @@ mod tests {
         assert!((result - 1001.0).abs() < 0.01);
     }
 
+    #[test]
+    fn test_adaptive_alpha() {
+        let mut filter = SensorFilter::with_params(1, 100.0, 0.5);
+        filter.set_adaptive(5.0, 0.05);
+        assert_eq!(filter.current_alpha(), 0.5);
+
+        // Readings that agree keep alpha near its maximum
+        for i in 0..10 {
+            filter.update(1000.0 + (i % 2) as f64);
+        }
+        assert!(filter.current_alpha() > 0.48, "{}", filter.current_alpha());
+
+        // Wind spreads them out and alpha drops toward the minimum
+        for i in 0..10 {
+            filter.update(if i % 2 == 0 { 960.0 } else { 1040.0 });
+        }
+        assert!(filter.current_alpha() < 0.06, "{}", filter.current_alpha());
+        assert!((filter.current_value().unwrap() - 1000.0).abs() < 10.0);
+
+        // Turning it off restores the fixed alpha
+        filter.set_adaptive(0.0, 0.05);
+        assert_eq!(filter.current_alpha(), 0.5);
+    }
+
     #[test]
     fn test_reset() {
         let mut filter = SensorFilter::new();
@@ -220,14 +330,14 @@ mod tests {
 
         for i in 0..4 {
             filter.update(1000.0);
-            assert_eq!(filter.is_initialized(), false, "Should not be initialized at reading {}", i + 1);
+            assert!(!filter.is_initialized(), "Should not be initialized at reading {}", i + 1);
         }
 
         filter.update(1000.0);
-        assert_eq!(filter.is_initialized(), true, "Should be initialized at reading 5");
+        assert!(filter.is_initialized(), "Should be initialized at reading 5");
 
         filter.update(1000.0);
-        assert_eq!(filter.is_initialized(), true, "Should remain initialized after reading 6");
+        assert!(filter.is_initialized(), "Should remain initialized after reading 6");
     }
 
     #[test]
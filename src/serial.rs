@@ -0,0 +1,117 @@
+/// Serial line settings for the sensor
+///
+/// MaxBotix sensors talk 9600 8N1, the default, but some are configured for
+/// other rates, and RS-485 adapters can need different framing.
+use std::time::Duration;
+
+use serialport::{DataBits, Parity, SerialPort, SerialPortBuilder, StopBits};
+
+use crate::remote::{Remote, RemotePort};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SerialConfig {
+    pub baud_rate: u32,
+    pub data_bits: DataBits,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        Self { baud_rate: 9600, data_bits: DataBits::Eight, parity: Parity::None, stop_bits: StopBits::One }
+    }
+}
+
+impl std::fmt::Display for SerialConfig {
+    /// The usual shorthand, e.g. `9600 8N1`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let data_bits = match self.data_bits {
+            DataBits::Five => 5,
+            DataBits::Six => 6,
+            DataBits::Seven => 7,
+            DataBits::Eight => 8,
+        };
+        let parity = match self.parity {
+            Parity::None => 'N',
+            Parity::Odd => 'O',
+            Parity::Even => 'E',
+        };
+        let stop_bits = match self.stop_bits {
+            StopBits::One => 1,
+            StopBits::Two => 2,
+        };
+        write!(f, "{} {}{}{}", self.baud_rate, data_bits, parity, stop_bits)
+    }
+}
+
+impl SerialConfig {
+    /// Settings for opening `port`
+    pub fn builder(&self, port: &str) -> SerialPortBuilder {
+        serialport::new(port, self.baud_rate)
+            .data_bits(self.data_bits)
+            .parity(self.parity)
+            .stop_bits(self.stop_bits)
+    }
+
+    /// Open `port`, a local device or a remote port's `tcp://` or
+    /// `rfc2217://` URL
+    pub fn open(&self, port: &str, timeout: Duration) -> serialport::Result<Box<dyn SerialPort>> {
+        match Remote::parse(port) {
+            Some(remote) => Ok(Box::new(RemotePort::connect(port, &remote, *self, timeout)?)),
+            None => self.builder(port).timeout(timeout).open(),
+        }
+    }
+}
+
+/// Parse 5, 6, 7 or 8
+pub fn parse_data_bits(s: &str) -> Result<DataBits, String> {
+    match s {
+        "5" => Ok(DataBits::Five),
+        "6" => Ok(DataBits::Six),
+        "7" => Ok(DataBits::Seven),
+        "8" => Ok(DataBits::Eight),
+        _ => Err(format!("Invalid data bits '{}'. Valid options: 5, 6, 7, 8", s)),
+    }
+}
+
+/// Parse none, odd or even
+pub fn parse_parity(s: &str) -> Result<Parity, String> {
+    match s.to_lowercase().as_str() {
+        "none" | "n" => Ok(Parity::None),
+        "odd" | "o" => Ok(Parity::Odd),
+        "even" | "e" => Ok(Parity::Even),
+        _ => Err(format!("Invalid parity '{}'. Valid options: none, odd, even", s)),
+    }
+}
+
+/// Parse 1 or 2
+pub fn parse_stop_bits(s: &str) -> Result<StopBits, String> {
+    match s {
+        "1" => Ok(StopBits::One),
+        "2" => Ok(StopBits::Two),
+        _ => Err(format!("Invalid stop bits '{}'. Valid options: 1, 2", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_data_bits("7"), Ok(DataBits::Seven));
+        assert!(parse_data_bits("9").is_err());
+        assert_eq!(parse_parity("Even"), Ok(Parity::Even));
+        assert_eq!(parse_parity("o"), Ok(Parity::Odd));
+        assert!(parse_parity("mark").is_err());
+        assert_eq!(parse_stop_bits("2"), Ok(StopBits::Two));
+        assert!(parse_stop_bits("1.5").is_err());
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(SerialConfig::default().to_string(), "9600 8N1");
+        let rs485 = SerialConfig { baud_rate: 19200, parity: Parity::Even, ..SerialConfig::default() };
+        assert_eq!(rs485.to_string(), "19200 8E1");
+    }
+}
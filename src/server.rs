@@ -0,0 +1,1109 @@
+/// The SnowGauge gRPC service's calls and the listeners serving them
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tokio_util::sync::CancellationToken;
+use tonic::service::interceptor::InterceptedService;
+use tonic::transport::Server;
+
+use crate::acl::{self, AccessList};
+use crate::args::Args;
+use crate::health;
+use crate::hooks::Hooks;
+use crate::metrics::MetricsLayer;
+use crate::snowgauge::snow_gauge_service_server::SnowGaugeServiceServer;
+use crate::stations::StationRouter;
+use crate::supervisor::Supervisor;
+
+use log::info;
+use tokio::sync::mpsc;
+use tokio::time;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::baseline::Baseline;
+use crate::board::BoardPeriod;
+use crate::config;
+use crate::events::EventPublisher;
+use crate::history::{self, Correction};
+use crate::logging;
+use crate::offset::Offset;
+use crate::pipeline::{self, FilterConfig};
+use crate::preset::Preset;
+use crate::queue;
+use crate::schedule;
+use crate::service::{fit_trend, rate_mm_per_hour, SnowGaugeServiceImpl};
+use crate::snowgauge::{
+    self, apply_offset_request, client_message, set_baseline_request, snow_gauge_service_server::SnowGaugeService,
+    AccumulationRequest, AccumulationResponse, AcquisitionStatus, AmendRequest, Amendment, AnnotateRequest, Annotation,
+    Anomaly, ApplyOffsetRequest, BuildInfo, BuildInfoRequest, ClearBoardRequest, ClearBoardResponse, ClientMessage,
+    ComparisonReading, ConfigSetting, ConfigSource, EffectiveConfig, EffectiveConfigRequest, EventKind,
+    ExportPresetRequest, FilterPreset, FrameStats, Gap, GapStats, HistoryEntry, HistoryRequest, HistoryResponse,
+    ListStationsRequest, ListStationsResponse, LogLevel, PauseAcquisitionRequest, RawFrame, RawFramesRequest,
+    ReadingBatch, RejectionStats, ResetFilterRequest, ResetFilterResponse, ResumeAcquisitionRequest, RpcStats,
+    SetBaselineRequest, SetLogLevelRequest, SetSnowDensityRequest, SnowDensity, SnowfallTotal, StationInfo,
+    StationInfoRequest, StormsRequest, StormsResponse, StreamMessage, StreamRequest, TotalsRequest, TotalsResponse,
+    TrendRequest, TrendResponse, UpdateFilterParamsRequest,
+};
+use crate::station::log_filter_config;
+use crate::stream::{self, ClientReceiver};
+use crate::swe::DensityModel;
+use crate::totals::{self, Date, Totals};
+use crate::trace;
+
+/// Default GetTrend window and extrapolation horizon
+const DEFAULT_TREND_WINDOW: Duration = Duration::from_secs(3 * 3600);
+const DEFAULT_TREND_HORIZON: Duration = Duration::from_secs(3600);
+
+#[tonic::async_trait]
+impl SnowGaugeService for SnowGaugeServiceImpl {
+    type StreamReadingStream = ClientReceiver;
+
+    async fn stream_reading(
+        &self,
+        request: Request<StreamRequest>,
+    ) -> Result<Response<Self::StreamReadingStream>, Status> {
+        let remote_addr = request
+            .remote_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        
+        let options = self.stream_options(request.get_ref())?;
+        info!("Registering new gRPC streaming client [{}] ({:?}, trace {})...",
+              remote_addr, options, trace::current(&request).trace_id_hex());
+
+        let rx = self.subscribe_with(options).await?;
+
+        Ok(Response::new(rx))
+    }
+
+    type StreamReadingBidiStream = ClientReceiver;
+
+    async fn stream_reading_bidi(
+        &self,
+        request: Request<Streaming<ClientMessage>>,
+    ) -> Result<Response<Self::StreamReadingBidiStream>, Status> {
+        let remote_addr = request
+            .remote_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+        let trace_id = trace::current(&request).trace_id_hex();
+        let mut inbound = request.into_inner();
+
+        let first = time::timeout(self.streams.heartbeat_timeout, inbound.message())
+            .await
+            .map_err(|_| Status::deadline_exceeded("no subscribe message received"))??;
+        let Some(ClientMessage { message: Some(client_message::Message::Subscribe(stream_request)) }) = first else {
+            return Err(Status::invalid_argument("the first message must be a subscribe"));
+        };
+        let options = self.stream_options(&stream_request)?;
+        info!("Registering new bidirectional streaming client [{}] ({:?}, trace {})...",
+              remote_addr, options, trace_id);
+
+        let readings = self.subscribe_with(options).await?;
+        let closer = readings.closer();
+        let clients = self.clients.clone();
+        let timeout = self.streams.heartbeat_timeout;
+        tokio::spawn(async move {
+            let ended = stream::watch_heartbeats(inbound, closer, timeout).await;
+            info!("Bidirectional streaming client [{}] disconnected: {}", remote_addr, ended);
+            // Free its registration now rather than at the next broadcast
+            clients.write().await.retain(|client| !client.is_closed());
+        });
+
+        Ok(Response::new(readings))
+    }
+
+    type StreamReadingBatchStream = ReceiverStream<Result<ReadingBatch, Status>>;
+
+    async fn stream_reading_batch(
+        &self,
+        request: Request<StreamRequest>,
+    ) -> Result<Response<Self::StreamReadingBatchStream>, Status> {
+        let remote_addr = request
+            .remote_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+
+        let options = self.stream_options(request.get_ref())?;
+        info!("Registering new batch streaming client [{}] ({:?}, trace {})...",
+              remote_addr, options, trace::current(&request).trace_id_hex());
+
+        let (count, interval) = (options.coalesce_count, options.coalesce_interval);
+        let readings = self.subscribe_with(options).await?;
+        let (tx, rx) = mpsc::channel(1);
+        let clients = self.clients.clone();
+        tokio::spawn(async move {
+            let ended = stream::forward_coalesced(readings, tx, count, interval).await;
+            info!("Batch streaming client [{}] disconnected: {}", remote_addr, ended);
+            clients.write().await.retain(|client| !client.is_closed());
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    type StreamReadingEventsStream = ReceiverStream<Result<StreamMessage, Status>>;
+
+    async fn stream_reading_events(
+        &self,
+        request: Request<StreamRequest>,
+    ) -> Result<Response<Self::StreamReadingEventsStream>, Status> {
+        let remote_addr = request
+            .remote_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+
+        let options = self.stream_options(request.get_ref())?;
+        info!("Registering new event streaming client [{}] ({:?}, trace {})...",
+              remote_addr, options, trace::current(&request).trace_id_hex());
+
+        // The events of every station the readings come from
+        let publishers = self.stations().filter(|station| options.accepts(&station.station_name)).map(|station| &station.events);
+        let events = EventPublisher::subscribe_all(publishers, self.streams.queue_size, self.streams.overflow_policy);
+        let readings = self.subscribe_with(options).await?;
+        let (tx, rx) = mpsc::channel(1);
+        let clients = self.clients.clone();
+        tokio::spawn(async move {
+            let ended = stream::forward_with_events(readings, events, tx).await;
+            info!("Event streaming client [{}] disconnected: {}", remote_addr, ended);
+            clients.write().await.retain(|client| !client.is_closed());
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    type StreamComparisonStream = queue::Receiver<ComparisonReading>;
+
+    async fn stream_comparison(
+        &self,
+        request: Request<StreamRequest>,
+    ) -> Result<Response<Self::StreamComparisonStream>, Status> {
+        if self.compare_config.is_none() {
+            return Err(Status::failed_precondition(
+                "filter comparison is not enabled (set --compare-filter-type)",
+            ));
+        }
+
+        let remote_addr = request
+            .remote_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+
+        info!("Registering new comparison streaming client [{}] (trace {})...", remote_addr, trace::current(&request).trace_id_hex());
+
+        let mut clients = self.clients.write().await;
+        self.check_client_limit(&mut clients).await?;
+
+        let (tx, rx) = queue::bounded(self.streams.queue_size, self.streams.overflow_policy);
+
+        self.comparison_channels.write().await.push(tx);
+        drop(clients);
+
+        Ok(Response::new(rx))
+    }
+
+    type StreamRawFramesStream = queue::Receiver<RawFrame>;
+
+    async fn stream_raw_frames(
+        &self,
+        request: Request<RawFramesRequest>,
+    ) -> Result<Response<Self::StreamRawFramesStream>, Status> {
+        if self.sensor_port.is_none() {
+            return Err(Status::failed_precondition("no serial port in simulator mode"));
+        }
+
+        let remote_addr = request
+            .remote_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+
+        info!("Registering new raw frame streaming client [{}] (trace {})...", remote_addr, trace::current(&request).trace_id_hex());
+
+        let mut clients = self.clients.write().await;
+        self.check_client_limit(&mut clients).await?;
+
+        let (tx, rx) = queue::bounded(self.streams.queue_size, self.streams.overflow_policy);
+
+        self.raw_frame_channels.lock().unwrap_or_else(|e| e.into_inner()).push(tx);
+        drop(clients);
+
+        Ok(Response::new(rx))
+    }
+
+    async fn get_history(
+        &self,
+        request: Request<HistoryRequest>,
+    ) -> Result<Response<HistoryResponse>, Status> {
+        self.rate_limit(&request)?;
+        let request = request.into_inner();
+        let start = request.start.map(to_system_time).transpose()?;
+        let end = request.end.map(to_system_time).transpose()?;
+
+        let history = self.history.read().await;
+        let entries = history
+            .query(start, end)
+            .into_iter()
+            .filter(|r| request.include_invalid || !r.invalid)
+            .map(|r| HistoryEntry {
+                reading: Some(self.stored_reading(&r)),
+                original_distance: r.original_distance,
+                invalid: r.invalid,
+                amendment_ids: r.amendment_ids,
+            })
+            .collect();
+        let amendments = history
+            .amendments(start, end)
+            .into_iter()
+            .map(amendment_to_proto)
+            .collect();
+        let annotations = history
+            .annotations(start, end)
+            .into_iter()
+            .map(annotation_to_proto)
+            .collect();
+        let now = SystemTime::now();
+        let gaps = history
+            .gaps(start, end, now)
+            .into_iter()
+            .map(|g| Gap {
+                start: Some(g.start.into()),
+                end: Some(g.end.into()),
+                ongoing: g.ongoing,
+            })
+            .collect();
+        let (gap_time, completeness) = history.completeness(start, end, now);
+        let anomalies = history
+            .anomalies(start, end)
+            .into_iter()
+            .map(|a| Anomaly {
+                timestamp: Some(a.timestamp.into()),
+                distance: a.distance,
+                expected_distance: a.expected,
+                score: a.score,
+            })
+            .collect();
+
+        Ok(Response::new(HistoryResponse {
+            entries,
+            amendments,
+            annotations,
+            gaps,
+            gap_time: prost_types::Duration::try_from(gap_time).ok(),
+            completeness,
+            anomalies,
+        }))
+    }
+
+    async fn amend_history(
+        &self,
+        request: Request<AmendRequest>,
+    ) -> Result<Response<Amendment>, Status> {
+        self.rate_limit(&request)?;
+        let trace = trace::current(&request);
+        let request = request.into_inner();
+        let start = request
+            .start
+            .map(to_system_time)
+            .transpose()?
+            .ok_or_else(|| Status::invalid_argument("start is required"))?;
+        let end = request
+            .end
+            .map(to_system_time)
+            .transpose()?
+            .ok_or_else(|| Status::invalid_argument("end is required"))?;
+        let correction = match request.correction {
+            Some(snowgauge::amend_request::Correction::Invalidate(true)) => Correction::Invalidate,
+            Some(snowgauge::amend_request::Correction::OffsetMm(offset)) => Correction::Offset(offset),
+            _ => return Err(Status::invalid_argument("either invalidate or offsetMm must be set")),
+        };
+
+        let amendment = self
+            .history
+            .write()
+            .await
+            .amend(start, end, correction, request.reason, trace.child().to_string())
+            .map_err(Status::invalid_argument)?;
+
+        info!(
+            "Recorded amendment {}: {:?} over {:?}..{:?} ({}) (trace {})",
+            amendment.id, amendment.correction, amendment.start, amendment.end, amendment.reason, trace.trace_id_hex()
+        );
+
+        Ok(Response::new(amendment_to_proto(amendment)))
+    }
+
+    async fn annotate(
+        &self,
+        request: Request<AnnotateRequest>,
+    ) -> Result<Response<Annotation>, Status> {
+        self.rate_limit(&request)?;
+        let trace = trace::current(&request);
+        let request = request.into_inner();
+        let start = request
+            .start
+            .map(to_system_time)
+            .transpose()?
+            .unwrap_or_else(SystemTime::now);
+        let end = request.end.map(to_system_time).transpose()?;
+
+        let annotation = self
+            .history
+            .write()
+            .await
+            .annotate(start, end, request.text)
+            .map_err(Status::invalid_argument)?;
+
+        info!("Recorded annotation {}: {} (trace {})", annotation.id, annotation.text, trace.trace_id_hex());
+        self.events.publish_caused(Some(&trace), EventKind::AnnotationAdded, annotation.text.clone());
+
+        Ok(Response::new(annotation_to_proto(annotation)))
+    }
+
+    async fn get_trend(
+        &self,
+        request: Request<TrendRequest>,
+    ) -> Result<Response<TrendResponse>, Status> {
+        self.rate_limit(&request)?;
+        let request = request.into_inner();
+        let window = request.window.map(to_duration).transpose()?.unwrap_or(DEFAULT_TREND_WINDOW);
+        let horizon = request.horizon.map(to_duration).transpose()?.unwrap_or(DEFAULT_TREND_HORIZON);
+
+        let now = SystemTime::now();
+        let readings = self.trend_readings(now, window).await;
+        let fit = fit_trend(&readings, now)
+            .ok_or_else(|| Status::failed_precondition("not enough readings in the window to fit a trend"))?;
+
+        Ok(Response::new(TrendResponse {
+            station_name: self.station_name.clone(),
+            samples: fit.samples as u32,
+            start: readings.first().map(|r| r.timestamp.into()),
+            end: readings.last().map(|r| r.timestamp.into()),
+            rate_mm_per_hour: rate_mm_per_hour(fit.slope),
+            rate_lower_mm_per_hour: rate_mm_per_hour(fit.slope_upper),
+            rate_upper_mm_per_hour: rate_mm_per_hour(fit.slope_lower),
+            fitted_distance: fit.intercept,
+            projection_time: Some((now + horizon).into()),
+            projected_distance: fit.value_at(horizon.as_secs_f64()),
+        }))
+    }
+
+    async fn get_storms(
+        &self,
+        request: Request<StormsRequest>,
+    ) -> Result<Response<StormsResponse>, Status> {
+        self.rate_limit(&request)?;
+        let request = request.into_inner();
+        let start = request.start.map(to_system_time).transpose()?;
+        let end = request.end.map(to_system_time).transpose()?;
+        let detector = self
+            .storm_detector
+            .as_ref()
+            .ok_or_else(|| Status::failed_precondition("storm detection is disabled (--storm-rate-threshold 0)"))?;
+        let storms = detector.lock().unwrap_or_else(|e| e.into_inner()).storms(start, end);
+
+        Ok(Response::new(StormsResponse {
+            station_name: self.station_name.clone(),
+            storms: storms.iter().map(|s| s.to_proto()).collect(),
+        }))
+    }
+
+    async fn get_totals(
+        &self,
+        request: Request<TotalsRequest>,
+    ) -> Result<Response<TotalsResponse>, Status> {
+        self.rate_limit(&request)?;
+        let today = Date::local(SystemTime::now());
+        let totals = self.totals.lock().unwrap_or_else(|e| e.into_inner());
+        let season_start = totals.season_start(today);
+        let to_proto = |(date, total): (Date, f64)| SnowfallTotal { date: date.to_string(), new_snow_mm: total };
+
+        Ok(Response::new(TotalsResponse {
+            station_name: self.station_name.clone(),
+            today_mm: totals.day(today),
+            month_mm: totals.month(today),
+            season_mm: totals.season(today),
+            season_start: season_start.to_string(),
+            days: totals.days(season_start).into_iter().map(to_proto).collect(),
+            months: totals.months(season_start).into_iter().map(to_proto).collect(),
+        }))
+    }
+
+    async fn get_accumulation(
+        &self,
+        request: Request<AccumulationRequest>,
+    ) -> Result<Response<AccumulationResponse>, Status> {
+        self.rate_limit(&request)?;
+        let now = SystemTime::now();
+        let accumulation = accumulation_to_proto(&self.totals.lock().unwrap_or_else(|e| e.into_inner()), now);
+
+        Ok(Response::new(AccumulationResponse {
+            station_name: self.station_name.clone(),
+            time: Some(now.into()),
+            accumulation: Some(accumulation),
+        }))
+    }
+
+    async fn get_station_info(
+        &self,
+        request: Request<StationInfoRequest>,
+    ) -> Result<Response<StationInfo>, Status> {
+        self.rate_limit(&request)?;
+        let filter = self.filter.borrow().clone();
+        let rpc = self.metrics.counts();
+        let rejections = self.rejections.counts();
+        let frames = self.frames.counts();
+        let gaps = self.history.read().await.gap_stats(SystemTime::now());
+        let candidate_filter = self
+            .compare_config
+            .clone()
+            .map(|config| preset_to_proto(&Preset::new("candidate".to_string(), config)));
+
+        Ok(Response::new(StationInfo {
+            station_name: self.station_name.clone(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            sensor_port: self.sensor_port.clone().unwrap_or_default(),
+            simulator: self.sensor_port.is_none(),
+            filter: Some(preset_to_proto(&filter)),
+            candidate_filter,
+            start_time: Some(self.started_at.into()),
+            application_uptime: prost_types::Duration::try_from(
+                SystemTime::now().duration_since(self.started_at).unwrap_or_default(),
+            )
+            .ok(),
+            rpc_stats: Some(RpcStats {
+                requests: rpc.requests,
+                errors: rpc.errors,
+                active_streams: rpc.active_streams,
+            }),
+            baseline: self.baseline.borrow().as_ref().map(baseline_to_proto),
+            metadata: Some(self.metadata.clone()),
+            rejections: Some(RejectionStats {
+                readings: rejections.readings,
+                out_of_range: rejections.out_of_range,
+                spikes: rejections.spikes,
+                no_target: rejections.no_target,
+                rate_limited: rejections.rate_limited,
+                trimmed: rejections.trimmed,
+                warming_up: rejections.warming_up,
+            }),
+            gaps: Some(GapStats {
+                count: gaps.count,
+                total_time: prost_types::Duration::try_from(gaps.total_time).ok(),
+                longest: prost_types::Duration::try_from(gaps.longest).ok(),
+                since: gaps.since.map(Into::into),
+            }),
+            frames: Some(FrameStats {
+                frames: frames.frames,
+                invalid_framing: frames.invalid_framing,
+                checksum_failures: frames.checksum_failures,
+            }),
+        }))
+    }
+
+    async fn get_build_info(
+        &self,
+        request: Request<BuildInfoRequest>,
+    ) -> Result<Response<BuildInfo>, Status> {
+        self.rate_limit(&request)?;
+        Ok(Response::new(build_info()))
+    }
+
+    async fn get_effective_config(
+        &self,
+        request: Request<EffectiveConfigRequest>,
+    ) -> Result<Response<EffectiveConfig>, Status> {
+        self.rate_limit(&request)?;
+        Ok(Response::new(EffectiveConfig {
+            settings: self.settings.iter().map(setting_to_proto).collect(),
+            filter: Some(preset_to_proto(&self.filter.borrow())),
+            baseline: self.baseline.borrow().as_ref().map(baseline_to_proto),
+            log_filter: logging::logger().map(|logger| logger.filter()).unwrap_or_default(),
+            paused: *self.paused.borrow(),
+            offset: self.offset.borrow().as_ref().map(offset_to_proto),
+        }))
+    }
+
+    async fn list_stations(
+        &self,
+        request: Request<ListStationsRequest>,
+    ) -> Result<Response<ListStationsResponse>, Status> {
+        self.rate_limit(&request)?;
+        let now = SystemTime::now();
+        let mut stations = Vec::new();
+        for station in self.stations() {
+            stations.push(station.station_status(now).await);
+        }
+        Ok(Response::new(ListStationsResponse { stations }))
+    }
+
+    async fn export_filter_preset(
+        &self,
+        request: Request<ExportPresetRequest>,
+    ) -> Result<Response<FilterPreset>, Status> {
+        self.rate_limit(&request)?;
+        let request = request.into_inner();
+        let mut preset = self.filter.borrow().clone();
+        if !request.name.is_empty() {
+            preset.name = request.name;
+        }
+
+        Ok(Response::new(preset_to_proto(&preset)))
+    }
+
+    async fn apply_filter_preset(
+        &self,
+        request: Request<FilterPreset>,
+    ) -> Result<Response<FilterPreset>, Status> {
+        self.rate_limit(&request)?;
+        let trace = trace::current(&request);
+        let preset = preset_from_proto(request.into_inner())?;
+
+        // Save first so a preset that cannot be persisted is not applied either
+        if let Some(ref path) = self.preset_path {
+            preset.save(path).map_err(Status::internal)?;
+        }
+
+        info!("Applying filter preset '{}' (trace {}):", preset.name, trace.trace_id_hex());
+        log_filter_config(&preset.config);
+        self.caused_by(trace);
+        self.filter.send_replace(preset.clone());
+
+        Ok(Response::new(preset_to_proto(&preset)))
+    }
+
+    async fn update_filter_params(
+        &self,
+        request: Request<UpdateFilterParamsRequest>,
+    ) -> Result<Response<FilterPreset>, Status> {
+        self.rate_limit(&request)?;
+        let trace = trace::current(&request);
+        let params = request.into_inner();
+        let mut preset = self.filter.borrow().clone();
+        let config = &mut preset.config;
+        config.alpha = params.alpha.unwrap_or(config.alpha);
+        config.adaptive_noise = params.adaptive_noise.unwrap_or(config.adaptive_noise);
+        config.alpha_min = params.alpha_min.unwrap_or(config.alpha_min);
+        config.rate_limit = params.rate_limit.unwrap_or(config.rate_limit);
+        config.trim_percentage = params.trim_percentage.unwrap_or(config.trim_percentage);
+        config.batch_size = params.batch_size.map_or(config.batch_size, |n| n as usize);
+        config.batch_step = params.batch_step.map_or(config.batch_step, |n| n as usize);
+        config.dead_band = params.dead_band.unwrap_or(config.dead_band);
+        config.process_noise = params.process_noise.unwrap_or(config.process_noise);
+        config.measurement_noise = params.measurement_noise.unwrap_or(config.measurement_noise);
+        config.median_window = params.median_window.map_or(config.median_window, |n| n as usize);
+        config.mode_bin_width = params.mode_bin_width.unwrap_or(config.mode_bin_width);
+        config.trend_window = params.trend_window.map_or(config.trend_window, |n| n as usize);
+        config.despike_threshold = params.despike_threshold.unwrap_or(config.despike_threshold);
+        config.despike_window = params.despike_window.map_or(config.despike_window, |n| n as usize);
+        config.min_distance = params.min_distance.unwrap_or(config.min_distance);
+        config.max_distance = params.max_distance.unwrap_or(config.max_distance);
+        config.validate().map_err(Status::invalid_argument)?;
+
+        // Save first so parameters that cannot be persisted are not applied either
+        if let Some(ref path) = self.preset_path {
+            preset.save(path).map_err(Status::internal)?;
+        }
+
+        info!("Updating filter parameters (trace {}):", trace.trace_id_hex());
+        log_filter_config(&preset.config);
+        self.caused_by(trace);
+        self.filter.send_replace(preset.clone());
+
+        Ok(Response::new(preset_to_proto(&preset)))
+    }
+
+    async fn pause_acquisition(
+        &self,
+        request: Request<PauseAcquisitionRequest>,
+    ) -> Result<Response<AcquisitionStatus>, Status> {
+        self.rate_limit(&request)?;
+        let trace = trace::current(&request);
+        let close_port = request.into_inner().close_port;
+        if close_port && self.sensor_port.is_none() {
+            return Err(Status::failed_precondition("no serial port to close in simulator mode"));
+        }
+
+        info!("Pausing acquisition{} (trace {})", if close_port { " and closing the serial port" } else { "" }, trace.trace_id_hex());
+        self.caused_by(trace);
+        self.release_port.send_if_modified(|release| std::mem::replace(release, close_port) != close_port);
+        self.paused.send_if_modified(|paused| !std::mem::replace(paused, true));
+        Ok(Response::new(self.acquisition_status()))
+    }
+
+    async fn resume_acquisition(
+        &self,
+        request: Request<ResumeAcquisitionRequest>,
+    ) -> Result<Response<AcquisitionStatus>, Status> {
+        self.rate_limit(&request)?;
+        let trace = trace::current(&request);
+        info!("Resuming acquisition (trace {})", trace.trace_id_hex());
+        self.caused_by(trace);
+        self.release_port.send_if_modified(|release| std::mem::replace(release, false));
+        self.paused.send_if_modified(|paused| std::mem::replace(paused, false));
+        Ok(Response::new(self.acquisition_status()))
+    }
+
+    async fn set_log_level(
+        &self,
+        request: Request<SetLogLevelRequest>,
+    ) -> Result<Response<LogLevel>, Status> {
+        self.rate_limit(&request)?;
+        let trace = trace::current(&request);
+        let request = request.into_inner();
+        let revert_after = request.duration.map(to_duration).transpose()?;
+        let logger = logging::logger().ok_or_else(|| Status::unavailable("logger not initialized"))?;
+        logging::validate(&request.filter).map_err(Status::invalid_argument)?;
+
+        info!("Changing log filter from '{}' to '{}' (trace {})", logger.filter(), request.filter, trace.trace_id_hex());
+        let (previous, generation) = logger.set_filter(&request.filter).map_err(Status::invalid_argument)?;
+        if let Some(revert_after) = revert_after {
+            let previous = previous.clone();
+            tokio::spawn(async move {
+                time::sleep(revert_after).await;
+                if logger.restore(generation, &previous) {
+                    info!("Log filter reverted to '{}'", previous);
+                }
+            });
+        }
+
+        Ok(Response::new(LogLevel {
+            filter: request.filter,
+            previous,
+        }))
+    }
+
+    async fn reset_filter(
+        &self,
+        request: Request<ResetFilterRequest>,
+    ) -> Result<Response<ResetFilterResponse>, Status> {
+        self.rate_limit(&request)?;
+        let trace = trace::current(&request);
+        info!("Filter reset requested (trace {})", trace.trace_id_hex());
+        self.caused_by(trace);
+        // Stored if the processor is busy, so the reset lands before the next reading
+        self.filter_reset.notify_one();
+        Ok(Response::new(ResetFilterResponse {}))
+    }
+
+    async fn set_baseline(
+        &self,
+        request: Request<SetBaselineRequest>,
+    ) -> Result<Response<snowgauge::Baseline>, Status> {
+        self.rate_limit(&request)?;
+        let trace = trace::current(&request);
+        let distance = match request.into_inner().baseline {
+            Some(set_baseline_request::Baseline::DistanceMm(distance)) => distance,
+            Some(set_baseline_request::Baseline::UseCurrent(true)) => self
+                .history
+                .read()
+                .await
+                .latest()
+                .map(|r| r.distance)
+                .ok_or_else(|| Status::failed_precondition("no filtered reading yet to take the baseline from"))?,
+            _ => return Err(Status::invalid_argument("either distanceMm or useCurrent is required")),
+        };
+        let baseline = Baseline::new(distance, SystemTime::now()).map_err(Status::invalid_argument)?;
+
+        // Save first so a baseline that cannot be persisted is not applied either
+        if let Some(ref path) = self.baseline_path {
+            baseline.save(path).map_err(Status::internal)?;
+        }
+
+        info!("Baseline set to {:.1}mm (trace {})", baseline.distance, trace.trace_id_hex());
+        self.caused_by(trace);
+        self.baseline.send_replace(Some(baseline));
+        self.events.publish_caused(Some(&trace), EventKind::BaselineChanged, format!("baseline set to {:.1}mm", baseline.distance));
+
+        Ok(Response::new(baseline_to_proto(&baseline)))
+    }
+
+    async fn apply_offset(
+        &self,
+        request: Request<ApplyOffsetRequest>,
+    ) -> Result<Response<snowgauge::Offset>, Status> {
+        self.rate_limit(&request)?;
+        let trace = trace::current(&request);
+        let request = request.into_inner();
+        let distance = match request.offset {
+            Some(apply_offset_request::Offset::OffsetMm(distance)) => distance,
+            Some(apply_offset_request::Offset::TrueDistanceMm(actual)) => {
+                let latest = self
+                    .history
+                    .read()
+                    .await
+                    .latest()
+                    .map(|r| r.distance)
+                    .ok_or_else(|| Status::failed_precondition("no filtered reading yet to take the offset from"))?;
+                // The latest reading already includes the current offset
+                let current = self.offset.borrow().as_ref().map_or(0.0, |o| o.distance);
+                current + actual - latest
+            }
+            None => return Err(Status::invalid_argument("either offsetMm or trueDistanceMm is required")),
+        };
+        let offset = Offset::new(distance, SystemTime::now(), request.reason).map_err(Status::invalid_argument)?;
+
+        // Save first so an offset that cannot be persisted is not applied either
+        if let Some(ref path) = self.offset_path {
+            offset.save(path).map_err(Status::internal)?;
+        }
+
+        info!("Offset set to {:+.1}mm (trace {})", offset.distance, trace.trace_id_hex());
+        self.caused_by(trace);
+        self.events.publish_caused(Some(&trace), EventKind::OffsetChanged, format!("offset set to {:+.1}mm", offset.distance));
+        let response = offset_to_proto(&offset);
+        self.offset.send_replace(Some(offset));
+
+        Ok(Response::new(response))
+    }
+
+    async fn set_snow_density(
+        &self,
+        request: Request<SetSnowDensityRequest>,
+    ) -> Result<Response<SnowDensity>, Status> {
+        self.rate_limit(&request)?;
+        let trace = trace::current(&request);
+        let model = DensityModel::fixed(request.into_inner().density_kg_per_m3).map_err(Status::invalid_argument)?;
+
+        info!("Snow density set to {} (trace {})", model, trace.trace_id_hex());
+        self.caused_by(trace);
+        self.events.publish_caused(Some(&trace), EventKind::SnowDensityChanged, format!("snow density set to {}", model));
+        self.snow_density.send_replace(Some(model));
+
+        Ok(Response::new(snow_density_to_proto(Some(model))))
+    }
+
+    async fn clear_board(
+        &self,
+        request: Request<ClearBoardRequest>,
+    ) -> Result<Response<ClearBoardResponse>, Status> {
+        self.rate_limit(&request)?;
+        let trace = trace::current(&request);
+        let now = SystemTime::now();
+        let (ended, next_clear) = {
+            let mut board = self.board.lock().unwrap_or_else(|e| e.into_inner());
+            if !board.has_reading() {
+                return Err(Status::failed_precondition("no batch reading to clear the board at yet"));
+            }
+            (board.clear(now, schedule::local_minute_of_day(now)), board.next_clear())
+        };
+
+        info!("Board cleared by ClearBoard (trace {})", trace.trace_id_hex());
+        self.caused_by(trace);
+        self.publish_board_cleared(&trace, ended.as_ref());
+
+        Ok(Response::new(ClearBoardResponse {
+            ended: ended.as_ref().map(board_period_to_proto),
+            next_clear: next_clear.map(Into::into),
+        }))
+    }
+}
+
+/// Convert a protobuf timestamp, rejecting out-of-range values
+#[allow(clippy::result_large_err)]
+fn to_system_time(timestamp: prost_types::Timestamp) -> Result<SystemTime, Status> {
+    SystemTime::try_from(timestamp).map_err(|e| Status::invalid_argument(format!("invalid timestamp: {}", e)))
+}
+
+/// Convert a protobuf duration, rejecting negative or out-of-range values
+#[allow(clippy::result_large_err)]
+fn to_duration(duration: prost_types::Duration) -> Result<Duration, Status> {
+    Duration::try_from(duration).map_err(|e| Status::invalid_argument(format!("invalid duration: {}", e)))
+}
+
+fn amendment_to_proto(amendment: history::Amendment) -> Amendment {
+    let correction = match amendment.correction {
+        Correction::Invalidate => snowgauge::amendment::Correction::Invalidate(true),
+        Correction::Offset(offset) => snowgauge::amendment::Correction::OffsetMm(offset),
+    };
+
+    Amendment {
+        id: amendment.id,
+        start: Some(amendment.start.into()),
+        end: Some(amendment.end.into()),
+        correction: Some(correction),
+        reason: amendment.reason,
+        created_at: Some(amendment.created_at.into()),
+        traceparent: amendment.traceparent,
+    }
+}
+
+fn annotation_to_proto(annotation: history::Annotation) -> Annotation {
+    Annotation {
+        id: annotation.id,
+        start: Some(annotation.start.into()),
+        end: Some(annotation.end.into()),
+        text: annotation.text,
+        created_at: Some(annotation.created_at.into()),
+    }
+}
+
+fn build_info() -> BuildInfo {
+    let build_time = env!("SNOWGAUGE_BUILD_TIME").parse().unwrap_or(0);
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        git_describe: env!("SNOWGAUGE_GIT_DESCRIBE").to_string(),
+        rustc_version: env!("SNOWGAUGE_RUSTC_VERSION").to_string(),
+        build_time: Some(prost_types::Timestamp {
+            seconds: build_time,
+            nanos: 0,
+        }),
+        proto_revision: env!("SNOWGAUGE_PROTO_REVISION").to_string(),
+        target: env!("SNOWGAUGE_TARGET").to_string(),
+    }
+}
+
+fn setting_to_proto(setting: &config::Setting) -> ConfigSetting {
+    let source = match setting.source {
+        Some(config::Source::Default) => ConfigSource::Default,
+        Some(config::Source::Environment) => ConfigSource::Environment,
+        Some(config::Source::CommandLine) => ConfigSource::CommandLine,
+        None => ConfigSource::Unspecified,
+    };
+    ConfigSetting {
+        name: setting.name.clone(),
+        values: setting.values.clone(),
+        source: source as i32,
+        redacted: setting.redacted,
+    }
+}
+
+fn baseline_to_proto(baseline: &Baseline) -> snowgauge::Baseline {
+    snowgauge::Baseline {
+        distance_mm: baseline.distance,
+        set_at: Some(baseline.set_at.into()),
+    }
+}
+
+fn snow_density_to_proto(model: Option<DensityModel>) -> SnowDensity {
+    match model {
+        Some(DensityModel::Fixed(density)) => SnowDensity { model: "fixed".to_string(), density_kg_per_m3: Some(density) },
+        Some(DensityModel::Temperature) => SnowDensity { model: "temperature".to_string(), density_kg_per_m3: None },
+        None => SnowDensity::default(),
+    }
+}
+
+pub fn accumulation_to_proto(totals: &Totals, now: SystemTime) -> snowgauge::Accumulation {
+    let [last_hour_mm, last3_hours_mm, last6_hours_mm, last12_hours_mm, last24_hours_mm] =
+        totals::WINDOWS.map(|window| totals.recent(now, window));
+    snowgauge::Accumulation { last_hour_mm, last3_hours_mm, last6_hours_mm, last12_hours_mm, last24_hours_mm }
+}
+
+fn board_period_to_proto(period: &BoardPeriod) -> snowgauge::BoardPeriod {
+    snowgauge::BoardPeriod {
+        start: Some(period.start.into()),
+        end: Some(period.end.into()),
+        new_snow_mm: period.new_snow,
+    }
+}
+
+fn offset_to_proto(offset: &Offset) -> snowgauge::Offset {
+    snowgauge::Offset {
+        offset_mm: offset.distance,
+        set_at: Some(offset.set_at.into()),
+        reason: offset.reason.clone(),
+    }
+}
+
+fn preset_to_proto(preset: &Preset) -> FilterPreset {
+    FilterPreset {
+        name: preset.name.clone(),
+        filter_type: preset.config.filter_type.to_string(),
+        pipeline: preset.config.pipeline.as_ref().map(|p| p.to_string()),
+        init_period: preset.config.init_period as u32,
+        rate_limit: preset.config.rate_limit,
+        alpha: preset.config.alpha,
+        trim_percentage: preset.config.trim_percentage,
+        batch_size: preset.config.batch_size as u32,
+        batch_step: Some(preset.config.batch_step as u32),
+        dead_band: Some(preset.config.dead_band),
+        process_noise: Some(preset.config.process_noise),
+        measurement_noise: Some(preset.config.measurement_noise),
+        median_window: Some(preset.config.median_window as u32),
+        mode_bin_width: Some(preset.config.mode_bin_width),
+        trend_window: Some(preset.config.trend_window as u32),
+        despike_threshold: Some(preset.config.despike_threshold),
+        despike_window: Some(preset.config.despike_window as u32),
+        adaptive_noise: Some(preset.config.adaptive_noise),
+        alpha_min: Some(preset.config.alpha_min),
+        min_distance: Some(preset.config.min_distance),
+        max_distance: Some(preset.config.max_distance),
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn preset_from_proto(preset: FilterPreset) -> Result<Preset, Status> {
+    if preset.name.trim().is_empty() {
+        return Err(Status::invalid_argument("preset name is required"));
+    }
+    let config = FilterConfig {
+        filter_type: preset.filter_type.parse().map_err(Status::invalid_argument)?,
+        pipeline: preset.pipeline.as_deref().map(str::parse).transpose().map_err(Status::invalid_argument)?,
+        init_period: preset.init_period as usize,
+        rate_limit: preset.rate_limit,
+        alpha: preset.alpha,
+        trim_percentage: preset.trim_percentage,
+        batch_size: preset.batch_size as usize,
+        batch_step: preset.batch_step.unwrap_or(0) as usize,
+        dead_band: preset.dead_band.unwrap_or(0.0),
+        process_noise: preset.process_noise.unwrap_or(pipeline::DEFAULT_PROCESS_NOISE),
+        measurement_noise: preset.measurement_noise.unwrap_or(pipeline::DEFAULT_MEASUREMENT_NOISE),
+        median_window: preset.median_window.map_or(pipeline::DEFAULT_MEDIAN_WINDOW, |n| n as usize),
+        mode_bin_width: preset.mode_bin_width.unwrap_or(pipeline::DEFAULT_MODE_BIN_WIDTH),
+        trend_window: preset.trend_window.map_or(pipeline::DEFAULT_TREND_WINDOW, |n| n as usize),
+        despike_threshold: preset.despike_threshold.unwrap_or(0.0),
+        despike_window: preset.despike_window.map_or(pipeline::DEFAULT_DESPIKE_WINDOW, |n| n as usize),
+        adaptive_noise: preset.adaptive_noise.unwrap_or(0.0),
+        alpha_min: preset.alpha_min.unwrap_or(pipeline::DEFAULT_ALPHA_MIN),
+        min_distance: preset.min_distance.unwrap_or(0.0),
+        max_distance: preset.max_distance.unwrap_or(0.0),
+    };
+    config.validate().map_err(Status::invalid_argument)?;
+    Ok(Preset::new(preset.name, config))
+}
+
+/// Serve the gRPC service on the `--listen-addr` and `--listen-unix`
+/// listeners until `cancel_token` is cancelled or one of them fails
+pub async fn serve(
+    args: &Args,
+    service: &SnowGaugeServiceImpl,
+    hooks: &Hooks,
+    supervisor: &Supervisor,
+    cancel_token: &CancellationToken,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let access = Arc::new(AccessList::new(args.allow_cidrs.clone(), args.deny_cidrs.clone()));
+    if !args.allow_cidrs.is_empty() || !args.deny_cidrs.is_empty() {
+        info!("gRPC clients restricted: allow {:?}, deny {:?}",
+              args.allow_cidrs.iter().map(|c| c.to_string()).collect::<Vec<_>>(),
+              args.deny_cidrs.iter().map(|c| c.to_string()).collect::<Vec<_>>());
+    }
+
+    // Enable gRPC reflection for easier debugging with grpcurl
+    let reflection_service = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(include_bytes!("../target/snowgauge_descriptor.bin"))
+        .build_v1()?;
+
+    // Every listener serves the same service instances, the station metadata
+    // picking between them
+    let others: Vec<&SnowGaugeServiceImpl> = service.stations().skip(1).collect();
+    let router = || {
+        let stations = StationRouter::new(
+            (service.station_name.clone(), SnowGaugeServiceServer::new(service.clone())),
+            others.iter().map(|station| (station.station_name.clone(), SnowGaugeServiceServer::new((*station).clone()))),
+        );
+        Server::builder()
+            .layer(
+                MetricsLayer::new(service.metrics.clone())
+                    .with_stations(others.iter().map(|station| (station.station_name.clone(), station.metrics.clone()))),
+            )
+            .layer(hooks.server_layer())
+            .add_service(InterceptedService::new(stations, hooks.interceptor(acl::interceptor(access.clone()))))
+            .add_service(reflection_service.clone())
+            .add_service(health::proto::health_server::HealthServer::new(health::HealthService::new(supervisor.health())))
+    };
+
+    let mut servers = tokio::task::JoinSet::new();
+    let listeners = bind_tcp(&args.listen_addr).map_err(|e| format!("Failed to listen on {:?}: {}", args.listen_addr, e))?;
+    for listener in listeners {
+        info!("gRPC server listening on {}", listener.local_addr()?);
+        let incoming = acl::filter_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener), access.clone());
+        servers.spawn(router().serve_with_incoming_shutdown(incoming, cancel_token.clone().cancelled_owned()));
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = &args.listen_unix {
+        let listener = bind_unix(path, args.listen_unix_mode)
+            .map_err(|e| format!("Failed to listen on {}: {}", path.display(), e))?;
+        info!("gRPC server listening on {}", path.display());
+        let incoming = tokio_stream::wrappers::UnixListenerStream::new(listener);
+        servers.spawn(router().serve_with_incoming_shutdown(incoming, cancel_token.clone().cancelled_owned()));
+    }
+    #[cfg(not(unix))]
+    if args.listen_unix.is_some() {
+        return Err("Unix socket listeners are only supported on Unix".into());
+    }
+
+    while let Some(result) = servers.join_next().await {
+        // One listener failing takes the others down with it
+        if let Err(e) = result.map_err(|e| e.to_string()).and_then(|r| r.map_err(|e| e.to_string())) {
+            cancel_token.cancel();
+            return Err(format!("gRPC server failed: {}", e).into());
+        }
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = &args.listen_unix {
+        let _ = std::fs::remove_file(path);
+    }
+    Ok(())
+}
+
+/// Bind the gRPC TCP listeners
+///
+/// An IPv6 wildcard listener also takes IPv4 connections, which clashes
+/// with an IPv4 listener on the same port, so IPv6 listeners sharing a port
+/// with an IPv4 one are made IPv6-only.
+fn bind_tcp(addrs: &[SocketAddr]) -> std::io::Result<Vec<tokio::net::TcpListener>> {
+    addrs
+        .iter()
+        .map(|&addr| {
+            let socket = if addr.is_ipv4() {
+                tokio::net::TcpSocket::new_v4()?
+            } else {
+                tokio::net::TcpSocket::new_v6()?
+            };
+            #[cfg(unix)]
+            socket.set_reuseaddr(true)?;
+            if addr.is_ipv6() && addrs.iter().any(|other| other.is_ipv4() && other.port() == addr.port()) {
+                set_only_v6(&socket)?;
+            }
+            socket.bind(addr)?;
+            socket.listen(1024)
+        })
+        .collect()
+}
+
+#[cfg(unix)]
+fn set_only_v6(socket: &tokio::net::TcpSocket) -> std::io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let on: libc::c_int = 1;
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_V6ONLY,
+            &on as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// IPv6 sockets are IPv6-only by default elsewhere
+#[cfg(not(unix))]
+fn set_only_v6(_socket: &tokio::net::TcpSocket) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// Bind a Unix socket at `path`, replacing a stale socket left by an earlier run
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path, mode: u32) -> std::io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
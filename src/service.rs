@@ -0,0 +1,572 @@
+/// The SnowGauge gRPC service's state: each station's settings, history
+/// and totals, and the clients streaming its readings
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use log::{debug, info, warn};
+use tokio::sync::{watch, RwLock};
+use tonic::{Request, Status};
+
+use crate::anomaly::AnomalyDetector;
+use crate::baseline::Baseline;
+use crate::battery::VoltageProvider;
+use crate::board::{Board, BoardPeriod};
+use crate::calibration::CalibrationCurve;
+use crate::compensation::CompensationConfig;
+use crate::config;
+use crate::events::{EventPublisher, StormDetector};
+use crate::frame::FrameCounters;
+use crate::fusion::{self, Estimate};
+use crate::history::{self, History};
+use crate::hooks::PipelineHooks;
+use crate::interpolate;
+use crate::metrics::RpcMetrics;
+use crate::offset::Offset;
+use crate::pipeline::{BatchResult, Divergence, FilterConfig, RejectionCounters};
+use crate::preset::Preset;
+use crate::quality::{Quality, QualityChecks};
+use crate::queue::{self, OverflowPolicy};
+use crate::ratelimit::RateLimiter;
+use crate::schedule::Schedule;
+use crate::settling::SettlingDetector;
+use crate::snowgauge::{
+    self, measurement, raw_frame, AcquisitionStatus, ComparisonReading, DivergenceStats, EventKind, Measurement,
+    RawFrame, Reading, StationMetadata, StationStatus, StreamRequest, TemperatureSource, Unit,
+};
+use crate::stream::{self, ClientReceiver, ReplayBuffer, StreamClient, StreamConfig, StreamOptions};
+use crate::swe::{self, DensityModel};
+use crate::totals::Totals;
+use crate::trace::TraceContext;
+use crate::trend::{self, DirectionHysteresis};
+use crate::wind::WindConfig;
+
+/// Client channel for the filter comparison stream
+type ComparisonChannel = queue::Sender<ComparisonReading>;
+
+/// Client channels for the raw frame stream. The serial reader sends on
+/// these from its blocking thread, hence the std lock.
+pub type RawFrameChannels = Arc<std::sync::Mutex<Vec<queue::Sender<RawFrame>>>>;
+
+/// The latest batch results of the stations a station fuses, by name; set
+/// once they have all started
+type FusionPartners = Arc<std::sync::OnceLock<Vec<(String, watch::Receiver<Option<Estimate>>)>>>;
+
+/// Send a serial frame and its parse result to any StreamRawFrames clients
+pub fn publish_frame(channels: &RawFrameChannels, data: &[u8], result: raw_frame::Result) {
+    let mut channels = channels.lock().unwrap_or_else(|e| e.into_inner());
+    if channels.is_empty() {
+        return;
+    }
+    let frame = RawFrame {
+        data: data.to_vec(),
+        timestamp: Some(SystemTime::now().into()),
+        result: Some(result),
+    };
+    channels.retain(|channel| channel.send(Ok(frame.clone())));
+}
+
+/// Main service implementation
+#[derive(Clone)]
+pub struct SnowGaugeServiceImpl {
+    pub clients: Arc<RwLock<Vec<StreamClient>>>,
+    /// Recent batch readings for resuming clients; locked after `clients`
+    replay: Arc<RwLock<ReplayBuffer>>,
+    pub comparison_channels: Arc<RwLock<Vec<ComparisonChannel>>>,
+    pub raw_frame_channels: RawFrameChannels,
+    pub events: EventPublisher,
+    pub station_name: String,
+    pub metadata: StationMetadata,
+    /// Serial port or I2C bus the sensor is read from, or None in simulator mode
+    pub sensor_port: Option<String>,
+    pub started_at: SystemTime,
+    pub streams: StreamConfig,
+    /// Limits unary RPCs per client, across every station; None for no limit
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    /// Counters kept by the server's MetricsLayer for this station's calls
+    pub metrics: Arc<RpcMetrics>,
+    /// Readings discarded by the production pipeline
+    pub rejections: Arc<RejectionCounters>,
+    /// Frames read from the sensor, and how many were rejected
+    pub frames: Arc<FrameCounters>,
+    /// False once the supervisor has given up on a task
+    healthy: watch::Receiver<bool>,
+    /// Production filter preset; the processor rebuilds its pipeline on change
+    pub filter: Arc<watch::Sender<Preset>>,
+    /// Tells the processor to reset its pipelines
+    pub filter_reset: Arc<tokio::sync::Notify>,
+    /// Readings are discarded while true
+    pub paused: Arc<watch::Sender<bool>>,
+    /// The serial reader closes the port while true
+    pub release_port: Arc<watch::Sender<bool>>,
+    /// File that applied presets are saved to
+    pub preset_path: Option<PathBuf>,
+    pub baseline: Arc<watch::Sender<Option<Baseline>>>,
+    /// File that SetBaseline saves to
+    pub baseline_path: Option<PathBuf>,
+    pub offset: Arc<watch::Sender<Option<Offset>>>,
+    /// File that ApplyOffset saves to
+    pub offset_path: Option<PathBuf>,
+    /// Density model for water equivalents; SetSnowDensity replaces it
+    pub snow_density: Arc<watch::Sender<Option<DensityModel>>>,
+    /// Options as resolved at startup
+    pub settings: Arc<Vec<config::Setting>>,
+    pub compare_config: Option<FilterConfig>,
+    pub schedule: Option<Schedule>,
+    pub anomaly_detector: Option<AnomalyDetector>,
+    pub quality_checks: QualityChecks,
+    pub battery_voltage: Option<VoltageProvider>,
+    pub compensation: Option<CompensationConfig>,
+    pub calibration: Option<CalibrationCurve>,
+    /// Degrees from vertical
+    pub mount_angle: f64,
+    pub wind: Option<WindConfig>,
+    pub interpolate_max_gap: Option<Duration>,
+    snowfall_rate_window: Option<Duration>,
+    /// Shared with GetStorms, which lists the storms it has seen
+    pub storm_detector: Option<Arc<std::sync::Mutex<StormDetector>>>,
+    pub direction_hysteresis: Option<DirectionHysteresis>,
+    pub settling_detector: Option<SettlingDetector>,
+    sensor_noise: f64,
+    pub fuse_with: Vec<String>,
+    fusion_max_age: Duration,
+    /// The latest batch result, before fusion
+    pub estimate: Arc<watch::Sender<Option<Estimate>>>,
+    pub fusion_partners: FusionPartners,
+    pub hooks: PipelineHooks,
+    /// Shared with ClearBoard
+    pub board: Arc<std::sync::Mutex<Board>>,
+    /// Shared with GetTotals and the CoAP summary
+    pub totals: Arc<std::sync::Mutex<Totals>>,
+    pub totals_path: Option<PathBuf>,
+    pub history: Arc<RwLock<History>>,
+    /// The other stations served here, set on the command line's station
+    pub peers: Arc<std::sync::OnceLock<Vec<SnowGaugeServiceImpl>>>,
+    /// Span of the last call that changed how readings are made: the
+    /// processor's events for the change and the next batch reading
+    /// continue its trace
+    pub cause: Arc<std::sync::Mutex<Option<TraceContext>>>,
+}
+
+/// A station's state as loaded at startup, with the files it is saved to,
+/// and the handles it shares
+pub struct StationState {
+    /// False once the supervisor has given up on a task
+    pub healthy: watch::Receiver<bool>,
+    /// Limits unary RPCs per client; None for no limit
+    pub rate_limiter: Option<RateLimiter>,
+    pub preset: Preset,
+    pub preset_path: Option<PathBuf>,
+    pub baseline: Option<Baseline>,
+    pub baseline_path: Option<PathBuf>,
+    pub offset: Option<Offset>,
+    pub offset_path: Option<PathBuf>,
+    pub snow_density: Option<DensityModel>,
+    pub board: Board,
+    pub totals: Totals,
+    pub totals_path: Option<PathBuf>,
+    pub history: History,
+}
+
+/// How a station turns filtered batches into readings; each stage is off
+/// when None
+pub struct Processing {
+    pub compare_config: Option<FilterConfig>,
+    pub schedule: Option<Schedule>,
+    pub anomaly_detector: Option<AnomalyDetector>,
+    pub quality_checks: QualityChecks,
+    pub battery_voltage: Option<VoltageProvider>,
+    pub compensation: Option<CompensationConfig>,
+    pub calibration: Option<CalibrationCurve>,
+    /// Degrees from vertical
+    pub mount_angle: f64,
+    pub wind: Option<WindConfig>,
+    pub interpolate_max_gap: Option<Duration>,
+    pub snowfall_rate_window: Option<Duration>,
+    pub storm_detector: Option<StormDetector>,
+    pub direction_hysteresis: Option<DirectionHysteresis>,
+    pub settling_detector: Option<SettlingDetector>,
+    /// Standard deviation of a reading in mm, for the stations fusing this one
+    pub sensor_noise: f64,
+    /// Stations fused with this one, and the oldest result used from them
+    pub fuse_with: Vec<String>,
+    pub fusion_max_age: Duration,
+    pub hooks: PipelineHooks,
+}
+
+impl SnowGaugeServiceImpl {
+    pub fn new(
+        station_name: String,
+        metadata: StationMetadata,
+        sensor_port: Option<String>,
+        streams: StreamConfig,
+        settings: Vec<config::Setting>,
+        state: StationState,
+        processing: Processing,
+    ) -> Self {
+        Self {
+            clients: Arc::new(RwLock::new(Vec::new())),
+            replay: Arc::new(RwLock::new(ReplayBuffer::new(streams.replay_buffer))),
+            comparison_channels: Arc::new(RwLock::new(Vec::new())),
+            raw_frame_channels: Arc::new(std::sync::Mutex::new(Vec::new())),
+            events: EventPublisher::new(station_name.clone()),
+            station_name,
+            metadata,
+            sensor_port,
+            started_at: SystemTime::now(),
+            streams,
+            rate_limiter: state.rate_limiter.map(Arc::new),
+            metrics: Arc::new(RpcMetrics::default()),
+            rejections: Arc::new(RejectionCounters::default()),
+            frames: Arc::new(FrameCounters::default()),
+            healthy: state.healthy,
+            filter: Arc::new(watch::channel(state.preset).0),
+            filter_reset: Arc::new(tokio::sync::Notify::new()),
+            paused: Arc::new(watch::channel(false).0),
+            release_port: Arc::new(watch::channel(false).0),
+            preset_path: state.preset_path,
+            baseline: Arc::new(watch::channel(state.baseline).0),
+            baseline_path: state.baseline_path,
+            offset: Arc::new(watch::channel(state.offset).0),
+            offset_path: state.offset_path,
+            snow_density: Arc::new(watch::channel(state.snow_density).0),
+            settings: Arc::new(settings),
+            compare_config: processing.compare_config,
+            schedule: processing.schedule,
+            anomaly_detector: processing.anomaly_detector,
+            quality_checks: processing.quality_checks,
+            battery_voltage: processing.battery_voltage,
+            compensation: processing.compensation,
+            calibration: processing.calibration,
+            mount_angle: processing.mount_angle,
+            wind: processing.wind,
+            interpolate_max_gap: processing.interpolate_max_gap,
+            snowfall_rate_window: processing.snowfall_rate_window,
+            storm_detector: processing.storm_detector.map(|s| Arc::new(std::sync::Mutex::new(s))),
+            direction_hysteresis: processing.direction_hysteresis,
+            settling_detector: processing.settling_detector,
+            sensor_noise: processing.sensor_noise,
+            fuse_with: processing.fuse_with,
+            fusion_max_age: processing.fusion_max_age,
+            estimate: Arc::new(watch::channel(None).0),
+            fusion_partners: Arc::new(std::sync::OnceLock::new()),
+            hooks: processing.hooks,
+            board: Arc::new(std::sync::Mutex::new(state.board)),
+            totals: Arc::new(std::sync::Mutex::new(state.totals)),
+            totals_path: state.totals_path,
+            history: Arc::new(RwLock::new(state.history)),
+            peers: Arc::new(std::sync::OnceLock::new()),
+            cause: Arc::new(std::sync::Mutex::new(None)),
+        }
+    }
+
+    /// Note `trace`, a call's span, as the cause of the next batch reading
+    pub fn caused_by(&self, trace: TraceContext) {
+        *self.cause.lock().unwrap_or_else(|e| e.into_inner()) = Some(trace);
+    }
+
+    pub fn cause(&self) -> Option<TraceContext> {
+        *self.cause.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Register a new receiver within the daemon for every batch reading
+    ///
+    /// These only ever want the latest reading, so a backlog drops the
+    /// oldest whatever the slow client policy.
+    pub async fn subscribe(&self) -> ClientReceiver {
+        let (tx, rx) = queue::bounded(self.streams.queue_size, OverflowPolicy::DropOldest);
+        let options = StreamOptions { station_names: vec![self.station_name.clone()], ..StreamOptions::default() };
+        self.clients.write().await.push(StreamClient::new(tx, options));
+        rx
+    }
+
+    /// Register a gRPC stream client for the readings selected by `options`,
+    /// first replaying retained readings if it is resuming
+    #[allow(clippy::result_large_err)]
+    pub async fn subscribe_with(&self, options: StreamOptions) -> Result<ClientReceiver, Status> {
+        // Holding the clients lock keeps a broadcast from landing between
+        // the replay and the registration
+        let mut clients = self.clients.write().await;
+        self.check_client_limit(&mut clients).await?;
+
+        let mut buffers = Vec::new();
+        for station in self.stations().filter(|station| options.accepts(&station.station_name)) {
+            buffers.push((station.station_name.as_str(), station.replay.read().await));
+        }
+        let replayed = stream::replay(buffers.iter().map(|(station, buffer)| (*station, &**buffer)), &options);
+        // Room for the replay on top of the usual queue
+        let (tx, rx) = queue::bounded(self.streams.queue_size + replayed.len(), self.streams.overflow_policy);
+        let mut client = StreamClient::remote(tx, options);
+        for reading in replayed {
+            client.offer(reading, false);
+        }
+        clients.push(client);
+        Ok(rx)
+    }
+
+    /// Fail with RESOURCE_EXHAUSTED if `--max-clients` gRPC streams are open
+    ///
+    /// Takes the locked clients so concurrent registrations are counted
+    /// one at a time.
+    #[allow(clippy::result_large_err)]
+    pub async fn check_client_limit(&self, clients: &mut Vec<StreamClient>) -> Result<(), Status> {
+        let max_clients = self.streams.max_clients;
+        if max_clients == 0 {
+            return Ok(());
+        }
+        // Clients that went away since the last broadcast don't count
+        clients.retain(|client| !client.is_closed());
+        let mut comparison_channels = self.comparison_channels.write().await;
+        comparison_channels.retain(|channel| !channel.is_closed());
+        let mut raw_frame_channels = self.raw_frame_channels.lock().unwrap_or_else(|e| e.into_inner());
+        raw_frame_channels.retain(|channel| !channel.is_closed());
+
+        let open = clients.iter().filter(|client| client.is_remote()).count()
+            + comparison_channels.len()
+            + raw_frame_channels.len();
+        if open >= max_clients {
+            warn!("Rejecting stream client: {} streams already open (--max-clients {})", open, max_clients);
+            return Err(Status::resource_exhausted(format!("too many open streams (limit {})", max_clients)));
+        }
+        Ok(())
+    }
+
+    /// Fail with RESOURCE_EXHAUSTED if the request's client is over `--rpc-rate-limit`
+    #[allow(clippy::result_large_err)]
+    pub fn rate_limit<T>(&self, request: &Request<T>) -> Result<(), Status> {
+        match &self.rate_limiter {
+            Some(limiter) => limiter.check(request),
+            None => Ok(()),
+        }
+    }
+
+    pub fn acquisition_status(&self) -> AcquisitionStatus {
+        AcquisitionStatus {
+            paused: *self.paused.borrow(),
+            port_closed: *self.release_port.borrow(),
+        }
+    }
+
+    /// Valid stored readings from the `window` before `now`
+    pub async fn trend_readings(&self, now: SystemTime, window: Duration) -> Vec<history::AmendedReading> {
+        self.history
+            .read()
+            .await
+            .query(now.checked_sub(window), None)
+            .into_iter()
+            .filter(|r| !r.invalid)
+            .collect()
+    }
+
+    /// Snowfall rate in mm/hr over the --snowfall-rate-window before `now`,
+    /// once recorded readings cover at least half the window
+    pub async fn snowfall_rate(&self, now: SystemTime) -> Option<f64> {
+        let window = self.snowfall_rate_window?;
+        let readings = self.trend_readings(now, window).await;
+        let covered = readings.first().is_some_and(|r| r.timestamp <= now - window / 2);
+        if !covered {
+            return None;
+        }
+        let (interpolation, max_gap) = self.totals.lock().unwrap_or_else(|e| e.into_inner()).interpolation();
+        let series: Vec<(SystemTime, f64)> = readings.iter().map(|r| (r.timestamp, r.distance)).collect();
+        trend::theil_sen(&trend_points(&interpolate::fill(&series, interpolation, max_gap), now))
+            .map(|fit| rate_mm_per_hour(fit.slope))
+    }
+
+    /// Send a board-cleared event for a clear that ended `ended`, if the
+    /// board had been cleared before, in the trace of the call or reading
+    /// that cleared it
+    pub fn publish_board_cleared(&self, cause: &TraceContext, ended: Option<&BoardPeriod>) {
+        let detail = match ended {
+            Some(period) => {
+                let hours = period.end.duration_since(period.start).unwrap_or_default();
+                format!("board cleared after {:.1}h: {:.1}mm new snow", hours.as_secs_f64() / 3600.0, period.new_snow)
+            }
+            None => "board cleared".to_string(),
+        };
+        info!("{}", detail);
+        self.events.publish_caused(Some(cause), EventKind::BoardCleared, detail);
+    }
+
+    /// A stored reading as sent to clients, with `value` in the default unit
+    pub fn stored_reading(&self, reading: &history::AmendedReading) -> Reading {
+        let unit = self.streams.default_unit;
+        Reading {
+            station_name: self.station_name.clone(),
+            distance: reading.distance as i32,
+            system_uptime: None,
+            application_uptime: None,
+            timestamp: Some(reading.timestamp.into()),
+            traceparent: String::new(),
+            value: stream::convert(reading.distance, unit),
+            distance_mm: reading.distance,
+            measurements: measurements(reading.distance, *self.baseline.borrow(), None, None),
+            battery_voltage: None,
+            batch_stats: None,
+            snowfall_rate_mm_per_hour: None,
+            temperature_c: None,
+            temperature_source: TemperatureSource::None as i32,
+            relative_humidity: None,
+            offset_mm: None,
+            settling_rate_mm_per_hour: None,
+            melt_rate_mm_per_hour: None,
+            accumulation: None,
+            fusion: Vec::new(),
+            unit: unit as i32,
+            sequence: 0,
+            quality: 0,
+        }
+    }
+
+    /// A reading interpolated over a gap in the batch readings
+    pub fn interpolated_reading(&self, timestamp: SystemTime, distance: f64) -> Reading {
+        Reading {
+            station_name: self.station_name.clone(),
+            distance: distance as i32,
+            timestamp: Some(timestamp.into()),
+            value: distance,
+            distance_mm: distance,
+            measurements: measurements(distance, *self.baseline.borrow(), None, None),
+            unit: Unit::Millimeters as i32,
+            quality: Quality::INTERPOLATED.bits(),
+            ..Default::default()
+        }
+    }
+
+    /// Parse stream options, failing if they match no station served here
+    #[allow(clippy::result_large_err)]
+    pub fn stream_options(&self, request: &StreamRequest) -> Result<StreamOptions, Status> {
+        let options = StreamOptions::from_request(request, self.streams.default_unit)?;
+        if !self.stations().any(|station| options.accepts(&station.station_name)) {
+            let names: Vec<&str> = self.stations().map(|station| station.station_name.as_str()).collect();
+            return Err(Status::not_found(format!(
+                "no station served here matches {:?} (stations here: {})",
+                options.station_names,
+                names.join(", ")
+            )));
+        }
+        Ok(options)
+    }
+
+    /// This station and any others served here
+    pub fn stations(&self) -> impl Iterator<Item = &SnowGaugeServiceImpl> {
+        std::iter::once(self).chain(self.peers.get().into_iter().flatten())
+    }
+
+    pub async fn station_status(&self, now: SystemTime) -> StationStatus {
+        let history = self.history.read().await;
+        StationStatus {
+            station_name: self.station_name.clone(),
+            healthy: *self.healthy.borrow(),
+            stale: history.open_gap(now).is_some(),
+            latest: history.latest().map(|r| self.stored_reading(&r)),
+            paused: *self.paused.borrow(),
+            metadata: Some(self.metadata.clone()),
+        }
+    }
+
+    /// Broadcast a batch reading (or, with `raw`, a raw sensor reading) to
+    /// the connected clients that asked for it
+    ///
+    /// Batch readings are numbered and retained for resuming clients.
+    pub async fn broadcast_reading(&self, mut reading: Reading, raw: bool) {
+        let mut clients = self.clients.write().await;
+        if !raw {
+            self.replay.write().await.push(&mut reading);
+        }
+
+        // Use retain_mut() to atomically filter out disconnected clients
+        // This avoids the TOCTOU race condition from collecting indices
+        clients.retain_mut(|client| client.offer(&reading, raw));
+    }
+
+    /// Broadcast a tagged batch result to all comparison stream clients
+    pub async fn broadcast_comparison(&self, variant: &str, average: f64, divergence: &Divergence) {
+        let reading = ComparisonReading {
+            station_name: self.station_name.clone(),
+            variant: variant.to_string(),
+            distance: average,
+            timestamp: Some(SystemTime::now().into()),
+            divergence: Some(DivergenceStats {
+                samples: divergence.samples(),
+                mean_difference: divergence.mean_difference(),
+                mean_abs_difference: divergence.mean_abs_difference(),
+                rms_difference: divergence.rms_difference(),
+                max_abs_difference: divergence.max_abs_difference(),
+            }),
+        };
+
+        let mut clients = self.comparison_channels.write().await;
+        clients.retain(|client| client.send(Ok(reading.clone())));
+    }
+
+    /// Publish a batch result for the stations fusing this one, then fuse it
+    /// with the latest results of the stations this one fuses, returning the
+    /// inputs. Others get the result as measured, so two stations fusing each
+    /// other don't feed back; when no input is usable it stands as it is.
+    pub fn fuse(&self, result: &mut BatchResult) -> Vec<snowgauge::FusionInput> {
+        let own = Estimate { time: SystemTime::now(), distance: result.average, noise: self.sensor_noise, quality: result.quality };
+        self.estimate.send_replace(Some(own));
+        let Some(partners) = self.fusion_partners.get().filter(|partners| !partners.is_empty()) else {
+            return Vec::new();
+        };
+
+        let mut inputs = vec![(self.station_name.clone(), own)];
+        inputs.extend(partners.iter().filter_map(|(name, estimate)| estimate.borrow().map(|estimate| (name.clone(), estimate))));
+        let estimates: Vec<Estimate> = inputs.iter().map(|(_, estimate)| *estimate).collect();
+        let weights = match fusion::fuse(&estimates, own.time, self.fusion_max_age) {
+            Some(fused) => {
+                debug!("Fused {:.2}mm from {} results into {:.2}mm", result.average, inputs.len(), fused.distance);
+                result.average = fused.distance;
+                result.quality = fused.quality;
+                fused.weights
+            }
+            None => vec![0.0; inputs.len()],
+        };
+        inputs
+            .into_iter()
+            .zip(weights)
+            .map(|((station_name, estimate), weight)| snowgauge::FusionInput {
+                station_name,
+                distance_mm: estimate.distance,
+                weight,
+                quality: estimate.quality.bits(),
+            })
+            .collect()
+    }
+}
+
+/// Theil–Sen fit of distance against seconds relative to `now`, so the
+/// intercept is the current trend value
+pub fn fit_trend(readings: &[history::AmendedReading], now: SystemTime) -> Option<trend::Fit> {
+    let series: Vec<(SystemTime, f64)> = readings.iter().map(|r| (r.timestamp, r.distance)).collect();
+    trend::theil_sen(&trend_points(&series, now))
+}
+
+/// `(seconds relative to now, distance)` points of a series
+pub fn trend_points(series: &[(SystemTime, f64)], now: SystemTime) -> Vec<(f64, f64)> {
+    series
+        .iter()
+        .map(|&(timestamp, distance)| (-now.duration_since(timestamp).unwrap_or_default().as_secs_f64(), distance))
+        .collect()
+}
+
+/// Snowfall rate in mm/hr for a distance slope in mm/s; depth grows as distance shrinks
+pub fn rate_mm_per_hour(slope: f64) -> f64 {
+    -slope * 3600.0
+}
+
+/// The measurements reported for a distance: the distance itself, the snow
+/// depth once a baseline is set, and the new snow once the board is cleared
+pub fn measurements(distance_mm: f64, baseline: Option<Baseline>, density: Option<f64>, new_snow: Option<f64>) -> Vec<Measurement> {
+    let distance = Some(measurement::Kind::DistanceMm(distance_mm));
+    let depth = baseline.map(|b| b.snow_depth(distance_mm));
+    let water = depth
+        .zip(density)
+        .map(|(depth, density)| measurement::Kind::SnowWaterEquivalentMm(swe::water_equivalent(depth, density)));
+    [distance, depth.map(measurement::Kind::SnowDepthMm), water, new_snow.map(measurement::Kind::NewSnowMm)]
+        .into_iter()
+        .flatten()
+        .map(|kind| Measurement { kind: Some(kind) })
+        .collect()
+}
@@ -0,0 +1,95 @@
+/// Settling and melt detection
+///
+/// Snow loses depth without losing water as it settles, and loses both as
+/// it melts, so a falling depth isn't negative snowfall. A decrease is
+/// classified once the snowfall rate has stayed slowly negative, between
+/// the minimum and maximum rates, for long enough that it isn't noise: the
+/// air temperature decides melt (above freezing) or settling (at or below,
+/// or with no temperature source). While a decrease is classified its rate
+/// is reported as settling or melt and the snowfall rate as 0, so totals
+/// built from the positive rates aren't pulled down by it. Faster drops,
+/// such as a cleared board or wind scour, aren't settling, and are left to
+/// the snowfall rate.
+use std::time::{Duration, SystemTime};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decrease {
+    /// Rate of depth loss in mm/hr
+    Settling(f64),
+    Melt(f64),
+}
+
+#[derive(Debug, Clone)]
+pub struct SettlingDetector {
+    /// How long the decrease must last
+    duration: Duration,
+    /// Rates of depth loss in mm/hr counted as settling or melt
+    min_rate: f64,
+    max_rate: f64,
+    since: Option<SystemTime>,
+}
+
+impl SettlingDetector {
+    pub fn new(duration: Duration, min_rate: f64, max_rate: f64) -> Self {
+        Self { duration, min_rate, max_rate, since: None }
+    }
+
+    /// Update with the latest snowfall rate (mm/hr, negative for decreasing
+    /// depth) and air temperature (°C), returning the decrease if the depth has
+    /// been settling or melting; an unknown rate neither continues nor
+    /// interrupts a decrease
+    pub fn update(&mut self, now: SystemTime, rate: Option<f64>, temperature: Option<f64>) -> Option<Decrease> {
+        let rate = rate?;
+        if !(self.min_rate..=self.max_rate).contains(&-rate) {
+            self.since = None;
+            return None;
+        }
+        let since = *self.since.get_or_insert(now);
+        if now.duration_since(since).unwrap_or_default() < self.duration {
+            return None;
+        }
+        match temperature {
+            Some(t) if t > 0.0 => Some(Decrease::Melt(-rate)),
+            _ => Some(Decrease::Settling(-rate)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn at(minutes: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(minutes * 60)
+    }
+
+    fn detector() -> SettlingDetector {
+        SettlingDetector::new(Duration::from_secs(3600), 0.5, 10.0)
+    }
+
+    #[test]
+    fn test_sustained_decrease() {
+        let mut detector = detector();
+        assert_eq!(detector.update(at(0), Some(-1.5), None), None);
+        assert_eq!(detector.update(at(30), Some(-2.0), Some(-4.0)), None);
+        assert_eq!(detector.update(at(45), None, None), None);
+        assert_eq!(detector.update(at(60), Some(-2.0), Some(-4.0)), Some(Decrease::Settling(2.0)));
+        assert_eq!(detector.update(at(90), Some(-3.0), Some(1.5)), Some(Decrease::Melt(3.0)));
+        // Snowfall ends it, and it has to last the duration again
+        assert_eq!(detector.update(at(100), Some(4.0), Some(1.5)), None);
+        assert_eq!(detector.update(at(110), Some(-3.0), Some(1.5)), None);
+    }
+
+    #[test]
+    fn test_rate_limits() {
+        let mut detector = detector();
+        detector.update(at(0), Some(-1.0), None);
+        // Within the noise, or too fast for settling
+        assert_eq!(detector.update(at(60), Some(-0.2), None), None);
+        detector.update(at(70), Some(-1.0), None);
+        assert_eq!(detector.update(at(130), Some(-25.0), None), None);
+        assert_eq!(detector.update(at(140), Some(-10.0), None), None);
+        assert_eq!(detector.update(at(200), Some(-0.5), None), Some(Decrease::Settling(0.5)));
+    }
+}
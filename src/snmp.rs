@@ -0,0 +1,684 @@
+/// Minimal SNMP agent exposing NTCIP 1204 ESS objects
+///
+/// Lets DOT road-weather central systems poll the gauge like any other
+/// environmental sensor station. Answers SNMPv1 and SNMPv2c Get, GetNext,
+/// and GetBulk requests for a read-only community over a small fixed MIB:
+/// the MIB-II system group plus the NTCIP 1204 snow depth object.
+///
+/// Snow depth needs the sensor's height above bare ground, taken from the
+/// baseline or else `--snmp-sensor-height`; without either, or without a
+/// valid reading, the NTCIP "missing" value is reported.
+use log::{debug, info, warn};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::{watch, RwLock};
+use tokio_util::sync::CancellationToken;
+
+use crate::baseline::Baseline;
+use crate::history::History;
+
+pub mod tag {
+    pub const INTEGER: u8 = 0x02;
+    pub const OCTET_STRING: u8 = 0x04;
+    pub const NULL: u8 = 0x05;
+    pub const OBJECT_ID: u8 = 0x06;
+    pub const SEQUENCE: u8 = 0x30;
+    pub const TIME_TICKS: u8 = 0x43;
+    pub const NO_SUCH_OBJECT: u8 = 0x80;
+    pub const NO_SUCH_INSTANCE: u8 = 0x81;
+    pub const END_OF_MIB_VIEW: u8 = 0x82;
+    pub const GET_REQUEST: u8 = 0xA0;
+    pub const GET_NEXT_REQUEST: u8 = 0xA1;
+    pub const RESPONSE: u8 = 0xA2;
+    pub const SET_REQUEST: u8 = 0xA3;
+    pub const GET_BULK_REQUEST: u8 = 0xA5;
+}
+
+pub const VERSION_1: i64 = 0;
+pub const VERSION_2C: i64 = 1;
+
+const NO_SUCH_NAME: i64 = 2;
+const NOT_WRITABLE: i64 = 17;
+
+/// Upper bound on GetBulk results, well beyond the size of this MIB
+const MAX_BULK_VARBINDS: usize = 64;
+
+pub const SYS_DESCR: &[u32] = &[1, 3, 6, 1, 2, 1, 1, 1, 0];
+pub const SYS_OBJECT_ID: &[u32] = &[1, 3, 6, 1, 2, 1, 1, 2, 0];
+pub const SYS_UPTIME: &[u32] = &[1, 3, 6, 1, 2, 1, 1, 3, 0];
+pub const SYS_NAME: &[u32] = &[1, 3, 6, 1, 2, 1, 1, 5, 0];
+
+/// NTCIP 1204 `ess` node (nema.transportation.devices.ess)
+pub const ESS: &[u32] = &[1, 3, 6, 1, 4, 1, 1206, 4, 2, 5];
+
+/// `essAdjacentSnowDepth.0` (precipitationStation 1), centimeters
+pub const ESS_ADJACENT_SNOW_DEPTH: &[u32] = &[1, 3, 6, 1, 4, 1, 1206, 4, 2, 5, 6, 1, 0];
+
+/// NTCIP value for a snow depth that is missing or in error
+pub const SNOW_DEPTH_MISSING: i64 = 3001;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Integer(i64),
+    OctetString(Vec<u8>),
+    Null,
+    ObjectId(Vec<u32>),
+    TimeTicks(u32),
+    NoSuchObject,
+    NoSuchInstance,
+    EndOfMibView,
+}
+
+impl Value {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Integer(v) => write_tlv(out, tag::INTEGER, &encode_integer(*v)),
+            Value::OctetString(v) => write_tlv(out, tag::OCTET_STRING, v),
+            Value::Null => write_tlv(out, tag::NULL, &[]),
+            Value::ObjectId(v) => write_tlv(out, tag::OBJECT_ID, &encode_oid(v)),
+            Value::TimeTicks(v) => write_tlv(out, tag::TIME_TICKS, &encode_unsigned(*v)),
+            Value::NoSuchObject => write_tlv(out, tag::NO_SUCH_OBJECT, &[]),
+            Value::NoSuchInstance => write_tlv(out, tag::NO_SUCH_INSTANCE, &[]),
+            Value::EndOfMibView => write_tlv(out, tag::END_OF_MIB_VIEW, &[]),
+        }
+    }
+
+    fn decode(tag_byte: u8, content: &[u8]) -> Result<Self, String> {
+        match tag_byte {
+            tag::INTEGER => Ok(Value::Integer(decode_integer(content)?)),
+            tag::OCTET_STRING => Ok(Value::OctetString(content.to_vec())),
+            tag::NULL => Ok(Value::Null),
+            tag::OBJECT_ID => Ok(Value::ObjectId(decode_oid(content)?)),
+            tag::TIME_TICKS => Ok(Value::TimeTicks(decode_integer(content)? as u32)),
+            tag::NO_SUCH_OBJECT => Ok(Value::NoSuchObject),
+            tag::NO_SUCH_INSTANCE => Ok(Value::NoSuchInstance),
+            tag::END_OF_MIB_VIEW => Ok(Value::EndOfMibView),
+            _ => Err(format!("unsupported value type 0x{:02x}", tag_byte)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pdu {
+    pub pdu_type: u8,
+    pub request_id: i64,
+    /// Error status, or non-repeaters for GetBulk
+    pub error_status: i64,
+    /// Error index, or max-repetitions for GetBulk
+    pub error_index: i64,
+    pub varbinds: Vec<(Vec<u32>, Value)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub version: i64,
+    pub community: Vec<u8>,
+    pub pdu: Pdu,
+}
+
+impl Message {
+    /// Parse a datagram, rejecting anything malformed
+    pub fn parse(buf: &[u8]) -> Result<Self, String> {
+        let mut outer = Reader::new(buf);
+        let mut message = Reader::new(outer.expect(tag::SEQUENCE)?);
+        let version = decode_integer(message.expect(tag::INTEGER)?)?;
+        let community = message.expect(tag::OCTET_STRING)?.to_vec();
+
+        let (pdu_type, body) = message.read()?;
+        let mut pdu = Reader::new(body);
+        let request_id = decode_integer(pdu.expect(tag::INTEGER)?)?;
+        let error_status = decode_integer(pdu.expect(tag::INTEGER)?)?;
+        let error_index = decode_integer(pdu.expect(tag::INTEGER)?)?;
+
+        let mut list = Reader::new(pdu.expect(tag::SEQUENCE)?);
+        let mut varbinds = Vec::new();
+        while !list.is_empty() {
+            let mut varbind = Reader::new(list.expect(tag::SEQUENCE)?);
+            let oid = decode_oid(varbind.expect(tag::OBJECT_ID)?)?;
+            let (value_tag, value) = varbind.read()?;
+            varbinds.push((oid, Value::decode(value_tag, value)?));
+        }
+
+        Ok(Self {
+            version,
+            community,
+            pdu: Pdu {
+                pdu_type,
+                request_id,
+                error_status,
+                error_index,
+                varbinds,
+            },
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut list = Vec::new();
+        for (oid, value) in &self.pdu.varbinds {
+            let mut varbind = Vec::new();
+            write_tlv(&mut varbind, tag::OBJECT_ID, &encode_oid(oid));
+            value.encode(&mut varbind);
+            write_tlv(&mut list, tag::SEQUENCE, &varbind);
+        }
+
+        let mut pdu = Vec::new();
+        write_tlv(&mut pdu, tag::INTEGER, &encode_integer(self.pdu.request_id));
+        write_tlv(&mut pdu, tag::INTEGER, &encode_integer(self.pdu.error_status));
+        write_tlv(&mut pdu, tag::INTEGER, &encode_integer(self.pdu.error_index));
+        write_tlv(&mut pdu, tag::SEQUENCE, &list);
+
+        let mut message = Vec::new();
+        write_tlv(&mut message, tag::INTEGER, &encode_integer(self.version));
+        write_tlv(&mut message, tag::OCTET_STRING, &self.community);
+        write_tlv(&mut message, self.pdu.pdu_type, &pdu);
+
+        let mut out = Vec::new();
+        write_tlv(&mut out, tag::SEQUENCE, &message);
+        out
+    }
+}
+
+/// Sequential BER TLV reader over a buffer
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    /// Read the next TLV, returning its tag and contents
+    fn read(&mut self) -> Result<(u8, &'a [u8]), String> {
+        let tag_byte = *self.buf.get(self.pos).ok_or("truncated tag")?;
+        let first = *self.buf.get(self.pos + 1).ok_or("truncated length")?;
+        self.pos += 2;
+
+        let length = if first & 0x80 == 0 {
+            first as usize
+        } else {
+            let count = (first & 0x7F) as usize;
+            if count == 0 || count > 4 {
+                return Err(format!("unsupported length encoding 0x{:02x}", first));
+            }
+            let bytes = self.buf.get(self.pos..self.pos + count).ok_or("truncated length")?;
+            self.pos += count;
+            bytes.iter().fold(0usize, |acc, b| (acc << 8) | *b as usize)
+        };
+
+        let content = self
+            .buf
+            .get(self.pos..self.pos + length)
+            .ok_or("truncated contents")?;
+        self.pos += length;
+        Ok((tag_byte, content))
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<&'a [u8], String> {
+        let (tag_byte, content) = self.read()?;
+        if tag_byte != expected {
+            return Err(format!("expected tag 0x{:02x}, got 0x{:02x}", expected, tag_byte));
+        }
+        Ok(content)
+    }
+}
+
+fn write_tlv(out: &mut Vec<u8>, tag_byte: u8, content: &[u8]) {
+    out.push(tag_byte);
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = (len as u32).to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        out.push(0x80 | (4 - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+    out.extend_from_slice(content);
+}
+
+/// Two's-complement integer in the minimal number of bytes
+pub fn encode_integer(value: i64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let mut start = 0;
+    while start < 7 {
+        let redundant = (bytes[start] == 0x00 && bytes[start + 1] & 0x80 == 0)
+            || (bytes[start] == 0xFF && bytes[start + 1] & 0x80 != 0);
+        if !redundant {
+            break;
+        }
+        start += 1;
+    }
+    bytes[start..].to_vec()
+}
+
+/// Unsigned application types (TimeTicks etc.) are encoded as non-negative integers
+fn encode_unsigned(value: u32) -> Vec<u8> {
+    encode_integer(value as i64)
+}
+
+pub fn decode_integer(content: &[u8]) -> Result<i64, String> {
+    if content.is_empty() || content.len() > 8 {
+        return Err(format!("invalid integer length {}", content.len()));
+    }
+    let initial = if content[0] & 0x80 != 0 { -1i64 } else { 0 };
+    Ok(content.iter().fold(initial, |acc, b| (acc << 8) | *b as i64))
+}
+
+pub fn encode_oid(oid: &[u32]) -> Vec<u8> {
+    let mut out = Vec::new();
+    if oid.len() < 2 {
+        return out;
+    }
+    let mut subids = vec![oid[0] * 40 + oid[1]];
+    subids.extend_from_slice(&oid[2..]);
+    for subid in subids {
+        let mut chunk = vec![(subid & 0x7F) as u8];
+        let mut rest = subid >> 7;
+        while rest > 0 {
+            chunk.push(0x80 | (rest & 0x7F) as u8);
+            rest >>= 7;
+        }
+        chunk.reverse();
+        out.extend(chunk);
+    }
+    out
+}
+
+pub fn decode_oid(content: &[u8]) -> Result<Vec<u32>, String> {
+    let mut subids = Vec::new();
+    let mut current: u32 = 0;
+    for (i, b) in content.iter().enumerate() {
+        current = current
+            .checked_mul(128)
+            .ok_or("object identifier sub-identifier overflow")?
+            | (b & 0x7F) as u32;
+        if b & 0x80 == 0 {
+            subids.push(current);
+            current = 0;
+        } else if i == content.len() - 1 {
+            return Err("truncated object identifier".to_string());
+        }
+    }
+
+    let Some(first) = subids.first().copied() else {
+        return Err("empty object identifier".to_string());
+    };
+    let mut oid = if first < 80 {
+        vec![first / 40, first % 40]
+    } else {
+        vec![2, first - 80]
+    };
+    oid.extend_from_slice(&subids[1..]);
+    Ok(oid)
+}
+
+/// Snow depth in centimeters from the sensor height and measured distance (mm)
+pub fn snow_depth_cm(sensor_height: Option<f64>, distance: Option<f64>) -> i64 {
+    match (sensor_height, distance) {
+        (Some(height), Some(distance)) => {
+            let depth_cm = ((height - distance).max(0.0) / 10.0).round() as i64;
+            depth_cm.min(SNOW_DEPTH_MISSING - 1)
+        }
+        _ => SNOW_DEPTH_MISSING,
+    }
+}
+
+/// Build the MIB view, sorted by OID
+pub fn mib(station_name: &str, uptime: Duration, snow_depth_cm: i64) -> Vec<(Vec<u32>, Value)> {
+    vec![
+        (
+            SYS_DESCR.to_vec(),
+            Value::OctetString(
+                format!("snowgauge {} ultrasonic snow depth sensor", env!("CARGO_PKG_VERSION")).into_bytes(),
+            ),
+        ),
+        (SYS_OBJECT_ID.to_vec(), Value::ObjectId(ESS.to_vec())),
+        (SYS_UPTIME.to_vec(), Value::TimeTicks((uptime.as_millis() / 10) as u32)),
+        (SYS_NAME.to_vec(), Value::OctetString(station_name.as_bytes().to_vec())),
+        (ESS_ADJACENT_SNOW_DEPTH.to_vec(), Value::Integer(snow_depth_cm)),
+    ]
+}
+
+/// Answer a request against `mib`, or None if it should be dropped
+pub fn respond(request: &Message, community: &[u8], mib: &[(Vec<u32>, Value)]) -> Option<Message> {
+    if request.community != community {
+        return None;
+    }
+    let v1 = match request.version {
+        VERSION_1 => true,
+        VERSION_2C => false,
+        _ => return None,
+    };
+
+    let lookup = |oid: &[u32]| mib.iter().find(|(o, _)| o.as_slice() == oid).map(|(_, v)| v.clone());
+    let next = |oid: &[u32]| mib.iter().find(|(o, _)| o.as_slice() > oid).cloned();
+    let requested: Vec<&Vec<u32>> = request.pdu.varbinds.iter().map(|(oid, _)| oid).collect();
+
+    let mut error = None;
+    let varbinds = match request.pdu.pdu_type {
+        tag::GET_REQUEST => requested
+            .iter()
+            .enumerate()
+            .map(|(i, oid)| match lookup(oid) {
+                Some(value) => ((*oid).clone(), value),
+                None if v1 => {
+                    error.get_or_insert((NO_SUCH_NAME, i + 1));
+                    ((*oid).clone(), Value::Null)
+                }
+                None if mib.iter().any(|(o, _)| o[..o.len() - 1] == oid[..oid.len().saturating_sub(1)]) => {
+                    ((*oid).clone(), Value::NoSuchInstance)
+                }
+                None => ((*oid).clone(), Value::NoSuchObject),
+            })
+            .collect(),
+        tag::GET_NEXT_REQUEST => requested
+            .iter()
+            .enumerate()
+            .map(|(i, oid)| match next(oid) {
+                Some(varbind) => varbind,
+                None if v1 => {
+                    error.get_or_insert((NO_SUCH_NAME, i + 1));
+                    ((*oid).clone(), Value::Null)
+                }
+                None => ((*oid).clone(), Value::EndOfMibView),
+            })
+            .collect(),
+        tag::GET_BULK_REQUEST if !v1 => {
+            let non_repeaters = (request.pdu.error_status.max(0) as usize).min(requested.len());
+            let max_repetitions = request.pdu.error_index.max(0) as usize;
+
+            let mut varbinds: Vec<(Vec<u32>, Value)> = requested[..non_repeaters]
+                .iter()
+                .map(|oid| next(oid).unwrap_or_else(|| ((*oid).clone(), Value::EndOfMibView)))
+                .collect();
+
+            let mut cursors: Vec<Vec<u32>> = requested[non_repeaters..].iter().map(|oid| (*oid).clone()).collect();
+            'repetitions: for _ in 0..max_repetitions {
+                let mut all_done = true;
+                for cursor in cursors.iter_mut() {
+                    if varbinds.len() >= MAX_BULK_VARBINDS {
+                        break 'repetitions;
+                    }
+                    match next(cursor) {
+                        Some((oid, value)) => {
+                            all_done = false;
+                            *cursor = oid.clone();
+                            varbinds.push((oid, value));
+                        }
+                        None => varbinds.push((cursor.clone(), Value::EndOfMibView)),
+                    }
+                }
+                if all_done {
+                    break;
+                }
+            }
+            varbinds
+        }
+        tag::SET_REQUEST => {
+            error = Some((if v1 { NO_SUCH_NAME } else { NOT_WRITABLE }, 1));
+            request.pdu.varbinds.clone()
+        }
+        _ => return None,
+    };
+
+    // On error, the request's variable bindings are returned unchanged
+    let (error_status, error_index, varbinds) = match error {
+        Some((status, index)) => (status, index as i64, request.pdu.varbinds.clone()),
+        None => (0, 0, varbinds),
+    };
+
+    Some(Message {
+        version: request.version,
+        community: request.community.clone(),
+        pdu: Pdu {
+            pdu_type: tag::RESPONSE,
+            request_id: request.pdu.request_id,
+            error_status,
+            error_index,
+            varbinds,
+        },
+    })
+}
+
+pub struct SnmpAgent {
+    socket: UdpSocket,
+    history: Arc<RwLock<History>>,
+    station_name: String,
+    community: String,
+    /// Snow depth is measured from the baseline once one is set
+    baseline: watch::Receiver<Option<Baseline>>,
+    /// Sensor height above bare ground in mm, used without a baseline
+    sensor_height: Option<f64>,
+    start_time: Instant,
+}
+
+impl SnmpAgent {
+    pub fn new(
+        socket: UdpSocket,
+        history: Arc<RwLock<History>>,
+        station_name: String,
+        community: String,
+        baseline: watch::Receiver<Option<Baseline>>,
+        sensor_height: Option<f64>,
+    ) -> Self {
+        Self {
+            socket,
+            history,
+            station_name,
+            community,
+            baseline,
+            sensor_height,
+            start_time: Instant::now(),
+        }
+    }
+
+    /// The baseline distance, or `--snmp-sensor-height` without one
+    fn sensor_height(&self) -> Option<f64> {
+        self.baseline.borrow().map(|b| b.distance).or(self.sensor_height)
+    }
+
+    /// Answer requests until shutdown
+    pub async fn run(self, cancel_token: CancellationToken) {
+        let mut buf = [0u8; 1500];
+
+        loop {
+            tokio::select! {
+                _ = cancel_token.cancelled() => {
+                    info!("SNMP agent received shutdown signal");
+                    break;
+                }
+                received = self.socket.recv_from(&mut buf) => {
+                    let (n, addr) = match received {
+                        Ok(received) => received,
+                        Err(e) => {
+                            warn!("Error receiving SNMP datagram: {}", e);
+                            continue;
+                        }
+                    };
+                    let request = match Message::parse(&buf[..n]) {
+                        Ok(request) => request,
+                        Err(e) => {
+                            debug!("Ignoring malformed SNMP message from {}: {}", addr, e);
+                            continue;
+                        }
+                    };
+
+                    let distance = self
+                        .history
+                        .read()
+                        .await
+                        .latest()
+                        .filter(|r| !r.invalid)
+                        .map(|r| r.distance);
+                    let view = mib(
+                        &self.station_name,
+                        self.start_time.elapsed(),
+                        snow_depth_cm(self.sensor_height(), distance),
+                    );
+
+                    match respond(&request, self.community.as_bytes(), &view) {
+                        Some(response) => {
+                            if let Err(e) = self.socket.send_to(&response.encode(), addr).await {
+                                warn!("Error sending SNMP response to {}: {}", addr, e);
+                            }
+                        }
+                        None => debug!("Dropping SNMP request from {} (bad community, version, or type)", addr),
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(version: i64, pdu_type: u8, oids: &[&[u32]]) -> Message {
+        Message {
+            version,
+            community: b"public".to_vec(),
+            pdu: Pdu {
+                pdu_type,
+                request_id: 42,
+                error_status: 0,
+                error_index: 0,
+                varbinds: oids.iter().map(|oid| (oid.to_vec(), Value::Null)).collect(),
+            },
+        }
+    }
+
+    fn test_mib() -> Vec<(Vec<u32>, Value)> {
+        mib("teststation", Duration::from_secs(12), 25)
+    }
+
+    #[test]
+    fn test_parse_get_request() {
+        // snmpget -v2c -c public host 1.3.6.1.2.1.1.5.0
+        let buf = [
+            0x30, 0x26, 0x02, 0x01, 0x01, 0x04, 0x06, b'p', b'u', b'b', b'l', b'i', b'c', 0xA0, 0x19, 0x02, 0x01,
+            0x2A, 0x02, 0x01, 0x00, 0x02, 0x01, 0x00, 0x30, 0x0E, 0x30, 0x0C, 0x06, 0x08, 0x2B, 0x06, 0x01, 0x02,
+            0x01, 0x01, 0x05, 0x00, 0x05, 0x00,
+        ];
+        let message = Message::parse(&buf).unwrap();
+        assert_eq!(message, request(VERSION_2C, tag::GET_REQUEST, &[SYS_NAME]));
+        assert_eq!(message.encode(), buf.to_vec());
+    }
+
+    #[test]
+    fn test_encode_roundtrip() {
+        let mut message = request(VERSION_1, tag::RESPONSE, &[]);
+        message.pdu.request_id = -300;
+        message.pdu.varbinds = vec![
+            (ESS_ADJACENT_SNOW_DEPTH.to_vec(), Value::Integer(3001)),
+            (SYS_OBJECT_ID.to_vec(), Value::ObjectId(ESS.to_vec())),
+            (SYS_UPTIME.to_vec(), Value::TimeTicks(u32::MAX)),
+            // Long-form length
+            (SYS_DESCR.to_vec(), Value::OctetString(vec![b'x'; 200])),
+            (SYS_NAME.to_vec(), Value::EndOfMibView),
+        ];
+        assert_eq!(Message::parse(&message.encode()).unwrap(), message);
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed() {
+        assert!(Message::parse(&[]).is_err());
+        assert!(Message::parse(&[0x30, 0x05, 0x02, 0x01]).is_err());
+        // Version is not an integer
+        assert!(Message::parse(&[0x30, 0x03, 0x04, 0x01, 0x00]).is_err());
+        assert!(decode_oid(&[0x2B, 0x86]).is_err());
+    }
+
+    #[test]
+    fn test_integer_and_oid_encoding() {
+        assert_eq!(encode_integer(0), vec![0x00]);
+        assert_eq!(encode_integer(127), vec![0x7F]);
+        assert_eq!(encode_integer(128), vec![0x00, 0x80]);
+        assert_eq!(encode_integer(-1), vec![0xFF]);
+        assert_eq!(encode_integer(-129), vec![0xFF, 0x7F]);
+        for v in [0, 1, -1, 255, -256, 3001, i32::MAX as i64, u32::MAX as i64] {
+            assert_eq!(decode_integer(&encode_integer(v)).unwrap(), v);
+        }
+
+        assert_eq!(encode_oid(ESS), vec![0x2B, 0x06, 0x01, 0x04, 0x01, 0x89, 0x36, 0x04, 0x02, 0x05]);
+        assert_eq!(decode_oid(&encode_oid(ESS_ADJACENT_SNOW_DEPTH)).unwrap(), ESS_ADJACENT_SNOW_DEPTH);
+    }
+
+    #[test]
+    fn test_snow_depth() {
+        assert_eq!(snow_depth_cm(Some(2000.0), Some(1500.0)), 50);
+        assert_eq!(snow_depth_cm(Some(2000.0), Some(2100.0)), 0);
+        assert_eq!(snow_depth_cm(None, Some(1500.0)), SNOW_DEPTH_MISSING);
+        assert_eq!(snow_depth_cm(Some(2000.0), None), SNOW_DEPTH_MISSING);
+    }
+
+    #[test]
+    fn test_get() {
+        let mib = test_mib();
+        let response = respond(&request(VERSION_2C, tag::GET_REQUEST, &[ESS_ADJACENT_SNOW_DEPTH, &[1, 3, 6, 1, 2, 1, 1, 5, 1], &[1, 3, 9]]), b"public", &mib).unwrap();
+        assert_eq!(response.pdu.pdu_type, tag::RESPONSE);
+        assert_eq!(response.pdu.request_id, 42);
+        assert_eq!(response.pdu.error_status, 0);
+        assert_eq!(response.pdu.varbinds[0].1, Value::Integer(25));
+        assert_eq!(response.pdu.varbinds[1].1, Value::NoSuchInstance);
+        assert_eq!(response.pdu.varbinds[2].1, Value::NoSuchObject);
+
+        // SNMPv1 reports a missing object as noSuchName with its index
+        let response = respond(&request(VERSION_1, tag::GET_REQUEST, &[SYS_NAME, &[1, 3, 9]]), b"public", &mib).unwrap();
+        assert_eq!((response.pdu.error_status, response.pdu.error_index), (NO_SUCH_NAME, 2));
+
+        // Wrong community is dropped
+        assert!(respond(&request(VERSION_2C, tag::GET_REQUEST, &[SYS_NAME]), b"private", &mib).is_none());
+    }
+
+    #[test]
+    fn test_get_next_walks_mib() {
+        let mib = test_mib();
+        let mut oid = vec![1, 3, 6];
+        let mut walked = Vec::new();
+        loop {
+            let response = respond(&request(VERSION_2C, tag::GET_NEXT_REQUEST, &[&oid]), b"public", &mib).unwrap();
+            let (next, value) = response.pdu.varbinds[0].clone();
+            if value == Value::EndOfMibView {
+                break;
+            }
+            walked.push(next.clone());
+            oid = next;
+        }
+        assert_eq!(walked, mib.iter().map(|(o, _)| o.clone()).collect::<Vec<_>>());
+
+        let response = respond(&request(VERSION_1, tag::GET_NEXT_REQUEST, &[ESS_ADJACENT_SNOW_DEPTH]), b"public", &mib).unwrap();
+        assert_eq!(response.pdu.error_status, NO_SUCH_NAME);
+    }
+
+    #[test]
+    fn test_get_bulk() {
+        let mib = test_mib();
+        let mut bulk = request(VERSION_2C, tag::GET_BULK_REQUEST, &[&[1, 3, 6, 1, 2, 1, 1], &[1, 3, 6, 1, 2, 1, 1, 3]]);
+        bulk.pdu.error_status = 1; // non-repeaters
+        bulk.pdu.error_index = 10; // max-repetitions
+
+        let response = respond(&bulk, b"public", &mib).unwrap();
+        let oids: Vec<&[u32]> = response.pdu.varbinds.iter().map(|(o, _)| o.as_slice()).collect();
+        assert_eq!(oids, vec![SYS_DESCR, SYS_UPTIME, SYS_NAME, ESS_ADJACENT_SNOW_DEPTH, ESS_ADJACENT_SNOW_DEPTH]);
+        assert_eq!(response.pdu.varbinds.last().unwrap().1, Value::EndOfMibView);
+
+        // GetBulk does not exist in SNMPv1
+        bulk.version = VERSION_1;
+        assert!(respond(&bulk, b"public", &mib).is_none());
+    }
+
+    #[test]
+    fn test_set_rejected() {
+        let mib = test_mib();
+        let response = respond(&request(VERSION_2C, tag::SET_REQUEST, &[SYS_NAME]), b"public", &mib).unwrap();
+        assert_eq!((response.pdu.error_status, response.pdu.error_index), (NOT_WRITABLE, 1));
+    }
+}
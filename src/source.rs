@@ -0,0 +1,75 @@
+/// Where distances are read from
+///
+/// Serial sensors stream frames on their own, read by the serial reader.
+/// Other sensors are polled as a `Rangefinder` at the poll interval, each
+/// poll ranging on request or waiting for the sensor's next reading, and
+/// their readings go through the same pipeline.
+use std::io;
+
+use crate::frame::Frame;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SensorSource {
+    /// Frames on a serial port or serial server
+    Serial,
+    /// An I2CXL-MaxSonar on an I2C bus
+    I2cxl,
+    /// A Garmin LIDAR-Lite on an I2C bus
+    LidarLite,
+    /// A level sensor polled over Modbus RTU on the serial port
+    Modbus,
+    /// A sensor's pulse-width output on a GPIO line
+    Gpio,
+    /// Distances another device publishes to an MQTT topic
+    Mqtt,
+}
+
+impl std::str::FromStr for SensorSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "serial" => Ok(SensorSource::Serial),
+            "i2cxl" => Ok(SensorSource::I2cxl),
+            "lidar-lite" => Ok(SensorSource::LidarLite),
+            "modbus" => Ok(SensorSource::Modbus),
+            "gpio" => Ok(SensorSource::Gpio),
+            "mqtt" => Ok(SensorSource::Mqtt),
+            _ => Err(format!("Invalid source '{}'. Valid options: serial, i2cxl, lidar-lite, modbus, gpio, mqtt", s)),
+        }
+    }
+}
+
+impl SensorSource {
+    /// Typical standard deviation of a single reading in mm, which weights
+    /// the sensor in fusion unless `--sensor-noise` is given. The cm sensors
+    /// are the coarser; readings of unknown devices are taken as middling.
+    pub fn noise(self) -> f64 {
+        match self {
+            SensorSource::Serial | SensorSource::Gpio => 2.0,
+            SensorSource::I2cxl | SensorSource::Modbus | SensorSource::Mqtt => 5.0,
+            SensorSource::LidarLite => 10.0,
+        }
+    }
+}
+
+impl std::fmt::Display for SensorSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SensorSource::Serial => write!(f, "serial"),
+            SensorSource::I2cxl => write!(f, "i2cxl"),
+            SensorSource::LidarLite => write!(f, "lidar-lite"),
+            SensorSource::Modbus => write!(f, "modbus"),
+            SensorSource::Gpio => write!(f, "gpio"),
+            SensorSource::Mqtt => write!(f, "mqtt"),
+        }
+    }
+}
+
+/// A sensor that ranges on request
+pub trait Rangefinder: Send {
+    /// Take a reading, returning the bytes read and the distance in mm; a
+    /// sensor whose readings arrive on their own returns `WouldBlock` when
+    /// none has yet, so the reader can check in
+    fn range(&mut self) -> io::Result<Frame>;
+}
@@ -0,0 +1,594 @@
+/// Validating a station's configuration and starting its tasks
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use log::{error, info};
+use tokio::sync::{mpsc, watch};
+use tokio_util::sync::CancellationToken;
+
+use crate::anomaly::AnomalyDetector;
+use crate::args::Args;
+use crate::baseline::Baseline;
+use crate::board::Board;
+use crate::calibration::{self, CalibrationCurve};
+use crate::compensation::{self, CompensationConfig};
+use crate::config;
+use crate::events::StormDetector;
+use crate::gpio::PulseWidth;
+use crate::history::History;
+use crate::hooks::Hooks;
+use crate::i2c::{I2cxl, LidarLite};
+use crate::interpolate::Interpolation;
+use crate::modbus::ModbusSensor;
+use crate::mqtt::MqttSubscriber;
+use crate::offset::Offset;
+use crate::pipeline::FilterConfig;
+use crate::preset::Preset;
+use crate::quality::QualityChecks;
+use crate::ratelimit::RateLimiter;
+use crate::schedule::Schedule;
+use crate::service::{Processing, SnowGaugeServiceImpl, StationState};
+use crate::settling::SettlingDetector;
+use crate::snowgauge::StationMetadata;
+use crate::source::{Rangefinder, SensorSource};
+use crate::stream::StreamConfig;
+use crate::supervisor::Supervisor;
+use crate::swe::DensityModel;
+use crate::totals::{Date, Totals};
+use crate::trend::DirectionHysteresis;
+use crate::wind::WindConfig;
+
+/// Log the parameters of a filter configuration
+pub fn log_filter_config(config: &FilterConfig) {
+    match config.pipeline {
+        Some(ref pipeline) => info!("  Filter pipeline: {}", pipeline),
+        None => info!("  Filter type: {} ({})", config.filter_type, config.stages()),
+    }
+    for stage in config.stages().iter() {
+        info!("    - {}", stage.describe(config));
+    }
+    if config.dead_band > 0.0 {
+        info!("  Dead-band: {} mm", config.dead_band);
+    }
+}
+
+/// The production filter preset: the preset file if there is one, or the
+/// filter options otherwise
+pub fn filter_preset(args: &Args) -> Result<Preset, String> {
+    let mut preset = match args.filter_preset {
+        Some(ref path) => Preset::load(path)?,
+        None => Preset::new(
+            args.station_name.clone(),
+            FilterConfig {
+                filter_type: args.filter_type,
+                pipeline: args.filter_pipeline.clone(),
+                init_period: args.filter_init_period,
+                rate_limit: args.filter_rate_limit,
+                alpha: args.filter_alpha,
+                adaptive_noise: args.filter_adaptive_noise,
+                alpha_min: args.filter_alpha_min,
+                trim_percentage: args.trim_percentage,
+                batch_size: args.batch_size,
+                batch_step: args.batch_step,
+                dead_band: args.dead_band,
+                process_noise: args.kalman_process_noise,
+                measurement_noise: args.kalman_measurement_noise,
+                median_window: args.median_window,
+                mode_bin_width: args.mode_bin_width,
+                trend_window: args.trend_window,
+                despike_threshold: args.despike_threshold,
+                despike_window: args.despike_window,
+                min_distance: args.min_distance,
+                max_distance: args.max_distance,
+            },
+        ),
+    };
+    if let Some(ref name) = args.filter_preset_name {
+        preset.name = name.clone();
+    }
+    Ok(preset)
+}
+
+/// A station's service, with the supervisors of the tasks reading its
+/// sensor and processing its readings
+pub struct Station {
+    pub service: Arc<SnowGaugeServiceImpl>,
+    pub data_source_task: tokio::task::JoinHandle<()>,
+    pub processing_task: tokio::task::JoinHandle<()>,
+}
+
+/// Validate a station's configuration and start reading its sensor; a
+/// station other than the command line's shares the `primary` station's
+/// reading streams, and its tasks are named after it
+pub fn start_station(
+    args: &Args,
+    settings: Vec<config::Setting>,
+    primary: Option<&SnowGaugeServiceImpl>,
+    hooks: &Hooks,
+    supervisor: &Supervisor,
+    cancel_token: &CancellationToken,
+) -> Result<Station, Box<dyn std::error::Error>> {
+    let task = |name: &str| match primary {
+        Some(_) => format!("{} for {}", name, args.station_name),
+        None => name.to_string(),
+    };
+
+    let preset = match filter_preset(args) {
+        Ok(preset) => preset,
+        Err(e) => {
+            error!("{}", e);
+            return Err(e.into());
+        }
+    };
+    let filter_config = &preset.config;
+
+    // Candidate parameters default to the production values, so only the
+    // settings under evaluation need to be given
+    let comparing = args.compare_filter_type.is_some() || args.compare_filter_pipeline.is_some();
+    let compare_config = comparing.then(|| FilterConfig {
+        filter_type: args.compare_filter_type.unwrap_or(filter_config.filter_type),
+        pipeline: args.compare_filter_pipeline.clone(),
+        init_period: args.compare_filter_init_period.unwrap_or(filter_config.init_period),
+        rate_limit: args.compare_filter_rate_limit.unwrap_or(filter_config.rate_limit),
+        alpha: args.compare_filter_alpha.unwrap_or(filter_config.alpha),
+        adaptive_noise: args.compare_filter_adaptive_noise.unwrap_or(filter_config.adaptive_noise),
+        alpha_min: args.compare_filter_alpha_min.unwrap_or(filter_config.alpha_min),
+        trim_percentage: args.compare_trim_percentage.unwrap_or(filter_config.trim_percentage),
+        batch_size: args.compare_batch_size.unwrap_or(filter_config.batch_size),
+        batch_step: args.compare_batch_step.unwrap_or(filter_config.batch_step),
+        dead_band: args.compare_dead_band.unwrap_or(filter_config.dead_band),
+        process_noise: args.compare_kalman_process_noise.unwrap_or(filter_config.process_noise),
+        measurement_noise: args.compare_kalman_measurement_noise.unwrap_or(filter_config.measurement_noise),
+        median_window: args.compare_median_window.unwrap_or(filter_config.median_window),
+        mode_bin_width: args.compare_mode_bin_width.unwrap_or(filter_config.mode_bin_width),
+        trend_window: args.compare_trend_window.unwrap_or(filter_config.trend_window),
+        despike_threshold: args.compare_despike_threshold.unwrap_or(filter_config.despike_threshold),
+        despike_window: args.compare_despike_window.unwrap_or(filter_config.despike_window),
+        // The valid range is the sensor's, not the filter's
+        min_distance: filter_config.min_distance,
+        max_distance: filter_config.max_distance,
+    });
+
+    if !(0.0..=0.5).contains(&args.wind_trim_percentage) {
+        let e = format!("wind-trim-percentage must be between 0.0 and 0.5, got {}", args.wind_trim_percentage);
+        error!("{}", e);
+        return Err(e.into());
+    }
+
+    let quality_checks = QualityChecks {
+        min_distance: args.sensor_min_distance,
+        max_distance: args.sensor_max_distance,
+        variance_threshold: args.variance_threshold,
+        stuck_readings: args.stuck_readings,
+        target_lost_readings: args.target_lost_readings,
+    };
+
+    // Validate parameters
+    for config in std::iter::once(filter_config).chain(compare_config.as_ref()) {
+        if let Err(e) = config.validate() {
+            error!("{}", e);
+            return Err(e.into());
+        }
+    }
+    if let Err(e) = quality_checks.validate() {
+        error!("{}", e);
+        return Err(e.into());
+    }
+    let metadata = match station_metadata(args) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            error!("{}", e);
+            return Err(e.into());
+        }
+    };
+
+    let saved_baseline = args.baseline_file.as_deref().filter(|path| path.exists());
+    let baseline = match (saved_baseline, args.baseline) {
+        (Some(path), _) => Some(Baseline::load(path)),
+        (None, Some(distance)) => Some(Baseline::new(distance, SystemTime::now())),
+        (None, None) => None,
+    };
+    let baseline = match baseline.transpose() {
+        Ok(baseline) => baseline,
+        Err(e) => {
+            error!("{}", e);
+            return Err(e.into());
+        }
+    };
+    if !(0.0..=calibration::MAX_MOUNT_ANGLE).contains(&args.mount_angle_deg) {
+        let e = format!("--mount-angle-deg must be between 0 and {}, got {}", calibration::MAX_MOUNT_ANGLE, args.mount_angle_deg);
+        error!("{}", e);
+        return Err(e.into());
+    }
+    if args.snow_density == Some(DensityModel::Temperature) && args.temperature_source.is_none() {
+        let e = "--snow-density temperature needs a --temperature-source";
+        error!("{}", e);
+        return Err(e.into());
+    }
+    let saved_offset = match args.offset_file.as_deref().filter(|path| path.exists()).map(Offset::load).transpose() {
+        Ok(offset) => offset,
+        Err(e) => {
+            error!("{}", e);
+            return Err(e.into());
+        }
+    };
+    let board = match Board::new((args.board_clear_interval > 0).then(|| Duration::from_secs(args.board_clear_interval * 3600))) {
+        Ok(board) => board,
+        Err(e) => {
+            error!("{}", e);
+            return Err(e.into());
+        }
+    };
+    let frame_format = match args.frame_format() {
+        Ok(format) => format,
+        Err(e) => {
+            error!("{}", e);
+            return Err(e.into());
+        }
+    };
+    if args.source == SensorSource::Gpio && args.gpio_line.is_none() {
+        let e = "--source gpio needs --gpio-line".to_string();
+        error!("{}", e);
+        return Err(e.into());
+    }
+    if args.source == SensorSource::Mqtt && (args.mqtt_broker.is_none() || args.mqtt_topic.is_none()) {
+        let e = "--source mqtt needs --mqtt-broker and --mqtt-topic".to_string();
+        error!("{}", e);
+        return Err(e.into());
+    }
+    if args.sensor_noise() <= 0.0 {
+        let e = format!("--sensor-noise must be positive, got {}", args.sensor_noise());
+        error!("{}", e);
+        return Err(e.into());
+    }
+    if !(1..=12).contains(&args.season_start_month) {
+        let e = format!("--season-start-month must be between 1 and 12, got {}", args.season_start_month);
+        error!("{}", e);
+        return Err(e.into());
+    }
+    let saved_totals = args.totals_file.as_deref().filter(|path| path.exists());
+    let mut totals = match saved_totals.map(|path| Totals::load(path, args.season_start_month)).transpose() {
+        Ok(totals) => totals.unwrap_or_else(|| Totals::new(args.season_start_month)),
+        Err(e) => {
+            error!("{}", e);
+            return Err(e.into());
+        }
+    };
+    totals.set_interpolation(args.derived_interpolation, Duration::from_secs(args.derived_max_gap));
+    let calibration = match args.calibration_file.as_deref().map(CalibrationCurve::load).transpose() {
+        Ok(calibration) => calibration,
+        Err(e) => {
+            error!("{}", e);
+            return Err(e.into());
+        }
+    };
+
+    info!("Configuration:");
+    info!("  Station name: {}", args.station_name);
+    if let (Some(latitude), Some(longitude)) = (args.latitude, args.longitude) {
+        info!("  Location: {}, {}", latitude, longitude);
+    }
+    if let Some(elevation) = args.elevation {
+        info!("  Elevation: {}m", elevation);
+    }
+    info!("  Reading unit: {}", args.unit);
+    if args.snowfall_rate_window > 0 {
+        info!("  Snowfall rate window: {}s", args.snowfall_rate_window);
+        if args.storm_rate_threshold > 0.0 {
+            info!("  Storm rate threshold: {}mm/hr", args.storm_rate_threshold);
+        }
+        if args.settling_duration > 0 {
+            info!("  Settling and melt: depth loss of {}-{}mm/hr for {}s", args.settling_min_rate,
+                  args.settling_max_rate, args.settling_duration);
+        }
+    }
+    if let Some(ref source) = args.battery_voltage {
+        info!("  Battery voltage: {}", source);
+    }
+    if let Some(ref source) = args.temperature_source {
+        info!("  Speed of sound correction: temperature from {}, humidity {}, true at {}°C",
+              source,
+              args.humidity_source.as_ref().map_or(format!("{}% assumed", compensation::REFERENCE_HUMIDITY),
+                                                   |h| format!("from {}", h)),
+              args.sound_reference_temperature);
+    }
+    if let Some(model) = args.snow_density {
+        info!("  Snow density: {}", model);
+    }
+    if args.board_clear_interval > 0 {
+        info!("  Board clearing: every {}h from midnight", args.board_clear_interval);
+    }
+    let today = Date::local(SystemTime::now());
+    info!("  Season: from {}, {:.1}mm so far{}", totals.season_start(today), totals.season(today),
+          saved_totals.map(|path| format!(" (from {})", path.display())).unwrap_or_default());
+    if let (Some(ref curve), Some(ref path)) = (&calibration, &args.calibration_file) {
+        info!("  Calibration: {} points from {}", curve.point_count(), path.display());
+    }
+    if args.mount_angle_deg != 0.0 {
+        info!("  Mounting angle: {}° from vertical", args.mount_angle_deg);
+    }
+    if args.interpolate_gaps {
+        info!("  Gap interpolation: up to {}s", args.interpolate_max_gap);
+    }
+    if args.derived_interpolation != Interpolation::None {
+        info!("  Derived gap interpolation: {}, up to {}s", args.derived_interpolation, args.derived_max_gap);
+    }
+    if !args.fuse_with.is_empty() {
+        info!("  Fusion: with {}, {}mm sensor noise, results up to {}s old",
+              args.fuse_with.join(", "), args.sensor_noise(), args.fusion_max_age);
+    }
+    if let Some(ref source) = args.wind_speed_source {
+        info!("  Wind speed: {}, high wind from {}m/s, trimming {}% from each end",
+              source, args.wind_speed_threshold, args.wind_trim_percentage * 100.0);
+    }
+    match (baseline, saved_baseline) {
+        (Some(baseline), Some(path)) => info!("  Baseline: {:.1}mm (from {})", baseline.distance, path.display()),
+        (Some(baseline), None) => info!("  Baseline: {:.1}mm", baseline.distance),
+        (None, _) => info!("  Baseline: not set"),
+    }
+    if let Some(ref offset) = saved_offset {
+        info!("  Offset: {:+.1}mm{}", offset.distance,
+              if offset.reason.is_empty() { String::new() } else { format!(" ({})", offset.reason) });
+    }
+    match args.filter_preset {
+        Some(ref path) => info!("  Filter preset: '{}' (from {})", preset.name, path.display()),
+        None => info!("  Filter preset: '{}'", preset.name),
+    }
+    log_filter_config(filter_config);
+
+    if let Some(ref config) = compare_config {
+        info!("  Filter comparison enabled, candidate configuration:");
+        log_filter_config(config);
+    }
+
+    let schedule = match args.schedule {
+        Some(ref spec) => match Schedule::parse(spec, Duration::from_secs(args.schedule_interval)) {
+            Ok(schedule) => {
+                info!("  Measurement schedule: {}", schedule);
+                Some(schedule)
+            }
+            Err(e) => {
+                error!("{}", e);
+                return Err(e.into());
+            }
+        },
+        None => None,
+    };
+
+    let mut history = History::new(args.history_size, Duration::from_secs(args.gap_threshold));
+    if let Some(path) = &args.history_file {
+        if let Err(e) = history.open_file(path) {
+            error!("{}", e);
+            return Err(e.into());
+        }
+        info!("  History file: {} ({} readings)", path.display(), history.query(None, None).len());
+    }
+    history.start_tracking(SystemTime::now());
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    let (sensor_power_tx, sensor_power_rx) = watch::channel(true);
+
+    let mut service = SnowGaugeServiceImpl::new(
+        args.station_name.clone(),
+        metadata,
+        (!args.simulator).then(|| args.sensor_port()),
+        StreamConfig {
+            heartbeat_timeout: Duration::from_secs(args.heartbeat_timeout),
+            replay_buffer: args.replay_buffer,
+            max_clients: args.max_clients,
+            queue_size: args.client_queue_size,
+            overflow_policy: args.slow_client_policy,
+            default_unit: args.unit,
+        },
+        settings,
+        StationState {
+            healthy: supervisor.health(),
+            rate_limiter: (args.rpc_rate_limit > 0.0).then(|| RateLimiter::new(args.rpc_rate_limit, args.rpc_burst)),
+            preset,
+            preset_path: args.filter_preset.clone(),
+            baseline,
+            baseline_path: args.baseline_file.clone(),
+            offset: saved_offset,
+            offset_path: args.offset_file.clone(),
+            snow_density: args.snow_density,
+            board,
+            totals,
+            totals_path: args.totals_file.clone(),
+            history,
+        },
+        Processing {
+            compare_config,
+            schedule,
+            anomaly_detector: (args.anomaly_threshold > 0.0).then(|| AnomalyDetector::new(args.anomaly_window, args.anomaly_threshold)),
+            quality_checks,
+            battery_voltage: args.battery_voltage.clone(),
+            compensation: args.temperature_source.clone().map(|temperature| CompensationConfig {
+                temperature,
+                humidity: args.humidity_source.clone(),
+                reference_temperature: args.sound_reference_temperature,
+            }),
+            calibration,
+            mount_angle: args.mount_angle_deg,
+            wind: args.wind_speed_source.clone().map(|source| WindConfig {
+                source,
+                threshold: args.wind_speed_threshold,
+                trim_percentage: args.wind_trim_percentage,
+            }),
+            interpolate_max_gap: args.interpolate_gaps.then(|| Duration::from_secs(args.interpolate_max_gap)),
+            snowfall_rate_window: (args.snowfall_rate_window > 0).then(|| Duration::from_secs(args.snowfall_rate_window)),
+            storm_detector: (args.storm_rate_threshold > 0.0).then(|| StormDetector::new(args.storm_rate_threshold)),
+            direction_hysteresis: (args.direction_hysteresis > 0.0).then(|| DirectionHysteresis::new(args.direction_hysteresis)),
+            settling_detector: (args.settling_duration > 0).then(|| {
+                SettlingDetector::new(Duration::from_secs(args.settling_duration), args.settling_min_rate, args.settling_max_rate)
+            }),
+            sensor_noise: args.sensor_noise(),
+            fuse_with: args.fuse_with.clone(),
+            fusion_max_age: Duration::from_secs(args.fusion_max_age),
+            hooks: hooks.pipeline().clone(),
+        },
+    );
+    // Readings from every station go out on the same streams, and a
+    // client's calls count against one rate limit whichever station they are for
+    if let Some(primary) = primary {
+        service.clients = primary.clients.clone();
+        service.rate_limiter = primary.rate_limiter.clone();
+    }
+    let service = Arc::new(service);
+
+    // Start the processing task; the receiver is shared so a restarted
+    // processor picks up where the crashed one left off
+    let rx = Arc::new(tokio::sync::Mutex::new(rx));
+    let sensor_power_tx = Arc::new(sensor_power_tx);
+    let processing_task = {
+        let service = Arc::clone(&service);
+        let log_distance = args.log;
+        supervisor.spawn(task("processor"), move || {
+            let service = Arc::clone(&service);
+            let rx = Arc::clone(&rx);
+            let sensor_power_tx = Arc::clone(&sensor_power_tx);
+            async move {
+                let mut rx = rx.lock().await;
+                service
+                    .process_readings(&mut rx, log_distance, &sensor_power_tx)
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+        })
+    };
+
+    // Start serial reader or simulator
+    let data_source_task = if args.simulator {
+        let base_distance = args.simulator_base_distance;
+        let log_distance = args.log;
+        let cancel_token = cancel_token.clone();
+        supervisor.spawn(task("simulator"), move || {
+            let tx = tx.clone();
+            let cancel_token = cancel_token.clone();
+            async move {
+                SnowGaugeServiceImpl::simulator(base_distance, tx, log_distance, cancel_token)
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+        })
+    } else if args.source != SensorSource::Serial {
+        let name = args.sensor_port();
+        let source = args.source;
+        let bus = args.i2c_bus.clone();
+        let address = args.i2c_address();
+        let port = args.port.clone();
+        let serial = args.serial();
+        let modbus = args.modbus();
+        let chip = args.gpio_chip.clone();
+        let line = args.gpio_line.unwrap_or_default();
+        let us_per_mm = args.pulse_us_per_mm;
+        let (lidar_mode, lidar_bias_interval) = (args.lidar_mode, args.lidar_bias_interval);
+        let mqtt = args.mqtt();
+        // Messages are read as they arrive
+        let interval = match source {
+            SensorSource::Mqtt => Duration::ZERO,
+            _ => Duration::from_millis(args.poll_interval),
+        };
+        let log_distance = args.log;
+        let release_port_rx = service.release_port.subscribe();
+        let raw_frames = service.raw_frame_channels.clone();
+        let frame_counters = service.frames.clone();
+        let events = service.events.clone();
+        let cancel_token = cancel_token.clone();
+        supervisor.spawn(task("sensor reader"), move || {
+            let name = name.clone();
+            let bus = bus.clone();
+            let port = port.clone();
+            let chip = chip.clone();
+            let mqtt = mqtt.clone();
+            let tx = tx.clone();
+            let sensor_power_rx = sensor_power_rx.clone();
+            let release_port_rx = release_port_rx.clone();
+            let raw_frames = raw_frames.clone();
+            let frame_counters = frame_counters.clone();
+            let events = events.clone();
+            let cancel_token = cancel_token.clone();
+            let open = move || -> std::io::Result<Box<dyn Rangefinder>> {
+                match source {
+                    SensorSource::Modbus => Ok(Box::new(ModbusSensor::new(serial.open(&port, Duration::from_secs(1))?, modbus))),
+                    SensorSource::Gpio => Ok(Box::new(PulseWidth::open(&chip, line, us_per_mm)?)),
+                    SensorSource::LidarLite => Ok(Box::new(LidarLite::open(&bus, address, lidar_mode, lidar_bias_interval)?)),
+                    SensorSource::Mqtt => Ok(Box::new(MqttSubscriber::connect(&mqtt)?)),
+                    _ => Ok(Box::new(I2cxl::open(&bus, address)?)),
+                }
+            };
+            async move {
+                SnowGaugeServiceImpl::polled_reader(
+                    name, open, interval, tx, log_distance, sensor_power_rx, release_port_rx, raw_frames, frame_counters, events,
+                    cancel_token,
+                )
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+        })
+    } else {
+        let port_name = args.port.clone();
+        let serial = args.serial();
+        let format = frame_format.clone();
+        let checksum = args.frame_checksum;
+        let log_distance = args.log;
+        let power_line = args.sensor_power_line;
+        let release_port_rx = service.release_port.subscribe();
+        let raw_frames = service.raw_frame_channels.clone();
+        let frame_counters = service.frames.clone();
+        let events = service.events.clone();
+        let cancel_token = cancel_token.clone();
+        supervisor.spawn(task("serial reader"), move || {
+            let port_name = port_name.clone();
+            let format = format.clone();
+            let tx = tx.clone();
+            let sensor_power_rx = sensor_power_rx.clone();
+            let release_port_rx = release_port_rx.clone();
+            let raw_frames = raw_frames.clone();
+            let frame_counters = frame_counters.clone();
+            let events = events.clone();
+            let cancel_token = cancel_token.clone();
+            async move {
+                SnowGaugeServiceImpl::serial_reader(
+                    port_name, serial, format, checksum, tx, log_distance, power_line, sensor_power_rx, release_port_rx, raw_frames,
+                    frame_counters, events, cancel_token,
+                )
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+        })
+    };
+
+    if args.simulator {
+        info!("Started simulator with base_distance={}", args.simulator_base_distance);
+    } else if args.source == SensorSource::Mqtt {
+        info!("Started mqtt sensor reader on {}", args.sensor_port());
+    } else if args.source != SensorSource::Serial {
+        info!("Started {} sensor reader on {}, polling every {}ms", args.source, args.sensor_port(), args.poll_interval);
+    } else {
+        info!("Started serial reader on port {} at {}, {} frames, {} checksum", args.port, args.serial(), frame_format, args.frame_checksum);
+    }
+
+    Ok(Station { service, data_source_task, processing_task })
+}
+
+
+/// Station metadata from the command line, checking coordinates are in range
+fn station_metadata(args: &Args) -> Result<StationMetadata, String> {
+    let in_range = |name: &str, value: Option<f64>, limit: f64| match value {
+        Some(v) if !(-limit..=limit).contains(&v) => {
+            Err(format!("{} must be between -{} and {} degrees, got {}", name, limit, limit, v))
+        }
+        _ => Ok(()),
+    };
+    in_range("latitude", args.latitude, 90.0)?;
+    in_range("longitude", args.longitude, 180.0)?;
+    if args.elevation.is_some_and(|e| !e.is_finite()) {
+        return Err("elevation must be a number of meters".to_string());
+    }
+    Ok(StationMetadata {
+        latitude: args.latitude,
+        longitude: args.longitude,
+        elevation: args.elevation,
+        description: args.station_description.clone(),
+    })
+}
@@ -0,0 +1,242 @@
+/// More stations served by the same daemon
+///
+/// A gateway with several gauges runs them in one process: `--stations`
+/// names a JSON file listing the stations besides the command line's own,
+/// each with its name, sensor, and filter preset, and each takes every
+/// other option from the command line. The stations share the gRPC
+/// listeners and one set of reading streams; every reading carries its
+/// station's name, which subscribers select by. Other calls go to the
+/// station named in the request's `station` metadata, or to the command
+/// line's station without it.
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use serde::{Deserialize, Deserializer};
+use tonic::body::BoxBody;
+use tonic::server::NamedService;
+use tonic::Status;
+use tower_service::Service;
+
+use crate::i2c;
+use crate::source::SensorSource;
+
+/// Request metadata naming the station a call is for
+pub const STATION_METADATA: &str = "station";
+
+/// A station from the stations file
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct StationSpec {
+    pub station_name: String,
+    // The sensor, defaulting to the command line's options
+    #[serde(default, deserialize_with = "parsed")]
+    pub source: Option<SensorSource>,
+    #[serde(default)]
+    pub port: Option<String>,
+    #[serde(default)]
+    pub i2c_bus: Option<PathBuf>,
+    #[serde(default, deserialize_with = "i2c_address")]
+    pub i2c_address: Option<u16>,
+    #[serde(default)]
+    pub modbus_unit: Option<u8>,
+    #[serde(default)]
+    pub gpio_chip: Option<PathBuf>,
+    #[serde(default)]
+    pub gpio_line: Option<u32>,
+    #[serde(default)]
+    pub mqtt_broker: Option<String>,
+    #[serde(default)]
+    pub mqtt_topic: Option<String>,
+    #[serde(default)]
+    pub mqtt_field: Option<String>,
+    /// Stations fused with this one, as with `--fuse-with`; none unless given
+    #[serde(default)]
+    pub fuse_with: Vec<String>,
+    /// The sensor's noise, as with `--sensor-noise`; the command line's only
+    /// when the sensor is too
+    #[serde(default)]
+    pub sensor_noise: Option<f64>,
+    /// Filter preset file, used like `--filter-preset`; the command line's
+    /// filter options without one
+    #[serde(default)]
+    pub filter_preset: Option<PathBuf>,
+    // The command line's state files belong to its own station, so these
+    // aren't inherited
+    #[serde(default)]
+    pub baseline_file: Option<PathBuf>,
+    #[serde(default)]
+    pub offset_file: Option<PathBuf>,
+    #[serde(default)]
+    pub totals_file: Option<PathBuf>,
+    #[serde(default)]
+    pub history_file: Option<PathBuf>,
+}
+
+/// An option given as on the command line, such as `"lidar-lite"`
+fn parsed<'de, D: Deserializer<'de>, T: std::str::FromStr<Err = String>>(deserializer: D) -> Result<Option<T>, D::Error> {
+    Option::<String>::deserialize(deserializer)?.map(|s| s.parse().map_err(serde::de::Error::custom)).transpose()
+}
+
+/// An I2C address as on the command line, such as `"0x70"`
+fn i2c_address<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u16>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| i2c::parse_address(&s).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+/// Parse the stations file, whose names must differ from each other and
+/// from `station_name`, the command line's
+pub fn from_json(json: &str, station_name: &str) -> Result<Vec<StationSpec>, String> {
+    let stations: Vec<StationSpec> = serde_json::from_str(json).map_err(|e| format!("invalid stations: {}", e))?;
+    let mut names = vec![station_name];
+    for station in &stations {
+        if station.station_name.is_empty() {
+            return Err("invalid stations: a station has no stationName".to_string());
+        }
+        if names.contains(&station.station_name.as_str()) {
+            return Err(format!("invalid stations: station '{}' appears twice", station.station_name));
+        }
+        names.push(&station.station_name);
+    }
+    Ok(stations)
+}
+
+pub fn load(path: &Path, station_name: &str) -> Result<Vec<StationSpec>, String> {
+    let json = std::fs::read_to_string(path).map_err(|e| format!("failed to read stations {}: {}", path.display(), e))?;
+    from_json(&json, station_name).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+/// Routes each call to the service of the station its metadata names
+#[derive(Clone)]
+pub struct StationRouter<S> {
+    primary: S,
+    stations: Arc<HashMap<String, S>>,
+}
+
+impl<S: Clone> StationRouter<S> {
+    /// Calls without station metadata go to `primary`, the named station
+    /// that is listed first
+    pub fn new(primary: (String, S), stations: impl IntoIterator<Item = (String, S)>) -> Self {
+        let stations = std::iter::once(primary.clone()).chain(stations).collect();
+        Self { primary: primary.1, stations: Arc::new(stations) }
+    }
+}
+
+impl<S, B> Service<http::Request<B>> for StationRouter<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<BoxBody>, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    B: Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Infallible>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        self.primary.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let station = match request.headers().get(STATION_METADATA) {
+            None => return Box::pin(self.primary.call(request)),
+            Some(station) => String::from_utf8_lossy(station.as_bytes()).into_owned(),
+        };
+        match self.stations.get(&station) {
+            Some(service) => {
+                let mut service = service.clone();
+                Box::pin(async move {
+                    std::future::poll_fn(|cx| service.poll_ready(cx)).await?;
+                    service.call(request).await
+                })
+            }
+            None => Box::pin(std::future::ready(Ok(Status::not_found(format!("no station '{}' served here", station)).into_http()))),
+        }
+    }
+}
+
+impl<S: NamedService> NamedService for StationRouter<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_json() {
+        let json = r#"[
+            {"stationName": "pole-2", "port": "/dev/ttyUSB1", "filterPreset": "pole-2.json"},
+            {"stationName": "pole-3", "totalsFile": "pole-3-totals.json"}
+        ]"#;
+        let stations = from_json(json, "pole-1").unwrap();
+        assert_eq!(stations[0].port.as_deref(), Some("/dev/ttyUSB1"));
+        assert_eq!(stations[0].filter_preset, Some(PathBuf::from("pole-2.json")));
+        assert_eq!((stations[1].port.as_ref(), stations[1].baseline_file.as_ref()), (None, None));
+
+        assert!(from_json(json, "pole-2").unwrap_err().contains("'pole-2' appears twice"));
+        assert!(from_json(r#"[{"stationName": ""}]"#, "pole-1").is_err());
+        assert!(from_json(r#"[{"stationName": "pole-2", "baud": 9600}]"#, "pole-1").is_err());
+    }
+
+    #[test]
+    fn test_sensor_options() {
+        let json = r#"[
+            {"stationName": "pole-2", "source": "lidar-lite", "i2cBus": "/dev/i2c-2", "i2cAddress": "0x63"},
+            {"stationName": "pole-3", "source": "mqtt", "mqttTopic": "gauges/pole-3", "mqttField": "distance", "fuseWith": ["pole-2"], "sensorNoise": 3.5}
+        ]"#;
+        let stations = from_json(json, "pole-1").unwrap();
+        assert_eq!(stations[0].source, Some(SensorSource::LidarLite));
+        assert_eq!((stations[0].i2c_bus.as_deref(), stations[0].i2c_address), (Some(Path::new("/dev/i2c-2")), Some(0x63)));
+        assert_eq!(stations[1].source, Some(SensorSource::Mqtt));
+        assert_eq!(stations[1].mqtt_topic.as_deref(), Some("gauges/pole-3"));
+        assert_eq!(stations[1].mqtt_broker, None);
+        assert_eq!((stations[0].fuse_with.len(), stations[0].sensor_noise), (0, None));
+        assert_eq!((stations[1].fuse_with.as_slice(), stations[1].sensor_noise), (&["pole-2".to_string()][..], Some(3.5)));
+
+        let invalid = from_json(r#"[{"stationName": "pole-2", "source": "sonar"}]"#, "pole-1").unwrap_err();
+        assert!(invalid.contains("Invalid source 'sonar'"), "{}", invalid);
+        assert!(from_json(r#"[{"stationName": "pole-2", "i2cAddress": "0x90"}]"#, "pole-1").is_err());
+    }
+
+    /// Answers with the name of the station it serves
+    #[derive(Clone)]
+    struct Station(&'static str);
+
+    impl Service<http::Request<()>> for Station {
+        type Response = http::Response<BoxBody>;
+        type Error = Infallible;
+        type Future = std::future::Ready<Result<Self::Response, Infallible>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _request: http::Request<()>) -> Self::Future {
+            std::future::ready(Ok(http::Response::builder().header("served-by", self.0).body(tonic::body::empty_body()).unwrap()))
+        }
+    }
+
+    async fn call(router: &mut StationRouter<Station>, station: Option<&str>) -> http::Response<BoxBody> {
+        let mut request = http::Request::builder();
+        if let Some(station) = station {
+            request = request.header(STATION_METADATA, station);
+        }
+        router.call(request.body(()).unwrap()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_router() {
+        let mut router = StationRouter::new(("pole-1".to_string(), Station("pole-1")), [("pole-2".to_string(), Station("pole-2"))]);
+        assert_eq!(call(&mut router, None).await.headers()["served-by"], "pole-1");
+        assert_eq!(call(&mut router, Some("pole-1")).await.headers()["served-by"], "pole-1");
+        assert_eq!(call(&mut router, Some("pole-2")).await.headers()["served-by"], "pole-2");
+        let unknown = call(&mut router, Some("pole-9")).await;
+        assert_eq!(unknown.headers()["grpc-status"], "5");
+        assert!(!unknown.headers().contains_key("served-by"));
+    }
+}
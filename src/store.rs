@@ -0,0 +1,517 @@
+/// Compact storage for the reading history
+///
+/// Readings are appended to blocks of up to `BLOCK_SIZE` entries. Within a
+/// block, timestamps are stored as the delta-of-delta of their millisecond
+/// value and distances as the delta of their value in hundredths of a mm,
+/// each as a zigzag varint. With a steady reporting cadence and slowly
+/// changing depth most readings take 2-3 bytes, against 24 for a plain
+/// `StoredReading`, so a season of 1-minute data fits in a few hundred KB.
+/// Blocks are not compressed any further: zstd or deflate would need a
+/// crate this build doesn't have.
+///
+/// Storage is lossy below the sensor's resolution: timestamps are kept to
+/// the millisecond and distances to 0.01 mm. Eviction from the front only
+/// advances a block's start offset; a block is freed once fully evicted.
+///
+/// A history file holds the same encoding. Each reading stored is appended
+/// to it as a record: a tag byte and the varints added to the block, or the
+/// block's first values for a reading that starts one. Rewriting the file
+/// writes each retained block as one record. Records the history keeps
+/// beside the readings are framed with their length.
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::history::StoredReading;
+
+pub const BLOCK_SIZE: usize = 256;
+
+/// Scale for fixed-point distances (hundredths of a mm)
+const DISTANCE_SCALE: f64 = 100.0;
+
+/// A reading that starts a block: its timestamp and distance
+const RECORD_START: u8 = 1;
+/// A reading added to the last block: its two deltas
+const RECORD_ENTRY: u8 = 2;
+/// A whole block, framed
+const RECORD_BLOCK: u8 = 3;
+
+/// Round a timestamp down to the precision kept by the store
+pub fn millis_precision(timestamp: SystemTime) -> SystemTime {
+    from_millis(to_millis(timestamp))
+}
+
+pub fn to_millis(timestamp: SystemTime) -> i64 {
+    match timestamp.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_millis() as i64,
+        Err(e) => -(e.duration().as_millis() as i64),
+    }
+}
+
+pub fn from_millis(ms: i64) -> SystemTime {
+    if ms >= 0 {
+        UNIX_EPOCH + Duration::from_millis(ms as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_millis(ms.unsigned_abs())
+    }
+}
+
+fn to_fixed(distance: f64) -> i64 {
+    (distance * DISTANCE_SCALE).round() as i64
+}
+
+fn from_fixed(value: i64) -> f64 {
+    value as f64 / DISTANCE_SCALE
+}
+
+fn write_varint(out: &mut Vec<u8>, value: i64) {
+    // Zigzag so small negative deltas stay small
+    let mut v = ((value << 1) ^ (value >> 63)) as u64;
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> i64 {
+    let mut v = 0u64;
+    let mut shift = 0;
+    while let Some(&b) = data.get(*pos) {
+        *pos += 1;
+        v |= ((b & 0x7F) as u64) << shift;
+        if b & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    ((v >> 1) as i64) ^ -((v & 1) as i64)
+}
+
+/// Read a varint from a file, or None if the data ends within it
+fn next_varint(data: &[u8], pos: &mut usize) -> Option<i64> {
+    let mut v = 0u64;
+    let mut shift = 0;
+    loop {
+        let b = *data.get(*pos)?;
+        *pos += 1;
+        v |= ((b & 0x7F) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Some(((v >> 1) as i64) ^ -((v & 1) as i64));
+        }
+        shift += 7;
+        if shift > 63 {
+            return None;
+        }
+    }
+}
+
+/// Frame a record with its tag and length, for the records of a history
+/// file that aren't readings
+pub fn record(tag: u8, payload: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    write_varint(&mut out, payload.len() as i64);
+    out.extend_from_slice(payload);
+    out
+}
+
+struct Block {
+    first_ms: i64,
+    first_value: i64,
+    last_ms: i64,
+    last_delta_ms: i64,
+    last_value: i64,
+    /// Entries encoded in this block, including evicted ones
+    len: usize,
+    /// Leading entries that have been evicted
+    skip: usize,
+    /// Encoded entries after the first
+    data: Vec<u8>,
+}
+
+impl Block {
+    fn new(ms: i64, value: i64) -> Self {
+        Self {
+            first_ms: ms,
+            first_value: value,
+            last_ms: ms,
+            last_delta_ms: 0,
+            last_value: value,
+            len: 1,
+            skip: 0,
+            data: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, ms: i64, value: i64) {
+        let delta_ms = ms - self.last_ms;
+        write_varint(&mut self.data, delta_ms - self.last_delta_ms);
+        write_varint(&mut self.data, value - self.last_value);
+        self.last_ms = ms;
+        self.last_delta_ms = delta_ms;
+        self.last_value = value;
+        self.len += 1;
+    }
+
+    /// Decode the entries still retained
+    fn readings(&self) -> impl Iterator<Item = StoredReading> + '_ {
+        let mut pos = 0;
+        let mut ms = self.first_ms;
+        let mut delta_ms = 0;
+        let mut value = self.first_value;
+        (0..self.len)
+            .map(move |i| {
+                if i > 0 {
+                    delta_ms += read_varint(&self.data, &mut pos);
+                    ms += delta_ms;
+                    value += read_varint(&self.data, &mut pos);
+                }
+                StoredReading {
+                    timestamp: from_millis(ms),
+                    distance: from_fixed(value),
+                }
+            })
+            .skip(self.skip)
+    }
+
+    /// The block as a history file record
+    fn record(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(self.data.len() + 32);
+        let header = [self.first_ms, self.first_value, self.last_ms, self.last_delta_ms, self.last_value];
+        for value in header.into_iter().chain([self.len as i64]) {
+            write_varint(&mut payload, value);
+        }
+        payload.extend_from_slice(&self.data);
+        record(RECORD_BLOCK, &payload)
+    }
+
+    fn from_record(payload: &[u8]) -> Option<Self> {
+        let mut pos = 0;
+        let header: Option<Vec<i64>> = (0..6).map(|_| next_varint(payload, &mut pos)).collect();
+        let [first_ms, first_value, last_ms, last_delta_ms, last_value, len] = header?[..] else {
+            return None;
+        };
+        let len = usize::try_from(len).ok().filter(|len| (1..=BLOCK_SIZE).contains(len))?;
+        let data = payload[pos..].to_vec();
+        Some(Self { first_ms, first_value, last_ms, last_delta_ms, last_value, len, skip: 0, data })
+    }
+}
+
+/// A store read back from a history file
+pub struct Decoded<'a> {
+    pub store: ReadingStore,
+    /// The framed records that aren't readings, as (tag, payload)
+    pub records: Vec<(u8, &'a [u8])>,
+    /// Length of the file up to the first record that couldn't be read
+    pub len: usize,
+}
+
+/// Read the records of a history file
+///
+/// Reading stops at a record cut short or corrupted, as by a power failure
+/// during the last write; everything before it is kept.
+pub fn decode(data: &[u8]) -> Decoded<'_> {
+    let mut store = ReadingStore::new();
+    let mut records = Vec::new();
+    let mut pos = 0;
+    let mut len = 0;
+    while pos < data.len() {
+        let tag = data[pos];
+        pos += 1;
+        let read = match tag {
+            RECORD_START => match (next_varint(data, &mut pos), next_varint(data, &mut pos)) {
+                (Some(ms), Some(value)) => {
+                    store.blocks.push_back(Block::new(ms, value));
+                    store.len += 1;
+                    true
+                }
+                _ => false,
+            },
+            RECORD_ENTRY => match (next_varint(data, &mut pos), next_varint(data, &mut pos), store.blocks.back_mut()) {
+                (Some(delta_of_delta), Some(delta), Some(block)) if block.len < BLOCK_SIZE => {
+                    let ms = block.last_ms + block.last_delta_ms + delta_of_delta;
+                    block.push(ms, block.last_value + delta);
+                    store.len += 1;
+                    true
+                }
+                _ => false,
+            },
+            _ => {
+                let payload = next_varint(data, &mut pos)
+                    .and_then(|n| usize::try_from(n).ok())
+                    .and_then(|n| data.get(pos..pos.checked_add(n)?));
+                match payload {
+                    Some(payload) if tag == RECORD_BLOCK => {
+                        pos += payload.len();
+                        Block::from_record(payload).map(|block| {
+                            store.len += block.len;
+                            store.blocks.push_back(block);
+                        }).is_some()
+                    }
+                    Some(payload) => {
+                        pos += payload.len();
+                        records.push((tag, payload));
+                        true
+                    }
+                    None => false,
+                }
+            }
+        };
+        if !read {
+            break;
+        }
+        len = pos;
+    }
+    Decoded { store, records, len }
+}
+
+#[derive(Default)]
+pub struct ReadingStore {
+    blocks: VecDeque<Block>,
+    len: usize,
+}
+
+impl ReadingStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Append a reading; timestamps are expected in non-decreasing order
+    ///
+    /// Returns the record that appends it to a history file.
+    pub fn push(&mut self, reading: StoredReading) -> Vec<u8> {
+        let ms = to_millis(reading.timestamp);
+        let value = to_fixed(reading.distance);
+        let mut record = Vec::new();
+        match self.blocks.back_mut() {
+            Some(block) if block.len < BLOCK_SIZE => {
+                let start = block.data.len();
+                block.push(ms, value);
+                record.push(RECORD_ENTRY);
+                record.extend_from_slice(&block.data[start..]);
+            }
+            _ => {
+                if let Some(full) = self.blocks.back_mut() {
+                    full.data.shrink_to_fit();
+                }
+                self.blocks.push_back(Block::new(ms, value));
+                record.push(RECORD_START);
+                write_varint(&mut record, ms);
+                write_varint(&mut record, value);
+            }
+        }
+        self.len += 1;
+        record
+    }
+
+    /// The retained readings as history file records, one to each block
+    pub fn records(&self) -> Vec<u8> {
+        let mut records = Vec::new();
+        for block in &self.blocks {
+            if block.skip == 0 {
+                records.extend(block.record());
+                continue;
+            }
+            // Evicted readings aren't written again
+            let mut retained = ReadingStore::new();
+            for reading in block.readings() {
+                retained.push(reading);
+            }
+            records.extend(retained.records());
+        }
+        records
+    }
+
+    /// Evict the oldest reading
+    pub fn pop_front(&mut self) {
+        let Some(block) = self.blocks.front_mut() else {
+            return;
+        };
+        block.skip += 1;
+        self.len -= 1;
+        if block.skip == block.len {
+            self.blocks.pop_front();
+        }
+    }
+
+    pub fn front(&self) -> Option<StoredReading> {
+        self.blocks.front().and_then(|b| b.readings().next())
+    }
+
+    pub fn back(&self) -> Option<StoredReading> {
+        self.blocks.back().map(|b| StoredReading {
+            timestamp: from_millis(b.last_ms),
+            distance: from_fixed(b.last_value),
+        })
+    }
+
+    /// Readings in `[start, end]` (either bound optional), oldest first
+    ///
+    /// Blocks entirely outside the range are skipped without decoding.
+    pub fn range(&self, start: Option<SystemTime>, end: Option<SystemTime>) -> impl Iterator<Item = StoredReading> + '_ {
+        let start_ms = start.map(to_millis);
+        let end_ms = end.map(to_millis);
+        self.blocks
+            .iter()
+            .filter(move |b| start_ms.is_none_or(|s| b.last_ms >= s) && end_ms.is_none_or(|e| b.first_ms <= e))
+            .flat_map(|b| b.readings())
+            .filter(move |r| start.is_none_or(|s| r.timestamp >= s) && end.is_none_or(|e| r.timestamp <= e))
+    }
+
+    /// Bytes used by the encoded readings
+    #[cfg(test)]
+    pub fn encoded_size(&self) -> usize {
+        self.blocks
+            .iter()
+            .map(|b| std::mem::size_of::<Block>() + b.data.len())
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(ms: i64, distance: f64) -> StoredReading {
+        StoredReading {
+            timestamp: from_millis(ms),
+            distance,
+        }
+    }
+
+    #[test]
+    fn test_varint_roundtrip() {
+        for v in [0, 1, -1, 63, -64, 64, 30_000, -30_000, i64::MAX, i64::MIN] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, v);
+            let mut pos = 0;
+            assert_eq!(read_varint(&buf, &mut pos), v);
+            assert_eq!(pos, buf.len());
+        }
+        let mut buf = Vec::new();
+        write_varint(&mut buf, -1);
+        assert_eq!(buf, vec![0x01]);
+    }
+
+    #[test]
+    fn test_roundtrip_across_blocks() {
+        let mut store = ReadingStore::new();
+        let expected: Vec<StoredReading> = (0..1000)
+            .map(|i| reading(1_700_000_000_000 + i * 30_000 + (i % 7) * 3, 1500.0 - i as f64 * 0.37))
+            .collect();
+        for r in &expected {
+            store.push(r.clone());
+        }
+
+        assert_eq!(store.len(), 1000);
+        let decoded: Vec<StoredReading> = store.range(None, None).collect();
+        assert_eq!(decoded.len(), expected.len());
+        for (d, e) in decoded.iter().zip(&expected) {
+            assert_eq!(d.timestamp, e.timestamp);
+            assert!((d.distance - e.distance).abs() < 0.005);
+        }
+        assert_eq!(store.front().unwrap().timestamp, expected[0].timestamp);
+        assert_eq!(store.back().unwrap().timestamp, expected[999].timestamp);
+    }
+
+    #[test]
+    fn test_eviction() {
+        let mut store = ReadingStore::new();
+        for i in 0..(BLOCK_SIZE as i64 + 10) {
+            store.push(reading(i * 1000, i as f64));
+        }
+        for _ in 0..BLOCK_SIZE {
+            store.pop_front();
+        }
+        assert_eq!(store.len(), 10);
+        assert_eq!(store.blocks.len(), 1);
+        assert_eq!(store.front().unwrap().distance, BLOCK_SIZE as f64);
+        assert_eq!(store.range(None, None).count(), 10);
+    }
+
+    #[test]
+    fn test_range() {
+        let mut store = ReadingStore::new();
+        for i in 0..1000 {
+            store.push(reading(i * 1000, i as f64));
+        }
+        let found: Vec<f64> = store
+            .range(Some(from_millis(300_000)), Some(from_millis(302_000)))
+            .map(|r| r.distance)
+            .collect();
+        assert_eq!(found, vec![300.0, 301.0, 302.0]);
+        assert_eq!(store.range(Some(from_millis(2_000_000)), None).count(), 0);
+    }
+
+    #[test]
+    fn test_compression_ratio() {
+        // A season of 1-minute averages with small jittery changes
+        let mut store = ReadingStore::new();
+        let n = 250_000;
+        for i in 0..n {
+            let jitter = (i * 7919 % 11) - 5;
+            store.push(reading(1_700_000_000_000 + i * 60_000 + jitter, 2000.0 - (i % 500) as f64 * 0.2));
+        }
+        let per_reading = store.encoded_size() as f64 / n as f64;
+        assert!(per_reading < 4.0, "{} bytes per reading", per_reading);
+        assert!(per_reading * 6.0 < std::mem::size_of::<StoredReading>() as f64);
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut store = ReadingStore::new();
+        let mut file = Vec::new();
+        for i in 0..300 {
+            file.extend(store.push(reading(1_700_000_000_000 + i * 30_000, 1500.0 - i as f64 * 0.37)));
+        }
+        file.extend(record(16, b"note"));
+        let decoded = decode(&file);
+        assert_eq!((decoded.len, decoded.records), (file.len(), vec![(16, &b"note"[..])]));
+        assert_eq!(decoded.store.range(None, None).collect::<Vec<_>>(), store.range(None, None).collect::<Vec<_>>());
+
+        // Rewritten as whole blocks, with evicted readings skipped
+        for _ in 0..10 {
+            store.pop_front();
+        }
+        let rewritten = store.records();
+        let mut decoded = decode(&rewritten).store;
+        assert_eq!(decoded.len(), 290);
+        assert_eq!(decoded.range(None, None).collect::<Vec<_>>(), store.range(None, None).collect::<Vec<_>>());
+        // Appending carries on from the last block
+        let mut file = rewritten.clone();
+        file.extend(store.push(reading(1_700_010_000_000, 1000.0)));
+        decoded.push(reading(1_700_010_000_000, 1000.0));
+        assert_eq!(decode(&file).store.range(None, None).collect::<Vec<_>>(), decoded.range(None, None).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_decode_cut_short() {
+        let mut store = ReadingStore::new();
+        let mut file = Vec::new();
+        let mut ends = Vec::new();
+        for i in 0..20 {
+            file.extend(store.push(reading(i * 1000, 1000.0 + i as f64 * 1000.0)));
+            ends.push(file.len());
+        }
+        file.extend(record(16, b"note"));
+        ends.push(file.len());
+        for cut in 0..file.len() {
+            let decoded = decode(&file[..cut]);
+            let complete = ends.iter().filter(|&&end| end <= cut).count();
+            assert_eq!(decoded.len, if complete == 0 { 0 } else { ends[complete - 1] }, "cut at {}", cut);
+            assert_eq!(decoded.store.len(), complete.min(20));
+        }
+        assert_eq!(decode(&[RECORD_ENTRY, 0, 0]).len, 0);
+        assert_eq!(decode(&[0xff, 0xff]).len, 0);
+    }
+
+    #[test]
+    fn test_millis_precision() {
+        let t = UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        assert_eq!(millis_precision(t), UNIX_EPOCH + Duration::new(1_700_000_000, 123_000_000));
+        assert_eq!(from_millis(to_millis(UNIX_EPOCH - Duration::from_secs(5))), UNIX_EPOCH - Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,893 @@
+/// Per-client reading streams
+///
+/// Each `StreamReading` client registers with its own options: whether it
+/// wants batch results or every raw sensor reading, the minimum interval
+/// between readings (decimation), the unit of the `value` field, which
+/// stations it wants readings from, and which quality flags it doesn't
+/// want.
+///
+/// Each station's batch readings are numbered as they are broadcast and the
+/// most recent ones retained, so a reconnecting client can ask for the ones
+/// it missed.
+///
+/// Bidirectional stream clients also send heartbeats, and are disconnected
+/// as soon as those stop rather than lingering until a send fails. Batch
+/// stream clients get several readings per message, to save per-message
+/// overhead on slow links, and event stream clients get state change events
+/// between their readings.
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use tokio::sync::mpsc;
+use tokio::time::{self, Instant};
+use tokio_stream::{Stream, StreamExt};
+use tonic::Status;
+
+use crate::queue::{self, OverflowPolicy, TryRecvError};
+use crate::snowgauge::{
+    client_message, stream_message, ClientMessage, Event, Quality, Reading, ReadingBatch, StreamMessage, StreamRequest, Unit,
+};
+
+pub type ClientChannel = queue::Sender<Reading>;
+pub type ClientReceiver = queue::Receiver<Reading>;
+
+/// Settings for the gRPC streams
+#[derive(Debug, Clone)]
+pub struct StreamConfig {
+    /// How long a bidirectional stream client may go without a heartbeat
+    pub heartbeat_timeout: Duration,
+    /// Batch readings retained for resuming clients
+    pub replay_buffer: usize,
+    /// Open gRPC streams allowed at once; 0 for no limit
+    pub max_clients: usize,
+    /// Items queued for a client before `overflow_policy` applies
+    pub queue_size: usize,
+    pub overflow_policy: OverflowPolicy,
+    /// Unit of `Reading.value` for clients that don't ask for one
+    pub default_unit: Unit,
+}
+
+const MM_PER_INCH: f64 = 25.4;
+
+impl std::str::FromStr for Unit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mm" | "millimeters" => Ok(Unit::Millimeters),
+            "cm" | "centimeters" => Ok(Unit::Centimeters),
+            "in" | "inches" => Ok(Unit::Inches),
+            _ => Err(format!("Invalid unit '{}'. Valid options: mm, cm, in", s)),
+        }
+    }
+}
+
+impl fmt::Display for Unit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Unit::Unspecified => write!(f, "unspecified"),
+            Unit::Millimeters => write!(f, "mm"),
+            Unit::Centimeters => write!(f, "cm"),
+            Unit::Inches => write!(f, "in"),
+        }
+    }
+}
+
+/// Convert a distance in mm to `unit`
+pub fn convert(distance_mm: f64, unit: Unit) -> f64 {
+    match unit {
+        Unit::Unspecified | Unit::Millimeters => distance_mm,
+        Unit::Centimeters => distance_mm / 10.0,
+        Unit::Inches => distance_mm / MM_PER_INCH,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamOptions {
+    /// Send every raw sensor reading instead of batch results
+    pub raw: bool,
+    /// Readings arriving sooner than this after the last one sent are dropped
+    pub min_interval: Duration,
+    pub unit: Unit,
+    /// Station name patterns to accept; all stations if empty
+    pub station_names: Vec<String>,
+    /// Quality flags of readings to drop; all of them to send only OK readings
+    pub exclude_quality: u32,
+    /// First sequence number to replay from each station's retained
+    /// readings; 0 for none
+    pub resume_from: u64,
+    /// Stations to replay from another sequence number than `resume_from`
+    pub resume_from_stations: Vec<(String, u64)>,
+    /// Readings per batch stream message; 0 for no limit
+    pub coalesce_count: usize,
+    /// Longest a reading waits for its batch stream message; zero to only
+    /// coalesce readings already waiting
+    pub coalesce_interval: Duration,
+}
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        Self {
+            raw: false,
+            min_interval: Duration::ZERO,
+            unit: Unit::Millimeters,
+            station_names: Vec::new(),
+            exclude_quality: 0,
+            resume_from: 0,
+            resume_from_stations: Vec::new(),
+            coalesce_count: 0,
+            coalesce_interval: Duration::ZERO,
+        }
+    }
+}
+
+impl StreamOptions {
+    /// Options for `request`, in `default_unit` unless it asks for another
+    #[allow(clippy::result_large_err)]
+    pub fn from_request(request: &StreamRequest, default_unit: Unit) -> Result<Self, Status> {
+        let min_interval = match request.min_interval {
+            Some(d) => Duration::try_from(d).map_err(|e| Status::invalid_argument(format!("invalid minInterval: {}", e)))?,
+            None => Duration::ZERO,
+        };
+        let coalesce_interval = match request.coalesce_interval {
+            Some(d) => Duration::try_from(d).map_err(|e| Status::invalid_argument(format!("invalid coalesceInterval: {}", e)))?,
+            None => Duration::ZERO,
+        };
+        let unit = match Unit::try_from(request.unit) {
+            Ok(Unit::Unspecified) => default_unit,
+            Ok(unit) => unit,
+            Err(_) => return Err(Status::invalid_argument(format!("unknown unit {}", request.unit))),
+        };
+        let station_names = request
+            .station_names
+            .iter()
+            .chain(&request.station_name)
+            .filter(|name| !name.is_empty())
+            .cloned()
+            .collect();
+        let mut exclude_quality = if request.quality_ok_only { u32::MAX } else { 0 };
+        for &flag in &request.exclude_quality {
+            Quality::try_from(flag).map_err(|_| Status::invalid_argument(format!("unknown quality flag {}", flag)))?;
+            exclude_quality |= flag as u32;
+        }
+        Ok(Self {
+            raw: request.raw,
+            min_interval,
+            unit,
+            station_names,
+            exclude_quality,
+            resume_from: request.resume_from_sequence,
+            resume_from_stations: request
+                .resume_from_stations
+                .iter()
+                .map(|resume| (resume.station_name.clone(), resume.sequence))
+                .collect(),
+            coalesce_count: request.coalesce_count as usize,
+            coalesce_interval,
+        })
+    }
+
+    /// True if readings from `station` should be sent to this client
+    pub fn accepts(&self, station: &str) -> bool {
+        self.station_names.is_empty() || self.station_names.iter().any(|p| glob_match(p, station))
+    }
+
+    /// First sequence number to replay from `station`'s retained readings
+    pub fn resume_point(&self, station: &str) -> u64 {
+        match self.resume_from_stations.iter().find(|(name, _)| name == station) {
+            Some(&(_, sequence)) => sequence,
+            None => self.resume_from,
+        }
+    }
+}
+
+/// Match `name` against a pattern where `*` matches any run of characters
+/// and `?` any single character
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Position of the last `*` and the name position it was tried at
+    let mut backtrack: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if let Some((star, tried)) = backtrack {
+            p = star + 1;
+            n = tried + 1;
+            backtrack = Some((star, n));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+pub struct StreamClient {
+    sender: ClientChannel,
+    options: StreamOptions,
+    last_sent: Option<SystemTime>,
+    /// A gRPC stream, as opposed to one of the daemon's own outputs
+    remote: bool,
+}
+
+impl StreamClient {
+    /// A subscriber within the daemon (CoAP, SNMP, and the like)
+    pub fn new(sender: ClientChannel, options: StreamOptions) -> Self {
+        Self {
+            sender,
+            options,
+            last_sent: None,
+            remote: false,
+        }
+    }
+
+    /// A gRPC stream client, which counts toward the client limit
+    pub fn remote(sender: ClientChannel, options: StreamOptions) -> Self {
+        Self {
+            remote: true,
+            ..Self::new(sender, options)
+        }
+    }
+
+    pub fn is_remote(&self) -> bool {
+        self.remote
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    /// Send a reading (given in mm) if this client wants it
+    ///
+    /// Returns false once the client has disconnected.
+    pub fn offer(&mut self, reading: &Reading, raw: bool) -> bool {
+        let excluded = reading.quality & self.options.exclude_quality != 0;
+        if raw != self.options.raw || excluded || !self.options.accepts(&reading.station_name) {
+            return !self.sender.is_closed();
+        }
+        let timestamp = reading
+            .timestamp
+            .and_then(|t| SystemTime::try_from(t).ok())
+            .unwrap_or_else(SystemTime::now);
+        let due = self.last_sent.is_none_or(|last| {
+            timestamp.duration_since(last).unwrap_or_default() >= self.options.min_interval
+        });
+        if !due {
+            return !self.sender.is_closed();
+        }
+
+        let mut reading = reading.clone();
+        reading.value = convert(reading.value, self.options.unit);
+        reading.unit = self.options.unit as i32;
+        self.last_sent = Some(timestamp);
+        self.sender.send(Ok(reading))
+    }
+}
+
+/// A station's most recent batch readings, numbered in broadcast order
+pub struct ReplayBuffer {
+    capacity: usize,
+    readings: VecDeque<Reading>,
+    next_sequence: u64,
+}
+
+impl ReplayBuffer {
+    /// Retain up to `capacity` readings (none if 0; readings are still numbered)
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            readings: VecDeque::with_capacity(capacity),
+            next_sequence: 1,
+        }
+    }
+
+    /// Assign a batch reading the next sequence number and retain it
+    pub fn push(&mut self, reading: &mut Reading) {
+        reading.sequence = self.next_sequence;
+        self.next_sequence += 1;
+        if self.capacity == 0 {
+            return;
+        }
+        if self.readings.len() >= self.capacity {
+            self.readings.pop_front();
+        }
+        self.readings.push_back(reading.clone());
+    }
+
+    /// Retained readings with sequence numbers from `from` on
+    ///
+    /// Sequence numbers restart at 1 with the server, so a `from` beyond
+    /// the next sequence number was seen before a restart and everything
+    /// retained is new to the client.
+    pub fn since(&self, from: u64) -> impl Iterator<Item = &Reading> {
+        let from = if from > self.next_sequence { 0 } else { from };
+        let start = self.readings.partition_point(|r| r.sequence < from);
+        self.readings.range(start..)
+    }
+}
+
+/// The readings to replay to a client with `options` from the retained
+/// readings of each `(station, buffer)` it accepts, oldest first
+pub fn replay<'a>(buffers: impl IntoIterator<Item = (&'a str, &'a ReplayBuffer)>, options: &StreamOptions) -> Vec<&'a Reading> {
+    let mut replayed: Vec<&Reading> = buffers
+        .into_iter()
+        .filter(|(station, _)| options.accepts(station))
+        .flat_map(|(station, buffer)| match options.resume_point(station) {
+            0 => None,
+            from => Some(buffer.since(from)),
+        })
+        .flatten()
+        .collect();
+    replayed.sort_by_key(|r| r.timestamp.map(|t| (t.seconds, t.nanos)));
+    replayed
+}
+
+/// Client channel for the batch stream
+///
+/// Kept short so a stalled client backs up into its reading queue, where
+/// the overflow policy applies.
+pub type BatchChannel = mpsc::Sender<Result<ReadingBatch, Status>>;
+
+/// Forward subscribed readings to a batch stream client, up to `count`
+/// readings per message (0 for no limit), each message sent `interval`
+/// after its first reading or as soon as it is full
+///
+/// With a zero interval, a message holds the readings already waiting when
+/// the first arrives. Returning drops `readings`, so the client's
+/// registration is closed.
+pub async fn forward_coalesced(
+    mut readings: ClientReceiver,
+    outbound: BatchChannel,
+    count: usize,
+    interval: Duration,
+) -> Disconnect {
+    let full = |batch: &Vec<Reading>| count > 0 && batch.len() >= count;
+    loop {
+        let first = tokio::select! {
+            first = readings.recv() => first,
+            _ = outbound.closed() => return Disconnect::Closed,
+        };
+        let mut batch = match first {
+            Some(Ok(reading)) => vec![reading],
+            Some(Err(status)) => {
+                let _ = outbound.send(Err(status)).await;
+                return Disconnect::Closed;
+            }
+            None => return Disconnect::Shutdown,
+        };
+
+        // An error or the end of the readings is passed on after the batch
+        let mut end = None;
+        if interval.is_zero() {
+            while !full(&batch) {
+                match readings.try_recv() {
+                    Ok(Ok(reading)) => batch.push(reading),
+                    Ok(Err(status)) => end = Some(Some(status)),
+                    Err(TryRecvError::Empty) => {}
+                    Err(TryRecvError::Disconnected) => end = Some(None),
+                }
+                if end.is_some() || readings.is_empty() {
+                    break;
+                }
+            }
+        } else {
+            let deadline = time::sleep(interval);
+            tokio::pin!(deadline);
+            while !full(&batch) {
+                tokio::select! {
+                    next = readings.recv() => match next {
+                        Some(Ok(reading)) => batch.push(reading),
+                        Some(Err(status)) => end = Some(Some(status)),
+                        None => end = Some(None),
+                    },
+                    _ = &mut deadline => break,
+                }
+                if end.is_some() {
+                    break;
+                }
+            }
+        }
+
+        if outbound.send(Ok(ReadingBatch { readings: batch })).await.is_err() {
+            return Disconnect::Closed;
+        }
+        match end {
+            Some(Some(status)) => {
+                let _ = outbound.send(Err(status)).await;
+                return Disconnect::Closed;
+            }
+            Some(None) => return Disconnect::Shutdown,
+            None => {}
+        }
+    }
+}
+
+/// Client channel for the event stream, kept short like the batch channel
+pub type EventChannel = mpsc::Sender<Result<StreamMessage, Status>>;
+
+/// Forward subscribed readings and `events` to an event stream client in
+/// the order they arrive
+///
+/// Returning drops both receivers, so the client's registrations are closed.
+pub async fn forward_with_events(
+    mut readings: ClientReceiver,
+    mut events: queue::Receiver<Event>,
+    outbound: EventChannel,
+) -> Disconnect {
+    loop {
+        let next = tokio::select! {
+            reading = readings.recv() => match reading {
+                Some(reading) => reading.map(stream_message::Payload::Reading),
+                None => return Disconnect::Shutdown,
+            },
+            Some(event) = events.recv() => event.map(stream_message::Payload::Event),
+            _ = outbound.closed() => return Disconnect::Closed,
+        };
+        let failed = next.is_err();
+        let message = next.map(|payload| StreamMessage { payload: Some(payload) });
+        if outbound.send(message).await.is_err() || failed {
+            return Disconnect::Closed;
+        }
+    }
+}
+
+/// Why a bidirectional, batch or event stream ended
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Disconnect {
+    /// The client closed the stream or went away
+    Closed,
+    /// No heartbeat within the timeout
+    HeartbeatTimeout,
+    /// The client sent something other than a heartbeat
+    Protocol,
+    /// The service stopped publishing readings
+    Shutdown,
+}
+
+impl fmt::Display for Disconnect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Disconnect::Closed => write!(f, "client closed the stream"),
+            Disconnect::HeartbeatTimeout => write!(f, "heartbeat timed out"),
+            Disconnect::Protocol => write!(f, "unexpected message"),
+            Disconnect::Shutdown => write!(f, "server shutting down"),
+        }
+    }
+}
+
+/// Watch a bidirectional stream client's heartbeats, ending its reading
+/// stream when it disconnects or goes longer than `timeout` without one
+pub async fn watch_heartbeats<S>(mut inbound: S, readings: queue::Closer<Reading>, timeout: Duration) -> Disconnect
+where
+    S: Stream<Item = Result<ClientMessage, Status>> + Unpin,
+{
+    let deadline = time::sleep(timeout);
+    tokio::pin!(deadline);
+
+    loop {
+        tokio::select! {
+            message = inbound.next() => match message {
+                Some(Ok(ClientMessage { message: Some(client_message::Message::Heartbeat(_)) })) => {
+                    deadline.as_mut().reset(Instant::now() + timeout);
+                }
+                Some(Ok(_)) => {
+                    readings.close_with(Status::invalid_argument("expected a heartbeat"));
+                    return Disconnect::Protocol;
+                }
+                Some(Err(_)) | None => {
+                    readings.close();
+                    return Disconnect::Closed;
+                }
+            },
+            _ = &mut deadline => {
+                readings.close_with(Status::deadline_exceeded(format!(
+                    "no heartbeat for {}s", timeout.as_secs_f64()
+                )));
+                return Disconnect::HeartbeatTimeout;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snowgauge::StationSequence;
+
+    fn reading(secs: u64, distance: f64) -> Reading {
+        Reading {
+            distance: distance as i32,
+            value: distance,
+            unit: Unit::Millimeters as i32,
+            distance_mm: distance,
+            timestamp: Some((SystemTime::UNIX_EPOCH + Duration::from_secs(secs)).into()),
+            ..Default::default()
+        }
+    }
+
+    fn client(options: StreamOptions) -> (StreamClient, ClientReceiver) {
+        let (tx, rx) = queue::bounded(16, OverflowPolicy::DropOldest);
+        (StreamClient::new(tx, options), rx)
+    }
+
+    #[test]
+    fn test_convert() {
+        assert_eq!(convert(254.0, Unit::Inches), 10.0);
+        assert_eq!(convert(254.0, Unit::Centimeters), 25.4);
+        assert_eq!(convert(254.0, Unit::Millimeters), 254.0);
+    }
+
+    #[test]
+    fn test_parse_unit() {
+        assert_eq!("in".parse(), Ok(Unit::Inches));
+        assert_eq!("Centimeters".parse(), Ok(Unit::Centimeters));
+        assert_eq!("mm".parse::<Unit>().unwrap().to_string(), "mm");
+        assert!("furlongs".parse::<Unit>().is_err());
+    }
+
+    #[test]
+    fn test_from_request() {
+        let options = StreamOptions::from_request(&StreamRequest::default(), Unit::Millimeters).unwrap();
+        assert_eq!(options, StreamOptions::default());
+        let options = StreamOptions::from_request(&StreamRequest::default(), Unit::Inches).unwrap();
+        assert_eq!(options.unit, Unit::Inches);
+
+        let request = StreamRequest {
+            raw: true,
+            min_interval: Some(prost_types::Duration { seconds: 60, nanos: 0 }),
+            unit: Unit::Inches as i32,
+            ..Default::default()
+        };
+        let options = StreamOptions::from_request(&request, Unit::Millimeters).unwrap();
+        assert!(options.raw);
+        assert_eq!(options.min_interval, Duration::from_secs(60));
+        assert_eq!(options.unit, Unit::Inches);
+
+        let request = StreamRequest {
+            unit: 99,
+            ..Default::default()
+        };
+        assert!(StreamOptions::from_request(&request, Unit::Millimeters).is_err());
+        let request = StreamRequest {
+            min_interval: Some(prost_types::Duration { seconds: -1, nanos: 0 }),
+            ..Default::default()
+        };
+        assert!(StreamOptions::from_request(&request, Unit::Millimeters).is_err());
+    }
+
+    #[test]
+    fn test_resume_from_request() {
+        let request = StreamRequest {
+            resume_from_sequence: 42,
+            ..Default::default()
+        };
+        assert_eq!(StreamOptions::from_request(&request, Unit::Millimeters).unwrap().resume_from, 42);
+
+        let request = StreamRequest {
+            resume_from_sequence: 42,
+            resume_from_stations: vec![StationSequence { station_name: "pole-2".to_string(), sequence: 7 }],
+            ..Default::default()
+        };
+        let options = StreamOptions::from_request(&request, Unit::Millimeters).unwrap();
+        assert_eq!(options.resume_point("pole-1"), 42);
+        assert_eq!(options.resume_point("pole-2"), 7);
+    }
+
+    #[test]
+    fn test_replay_buffer() {
+        let mut buffer = ReplayBuffer::new(3);
+        let mut sequences = Vec::new();
+        for i in 0..5 {
+            let mut r = reading(i, 1000.0);
+            buffer.push(&mut r);
+            sequences.push(r.sequence);
+        }
+        assert_eq!(sequences, vec![1, 2, 3, 4, 5]);
+
+        let since = |from| buffer.since(from).map(|r| r.sequence).collect::<Vec<_>>();
+        assert_eq!(since(4), vec![4, 5]);
+        assert_eq!(since(6), Vec::<u64>::new());
+        // Older than anything retained: replay what there is
+        assert_eq!(since(1), vec![3, 4, 5]);
+        // From before a restart
+        assert_eq!(since(1000), vec![3, 4, 5]);
+
+        let mut buffer = ReplayBuffer::new(0);
+        let mut r = reading(0, 1000.0);
+        buffer.push(&mut r);
+        assert_eq!(r.sequence, 1);
+        assert_eq!(buffer.since(1).count(), 0);
+    }
+
+    #[test]
+    fn test_replay_per_station() {
+        let (mut pole1, mut pole2) = (ReplayBuffer::new(10), ReplayBuffer::new(10));
+        for i in 0..6 {
+            let mut r = Reading { station_name: if i % 3 == 0 { "pole-2" } else { "pole-1" }.to_string(), ..reading(i, 1000.0) };
+            match r.station_name.as_str() {
+                "pole-1" => pole1.push(&mut r),
+                _ => pole2.push(&mut r),
+            }
+        }
+        // Each station counts its own readings, with no gaps from the other's
+        let sequences = |buffer: &ReplayBuffer| buffer.since(1).map(|r| r.sequence).collect::<Vec<_>>();
+        assert_eq!(sequences(&pole1), vec![1, 2, 3, 4]);
+        assert_eq!(sequences(&pole2), vec![1, 2]);
+
+        let buffers = [("pole-1", &pole1), ("pole-2", &pole2)];
+        let replayed = |options: StreamOptions| {
+            replay(buffers, &options).iter().map(|r| (r.station_name.clone(), r.sequence)).collect::<Vec<_>>()
+        };
+        // Merged in time order, from each station's own resume point
+        let options = StreamOptions {
+            resume_from: 3,
+            resume_from_stations: vec![("pole-2".to_string(), 2)],
+            ..Default::default()
+        };
+        assert_eq!(
+            replayed(options),
+            vec![("pole-2".to_string(), 2), ("pole-1".to_string(), 3), ("pole-1".to_string(), 4)]
+        );
+        let options = StreamOptions {
+            station_names: vec!["pole-2".to_string()],
+            resume_from: 1,
+            ..Default::default()
+        };
+        assert_eq!(replayed(options), vec![("pole-2".to_string(), 1), ("pole-2".to_string(), 2)]);
+        // Live only for a station resumed from 0
+        let options = StreamOptions { resume_from_stations: vec![("pole-1".to_string(), 4)], ..Default::default() };
+        assert_eq!(replayed(options), vec![("pole-1".to_string(), 4)]);
+    }
+
+    #[test]
+    fn test_decimation_and_unit() {
+        let (mut client, mut rx) = client(StreamOptions {
+            min_interval: Duration::from_secs(60),
+            unit: Unit::Inches,
+            ..Default::default()
+        });
+        for secs in [0, 30, 59, 60, 90, 125] {
+            assert!(client.offer(&reading(secs, 508.0), false));
+        }
+        let mut sent = Vec::new();
+        while let Ok(Ok(r)) = rx.try_recv() {
+            sent.push(r);
+        }
+        let times: Vec<i64> = sent.iter().map(|r| r.timestamp.unwrap().seconds).collect();
+        assert_eq!(times, vec![0, 60, 125]);
+        assert_eq!(sent[0].value, 20.0);
+        assert_eq!(sent[0].unit, Unit::Inches as i32);
+        assert_eq!(sent[0].distance, 508);
+        assert_eq!(sent[0].distance_mm, 508.0);
+    }
+
+    #[test]
+    fn test_raw_and_batch_routing() {
+        let (mut raw_client, mut raw_rx) = client(StreamOptions {
+            raw: true,
+            ..Default::default()
+        });
+        let (mut batch_client, mut batch_rx) = client(StreamOptions::default());
+        for c in [&mut raw_client, &mut batch_client] {
+            c.offer(&reading(0, 1000.0), true);
+            c.offer(&reading(1, 1001.0), false);
+        }
+        assert_eq!(raw_rx.try_recv().unwrap().unwrap().value, 1000.0);
+        assert!(raw_rx.try_recv().is_err());
+        assert_eq!(batch_rx.try_recv().unwrap().unwrap().value, 1001.0);
+        assert!(batch_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("ridge", "ridge"));
+        assert!(!glob_match("ridge", "ridge2"));
+        assert!(glob_match("ridge*", "ridge2"));
+        assert!(glob_match("*-north", "pass-north"));
+        assert!(!glob_match("*-north", "pass-south"));
+        assert!(glob_match("a*b*c", "axxbyybc"));
+        assert!(glob_match("site-?", "site-3"));
+        assert!(!glob_match("site-?", "site-10"));
+        assert!(glob_match("*", ""));
+    }
+
+    #[test]
+    fn test_station_filter() {
+        let request = StreamRequest {
+            station_name: Some("summit".to_string()),
+            station_names: vec!["pass-*".to_string()],
+            ..Default::default()
+        };
+        let options = StreamOptions::from_request(&request, Unit::Millimeters).unwrap();
+        assert!(options.accepts("summit"));
+        assert!(options.accepts("pass-north"));
+        assert!(!options.accepts("valley"));
+        assert!(StreamOptions::default().accepts("valley"));
+
+        let (mut client, mut rx) = client(options);
+        let mut valley = reading(0, 1000.0);
+        valley.station_name = "valley".to_string();
+        assert!(client.offer(&valley, false));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_quality_filter() {
+        let flagged = |quality: Quality| Reading { quality: quality as u32, ..reading(0, 1000.0) };
+        let request = StreamRequest { exclude_quality: vec![Quality::Interpolated as i32, Quality::Anomalous as i32], ..Default::default() };
+        let (mut excluding, mut rx) = client(StreamOptions::from_request(&request, Unit::Millimeters).unwrap());
+        assert!(excluding.offer(&flagged(Quality::Interpolated), false));
+        assert!(excluding.offer(&Reading { quality: (Quality::Anomalous as u32) | (Quality::HighWind as u32), ..reading(1, 1000.0) }, false));
+        assert!(rx.try_recv().is_err());
+        assert!(excluding.offer(&flagged(Quality::HighWind), false));
+        assert!(rx.try_recv().is_ok());
+
+        let request = StreamRequest { quality_ok_only: true, ..Default::default() };
+        let (mut ok_only, mut rx) = client(StreamOptions::from_request(&request, Unit::Millimeters).unwrap());
+        assert!(ok_only.offer(&flagged(Quality::FilterWarmingUp), false));
+        assert!(rx.try_recv().is_err());
+        assert!(ok_only.offer(&flagged(Quality::Ok), false));
+        assert!(rx.try_recv().is_ok());
+
+        let request = StreamRequest { exclude_quality: vec![3], ..Default::default() };
+        assert!(StreamOptions::from_request(&request, Unit::Millimeters).is_err());
+    }
+
+    #[test]
+    fn test_disconnected_client() {
+        let (mut client, rx) = client(StreamOptions::default());
+        drop(rx);
+        assert!(!client.offer(&reading(0, 1000.0), true));
+        assert!(!client.offer(&reading(0, 1000.0), false));
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn heartbeat() -> Result<ClientMessage, Status> {
+        Ok(ClientMessage {
+            message: Some(client_message::Message::Heartbeat(crate::snowgauge::Heartbeat {})),
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_heartbeats_keep_stream_open() {
+        let (inbound_tx, inbound_rx) = mpsc::unbounded_channel();
+        let (readings_tx, mut readings_rx) = queue::bounded(16, OverflowPolicy::DropOldest);
+        let timeout = Duration::from_secs(30);
+        let task = tokio::spawn(watch_heartbeats(
+            tokio_stream::wrappers::UnboundedReceiverStream::new(inbound_rx),
+            readings_rx.closer(),
+            timeout,
+        ));
+
+        for i in 0..5 {
+            time::sleep(Duration::from_secs(20)).await;
+            inbound_tx.send(heartbeat()).unwrap();
+            readings_tx.send(Ok(reading(i, 1000.0)));
+        }
+        time::sleep(Duration::from_secs(1)).await;
+        for _ in 0..5 {
+            assert!(readings_rx.try_recv().unwrap().is_ok());
+        }
+        assert!(!task.is_finished());
+
+        // Heartbeats stop: the stream is ended once the timeout passes
+        time::sleep(Duration::from_secs(31)).await;
+        assert_eq!(task.await.unwrap(), Disconnect::HeartbeatTimeout);
+        assert_eq!(readings_rx.recv().await.unwrap().unwrap_err().code(), tonic::Code::DeadlineExceeded);
+        assert!(readings_rx.recv().await.is_none());
+        assert!(readings_tx.is_closed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_client_close_and_protocol_error() {
+        let (inbound_tx, inbound_rx) = mpsc::unbounded_channel::<Result<ClientMessage, Status>>();
+        let (readings_tx, mut readings_rx) = queue::bounded(16, OverflowPolicy::DropOldest);
+        drop(inbound_tx);
+        let ended = watch_heartbeats(
+            tokio_stream::wrappers::UnboundedReceiverStream::new(inbound_rx),
+            readings_rx.closer(),
+            Duration::from_secs(30),
+        )
+        .await;
+        assert_eq!(ended, Disconnect::Closed);
+        assert!(readings_tx.is_closed());
+        assert!(readings_rx.recv().await.is_none());
+
+        let (inbound_tx, inbound_rx) = mpsc::unbounded_channel();
+        let (_readings_tx, mut readings_rx) = queue::bounded::<Reading>(16, OverflowPolicy::DropOldest);
+        inbound_tx
+            .send(Ok(ClientMessage {
+                message: Some(client_message::Message::Subscribe(StreamRequest::default())),
+            }))
+            .unwrap();
+        let ended = watch_heartbeats(
+            tokio_stream::wrappers::UnboundedReceiverStream::new(inbound_rx),
+            readings_rx.closer(),
+            Duration::from_secs(30),
+        )
+        .await;
+        assert_eq!(ended, Disconnect::Protocol);
+        assert_eq!(readings_rx.recv().await.unwrap().unwrap_err().code(), tonic::Code::InvalidArgument);
+    }
+
+    #[test]
+    fn test_slow_client_disconnected() {
+        let (tx, mut rx) = queue::bounded(2, OverflowPolicy::Disconnect);
+        let mut client = StreamClient::new(tx, StreamOptions::default());
+        assert!(client.offer(&reading(0, 1000.0), false));
+        assert!(client.offer(&reading(1, 1000.0), false));
+        assert!(!client.offer(&reading(2, 1000.0), false));
+        assert!(rx.try_recv().unwrap().is_ok());
+        assert!(rx.try_recv().unwrap().is_ok());
+        assert_eq!(rx.try_recv().unwrap().unwrap_err().code(), tonic::Code::ResourceExhausted);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_coalesce_by_count_and_interval() {
+        let (readings_tx, readings_rx) = queue::bounded(16, OverflowPolicy::DropOldest);
+        let (out_tx, mut out_rx) = mpsc::channel(1);
+        let task = tokio::spawn(forward_coalesced(readings_rx, out_tx, 3, Duration::from_secs(60)));
+
+        for i in 0..4 {
+            readings_tx.send(Ok(reading(i, 1000.0)));
+        }
+        // Full after three
+        let batch = out_rx.recv().await.unwrap().unwrap();
+        assert_eq!(batch.readings.len(), 3);
+
+        // The fourth waits out the interval
+        time::sleep(Duration::from_secs(59)).await;
+        assert!(out_rx.try_recv().is_err());
+        time::sleep(Duration::from_secs(2)).await;
+        let batch = out_rx.recv().await.unwrap().unwrap();
+        assert_eq!(batch.readings.len(), 1);
+        assert_eq!(batch.readings[0].timestamp, reading(3, 1000.0).timestamp);
+
+        drop(readings_tx);
+        assert_eq!(task.await.unwrap(), Disconnect::Shutdown);
+    }
+
+    #[tokio::test]
+    async fn test_forward_with_events() {
+        let (readings_tx, readings_rx) = queue::bounded(16, OverflowPolicy::DropOldest);
+        let (events_tx, events_rx) = queue::bounded(16, OverflowPolicy::DropOldest);
+        let (out_tx, mut out_rx) = mpsc::channel(1);
+        let task = tokio::spawn(forward_with_events(readings_rx, events_rx, out_tx));
+
+        readings_tx.send(Ok(reading(0, 1000.0)));
+        let message = out_rx.recv().await.unwrap().unwrap();
+        assert!(matches!(message.payload, Some(stream_message::Payload::Reading(_))));
+        events_tx.send(Ok(Event { message: "filter reset".to_string(), ..Default::default() }));
+        let message = out_rx.recv().await.unwrap().unwrap();
+        assert!(matches!(message.payload, Some(stream_message::Payload::Event(e)) if e.message == "filter reset"));
+
+        // Readings carry on without any more events
+        drop(events_tx);
+        readings_tx.send(Ok(reading(1, 1000.0)));
+        assert!(out_rx.recv().await.unwrap().is_ok());
+
+        drop(out_rx);
+        readings_tx.send(Ok(reading(2, 1000.0)));
+        assert_eq!(task.await.unwrap(), Disconnect::Closed);
+    }
+
+    #[tokio::test]
+    async fn test_coalesce_waiting_readings() {
+        let (readings_tx, readings_rx) = queue::bounded(16, OverflowPolicy::DropOldest);
+        let (out_tx, mut out_rx) = mpsc::channel(1);
+        for i in 0..5 {
+            readings_tx.send(Ok(reading(i, 1000.0)));
+        }
+        drop(readings_tx);
+        let ended = forward_coalesced(readings_rx, out_tx, 0, Duration::ZERO).await;
+        assert_eq!(ended, Disconnect::Shutdown);
+        assert_eq!(out_rx.recv().await.unwrap().unwrap().readings.len(), 5);
+        assert!(out_rx.recv().await.is_none());
+    }
+}
@@ -0,0 +1,413 @@
+/// Supervision of long-running tasks
+///
+/// Each supervised task is built by a factory and run on its own tokio task,
+/// so a panic is caught rather than silently ending that part of the
+/// daemon. Depending on the restart policy the task is started again after a
+/// backoff. A task that crashes more than `max_restarts` times within
+/// `restart_window` is given up on, which turns the health check to
+/// NOT_SERVING so an orchestrator or watchdog can restart the whole process.
+use log::{error, info, warn};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RestartPolicy {
+    /// Never restart; a failure marks the daemon unhealthy
+    Never,
+    /// Restart after an error or panic
+    OnFailure,
+    /// Restart whenever the task ends before shutdown, even cleanly
+    Always,
+}
+
+impl std::str::FromStr for RestartPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "never" | "no" => Ok(RestartPolicy::Never),
+            "on-failure" | "onfailure" => Ok(RestartPolicy::OnFailure),
+            "always" => Ok(RestartPolicy::Always),
+            _ => Err(format!(
+                "Invalid restart policy '{}'. Valid options: never, on-failure, always",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for RestartPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RestartPolicy::Never => write!(f, "never"),
+            RestartPolicy::OnFailure => write!(f, "on-failure"),
+            RestartPolicy::Always => write!(f, "always"),
+        }
+    }
+}
+
+/// How one run of a task ended
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Completed,
+    Failed(String),
+    Panicked(String),
+}
+
+impl Outcome {
+    pub fn is_failure(&self) -> bool {
+        *self != Outcome::Completed
+    }
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Completed => write!(f, "completed"),
+            Outcome::Failed(e) => write!(f, "failed: {}", e),
+            Outcome::Panicked(e) => write!(f, "panicked: {}", e),
+        }
+    }
+}
+
+impl RestartPolicy {
+    pub fn restarts(&self, outcome: &Outcome) -> bool {
+        match self {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => outcome.is_failure(),
+            RestartPolicy::Always => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SupervisorConfig {
+    pub policy: RestartPolicy,
+    pub max_restarts: usize,
+    pub restart_window: Duration,
+}
+
+/// Restarts within the sliding window, to detect crash loops
+pub struct RestartBudget {
+    max_restarts: usize,
+    window: Duration,
+    restarts: VecDeque<Instant>,
+}
+
+impl RestartBudget {
+    pub fn new(max_restarts: usize, window: Duration) -> Self {
+        Self {
+            max_restarts,
+            window,
+            restarts: VecDeque::new(),
+        }
+    }
+
+    /// Record a restart at `now`; false if it exceeds the budget
+    pub fn record(&mut self, now: Instant) -> bool {
+        while self
+            .restarts
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > self.window)
+        {
+            self.restarts.pop_front();
+        }
+        if self.restarts.len() >= self.max_restarts {
+            return false;
+        }
+        self.restarts.push_back(now);
+        true
+    }
+
+    /// Backoff before the next restart, doubling with each recent restart
+    pub fn backoff(&self) -> Duration {
+        let exponent = self.restarts.len().saturating_sub(1).min(16) as u32;
+        (INITIAL_BACKOFF * 2u32.pow(exponent)).min(MAX_BACKOFF)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TaskState {
+    Running,
+    Restarting,
+    Stopped,
+    Failed,
+}
+
+#[derive(Debug, Clone)]
+pub struct TaskStatus {
+    pub name: String,
+    pub state: TaskState,
+    pub restarts: u64,
+    pub last_error: Option<String>,
+}
+
+/// Called with a task's name and how it ended whenever it is restarted
+type RestartHook = Box<dyn Fn(&str, &str) + Send>;
+
+#[derive(Clone)]
+pub struct Supervisor {
+    config: SupervisorConfig,
+    cancel_token: CancellationToken,
+    tasks: Arc<Mutex<Vec<TaskStatus>>>,
+    healthy: Arc<watch::Sender<bool>>,
+    on_restart: Arc<Mutex<Option<RestartHook>>>,
+}
+
+impl Supervisor {
+    pub fn new(config: SupervisorConfig, cancel_token: CancellationToken) -> Self {
+        Self {
+            config,
+            cancel_token,
+            tasks: Arc::new(Mutex::new(Vec::new())),
+            healthy: Arc::new(watch::channel(true).0),
+            on_restart: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Call `hook` each time a task is restarted, replacing any earlier hook
+    pub fn on_restart(&self, hook: impl Fn(&str, &str) + Send + 'static) {
+        *self.on_restart.lock().unwrap() = Some(Box::new(hook));
+    }
+
+    /// Health of the daemon: false once any task has failed permanently
+    pub fn health(&self) -> watch::Receiver<bool> {
+        self.healthy.subscribe()
+    }
+
+    /// Run `factory`'s task under supervision until shutdown
+    ///
+    /// The factory is dropped when supervision ends, releasing anything it
+    /// holds (such as a channel sender) so downstream tasks can finish.
+    pub fn spawn<F, Fut>(&self, name: impl Into<String>, mut factory: F) -> JoinHandle<()>
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        let supervisor = self.clone();
+        let name = name.into();
+        let index = {
+            let mut tasks = self.tasks.lock().unwrap();
+            tasks.push(TaskStatus {
+                name: name.clone(),
+                state: TaskState::Running,
+                restarts: 0,
+                last_error: None,
+            });
+            tasks.len() - 1
+        };
+
+        tokio::spawn(async move {
+            let mut budget = RestartBudget::new(supervisor.config.max_restarts, supervisor.config.restart_window);
+
+            loop {
+                supervisor.update(index, |t| t.state = TaskState::Running);
+                let outcome = match tokio::spawn(factory()).await {
+                    Ok(Ok(())) => Outcome::Completed,
+                    Ok(Err(e)) => Outcome::Failed(e),
+                    Err(e) if e.is_panic() => Outcome::Panicked(panic_message(e.into_panic())),
+                    Err(e) => Outcome::Failed(e.to_string()),
+                };
+
+                if supervisor.cancel_token.is_cancelled() {
+                    if outcome.is_failure() {
+                        error!("{} {} during shutdown", name, outcome);
+                    }
+                    supervisor.update(index, |t| t.state = TaskState::Stopped);
+                    break;
+                }
+
+                if !supervisor.config.policy.restarts(&outcome) {
+                    if outcome.is_failure() {
+                        error!("{} {}; restart policy is {}, not restarting", name, outcome, supervisor.config.policy);
+                        supervisor.fail(index, &outcome);
+                    } else {
+                        info!("{} completed", name);
+                        supervisor.update(index, |t| t.state = TaskState::Stopped);
+                    }
+                    break;
+                }
+
+                if !budget.record(Instant::now()) {
+                    error!(
+                        "{} {}; restarted {} times within {:?}, giving up",
+                        name, outcome, supervisor.config.max_restarts, supervisor.config.restart_window
+                    );
+                    supervisor.fail(index, &outcome);
+                    break;
+                }
+
+                let backoff = budget.backoff();
+                warn!("{} {}; restarting in {:?}", name, outcome, backoff);
+                if let Some(hook) = supervisor.on_restart.lock().unwrap().as_ref() {
+                    hook(&name, &outcome.to_string());
+                }
+                supervisor.update(index, |t| {
+                    t.state = TaskState::Restarting;
+                    t.restarts += 1;
+                    if outcome.is_failure() {
+                        t.last_error = Some(outcome.to_string());
+                    }
+                });
+
+                tokio::select! {
+                    _ = supervisor.cancel_token.cancelled() => {
+                        supervisor.update(index, |t| t.state = TaskState::Stopped);
+                        break;
+                    }
+                    _ = tokio::time::sleep(backoff) => {}
+                }
+            }
+        })
+    }
+
+    /// Snapshot of every supervised task
+    pub fn tasks(&self) -> Vec<TaskStatus> {
+        self.tasks.lock().unwrap().clone()
+    }
+
+    fn update(&self, index: usize, f: impl FnOnce(&mut TaskStatus)) {
+        f(&mut self.tasks.lock().unwrap()[index]);
+    }
+
+    fn fail(&self, index: usize, outcome: &Outcome) {
+        self.update(index, |t| {
+            t.state = TaskState::Failed;
+            t.last_error = Some(outcome.to_string());
+        });
+        self.healthy.send_replace(false);
+    }
+}
+
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn config(policy: RestartPolicy, max_restarts: usize) -> SupervisorConfig {
+        SupervisorConfig {
+            policy,
+            max_restarts,
+            restart_window: Duration::from_secs(300),
+        }
+    }
+
+    #[test]
+    fn test_policy() {
+        let failed = Outcome::Failed("port closed".to_string());
+        assert!(!RestartPolicy::Never.restarts(&failed));
+        assert!(RestartPolicy::OnFailure.restarts(&failed));
+        assert!(RestartPolicy::OnFailure.restarts(&Outcome::Panicked("boom".to_string())));
+        assert!(!RestartPolicy::OnFailure.restarts(&Outcome::Completed));
+        assert!(RestartPolicy::Always.restarts(&Outcome::Completed));
+
+        assert_eq!("on-failure".parse::<RestartPolicy>(), Ok(RestartPolicy::OnFailure));
+        assert!("sometimes".parse::<RestartPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_restart_budget() {
+        let start = Instant::now();
+        let mut budget = RestartBudget::new(3, Duration::from_secs(60));
+        assert!(budget.record(start));
+        assert_eq!(budget.backoff(), Duration::from_secs(1));
+        assert!(budget.record(start + Duration::from_secs(1)));
+        assert_eq!(budget.backoff(), Duration::from_secs(2));
+        assert!(budget.record(start + Duration::from_secs(2)));
+        assert!(!budget.record(start + Duration::from_secs(3)));
+
+        // Old restarts fall out of the window
+        assert!(budget.record(start + Duration::from_secs(62)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_restarts_panicking_task() {
+        let supervisor = Supervisor::new(config(RestartPolicy::OnFailure, 5), CancellationToken::new());
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        let counter = Arc::clone(&runs);
+        let handle = supervisor.spawn("flaky", move || {
+            let run = counter.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if run < 2 {
+                    panic!("run {} crashed", run);
+                }
+                Ok(())
+            }
+        });
+        handle.await.unwrap();
+
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        let status = &supervisor.tasks()[0];
+        assert_eq!(status.state, TaskState::Stopped);
+        assert_eq!(status.restarts, 2);
+        assert_eq!(status.last_error.as_deref(), Some("panicked: run 1 crashed"));
+        assert!(*supervisor.health().borrow());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_crash_loop_fails_health() {
+        let supervisor = Supervisor::new(config(RestartPolicy::Always, 2), CancellationToken::new());
+        let health = supervisor.health();
+        let restarts = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&restarts);
+        supervisor.on_restart(move |name, outcome| seen.lock().unwrap().push(format!("{}: {}", name, outcome)));
+
+        let handle = supervisor.spawn("broken", || async { Err("cannot open port".to_string()) });
+        handle.await.unwrap();
+
+        let status = &supervisor.tasks()[0];
+        assert_eq!(status.state, TaskState::Failed);
+        assert_eq!(status.restarts, 2);
+        assert!(!*health.borrow());
+        // Giving up is not a restart
+        assert_eq!(restarts.lock().unwrap().len(), 2);
+        assert_eq!(restarts.lock().unwrap()[0], "broken: failed: cannot open port");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_never_policy() {
+        let supervisor = Supervisor::new(config(RestartPolicy::Never, 5), CancellationToken::new());
+        supervisor.spawn("once", || async { Err("fatal".to_string()) }).await.unwrap();
+        assert_eq!(supervisor.tasks()[0].state, TaskState::Failed);
+        assert!(!*supervisor.health().borrow());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cancel_stops_supervision() {
+        let cancel_token = CancellationToken::new();
+        let supervisor = Supervisor::new(config(RestartPolicy::Always, 100), cancel_token.clone());
+
+        let token = cancel_token.clone();
+        let handle = supervisor.spawn("worker", move || {
+            let token = token.clone();
+            async move {
+                token.cancelled().await;
+                Ok(())
+            }
+        });
+        cancel_token.cancel();
+        handle.await.unwrap();
+
+        assert_eq!(supervisor.tasks()[0].state, TaskState::Stopped);
+        assert_eq!(supervisor.tasks()[0].restarts, 0);
+    }
+}
@@ -0,0 +1,358 @@
+/// Daily, monthly and season-to-date snowfall totals
+///
+/// Totals count snow that fell, not the change in depth: each batch
+/// reading adds the positive snowfall rate over the time since the last
+/// one. Settling and melt, which the settling detector holds at a 0 rate,
+/// and falls in depth such as a cleared board don't take anything off.
+/// The rate is fitted over the stored readings either side of a dropout, so
+/// a gap is counted at the rate across it: the snow that fell meanwhile.
+/// With an `Interpolation`, a gap of up to the maximum is counted instead at
+/// the last rate before it (hold) or the mean of the rates either side
+/// (linear), for when the fit across it is skewed or missing.
+///
+/// Totals are kept per local day, with months and seasons summed from the
+/// days. A season starts on the first of `season_start_month`. The snow
+/// from each reading over the last day is kept too, for the rolling
+/// accumulation over the windows avalanche forecasters use.
+use std::collections::{BTreeMap, VecDeque};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::interpolate::{Interpolation, Spacing};
+use crate::schedule;
+
+/// Days kept, for this season and all of the last
+const MAX_DAYS: usize = 800;
+
+/// Rolling accumulation windows: 1, 3, 6, 12 and 24 hours
+pub const WINDOWS: [Duration; 5] = [
+    Duration::from_secs(3600),
+    Duration::from_secs(3 * 3600),
+    Duration::from_secs(6 * 3600),
+    Duration::from_secs(12 * 3600),
+    Duration::from_secs(24 * 3600),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Date {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+}
+
+impl Date {
+    pub fn new(year: i32, month: u32, day: u32) -> Self {
+        Self { year, month, day }
+    }
+
+    /// Local calendar date of `time`
+    pub fn local(time: SystemTime) -> Self {
+        let (year, month, day) = schedule::local_date(time);
+        Self::new(year, month, day)
+    }
+}
+
+impl std::fmt::Display for Date {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+impl std::str::FromStr for Date {
+    type Err = String;
+
+    /// Parse `YYYY-MM-DD`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid date '{}'. Expected YYYY-MM-DD", s);
+        let mut parts = s.splitn(3, '-');
+        let mut next = || parts.next().and_then(|p| p.parse::<u32>().ok()).ok_or_else(invalid);
+        let (year, month, day) = (next()?, next()?, next()?);
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+            return Err(invalid());
+        }
+        Ok(Self::new(year as i32, month, day))
+    }
+}
+
+/// On-disk representation
+#[derive(Debug, Serialize, Deserialize)]
+struct TotalsFile {
+    /// New snow in mm by `YYYY-MM-DD`
+    days: BTreeMap<String, f64>,
+}
+
+#[derive(Debug)]
+pub struct Totals {
+    season_start_month: u32,
+    /// New snow in mm by local day
+    days: BTreeMap<Date, f64>,
+    last: Option<SystemTime>,
+    /// Snow added by each reading over the longest window, oldest first
+    recent: VecDeque<(SystemTime, f64)>,
+    /// First reading since startup, before which the recent snow is unknown
+    since: Option<SystemTime>,
+    interpolation: Interpolation,
+    max_gap: Duration,
+    spacing: Spacing,
+    /// Rate at the last reading
+    last_rate: Option<f64>,
+}
+
+impl Totals {
+    pub fn new(season_start_month: u32) -> Self {
+        Self {
+            season_start_month,
+            days: BTreeMap::new(),
+            last: None,
+            recent: VecDeque::new(),
+            since: None,
+            interpolation: Interpolation::None,
+            max_gap: Duration::ZERO,
+            spacing: Spacing::default(),
+            last_rate: None,
+        }
+    }
+
+    /// Bridge gaps of up to `max_gap` in the batch readings by `interpolation`
+    pub fn set_interpolation(&mut self, interpolation: Interpolation, max_gap: Duration) {
+        self.interpolation = interpolation;
+        self.max_gap = max_gap;
+    }
+
+    /// How gaps are bridged, and the longest bridged
+    pub fn interpolation(&self) -> (Interpolation, Duration) {
+        (self.interpolation, self.max_gap)
+    }
+
+    /// Start the series over, for a deliberate break such as a pause or an
+    /// idle schedule period, which is never bridged
+    pub fn interrupt(&mut self) {
+        self.spacing.reset();
+        self.last_rate = None;
+    }
+
+    /// Add the snow that fell up to a batch reading at `now`, on local day
+    /// `date`, at `rate` mm/hr; an unknown or negative rate adds nothing
+    pub fn update(&mut self, now: SystemTime, date: Date, rate: Option<f64>) {
+        let elapsed = self.last.replace(now).and_then(|last| now.duration_since(last).ok());
+        self.since.get_or_insert(now);
+        let gap = elapsed.is_some_and(|elapsed| self.spacing.gap(elapsed).is_some() && elapsed <= self.max_gap);
+        let last_rate = std::mem::replace(&mut self.last_rate, rate);
+        let rate = match self.interpolation {
+            Interpolation::Hold if gap => last_rate.or(rate),
+            Interpolation::Linear if gap => match (last_rate, rate) {
+                (Some(before), Some(after)) => Some((before + after) / 2.0),
+                (before, after) => before.or(after),
+            },
+            _ => rate,
+        };
+        let total = self.days.entry(date).or_insert(0.0);
+        if let (Some(rate), Some(elapsed)) = (rate, elapsed) {
+            if rate > 0.0 {
+                let snow = rate * elapsed.as_secs_f64() / 3600.0;
+                *total += snow;
+                self.recent.push_back((now, snow));
+            }
+        }
+        while self.days.len() > MAX_DAYS {
+            self.days.pop_first();
+        }
+        let longest = WINDOWS[WINDOWS.len() - 1];
+        while self.recent.front().is_some_and(|&(at, _)| now.duration_since(at).unwrap_or_default() >= longest) {
+            self.recent.pop_front();
+        }
+    }
+
+    /// New snow in mm over the `window` up to `now`, or None if it goes back
+    /// before the first reading since startup
+    pub fn recent(&self, now: SystemTime, window: Duration) -> Option<f64> {
+        let start = now.checked_sub(window)?;
+        if self.since.is_none_or(|since| since > start) {
+            return None;
+        }
+        Some(self.recent.iter().filter(|&&(at, _)| at > start).map(|(_, snow)| snow).sum())
+    }
+
+    /// New snow in mm on `date`
+    pub fn day(&self, date: Date) -> f64 {
+        self.days.get(&date).copied().unwrap_or_default()
+    }
+
+    /// New snow in mm in the month of `date`
+    pub fn month(&self, date: Date) -> f64 {
+        let start = Date::new(date.year, date.month, 1);
+        self.days.range(start..=Date::new(date.year, date.month, 31)).map(|(_, total)| total).sum()
+    }
+
+    /// First day of the season `date` falls in
+    pub fn season_start(&self, date: Date) -> Date {
+        let year = if date.month >= self.season_start_month { date.year } else { date.year - 1 };
+        Date::new(year, self.season_start_month, 1)
+    }
+
+    /// New snow in mm from the start of the season up to and including `date`
+    pub fn season(&self, date: Date) -> f64 {
+        self.days.range(self.season_start(date)..=date).map(|(_, total)| total).sum()
+    }
+
+    /// Daily totals from `start` on, oldest first
+    pub fn days(&self, start: Date) -> Vec<(Date, f64)> {
+        self.days.range(start..).map(|(date, total)| (*date, *total)).collect()
+    }
+
+    /// Monthly totals from the month of `start` on, oldest first, dated the
+    /// first of the month
+    pub fn months(&self, start: Date) -> Vec<(Date, f64)> {
+        let mut months: Vec<(Date, f64)> = Vec::new();
+        for (date, total) in self.days.range(Date::new(start.year, start.month, 1)..) {
+            let month = Date::new(date.year, date.month, 1);
+            match months.last_mut() {
+                Some((last, sum)) if *last == month => *sum += total,
+                _ => months.push((month, *total)),
+            }
+        }
+        months
+    }
+
+    pub fn to_json(&self) -> String {
+        let file = TotalsFile { days: self.days.iter().map(|(date, total)| (date.to_string(), *total)).collect() };
+        // Serializing plain numbers cannot fail
+        serde_json::to_string_pretty(&file).unwrap() + "\n"
+    }
+
+    pub fn from_json(json: &str, season_start_month: u32) -> Result<Self, String> {
+        let file: TotalsFile = serde_json::from_str(json).map_err(|e| format!("invalid totals: {}", e))?;
+        let mut totals = Self::new(season_start_month);
+        for (date, total) in file.days {
+            totals.days.insert(date.parse()?, total);
+        }
+        Ok(totals)
+    }
+
+    pub fn load(path: &Path, season_start_month: u32) -> Result<Self, String> {
+        let json = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read totals {}: {}", path.display(), e))?;
+        Self::from_json(&json, season_start_month).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Write the totals, replacing any existing file atomically
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, self.to_json())
+            .and_then(|_| std::fs::rename(&tmp, path))
+            .map_err(|e| format!("failed to write totals {}: {}", path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn at(minutes: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(minutes * 60)
+    }
+
+    #[test]
+    fn test_accumulates_positive_rate() {
+        let mut totals = Totals::new(7);
+        let day = Date::new(2024, 12, 31);
+        // The first reading, and readings without a rate, only start the clock
+        totals.update(at(0), day, Some(10.0));
+        totals.update(at(30), day, None);
+        totals.update(at(60), day, Some(4.0));
+        assert_eq!(totals.day(day), 2.0);
+        // Settling and melt don't subtract
+        totals.update(at(90), day, Some(-3.0));
+        totals.update(at(120), day, Some(0.0));
+        assert_eq!(totals.day(day), 2.0);
+
+        let next = Date::new(2025, 1, 1);
+        totals.update(at(150), next, Some(6.0));
+        assert_eq!(totals.day(next), 3.0);
+        assert_eq!(totals.month(day), 2.0);
+        assert_eq!(totals.month(next), 3.0);
+        assert_eq!(totals.season(next), 5.0);
+        assert_eq!(totals.months(day), vec![(Date::new(2024, 12, 1), 2.0), (Date::new(2025, 1, 1), 3.0)]);
+        assert_eq!(totals.days(next), vec![(next, 3.0)]);
+    }
+
+    #[test]
+    fn test_recent() {
+        let mut totals = Totals::new(7);
+        let day = Date::new(2025, 1, 10);
+        totals.update(at(60), day, None);
+        assert_eq!(totals.recent(at(60), WINDOWS[0]), None);
+        totals.update(at(120), day, Some(6.0));
+        assert_eq!(totals.recent(at(120), WINDOWS[0]), Some(6.0));
+        assert_eq!(totals.recent(at(120), WINDOWS[1]), None);
+        totals.update(at(240), day, Some(1.5));
+        assert_eq!(totals.recent(at(240), WINDOWS[0]), Some(3.0));
+        assert_eq!(totals.recent(at(240), WINDOWS[1]), Some(9.0));
+        // Snow falls out of the windows as they move on
+        totals.update(at(60 + 24 * 60), day, Some(0.0));
+        assert_eq!(totals.recent(at(60 + 24 * 60), WINDOWS[4]), Some(9.0));
+        totals.update(at(121 + 24 * 60), day, Some(0.0));
+        assert_eq!(totals.recent(at(121 + 24 * 60), WINDOWS[4]), Some(3.0));
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let day = Date::new(2025, 1, 10);
+        let run = |interpolation, max_gap_minutes: u64| {
+            let mut totals = Totals::new(7);
+            totals.set_interpolation(interpolation, Duration::from_secs(max_gap_minutes * 60));
+            totals.update(at(0), day, Some(2.0));
+            totals.update(at(30), day, Some(2.0));
+            totals.update(at(60), day, Some(2.0));
+            // A 60 minute gap, ended by a reading at 8 mm/hr
+            totals.update(at(120), day, Some(8.0));
+            totals.day(day)
+        };
+        assert_eq!(run(Interpolation::None, 60), 10.0);
+        assert_eq!(run(Interpolation::Hold, 60), 4.0);
+        assert_eq!(run(Interpolation::Linear, 60), 7.0);
+        // Just over the maximum the gap counts at the rate fitted across it
+        assert_eq!(run(Interpolation::Hold, 59), 10.0);
+        assert_eq!(run(Interpolation::Linear, 59), 10.0);
+
+        // A gap after a reading without a rate counts at the rate after it
+        let mut totals = Totals::new(7);
+        totals.set_interpolation(Interpolation::Hold, Duration::from_secs(3600));
+        totals.update(at(0), day, None);
+        totals.update(at(30), day, None);
+        totals.update(at(90), day, Some(4.0));
+        assert_eq!(totals.day(day), 4.0);
+        // Nothing is bridged across a deliberate break
+        totals.interrupt();
+        totals.update(at(150), day, Some(1.0));
+        assert_eq!(totals.day(day), 5.0);
+    }
+
+    #[test]
+    fn test_season_start() {
+        let mut totals = Totals::new(7);
+        assert_eq!(totals.season_start(Date::new(2025, 3, 1)), Date::new(2024, 7, 1));
+        assert_eq!(totals.season_start(Date::new(2025, 7, 1)), Date::new(2025, 7, 1));
+        totals.update(at(0), Date::new(2025, 6, 30), None);
+        totals.update(at(60), Date::new(2025, 6, 30), Some(1.0));
+        totals.update(at(120), Date::new(2025, 7, 1), Some(2.0));
+        assert_eq!(totals.season(Date::new(2025, 6, 30)), 1.0);
+        assert_eq!(totals.season(Date::new(2025, 7, 1)), 2.0);
+    }
+
+    #[test]
+    fn test_json_roundtrip() {
+        let mut totals = Totals::new(10);
+        totals.update(at(0), Date::new(2024, 11, 2), None);
+        totals.update(at(60), Date::new(2024, 11, 2), Some(12.5));
+        let restored = Totals::from_json(&totals.to_json(), 10).unwrap();
+        assert_eq!(restored.day(Date::new(2024, 11, 2)), 12.5);
+        assert!(Totals::from_json(r#"{"days": {"2024-13-01": 1.0}}"#, 10).is_err());
+        assert_eq!("2024-02-09".parse(), Ok(Date::new(2024, 2, 9)));
+        assert_eq!(Date::new(2024, 2, 9).to_string(), "2024-02-09");
+    }
+}
@@ -0,0 +1,204 @@
+/// W3C Trace Context (`traceparent`) support
+///
+/// Incoming RPCs carrying a `traceparent` header get a child span of the
+/// caller's trace; other RPCs start a new trace. What a call causes gets a
+/// span in the call's trace: the amendment it records, its events, and the
+/// next batch reading after it changes how readings are made. Other
+/// readings start their own trace, which their events continue. Each
+/// reading's context is carried in its `traceparent` field, and continued
+/// in the CoAP and LoRa messages sent for it, so it can be followed through
+/// consumers that propagate it further.
+use std::fmt;
+
+use rand::Rng;
+use tonic::{Request, Status};
+
+pub const TRACEPARENT: &str = "traceparent";
+
+/// Sampled flag in `trace_flags`
+const SAMPLED: u8 = 0x01;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub trace_flags: u8,
+}
+
+impl TraceContext {
+    /// Start a new sampled trace
+    pub fn new_root() -> Self {
+        let mut rng = rand::thread_rng();
+        Self {
+            trace_id: nonzero(|| rng.gen()),
+            span_id: nonzero(|| rng.gen()),
+            trace_flags: SAMPLED,
+        }
+    }
+
+    /// A new span in the same trace
+    pub fn child(&self) -> Self {
+        let mut rng = rand::thread_rng();
+        Self {
+            span_id: nonzero(|| rng.gen()),
+            ..*self
+        }
+    }
+
+    /// Parse a `traceparent` header value, or None if it is not valid
+    ///
+    /// Versions other than 00 are accepted if they start with the version
+    /// 00 fields, as the specification requires.
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.trim().split('-');
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let span_id = parts.next()?;
+        let flags = parts.next()?;
+        let rest = parts.next();
+
+        if version.len() != 2 || version.eq_ignore_ascii_case("ff") || trace_id.len() != 32 || span_id.len() != 16 || flags.len() != 2 {
+            return None;
+        }
+        if version == "00" && rest.is_some() {
+            return None;
+        }
+
+        let context = Self {
+            trace_id: decode_hex(trace_id)?,
+            span_id: decode_hex(span_id)?,
+            trace_flags: decode_hex::<1>(flags)?[0],
+        };
+        decode_hex::<1>(version)?;
+        if context.trace_id == [0; 16] || context.span_id == [0; 8] {
+            return None;
+        }
+        Some(context)
+    }
+
+    pub fn trace_id_hex(&self) -> String {
+        encode_hex(&self.trace_id)
+    }
+}
+
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "00-{}-{}-{:02x}", encode_hex(&self.trace_id), encode_hex(&self.span_id), self.trace_flags)
+    }
+}
+
+/// gRPC interceptor attaching the request's server span to its extensions
+///
+/// A missing or malformed `traceparent` starts a new trace rather than
+/// failing the request.
+#[allow(clippy::result_large_err)]
+pub fn interceptor(mut request: Request<()>) -> Result<Request<()>, Status> {
+    let context = request
+        .metadata()
+        .get(TRACEPARENT)
+        .and_then(|v| v.to_str().ok())
+        .and_then(TraceContext::parse)
+        .map(|parent| parent.child())
+        .unwrap_or_else(TraceContext::new_root);
+    request.extensions_mut().insert(context);
+    Ok(request)
+}
+
+/// The server span of an intercepted request
+pub fn current<T>(request: &Request<T>) -> TraceContext {
+    request
+        .extensions()
+        .get::<TraceContext>()
+        .copied()
+        .unwrap_or_else(TraceContext::new_root)
+}
+
+/// Draw random IDs until one is not all zeros (which is invalid)
+fn nonzero<const N: usize>(mut draw: impl FnMut() -> [u8; N]) -> [u8; N] {
+    loop {
+        let id = draw();
+        if id != [0; N] {
+            return id;
+        }
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decode lowercase hex, as the specification requires
+fn decode_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != N * 2 || !hex.bytes().all(|c| c.is_ascii_digit() || (b'a'..=b'f').contains(&c)) {
+        return None;
+    }
+    let mut out = [0u8; N];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXAMPLE: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn test_parse_and_format() {
+        let context = TraceContext::parse(EXAMPLE).unwrap();
+        assert_eq!(context.trace_id_hex(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(context.span_id, [0x00, 0xf0, 0x67, 0xaa, 0x0b, 0xa9, 0x02, 0xb7]);
+        assert_eq!(context.trace_flags, 0x01);
+        assert_eq!(context.to_string(), EXAMPLE);
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid() {
+        for value in [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "0g-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        ] {
+            assert!(TraceContext::parse(value).is_none(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_future_version_accepted() {
+        let value = "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra";
+        assert!(TraceContext::parse(value).is_some());
+    }
+
+    #[test]
+    fn test_child_keeps_trace() {
+        let parent = TraceContext::parse(EXAMPLE).unwrap();
+        let child = parent.child();
+        assert_eq!(child.trace_id, parent.trace_id);
+        assert_eq!(child.trace_flags, parent.trace_flags);
+        assert_ne!(child.span_id, parent.span_id);
+    }
+
+    #[test]
+    fn test_interceptor() {
+        let mut request = Request::new(());
+        request.metadata_mut().insert(TRACEPARENT, EXAMPLE.parse().unwrap());
+        let request = interceptor(request).unwrap();
+        let context = current(&request);
+        assert_eq!(context.trace_id_hex(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_ne!(context.to_string(), EXAMPLE);
+
+        let mut request = Request::new(());
+        request.metadata_mut().insert(TRACEPARENT, "garbage".parse().unwrap());
+        let context = current(&interceptor(request).unwrap());
+        assert_ne!(context.trace_id_hex(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(context.trace_flags, SAMPLED);
+    }
+}
@@ -0,0 +1,230 @@
+/// Robust trend estimation over a stored series
+///
+/// Uses the Theil–Sen estimator: the slope is the median of all pairwise
+/// slopes, so a few spikes or a cleared board have little influence, and
+/// the confidence interval comes from the ranks of those pairwise slopes
+/// (Sen, 1968) without assuming normally distributed noise.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fit {
+    /// Slope in y units per x unit
+    pub slope: f64,
+    /// Fitted value at x = 0
+    pub intercept: f64,
+    /// 95% confidence bounds on the slope
+    pub slope_lower: f64,
+    pub slope_upper: f64,
+    /// Number of points used
+    pub samples: usize,
+}
+
+/// Fits over more points than this use an evenly spaced subset, bounding
+/// the number of pairwise slopes
+pub const MAX_POINTS: usize = 1000;
+
+/// Two-sided 95% normal quantile
+const Z_95: f64 = 1.96;
+
+impl Fit {
+    pub fn value_at(&self, x: f64) -> f64 {
+        self.intercept + self.slope * x
+    }
+}
+
+/// Fit a Theil–Sen line to `(x, y)` points, or None with fewer than 3 distinct x
+pub fn theil_sen(points: &[(f64, f64)]) -> Option<Fit> {
+    let points: Vec<(f64, f64)> = if points.len() > MAX_POINTS {
+        (0..MAX_POINTS)
+            .map(|i| points[i * (points.len() - 1) / (MAX_POINTS - 1)])
+            .collect()
+    } else {
+        points.to_vec()
+    };
+    let n = points.len();
+
+    let mut slopes = Vec::with_capacity(n * n.saturating_sub(1) / 2);
+    for i in 0..n {
+        for j in i + 1..n {
+            let dx = points[j].0 - points[i].0;
+            if dx != 0.0 {
+                slopes.push((points[j].1 - points[i].1) / dx);
+            }
+        }
+    }
+    if n < 3 || slopes.len() < 2 {
+        return None;
+    }
+    slopes.sort_by(|a, b| a.total_cmp(b));
+    let slope = median(&slopes);
+
+    let mut residuals: Vec<f64> = points.iter().map(|(x, y)| y - slope * x).collect();
+    residuals.sort_by(|a, b| a.total_cmp(b));
+    let intercept = median(&residuals);
+
+    // Rank bounds of the pairwise slopes for the confidence interval
+    let pairs = slopes.len() as f64;
+    let nf = n as f64;
+    let c = Z_95 * (nf * (nf - 1.0) * (2.0 * nf + 5.0) / 18.0).sqrt();
+    let lower_rank = ((pairs - c) / 2.0).floor().max(0.0) as usize;
+    let upper_rank = (((pairs + c) / 2.0).ceil() as usize).min(slopes.len() - 1);
+
+    Some(Fit {
+        slope,
+        intercept,
+        slope_lower: slopes[lower_rank],
+        slope_upper: slopes[upper_rank],
+        samples: n,
+    })
+}
+
+/// Which way the depth is moving
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Accumulating,
+    Settling,
+}
+
+/// Holds the direction of depth change until the depth has moved back by a
+/// threshold
+///
+/// A surface that is barely changing, settling between small snowfalls say,
+/// gives a rate that flips sign with every reading. The direction only
+/// reverses once the depth has come `threshold` mm back from the furthest it
+/// got in the current direction, and a rate against the current direction
+/// reads as 0 until then.
+#[derive(Debug, Clone)]
+pub struct DirectionHysteresis {
+    threshold: f64,
+    direction: Option<Direction>,
+    /// Depth the first reading was at until a direction is set, then the
+    /// furthest depth in the current direction
+    extreme: Option<f64>,
+}
+
+impl DirectionHysteresis {
+    pub fn new(threshold: f64) -> Self {
+        Self {
+            threshold,
+            direction: None,
+            extreme: None,
+        }
+    }
+
+    /// Record a depth (from any datum, larger is deeper), returning the
+    /// direction once the depth has moved by the threshold
+    pub fn update(&mut self, depth: f64) -> Option<Direction> {
+        let Some(extreme) = self.extreme else {
+            self.extreme = Some(depth);
+            return None;
+        };
+        let (extreme, direction) = match self.direction {
+            Some(Direction::Accumulating) if depth > extreme => (depth, Direction::Accumulating),
+            Some(Direction::Accumulating) if extreme - depth < self.threshold => (extreme, Direction::Accumulating),
+            Some(Direction::Settling) if depth < extreme => (depth, Direction::Settling),
+            Some(Direction::Settling) if depth - extreme < self.threshold => (extreme, Direction::Settling),
+            Some(Direction::Accumulating) => (depth, Direction::Settling),
+            Some(Direction::Settling) => (depth, Direction::Accumulating),
+            None if depth - extreme >= self.threshold => (depth, Direction::Accumulating),
+            None if extreme - depth >= self.threshold => (depth, Direction::Settling),
+            None => return None,
+        };
+        self.extreme = Some(extreme);
+        self.direction = Some(direction);
+        self.direction
+    }
+
+    /// The rate (positive for accumulation), or 0 if it is against the
+    /// current direction
+    pub fn apply(&self, rate: f64) -> f64 {
+        match self.direction {
+            Some(Direction::Accumulating) if rate < 0.0 => 0.0,
+            Some(Direction::Settling) if rate > 0.0 => 0.0,
+            _ => rate,
+        }
+    }
+}
+
+/// Median of a sorted, non-empty slice
+fn median(sorted: &[f64]) -> f64 {
+    let mid = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        (sorted[mid - 1] + sorted[mid]) / 2.0
+    } else {
+        sorted[mid]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exact_line() {
+        let points: Vec<(f64, f64)> = (0..10).map(|i| (i as f64, 1000.0 - 2.0 * i as f64)).collect();
+        let fit = theil_sen(&points).unwrap();
+        assert_eq!(fit.slope, -2.0);
+        assert_eq!(fit.intercept, 1000.0);
+        assert_eq!((fit.slope_lower, fit.slope_upper), (-2.0, -2.0));
+        assert_eq!(fit.value_at(20.0), 960.0);
+        assert_eq!(fit.samples, 10);
+    }
+
+    #[test]
+    fn test_outliers_rejected() {
+        let mut points: Vec<(f64, f64)> = (0..20).map(|i| (i as f64, 500.0 + i as f64)).collect();
+        points[5].1 = 5000.0;
+        points[12].1 = 0.0;
+        let fit = theil_sen(&points).unwrap();
+        assert!((fit.slope - 1.0).abs() < 1e-9);
+        assert!((fit.intercept - 500.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_confidence_interval_brackets_slope() {
+        // Deterministic noise of up to ±6 around slope 0.5
+        let points: Vec<(f64, f64)> = (0..30)
+            .map(|i| (i as f64, 0.5 * i as f64 + ((i * 7919) % 13) as f64 - 6.0))
+            .collect();
+        let fit = theil_sen(&points).unwrap();
+        assert!(fit.slope_lower < fit.slope_upper);
+        assert!(fit.slope_lower <= fit.slope && fit.slope <= fit.slope_upper);
+        assert!(fit.slope_lower > 0.0 && fit.slope_upper < 1.0);
+    }
+
+    #[test]
+    fn test_too_few_points() {
+        assert!(theil_sen(&[]).is_none());
+        assert!(theil_sen(&[(0.0, 1.0), (1.0, 2.0)]).is_none());
+        assert!(theil_sen(&[(0.0, 1.0), (0.0, 2.0), (0.0, 3.0)]).is_none());
+    }
+
+    #[test]
+    fn test_direction_hysteresis() {
+        let mut hysteresis = DirectionHysteresis::new(5.0);
+        assert_eq!(hysteresis.update(100.0), None);
+        assert_eq!(hysteresis.update(103.0), None);
+        assert_eq!(hysteresis.apply(-2.0), -2.0);
+        assert_eq!(hysteresis.update(105.0), Some(Direction::Accumulating));
+        assert_eq!(hysteresis.update(110.0), Some(Direction::Accumulating));
+
+        // Settling back by less than 5 mm from the peak doesn't reverse it
+        assert_eq!(hysteresis.update(106.0), Some(Direction::Accumulating));
+        assert_eq!(hysteresis.apply(-1.5), 0.0);
+        assert_eq!(hysteresis.apply(2.0), 2.0);
+        assert_eq!(hysteresis.update(105.0), Some(Direction::Settling));
+        assert_eq!(hysteresis.apply(-1.5), -1.5);
+        assert_eq!(hysteresis.apply(0.5), 0.0);
+
+        // Nor does a new low followed by a small rise
+        assert_eq!(hysteresis.update(101.0), Some(Direction::Settling));
+        assert_eq!(hysteresis.update(104.0), Some(Direction::Settling));
+        assert_eq!(hysteresis.update(106.0), Some(Direction::Accumulating));
+    }
+
+    #[test]
+    fn test_large_series_subsampled() {
+        let points: Vec<(f64, f64)> = (0..5000).map(|i| (i as f64, 3.0 * i as f64)).collect();
+        let fit = theil_sen(&points).unwrap();
+        assert_eq!(fit.samples, MAX_POINTS);
+        assert_eq!(fit.slope, 3.0);
+    }
+}
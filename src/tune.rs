@@ -0,0 +1,310 @@
+/// Offline filter tuning against recorded data
+///
+/// Candidate configurations are replayed through the same `Pipeline` the
+/// gauge runs, and each batch result is compared with a reference:
+///
+/// - manual observations, if given: each observation is compared with the
+///   last batch result the gauge would have reported at that time
+/// - otherwise a centered rolling median of the raw readings, which stands in
+///   for the true surface, so the score rewards outputs that are both smooth
+///   and not lagging behind it
+///
+/// Candidates are ranked by RMS error, which combines lag (bias) and noise.
+use crate::pipeline::{FilterConfig, Pipeline};
+use crate::sensor_filter::FilterType;
+
+/// Parameter values swept for each filter type
+const ALPHAS: [f64; 5] = [0.05, 0.1, 0.2, 0.3, 0.5];
+const RATE_LIMITS: [f64; 5] = [0.5, 1.0, 2.0, 5.0, 10.0];
+const TRIM_PERCENTAGES: [f64; 5] = [0.0, 0.1, 0.15, 0.25, 0.4];
+const BATCH_SIZES: [usize; 4] = [10, 20, 30, 60];
+const PROCESS_NOISES: [f64; 4] = [0.0001, 0.001, 0.01, 0.1];
+const MEDIAN_WINDOWS: [usize; 4] = [3, 5, 9, 15];
+const MODE_BIN_WIDTHS: [f64; 4] = [1.0, 2.0, 5.0, 10.0];
+const TREND_WINDOWS: [usize; 3] = [60, 120, 300];
+
+/// Half-width (seconds) of the rolling median used without observations
+const PROXY_HALF_WINDOW: f64 = 150.0;
+
+/// A `(unix seconds, distance mm)` point
+pub type Sample = (f64, f64);
+
+/// Error statistics of a candidate against the reference
+#[derive(Debug, Clone, PartialEq)]
+pub struct Score {
+    pub rmse: f64,
+    /// Mean error; positive means the filter reads longer than the reference
+    pub bias: f64,
+    /// Standard deviation of the error
+    pub noise: f64,
+    /// Reference points compared
+    pub matched: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub config: FilterConfig,
+    pub score: Score,
+}
+
+/// Parse `timestamp,distance` rows (unix seconds, mm)
+///
+/// Blank lines, `#` comments, a header row, and extra columns are ignored.
+/// Rows are returned in timestamp order.
+pub fn parse_csv(csv: &str) -> Result<Vec<Sample>, String> {
+    let mut samples = Vec::new();
+    for (i, line) in csv.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split(',').map(str::trim);
+        let timestamp = fields.next().unwrap_or_default().parse::<f64>();
+        let distance = fields.next().unwrap_or_default().parse::<f64>();
+        match (timestamp, distance) {
+            (Ok(t), Ok(d)) if t.is_finite() && d.is_finite() => samples.push((t, d)),
+            _ if samples.is_empty() && i == 0 => continue,
+            _ => return Err(format!("line {}: expected timestamp,distance, got '{}'", i + 1, line)),
+        }
+    }
+    samples.sort_by(|a, b| a.0.total_cmp(&b.0));
+    Ok(samples)
+}
+
+/// Configurations to sweep, keeping `init_period` fixed
+///
+/// Only the parameters a filter type uses are varied for it.
+pub fn candidates(filter_types: &[FilterType], init_period: usize) -> Vec<FilterConfig> {
+    let base = FilterConfig {
+        filter_type: FilterType::None,
+        init_period,
+        rate_limit: 1.0,
+        alpha: 0.2,
+        trim_percentage: 0.0,
+        batch_size: 30,
+        ..FilterConfig::default()
+    };
+    let mut configs = Vec::new();
+    for &filter_type in filter_types {
+        let exponential = [FilterType::Exponential, FilterType::Both].contains(&filter_type);
+        let trimmed = [FilterType::TrimmedMean, FilterType::Both].contains(&filter_type);
+        let alphas: &[f64] = if exponential { &ALPHAS } else { &[base.alpha] };
+        let rate_limits: &[f64] = if exponential { &RATE_LIMITS } else { &[base.rate_limit] };
+        let trims: &[f64] = if trimmed { &TRIM_PERCENTAGES } else { &[base.trim_percentage] };
+        let process_noises: &[f64] = if filter_type == FilterType::Kalman { &PROCESS_NOISES } else { &[base.process_noise] };
+        let median_windows: &[usize] = if filter_type == FilterType::Median { &MEDIAN_WINDOWS } else { &[base.median_window] };
+        let bin_widths: &[f64] = if filter_type == FilterType::Mode { &MODE_BIN_WIDTHS } else { &[base.mode_bin_width] };
+        let trend_windows: &[usize] = if filter_type == FilterType::Trend { &TREND_WINDOWS } else { &[base.trend_window] };
+        for &alpha in alphas {
+            for &rate_limit in rate_limits {
+                for &trim_percentage in trims {
+                    for &process_noise in process_noises {
+                        for &median_window in median_windows {
+                            for &mode_bin_width in bin_widths {
+                                for &trend_window in trend_windows {
+                                    for &batch_size in &BATCH_SIZES {
+                                        configs.push(FilterConfig {
+                                            filter_type,
+                                            alpha,
+                                            rate_limit,
+                                            trim_percentage,
+                                            batch_size,
+                                            process_noise,
+                                            median_window,
+                                            mode_bin_width,
+                                            trend_window,
+                                            ..base.clone()
+                                        });
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    configs
+}
+
+/// Replay raw samples through a pipeline, returning `(sample index, batch result)`
+fn replay(config: &FilterConfig, samples: &[Sample]) -> Vec<(usize, f64)> {
+    let mut pipeline = Pipeline::new(config.clone());
+    samples
+        .iter()
+        .enumerate()
+        .filter_map(|(i, &(_, distance))| pipeline.push(distance).1.map(|r| (i, r.average)))
+        .collect()
+}
+
+/// Centered rolling median of the raw readings at each sample
+fn rolling_median(samples: &[Sample]) -> Vec<f64> {
+    let mut window = Vec::new();
+    samples
+        .iter()
+        .map(|&(t, _)| {
+            let lo = samples.partition_point(|s| s.0 < t - PROXY_HALF_WINDOW);
+            let hi = samples.partition_point(|s| s.0 <= t + PROXY_HALF_WINDOW);
+            window.clear();
+            window.extend(samples[lo..hi].iter().map(|s| s.1));
+            window.sort_by(|a, b| a.total_cmp(b));
+            let mid = window.len() / 2;
+            if window.len().is_multiple_of(2) {
+                (window[mid - 1] + window[mid]) / 2.0
+            } else {
+                window[mid]
+            }
+        })
+        .collect()
+}
+
+fn score(errors: &[f64]) -> Option<Score> {
+    if errors.is_empty() {
+        return None;
+    }
+    let n = errors.len() as f64;
+    let bias = errors.iter().sum::<f64>() / n;
+    let mse = errors.iter().map(|e| e * e).sum::<f64>() / n;
+    Some(Score {
+        rmse: mse.sqrt(),
+        bias,
+        noise: (mse - bias * bias).max(0.0).sqrt(),
+        matched: errors.len(),
+    })
+}
+
+/// Score every configuration and return them best first
+///
+/// Configurations that produce no comparable output (e.g. a batch larger
+/// than the capture) are dropped.
+pub fn tune(configs: &[FilterConfig], samples: &[Sample], reference: Option<&[Sample]>) -> Vec<Candidate> {
+    let proxy = match reference {
+        Some(_) => Vec::new(),
+        None => rolling_median(samples),
+    };
+
+    let mut ranked: Vec<Candidate> = configs
+        .iter()
+        .filter_map(|config| {
+            let outputs = replay(config, samples);
+            let errors: Vec<f64> = match reference {
+                Some(observations) => observations
+                    .iter()
+                    .filter_map(|&(t, observed)| {
+                        // Last result reported at or before the observation
+                        let reported = outputs.partition_point(|&(i, _)| samples[i].0 <= t);
+                        reported.checked_sub(1).map(|k| outputs[k].1 - observed)
+                    })
+                    .collect(),
+                None => outputs.iter().map(|&(i, value)| value - proxy[i]).collect(),
+            };
+            score(&errors).map(|score| Candidate {
+                config: config.clone(),
+                score,
+            })
+        })
+        .collect();
+    ranked.sort_by(|a, b| a.score.rmse.total_cmp(&b.score.rmse));
+    ranked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One reading per second on a steady 2 mm/min accumulation with
+    /// deterministic noise and occasional spikes
+    fn capture() -> Vec<Sample> {
+        (0..3600)
+            .map(|i| {
+                let t = 1_700_000_000.0 + i as f64;
+                let noise = ((i * 7919) % 11) as f64 - 5.0;
+                let spike = if i % 97 == 0 { 400.0 } else { 0.0 };
+                (t, 2000.0 - i as f64 / 30.0 + noise + spike)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_csv() {
+        let csv = "timestamp,distance\n# comment\n\n1700000010,1500.5\n1700000000, 1501 ,extra\n";
+        assert_eq!(parse_csv(csv).unwrap(), vec![(1_700_000_000.0, 1501.0), (1_700_000_010.0, 1500.5)]);
+        assert!(parse_csv("1700000000,1500\nbad,row\n").is_err());
+        assert!(parse_csv("1700000000,1500\n1700000001\n").is_err());
+    }
+
+    #[test]
+    fn test_candidates_vary_relevant_parameters() {
+        let exponential = candidates(&[FilterType::Exponential], 40);
+        assert_eq!(exponential.len(), ALPHAS.len() * RATE_LIMITS.len() * BATCH_SIZES.len());
+        assert!(exponential.iter().all(|c| c.trim_percentage == 0.0 && c.init_period == 40));
+
+        let trimmed = candidates(&[FilterType::TrimmedMean], 40);
+        assert_eq!(trimmed.len(), TRIM_PERCENTAGES.len() * BATCH_SIZES.len());
+        assert!(candidates(&[FilterType::Both], 40).iter().all(|c| c.validate().is_ok()));
+
+        let kalman = candidates(&[FilterType::Kalman], 40);
+        assert_eq!(kalman.len(), PROCESS_NOISES.len() * BATCH_SIZES.len());
+        assert!(kalman.iter().all(|c| c.validate().is_ok()));
+
+        let median = candidates(&[FilterType::Median], 40);
+        assert_eq!(median.len(), MEDIAN_WINDOWS.len() * BATCH_SIZES.len());
+        assert!(median.iter().all(|c| c.validate().is_ok()));
+
+        let mode = candidates(&[FilterType::Mode], 40);
+        assert_eq!(mode.len(), MODE_BIN_WIDTHS.len() * BATCH_SIZES.len());
+        assert!(mode.iter().all(|c| c.validate().is_ok()));
+
+        let trend = candidates(&[FilterType::Trend], 40);
+        assert_eq!(trend.len(), TREND_WINDOWS.len() * BATCH_SIZES.len());
+        assert!(trend.iter().all(|c| c.validate().is_ok()));
+    }
+
+    #[test]
+    fn test_trimmed_mean_beats_no_filter_on_spikes() {
+        let samples = capture();
+        let configs = candidates(&[FilterType::None, FilterType::TrimmedMean], 40);
+        let ranked = tune(&configs, &samples, None);
+        assert_eq!(ranked.len(), configs.len());
+        assert_eq!(ranked[0].config.filter_type, FilterType::TrimmedMean);
+        assert!(ranked[0].config.trim_percentage > 0.0);
+        assert!(ranked.windows(2).all(|w| w[0].score.rmse <= w[1].score.rmse));
+    }
+
+    #[test]
+    fn test_reference_scoring() {
+        let samples = capture();
+        // Observations of the true surface every 10 minutes
+        let reference: Vec<Sample> = (1..6)
+            .map(|k| (1_700_000_000.0 + k as f64 * 600.0, 2000.0 - k as f64 * 20.0))
+            .collect();
+        let config = FilterConfig {
+            filter_type: FilterType::TrimmedMean,
+            init_period: 40,
+            rate_limit: 1.0,
+            alpha: 0.2,
+            trim_percentage: 0.15,
+            batch_size: 30,
+            ..FilterConfig::default()
+        };
+        let ranked = tune(&[config], &samples, Some(&reference));
+        let score = &ranked[0].score;
+        assert_eq!(score.matched, 5);
+        // A 30-reading batch reported up to 30 s late reads about 0.5 mm long per 15 s of lag
+        assert!(score.bias > 0.0 && score.bias < 2.0, "{:?}", score);
+    }
+
+    #[test]
+    fn test_config_without_output_dropped() {
+        let config = FilterConfig {
+            filter_type: FilterType::None,
+            init_period: 40,
+            rate_limit: 1.0,
+            alpha: 0.2,
+            trim_percentage: 0.0,
+            batch_size: 60,
+            ..FilterConfig::default()
+        };
+        assert!(tune(&[config], &capture()[..30], None).is_empty());
+    }
+}
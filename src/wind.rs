@@ -0,0 +1,91 @@
+/// Wind-aware batch handling
+///
+/// Blowing snow drifts through the beam in gusts, producing clusters of
+/// short readings that can outnumber a fixed trim. With a wind speed
+/// source, each batch collected while the wind was over the threshold is
+/// flagged `HIGH_WIND` and, with a trimmed-mean batch stage, trimmed by the
+/// wider high-wind percentage. The source is read every ten seconds, often
+/// enough to catch a gust without reading an anemometer file every reading.
+use std::time::{Duration, Instant};
+
+use crate::compensation::{AmbientSource, Sampler};
+
+/// Time between reads of the wind speed source
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindConfig {
+    /// Wind speed in m/s
+    pub source: AmbientSource,
+    /// Speed in m/s at and above which batches count as collected in high wind
+    pub threshold: f64,
+    /// Percentage trimmed from each end of a high-wind batch, if more than usual
+    pub trim_percentage: f64,
+}
+
+pub struct WindMonitor {
+    sampler: Sampler,
+    threshold: f64,
+    trim_percentage: f64,
+    speed: Option<f64>,
+    sampled_at: Option<Instant>,
+}
+
+impl WindMonitor {
+    pub fn new(config: WindConfig) -> Self {
+        Self {
+            sampler: Sampler::new("Wind speed", config.source),
+            threshold: config.threshold,
+            trim_percentage: config.trim_percentage,
+            speed: None,
+            sampled_at: None,
+        }
+    }
+
+    /// Read the source again if it is due, returning true while the wind is
+    /// over the threshold; an unreadable source counts as calm
+    pub fn update(&mut self, now: Instant) -> bool {
+        if self.sampled_at.is_none_or(|at| now.duration_since(at) >= SAMPLE_INTERVAL) {
+            self.sampled_at = Some(now);
+            self.speed = self.sampler.sample();
+        }
+        self.speed.is_some_and(|speed| speed >= self.threshold)
+    }
+
+    /// The last speed read, in m/s
+    pub fn speed(&self) -> Option<f64> {
+        self.speed
+    }
+
+    pub fn trim_percentage(&self) -> f64 {
+        self.trim_percentage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_update() {
+        let path = std::env::temp_dir().join(format!("snowgauge-wind-{}", std::process::id()));
+        let mut wind = WindMonitor::new(WindConfig {
+            source: AmbientSource::File(PathBuf::from(&path)),
+            threshold: 10.0,
+            trim_percentage: 0.3,
+        });
+        let start = Instant::now();
+        assert!(!wind.update(start));
+        assert_eq!(wind.speed(), None);
+
+        std::fs::write(&path, "12.4").unwrap();
+        assert!(!wind.update(start + Duration::from_secs(5)));
+        assert!(wind.update(start + SAMPLE_INTERVAL));
+        assert_eq!(wind.speed(), Some(12.4));
+
+        std::fs::write(&path, "3.1").unwrap();
+        assert!(!wind.update(start + SAMPLE_INTERVAL * 2));
+        std::fs::remove_file(&path).unwrap();
+    }
+}